@@ -1,46 +1,118 @@
 use std::cmp::{self, Ord};
+use std::time::Duration;
 
 use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
 
-use rulebook::{action, do_if_admin, random, sync_admin_if, PlayerId, RoomInfo, Store};
+use rulebook::schemars::JsonSchema;
+use rulebook::{
+    do_if_admin, forfeited_players, random, report_result, request_action_timed, sync_admin_if,
+    turn_limit, Action, GameInfo, Outcome, PlayerId, RoomInfo, Store,
+};
 
-rulebook::setup!(run);
+rulebook::setup!(
+    run,
+    GameInfo {
+        min_players: Some(1),
+        ..Default::default()
+    },
+    schema: State,
+    Guess,
+);
 
 fn run(room: &RoomInfo, store: &mut Store<State>) -> Result<()> {
-    let target = do_if_admin(|| random(1, 99));
+    let options: Option<Options> =
+        serde_json::from_str(room.options.get()).context("invalid room options")?;
+    let max = options.as_ref().and_then(|options| options.max);
+    let max = max.unwrap_or(DEFAULT_MAX);
+    let turn_ms = options.as_ref().and_then(|options| options.turn_ms);
+    let turn_deadline = Duration::from_millis(turn_ms.unwrap_or(DEFAULT_TURN_MS));
+    let target = do_if_admin(|| random(1, max));
 
-    loop {
-        let turn_player = store.get().turns[0].player;
-        let guess: i32 = action(turn_player, "Guess");
+    let outcome = turn_limit(MAX_TURNS, |_| {
+        // forfeited players leave the rotation for good
+        let forfeited = forfeited_players();
+        let is_out = |turn: &Turn| forfeited.contains(&turn.player);
+        if store.get().turns.iter().any(is_out) {
+            store.mutate(|s| s.turns.retain(|turn| !is_out(turn)));
+        }
+        let Some(turn_player) = store.get().turns.first().map(|turn| turn.player) else {
+            // everyone left, no one wins
+            return Ok(Some(None));
+        };
+
+        let guess = request_action_timed::<Guess>(turn_player, (), turn_deadline);
         store.mutate(|s| {
-            s.turns[0].guess = Some(guess);
+            s.turns[0].guess = guess;
             s.turns[0].result = None;
+            s.turns[0].timed_out = guess.is_none();
         });
+        let Some(guess) = guess else {
+            // the turn is skipped
+            store.mutate(|s| s.turns.rotate_left(1));
+            return Ok(None);
+        };
 
         let result: Ordering = sync_admin_if(room.players.clone(), || {
             Ord::cmp(&target.unwrap(), &guess).into()
         })
         .context("sync all result not received")?;
 
+        store.mutate(|s| s.turns[0].result = Some(result));
         match result {
-            Ordering::Equal => {
-                store.mutate(|s| {
-                    s.turns[0].result = Some(result);
-                    s.winner = Some(turn_player);
-                });
-                return Ok(());
+            Ordering::Equal => Ok(Some(Some(turn_player))),
+            _ => {
+                store.mutate(|s| s.turns.rotate_left(1));
+                Ok(None)
             }
-            _ => store.mutate(|s| {
-                s.turns[0].result = Some(result);
-                s.turns.rotate_left(1);
-            }),
         }
+    })?;
+
+    let winner = match outcome {
+        Outcome::Finished(winner) => winner,
+        Outcome::Draw => None,
+    };
+    if winner.is_some() {
+        store.mutate(|s| s.winner = winner);
     }
+    report_result(&GameResult { winner });
+
+    Ok(())
 }
 
-#[derive(Default, Serialize)]
+/// Reported once the game ends, `winner` is `null` if no one guessed it.
+#[derive(Debug, Serialize)]
+struct GameResult {
+    winner: Option<PlayerId>,
+}
+
+struct Guess;
+
+impl Action for Guess {
+    const NAME: &'static str = "guess";
+    type Param = ();
+    type Response = i32;
+}
+
+/// Game ends without a winner after this many guesses.
+const MAX_TURNS: u32 = 100;
+
+/// Upper bound of the target if the room doesn't set `max`.
+const DEFAULT_MAX: i32 = 99;
+
+/// Time a player has to guess if the room doesn't set `turn_ms`.
+const DEFAULT_TURN_MS: u64 = 30_000;
+
+/// Room options, e.g. `{"max": 999, "turn_ms": 10000}`.
+#[derive(Debug, Deserialize)]
+struct Options {
+    max: Option<i32>,
+    turn_ms: Option<u64>,
+}
+
+#[derive(Default, Serialize, JsonSchema)]
 #[serde(tag = "type")]
+#[schemars(crate = "rulebook::schemars")]
 struct State {
     turns: Vec<Turn>,
     winner: Option<PlayerId>,
@@ -48,6 +120,9 @@ struct State {
 
 impl rulebook::State for State {
     fn from_room_info(room_info: &RoomInfo) -> Self {
+        // players are in the order of their seats, so the first to join guesses first.
+        // A game which declares `GameInfo::roles` like `["first", "second"]` would
+        // look up `room_info.player_with_role("first")` instead, whatever color it is.
         State {
             turns: room_info
                 .players
@@ -56,6 +131,7 @@ impl rulebook::State for State {
                     player,
                     guess: None,
                     result: None,
+                    timed_out: false,
                 })
                 .collect(),
             winner: None,
@@ -63,14 +139,18 @@ impl rulebook::State for State {
     }
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, JsonSchema)]
+#[schemars(crate = "rulebook::schemars")]
 struct Turn {
     player: PlayerId,
     guess: Option<i32>,
     result: Option<Ordering>,
+    /// The player didn't guess in time on their last turn.
+    timed_out: bool,
 }
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, JsonSchema)]
+#[schemars(crate = "rulebook::schemars")]
 enum Ordering {
     Less,
     Equal,
@@ -1,16 +1,140 @@
-use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
 
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::value::RawValue;
+use serde_json::Value;
+
+/// Version of the guest ABI described in `docs/abi.md`.
+///
+/// Games report it by exporting `rulebook_abi_version`.
+/// It's bumped on every incompatible change of the host functions, exports or `IoParams` layout.
+pub const ABI_VERSION: u32 = 2;
+
+/// Version of the protocol between the server and clients,
+/// the frames of `Channel` and the JSON exchanged for each `Output`.
+///
+/// It's bumped on every incompatible change of them, the server refuses clients of other versions.
+pub const PROTOCOL_VERSION: u32 = 4;
+
+/// Websocket subprotocol of `PROTOCOL_VERSION`, clients request it on connect
+/// with the `Sec-WebSocket-Protocol` header.
+pub fn ws_protocol() -> String {
+    format!("rulebook.v{PROTOCOL_VERSION}")
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(tag = "type", content = "data", rename_all = "camelCase")]
 pub enum Output<T> {
-    Error(String),
+    Error(GameError),
     SessionStart,
-    SessionEnd,
+    /// Last output of the game, the host tells every client why it ended.
+    SessionEnd {
+        reason: EndReason,
+    },
     UpdateState(T),
-    DoTaskIf { allowed: Vec<PlayerId> },
-    TaskDone { targets: Vec<PlayerId>, value: T },
-    Random { start: i32, end: i32 },
-    Action { from: PlayerId, param: T },
+    /// Changes since the last state, to apply to it.
+    PatchState(Vec<PatchOp>),
+    /// State of the private store `name` which only `target` sees.
+    UpdatePrivateState {
+        name: String,
+        target: PlayerId,
+        state: T,
+    },
+    DoTaskIf {
+        allowed: Vec<PlayerId>,
+    },
+    TaskDone {
+        targets: Vec<PlayerId>,
+        value: T,
+    },
+    Notify {
+        targets: Vec<PlayerId>,
+        value: T,
+    },
+    Random {
+        start: i32,
+        end: i32,
+    },
+    RandomBytes {
+        len: usize,
+    },
+    Action {
+        from: PlayerId,
+        param: T,
+    },
+    /// Like `Action`, but the host gives up waiting after `deadline_ms`.
+    /// Answered with `TimedAction`.
+    #[serde(rename_all = "camelCase")]
+    ActionWithDeadline {
+        from: PlayerId,
+        param: T,
+        deadline_ms: u64,
+    },
+    SimultaneousAction {
+        from: Vec<PlayerId>,
+        param: T,
+    },
+    /// Each player acts on their own param at the same time, each player at most once.
+    /// Answered with the actions in the order of `requests`.
+    GatherActions {
+        requests: Vec<(PlayerId, T)>,
+    },
+    /// Whoever of `from` acts first, the requests to the others are cancelled.
+    /// Answered with `TimedAction` of the player and the action, timed out only
+    /// if `deadline_ms` is given and passes before anyone acts.
+    #[serde(rename_all = "camelCase")]
+    FirstAction {
+        from: Vec<PlayerId>,
+        param: T,
+        deadline_ms: Option<u64>,
+    },
+    /// Players the host removed from the game so far, answered with them in order.
+    ForfeitedPlayers,
+    /// Final result of the game like its winner, at most once before `SessionEnd`.
+    GameResult(T),
+    DebugSnapshot {
+        label: String,
+        value: T,
+    },
+}
+
+/// How a session ended, so clients can tell a finished game from an aborted one.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", content = "data", rename_all = "camelCase")]
+pub enum EndReason {
+    /// Game played to its end.
+    Completed,
+    /// Session stopped early, like on a timeout or by the host, with why.
+    Aborted(String),
+    /// Game can't go on without the player, like one who forfeited.
+    PlayerLeft(PlayerId),
+    /// Game reported an error, like a panic or a broken rule.
+    Error,
+}
+
+impl fmt::Display for EndReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EndReason::Completed => f.write_str("game completed"),
+            EndReason::Aborted(reason) => write!(f, "game aborted: {reason}"),
+            EndReason::PlayerLeft(player) => write!(f, "player {player} left the game"),
+            EndReason::Error => f.write_str("game failed with an error"),
+        }
+    }
+}
+
+/// Operation of JSON Patch (RFC 6902), `path` is a JSON Pointer (RFC 6901).
+///
+/// Only a subset of operations games need to describe state changes.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "camelCase")]
+pub enum PatchOp {
+    Add { path: String, value: Value },
+    Remove { path: String },
+    Replace { path: String, value: Value },
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
@@ -21,19 +145,369 @@ pub enum TaskResult<T> {
     Restricted,
 }
 
-#[derive(Debug, Default, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+/// Answer of `Output::ActionWithDeadline`, decided by the host.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(tag = "type", content = "data", rename_all = "camelCase")]
+pub enum TimedAction<T> {
+    Acted(T),
+    TimedOut,
+}
+
+/// Compared and hashed by the JSON text of its options and initial state.
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct RoomInfo {
+    /// In the order they joined, which is also the order of their seats.
     pub players: Vec<PlayerId>,
+    /// Game specific options chosen on room creation, `null` if not given.
+    #[serde(default = "null_options")]
+    pub options: Box<RawValue>,
+    /// Role of each player's seat, empty unless the game declares `GameInfo::roles`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub roles: BTreeMap<PlayerId, String>,
+    /// State to start the game from instead of deriving it from the room,
+    /// like a preset board or a resumed game. `None` unless the host is given one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub init_state: Option<Box<RawValue>>,
+}
+
+impl Default for RoomInfo {
+    fn default() -> Self {
+        RoomInfo {
+            players: vec![],
+            options: null_options(),
+            roles: BTreeMap::new(),
+            init_state: None,
+        }
+    }
+}
+
+impl RoomInfo {
+    /// Fields to compare, `RawValue` doesn't compare by itself.
+    fn key(&self) -> (&[PlayerId], &str, &BTreeMap<PlayerId, String>, Option<&str>) {
+        (
+            &self.players,
+            self.options.get(),
+            &self.roles,
+            self.init_state.as_deref().map(RawValue::get),
+        )
+    }
+
+    /// Player seated after the player, wrapping around to the first, in the order turns go.
+    ///
+    /// Seats are the same on every machine, so games may rotate turns by it.
+    /// `None` if the player isn't in the room.
+    pub fn next_player(&self, player: PlayerId) -> Option<PlayerId> {
+        let idx = self.players.iter().position(|&p| p == player)?;
+        Some(self.players[(idx + 1) % self.players.len()])
+    }
+
+    /// Role of the player's seat, see `GameInfo::roles`.
+    pub fn role(&self, player: PlayerId) -> Option<&str> {
+        self.roles.get(&player).map(|role| &**role)
+    }
+
+    /// Player seated in the role, see `GameInfo::roles`.
+    pub fn player_with_role(&self, role: &str) -> Option<PlayerId> {
+        self.roles
+            .iter()
+            .find(|(_, r)| *r == role)
+            .map(|(&player, _)| player)
+    }
+}
+
+impl PartialEq for RoomInfo {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Eq for RoomInfo {}
+
+impl PartialOrd for RoomInfo {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for RoomInfo {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.key().cmp(&other.key())
+    }
+}
+
+impl std::hash::Hash for RoomInfo {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.key().hash(state)
+    }
+}
+
+fn null_options() -> Box<RawValue> {
+    RawValue::from_string("null".into()).unwrap()
+}
+
+/// Response header of the connect request, holding the token to take the same seat again.
+pub const RECONNECT_TOKEN_HEADER: &str = "x-rulebook-reconnect-token";
+
+/// Metadata games declare about themselves.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct GameInfo {
+    /// Players allowed to join, every player if `None`.
+    pub roster: Option<Vec<PlayerId>>,
+    /// Number of players required to start the session.
+    pub min_players: Option<usize>,
+    /// Max number of players allowed to join.
+    pub max_players: Option<usize>,
+    /// Roles of the seats in order, like `["dealer", "player"]`, for games which need
+    /// specific roles rather than any color. Such a game starts only with every seat taken,
+    /// and the host assigns the roles to players in the order they joined.
+    pub roles: Option<Vec<String>>,
+}
+
+impl GameInfo {
+    /// Role of each of the players in the order they joined, empty without `roles`.
+    pub fn assign_roles(&self, players: &[PlayerId]) -> BTreeMap<PlayerId, String> {
+        let Some(roles) = &self.roles else {
+            return BTreeMap::new();
+        };
+        players.iter().copied().zip(roles.iter().cloned()).collect()
+    }
+}
+
+/// JSON schemas of a game's types, exported by games built with the `schema` feature.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GameSchema {
+    pub state: Box<RawValue>,
+    /// Keyed by the name of each action.
+    pub actions: BTreeMap<String, ActionSchema>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ActionSchema {
+    pub param: Box<RawValue>,
+    pub response: Box<RawValue>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct SessionInfo {
     pub room: RoomInfo,
-    pub player: PlayerId,
+    /// `None` for spectators.
+    pub player: Option<PlayerId>,
+}
+
+/// Sent to each connection of a room from its connect until the room starts, `Start` last.
+///
+/// Clients answer `Start` with `LobbyRequest::Start` before the session's messages.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", content = "data", rename_all = "camelCase")]
+pub enum LobbyMessage {
+    /// Who's in the room, sent on connect and on every change.
+    Update(LobbyUpdate),
+    /// The room started, the session's messages follow.
+    Start(SessionInfo),
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LobbyUpdate {
+    /// Connected players in the order of their seats.
+    pub players: Vec<PlayerId>,
+    /// Those of `players` who said they're ready.
+    pub ready: Vec<PlayerId>,
 }
 
+/// Sent by players while their room waits to start.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", content = "data", rename_all = "camelCase")]
+pub enum LobbyRequest {
+    /// Whether the player is ready, shown to others but the room starts regardless.
+    Ready(bool),
+    /// Answers `LobbyMessage::Start`, the last request before the session's messages.
+    /// Requests crossing the start are discarded until it, so they never reach the game.
+    Start,
+}
+
+/// Error which aborted the game, with where it panicked if it did.
+///
+/// Deserializes from a bare message too, as sent by games built before it's structured.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "GameErrorRepr", rename_all = "camelCase")]
+pub struct GameError {
+    pub message: String,
+    /// `file:line:column` of the panic.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub location: Option<String>,
+    /// Captured only with the `backtrace` feature of the game library.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backtrace: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum GameErrorRepr {
+    Message(String),
+    #[serde(rename_all = "camelCase")]
+    Structured {
+        message: String,
+        #[serde(default)]
+        location: Option<String>,
+        #[serde(default)]
+        backtrace: Option<String>,
+    },
+}
+
+impl From<GameErrorRepr> for GameError {
+    fn from(repr: GameErrorRepr) -> Self {
+        match repr {
+            GameErrorRepr::Message(message) => GameError {
+                message,
+                location: None,
+                backtrace: None,
+            },
+            GameErrorRepr::Structured {
+                message,
+                location,
+                backtrace,
+            } => GameError {
+                message,
+                location,
+                backtrace,
+            },
+        }
+    }
+}
+
+impl fmt::Display for GameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)?;
+        if let Some(location) = &self.location {
+            write!(f, ", at {location}")?;
+        }
+        if let Some(backtrace) = &self.backtrace {
+            write!(f, "\n{backtrace}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for GameError {}
+
+/// Player within a session, numbered from 0.
+///
+/// First `PlayerId::DEFAULT_COUNT` players are named after their `Color` like `"red"`,
+/// later ones are named by their index like `"player8"`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PlayerId(u8);
+
+impl PlayerId {
+    /// Number of players with their own color.
+    pub const DEFAULT_COUNT: usize = 8;
+    /// Max number of players in a session.
+    pub const MAX_COUNT: usize = u8::MAX as usize + 1;
+
+    pub const fn new(index: u8) -> Self {
+        PlayerId(index)
+    }
+
+    pub const fn index(self) -> u8 {
+        self.0
+    }
+
+    /// `None` for players beyond `PlayerId::DEFAULT_COUNT`.
+    pub fn color(self) -> Option<Color> {
+        Color::from_repr(self.0)
+    }
+
+    /// First `count` players, up to `PlayerId::MAX_COUNT`.
+    pub fn candidates(count: usize) -> impl ExactSizeIterator<Item = Self> + DoubleEndedIterator {
+        (0..count.min(Self::MAX_COUNT)).map(|index| PlayerId(index as u8))
+    }
+}
+
+impl From<Color> for PlayerId {
+    fn from(color: Color) -> Self {
+        PlayerId(color as u8)
+    }
+}
+
+impl fmt::Display for PlayerId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.color() {
+            Some(color) => f.write_str(color.into()),
+            None => write!(f, "player{}", self.0),
+        }
+    }
+}
+
+impl FromStr for PlayerId {
+    type Err = ParsePlayerIdError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(color) = s.parse::<Color>() {
+            return Ok(color.into());
+        }
+
+        // Colored players only have their color name, and others only the digits
+        // `Display` writes, without sign or leading zeros, to keep it unique.
+        match s
+            .strip_prefix("player")
+            .filter(|index| index.bytes().all(|b| b.is_ascii_digit()) && !index.starts_with('0'))
+            .and_then(|index| index.parse().ok())
+        {
+            Some(index) if index as usize >= Self::DEFAULT_COUNT => Ok(PlayerId(index)),
+            _ => Err(ParsePlayerIdError(s.into())),
+        }
+    }
+}
+
+impl Serialize for PlayerId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(feature = "schema")]
+impl schemars::JsonSchema for PlayerId {
+    fn schema_name() -> String {
+        "PlayerId".into()
+    }
+
+    fn json_schema(_gen: &mut schemars::gen::SchemaGenerator) -> schemars::schema::Schema {
+        schemars::schema::SchemaObject {
+            instance_type: Some(schemars::schema::InstanceType::String.into()),
+            metadata: Some(Box::new(schemars::schema::Metadata {
+                description: Some("Color name like `red`, or `player8` and later.".into()),
+                ..Default::default()
+            })),
+            ..Default::default()
+        }
+        .into()
+    }
+}
+
+impl<'de> Deserialize<'de> for PlayerId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = Cow::<str>::deserialize(deserializer)?;
+        s.parse().map_err(de::Error::custom)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParsePlayerIdError(String);
+
+impl fmt::Display for ParsePlayerIdError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid player id {:?}", self.0)
+    }
+}
+
+impl std::error::Error for ParsePlayerIdError {}
+
+/// Color of the first `PlayerId::DEFAULT_COUNT` players.
 #[derive(
     Debug,
     Clone,
@@ -49,11 +523,13 @@ pub struct SessionInfo {
     strum::Display,
     strum::EnumIter,
     strum::EnumString,
+    strum::FromRepr,
     strum::IntoStaticStr,
 )]
+#[repr(u8)]
 #[serde(rename_all = "camelCase")]
 #[strum(serialize_all = "camelCase")]
-pub enum PlayerId {
+pub enum Color {
     Red,
     Fuchsia,
     Green,
@@ -64,10 +540,121 @@ pub enum PlayerId {
     Orange,
 }
 
-impl PlayerId {
-    pub fn candidates() -> impl Iterator<Item = Self> + ExactSizeIterator + DoubleEndedIterator {
-        use strum::IntoEnumIterator;
+/// Severity of a guest log message, passed to `rulebook_log_at` as its discriminant.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Serialize,
+    Deserialize,
+    strum::Display,
+    strum::FromRepr,
+)]
+#[repr(u8)]
+#[serde(rename_all = "camelCase")]
+#[strum(serialize_all = "camelCase")]
+pub enum LogLevel {
+    Trace,
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+/// Category of errors which end a session.
+///
+/// Hosts attach it to their errors, retrieve it with `err.downcast_ref::<RuntimeError>()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum RuntimeError {
+    /// Game tried to reveal information beyond the current visibility scope.
+    VisibilityViolation,
+    /// Players didn't submit an action in time.
+    Timeout,
+    /// Game ran longer than its execution budget without performing IO.
+    ExecutionBudgetExceeded,
+    /// Game consumed all of its fuel.
+    OutOfFuel,
+    /// Game performed more IO than allowed for a session.
+    IoLimit,
+    /// Game tried to grow its memory beyond the limit.
+    MemoryLimit,
+    /// Game tried to grow its table beyond the limit.
+    TableLimit,
+    /// Game reported an error, like a panic or a broken rule.
+    GameLogic,
+    /// Game or peer didn't follow the protocol.
+    Protocol,
+    /// Host already runs as many sessions as allowed.
+    TooManySessions,
+    /// Session is cancelled by the host.
+    Cancelled,
+    /// Every player disconnected.
+    Abandoned,
+    /// Game requested an action from a player the host removed.
+    Forfeited,
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            RuntimeError::VisibilityViolation => "game violated visibility",
+            RuntimeError::Timeout => "player action timed out",
+            RuntimeError::ExecutionBudgetExceeded => "game exceeded execution budget",
+            RuntimeError::OutOfFuel => "game ran out of fuel",
+            RuntimeError::IoLimit => "game exceeded IO limit",
+            RuntimeError::MemoryLimit => "game exceeded memory limit",
+            RuntimeError::TableLimit => "game exceeded table limit",
+            RuntimeError::GameLogic => "game logic error",
+            RuntimeError::Protocol => "protocol error",
+            RuntimeError::TooManySessions => "too many concurrent sessions",
+            RuntimeError::Cancelled => "session cancelled",
+            RuntimeError::Abandoned => "every player disconnected",
+            RuntimeError::Forfeited => "player forfeited",
+        })
+    }
+}
+
+impl std::error::Error for RuntimeError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn player_id_parses_only_the_name_it_displays() {
+        for player in PlayerId::candidates(PlayerId::MAX_COUNT) {
+            assert_eq!(player.to_string().parse(), Ok(player));
+        }
+        for name in [
+            "player08", "player+8", "player-8", "player 8", "player", "player3",
+        ] {
+            assert_eq!(
+                name.parse::<PlayerId>(),
+                Err(ParsePlayerIdError(name.into()))
+            );
+        }
+    }
 
-        Self::iter()
+    #[test]
+    fn room_info_compares_its_options() {
+        let room = |options: &str| RoomInfo {
+            options: RawValue::from_string(options.into()).unwrap(),
+            ..Default::default()
+        };
+        assert_eq!(room(r#"{"max":10}"#), room(r#"{"max":10}"#));
+        assert_ne!(room(r#"{"max":10}"#), room(r#"{"max":20}"#));
+        assert_ne!(
+            room("null"),
+            RoomInfo {
+                init_state: Some(null_options()),
+                ..room("null")
+            }
+        );
     }
 }
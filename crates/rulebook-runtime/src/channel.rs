@@ -1,35 +1,198 @@
-use anyhow::Result;
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::marker::PhantomData;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
 use futures::sink::{Sink, SinkExt};
 use futures::stream::{Stream, StreamExt};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
-use serde_json::value::RawValue;
+use tokio::time::Instant;
+
+use crate::codec::{Codec, JsonCodec};
+use crate::EndReason;
+
+/// Max size of frames `Channel::new` accepts, way larger than any message of the example game.
+pub const DEFAULT_MAX_FRAME_BYTES: usize = 64 * 1024;
+
+/// Max number of peer messages buffered until they're received, more fail the channel.
+///
+/// Messages arriving during `send` are acked before they're received, so it's the peer
+/// flooding the channel rather than waiting for its acks which reaches it.
+pub const MAX_BUFFERED_MESSAGES: usize = 256;
+
+/// Max total size of the frames of peer messages buffered until they're received.
+pub const MAX_BUFFERED_BYTES: usize = 1024 * 1024;
 
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(tag = "type", content = "data", rename_all = "camelCase")]
 enum Frame<T> {
-    Msg { id: u32, val: T },
+    Msg {
+        id: u32,
+        val: T,
+    },
     Ack(u32),
+    /// Sent on `resume` with the id of the last message received.
+    Resume(Option<u32>),
+    /// Reply to `Resume` with the id of the last message received.
+    Resumed(Option<u32>),
+    Ping,
+    Pong,
+    /// Out of the message sequence, see `Channel::send_notice`.
+    Notice(String),
+    /// Out of the message sequence, see `Channel::send_end`.
+    End(EndReason),
 }
 
+/// Message stream over a transport which may be replaced on reconnects.
+///
+/// Messages are numbered in the order they're sent.
+/// `receive` returns them in this order exactly once, duplicates are acked again but dropped
+/// and a message skipping an id fails the channel.
+/// Frames are encoded by `C`, JSON by default.
 #[derive(Debug)]
-pub struct Channel<T> {
+pub struct Channel<T, C: Codec = JsonCodec> {
     inner: T,
     next_id: u32,
-    received: Option<(u32, Box<RawValue>)>,
+    /// Messages received while waiting for something else, in order of id,
+    /// with the size of their frames.
+    received: VecDeque<(u32, C::Value, usize)>,
+    /// Total size of the frames in `received`.
+    received_bytes: usize,
+    /// Id of the last message returned from `receive`.
+    last_received: Option<u32>,
+    /// Frames of sent messages until they're acked, sent again on `resume`
+    /// if the peer hasn't received them.
+    unacked: VecDeque<(u32, Vec<u8>)>,
+    keepalive: Option<Keepalive>,
+    /// Frames larger than this fail the channel before being parsed.
+    max_frame_bytes: usize,
+    /// When the ping not yet answered was sent.
+    ping_sent: Option<Instant>,
+    /// Received but not yet taken with `take_notices`.
+    notices: Vec<String>,
+    /// Why the peer's session ended, see `send_end`.
+    end: Option<EndReason>,
+    codec: PhantomData<fn() -> C>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Keepalive {
+    interval: Duration,
+    timeout: Duration,
 }
 
 impl<T> Channel<T>
 where
-    T: Stream<Item = Result<String>> + Sink<String, Error = anyhow::Error> + Unpin,
+    T: Stream<Item = Result<Vec<u8>>> + Sink<Vec<u8>, Error = anyhow::Error> + Unpin,
 {
     pub fn new(inner: T) -> Self {
+        Self::with_limit(inner, DEFAULT_MAX_FRAME_BYTES)
+    }
+
+    /// Channel which fails on receiving frames larger than `max_frame_bytes`.
+    pub fn with_limit(inner: T, max_frame_bytes: usize) -> Self {
+        Self::with_codec(inner, max_frame_bytes)
+    }
+}
+
+impl<T, C> Channel<T, C>
+where
+    T: Stream<Item = Result<Vec<u8>>> + Sink<Vec<u8>, Error = anyhow::Error> + Unpin,
+    C: Codec,
+{
+    /// Channel of frames encoded by `C`, like `with_limit` otherwise.
+    ///
+    /// Both sides must use the same codec.
+    pub fn with_codec(inner: T, max_frame_bytes: usize) -> Self {
         Channel {
             inner,
             next_id: 0,
-            received: None,
+            received: VecDeque::new(),
+            received_bytes: 0,
+            last_received: None,
+            unacked: VecDeque::new(),
+            keepalive: None,
+            max_frame_bytes,
+            ping_sent: None,
+            notices: vec![],
+            end: None,
+            codec: PhantomData,
+        }
+    }
+
+    /// Pings the peer after waiting for a frame for `interval`,
+    /// and fails the channel if it doesn't answer within `timeout`.
+    ///
+    /// Pings are answered only while the peer is in `send`, `receive`, `resume` or `tick`.
+    pub fn set_keepalive(&mut self, interval: Duration, timeout: Duration) {
+        self.keepalive = Some(Keepalive { interval, timeout });
+    }
+
+    /// Replaces the underlying transport, returning the old one.
+    ///
+    /// Message ids and buffered messages are kept so the peer sees a continuous channel.
+    /// Since `send` doesn't return until its message is acked, there's no message
+    /// in flight between calls, which makes any point outside of them safe to swap.
+    /// If the old transport failed in the middle of `send`, call `resume` after it.
+    pub fn replace_inner(&mut self, inner: T) -> T {
+        self.ping_sent = None;
+        std::mem::replace(&mut self.inner, inner)
+    }
+
+    /// Sends a notice to the peer, like a warning that the server is shutting down.
+    ///
+    /// Notices aren't part of the message sequence, they're neither acked nor sent again
+    /// on `resume`. The peer collects them while handling frames, see `take_notices`.
+    pub async fn send_notice(&mut self, notice: &str) -> Result<()> {
+        self.send_frame(&Frame::Notice::<()>(notice.into())).await
+    }
+
+    /// Notices received since the last call, see `send_notice`.
+    pub fn take_notices(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.notices)
+    }
+
+    /// Tells the peer why the session ended, right before the channel is closed.
+    ///
+    /// Like notices it's out of the message sequence, since the peer may be waiting
+    /// for a message that never comes. The peer finds it in `end_reason`.
+    pub async fn send_end(&mut self, reason: &EndReason) -> Result<()> {
+        self.send_frame(&Frame::End::<()>(reason.clone())).await
+    }
+
+    /// Why the session ended, once the peer sent it with `send_end`.
+    pub fn end_reason(&self) -> Option<&EndReason> {
+        self.end.as_ref()
+    }
+
+    /// Reconciles with the peer after `replace_inner`.
+    ///
+    /// Both sides exchange the id of the last message they received,
+    /// and messages the peer hasn't received yet are sent again.
+    /// A failed `send` is completed by this and must not be retried.
+    pub async fn resume(&mut self) -> Result<()> {
+        self.send_frame(&Frame::Resume::<()>(self.last_received))
+            .await?;
+
+        let mut resumed = false;
+        while !resumed {
+            let frame = self
+                .next_frame()
+                .await?
+                .context("connection closed before resume complete")?;
+            resumed |= self.handle_frame(&frame).await?;
         }
+
+        Ok(())
     }
 
+    /// Returns once the peer acked the message.
+    ///
+    /// Messages of the peer arriving meanwhile are acked right away instead of when received,
+    /// since the peer may be sending at the same time, like a player whose action crosses
+    /// the result of a race it lost, and both sides would wait for each other otherwise.
+    /// They stay buffered for `receive` which acks them again, harmless as acks are idempotent.
     pub async fn send<M: Serialize + ?Sized>(&mut self, val: &M) -> Result<()> {
         let current_id = self.next_id;
         self.next_id = self
@@ -37,50 +200,447 @@ where
             .checked_add(1)
             .expect("channel msg id u32 overflowed");
 
-        let req = serde_json::to_string(&Frame::Msg {
+        let req = C::encode(&Frame::Msg {
             id: current_id,
             val,
         })?;
-        println!("sending msg, req: {req}");
+        tracing::trace!(req = %printable(&req), "sending msg");
+        self.unacked.push_back((current_id, req.clone()));
         self.inner.send(req).await?;
-        println!("msg sent");
-
-        while let Some(received) = self.inner.next().await {
-            let received = received?;
-            println!("got frame on send: {received}");
-            let received: Frame<Box<RawValue>> = serde_json::from_str(&received)?;
-
-            match received {
-                Frame::Ack(id) => {
-                    if id == current_id {
-                        return Ok(());
-                    }
-                }
-                Frame::Msg { id, val } => self.received = Some((id, val)),
+        tracing::trace!("msg sent");
+
+        while self.unacked.iter().any(|(id, _)| *id == current_id) {
+            let Some(received) = self.next_frame().await? else {
+                anyhow::bail!("connection closed before send complete")
+            };
+            tracing::trace!(frame = %printable(&received), "got frame on send");
+            let buffered = self.received.len();
+            self.handle_frame(&received).await?;
+            if self.received.len() > buffered {
+                let (id, ..) = self.received.back().expect("just buffered");
+                self.send_frame(&Frame::Ack::<()>(*id)).await?;
             }
         }
 
-        anyhow::bail!("connection closed before send complete")
+        Ok(())
     }
 
+    /// Safe to cancel, the message stays buffered for the next call until it's acked.
     pub async fn receive<M: DeserializeOwned>(&mut self) -> Result<M> {
-        if let Some((id, val)) = self.received.take() {
-            let msg = serde_json::from_str(val.get())?;
-            let ack = serde_json::to_string(&Frame::Ack::<()>(id))?;
-            self.inner.send(ack).await?;
-            return Ok(msg);
+        loop {
+            if let Some((id, val, bytes)) = self.received.front() {
+                let (id, bytes) = (*id, *bytes);
+                // it's typed only now, through the codec again
+                let msg = C::decode(&C::encode(val)?)?;
+                // acking twice is harmless if cancelled after this
+                self.send_frame(&Frame::Ack::<()>(id)).await?;
+                self.received.pop_front();
+                self.received_bytes -= bytes;
+                self.last_received = Some(id);
+                return Ok(msg);
+            }
+
+            let Some(received) = self.next_frame().await? else {
+                anyhow::bail!("connection closed before receive complete")
+            };
+            self.handle_frame(&received).await?;
+        }
+    }
+
+    /// Waits for a frame and handles it, to answer pings while the caller is idle.
+    /// Messages are kept for the next `receive`.
+    ///
+    /// It's safe to cancel, typically raced against what the caller is waiting for.
+    pub async fn tick(&mut self) -> Result<()> {
+        let Some(frame) = self.next_frame().await? else {
+            anyhow::bail!("connection closed")
+        };
+        self.handle_frame(&frame).await?;
+        Ok(())
+    }
+
+    /// Receives the next frame, pinging the peer in the meantime if keepalive is set.
+    async fn next_frame(&mut self) -> Result<Option<Vec<u8>>> {
+        let frame = self.next_frame_unchecked().await?;
+
+        if let Some(frame) = &frame {
+            anyhow::ensure!(
+                frame.len() <= self.max_frame_bytes,
+                "channel frame of {} bytes exceeds the limit of {} bytes",
+                frame.len(),
+                self.max_frame_bytes,
+            );
         }
 
-        while let Some(received) = self.inner.next().await {
-            let received: Frame<_> = serde_json::from_str(&received?)?;
+        Ok(frame)
+    }
+
+    async fn next_frame_unchecked(&mut self) -> Result<Option<Vec<u8>>> {
+        let Some(keepalive) = self.keepalive else {
+            return self.inner.next().await.transpose();
+        };
+
+        loop {
+            let deadline = match self.ping_sent {
+                Some(sent) => sent + keepalive.timeout,
+                None => Instant::now() + keepalive.interval,
+            };
 
-            if let Frame::Msg { id, val } = received {
-                let ack = serde_json::to_string(&Frame::Ack::<()>(id))?;
-                self.inner.send(ack).await?;
-                return Ok(val);
+            match tokio::time::timeout_at(deadline, self.inner.next()).await {
+                Ok(frame) => return frame.transpose(),
+                Err(_) if self.ping_sent.is_some() => {
+                    anyhow::bail!("connection dead, no pong in {:?}", keepalive.timeout)
+                }
+                Err(_) => {
+                    self.ping_sent = Some(Instant::now());
+                    self.send_frame(&Frame::Ping::<()>).await?;
+                }
             }
         }
+    }
+
+    async fn send_frame<M: Serialize>(&mut self, frame: &Frame<M>) -> Result<()> {
+        let frame = C::encode(frame)?;
+        self.inner.send(frame).await
+    }
+
+    /// Handles a frame received while waiting for something else.
+    /// Returns `true` if it was a reply to `resume`.
+    async fn handle_frame(&mut self, frame: &[u8]) -> Result<bool> {
+        let bytes = frame.len();
+        let frame: Frame<C::Value> = C::decode(frame)?;
+
+        match frame {
+            Frame::Msg { id, val } => {
+                let expected = match self.received.back() {
+                    Some((last, ..)) => last + 1,
+                    None => self.last_received.map_or(0, |last| last + 1),
+                };
+                if self.last_received.is_some_and(|last| id <= last) {
+                    // peer missed the ack before reconnecting
+                    self.send_frame(&Frame::Ack::<()>(id)).await?;
+                } else if id > expected {
+                    anyhow::bail!("channel msg out of order, expected {expected} but got {id}");
+                } else if id == expected {
+                    anyhow::ensure!(
+                        self.received.len() < MAX_BUFFERED_MESSAGES
+                            && self.received_bytes + bytes <= MAX_BUFFERED_BYTES,
+                        "peer sent {} messages of {} bytes not yet received, exceeding the limit of {MAX_BUFFERED_MESSAGES} messages or {MAX_BUFFERED_BYTES} bytes",
+                        self.received.len() + 1,
+                        self.received_bytes + bytes,
+                    );
+                    self.received.push_back((id, val, bytes));
+                    self.received_bytes += bytes;
+                }
+                // otherwise it's already buffered, acked when received
+            }
+            // acks may come late or out of order after reconnects
+            Frame::Ack(id) => self.unacked.retain(|(unacked, _)| *unacked != id),
+            Frame::Resume(peer_last) => {
+                self.send_frame(&Frame::Resumed::<()>(self.last_received))
+                    .await?;
+                self.reconcile(peer_last).await?;
+            }
+            Frame::Ping => self.send_frame(&Frame::Pong::<()>).await?,
+            Frame::Pong => self.ping_sent = None,
+            Frame::Notice(notice) => self.notices.push(notice),
+            Frame::End(reason) => self.end = Some(reason),
+            Frame::Resumed(peer_last) => {
+                self.reconcile(peer_last).await?;
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+
+    /// Sends unacked messages again unless the peer has already received them.
+    async fn reconcile(&mut self, peer_last: Option<u32>) -> Result<()> {
+        self.unacked
+            .retain(|(id, _)| peer_last.is_none_or(|last| last < *id));
+
+        for (_, frame) in self.unacked.clone() {
+            self.inner.send(frame).await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Frame as a text for logs, lossy unless it's encoded as one.
+fn printable(frame: &[u8]) -> Cow<'_, str> {
+    String::from_utf8_lossy(frame)
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::FutureExt;
+
+    use super::*;
+    use crate::duplex::DuplexChannel;
+
+    fn pair() -> (Channel<DuplexChannel>, Channel<DuplexChannel>) {
+        let (a, b) = DuplexChannel::pair();
+        (Channel::new(a), Channel::new(b))
+    }
+
+    #[tokio::test]
+    async fn send_returns_once_acked() {
+        let (mut a, mut b) = pair();
+
+        let (sent, received) = tokio::join!(a.send("hello"), b.receive::<String>());
+        sent.unwrap();
+        assert_eq!(received.unwrap(), "hello");
+        assert!(a.unacked.is_empty());
+
+        let (sent, received) = tokio::join!(b.send(&42), a.receive::<u32>());
+        sent.unwrap();
+        assert_eq!(received.unwrap(), 42);
+        assert!(b.unacked.is_empty());
+    }
+
+    #[tokio::test]
+    async fn receive_returns_messages_buffered_while_sending() {
+        let (mut a, mut b) = pair();
+
+        // each side gets the other's first message while waiting for its own ack
+        let (a_res, b_res) = tokio::join!(
+            async {
+                a.send("a1").await?;
+                a.send("a2").await?;
+                a.receive::<String>().await
+            },
+            async {
+                b.send("b1").await?;
+                anyhow::Ok([b.receive::<String>().await?, b.receive().await?])
+            },
+        );
+
+        assert_eq!(a_res.unwrap(), "b1");
+        assert_eq!(b_res.unwrap(), ["a1", "a2"]);
+        assert!(a.received.is_empty() && b.received.is_empty());
+    }
+
+    #[tokio::test]
+    async fn missing_pong_fails_the_channel() {
+        let (mut a, mut b) = pair();
+        a.set_keepalive(Duration::from_millis(20), Duration::from_millis(20));
+
+        // pings are answered while the peer ticks
+        let (received, sent) = tokio::join!(a.receive::<String>(), async {
+            let ticking = async {
+                loop {
+                    b.tick().await?;
+                }
+            };
+            let res: Result<Result<()>, _> =
+                tokio::time::timeout(Duration::from_millis(100), ticking).await;
+            assert!(res.is_err(), "ticking failed: {res:?}");
+            b.send("late").await
+        });
+        sent.unwrap();
+        assert_eq!(received.unwrap(), "late");
+
+        // but not while it's idle, though the connection is open
+        let err = a.receive::<String>().await.unwrap_err();
+        assert_eq!(err.to_string(), "connection dead, no pong in 20ms");
+    }
+
+    fn encode(frame: Frame<&str>) -> Vec<u8> {
+        serde_json::to_vec(&frame).unwrap()
+    }
+
+    fn decode(frame: Vec<u8>) -> serde_json::Value {
+        serde_json::from_slice(&frame).unwrap()
+    }
+
+    #[tokio::test]
+    async fn interleaved_messages_and_early_ack() {
+        let (a, mut peer) = DuplexChannel::pair();
+        let mut a = Channel::new(a);
+
+        // all of them arrive before `a` handles any, the ack among the messages
+        for frame in [
+            Frame::Msg { id: 0, val: "p0" },
+            Frame::Ack(0),
+            Frame::Msg { id: 1, val: "p1" },
+            Frame::Msg { id: 0, val: "p0" },
+            Frame::Ack(0),
+        ] {
+            peer.send(encode(frame)).await.unwrap();
+        }
+
+        a.send("a0").await.unwrap();
+        assert!(a.unacked.is_empty());
+        assert_eq!(a.receive::<String>().await.unwrap(), "p0");
+        assert_eq!(a.receive::<String>().await.unwrap(), "p1");
+        // the duplicate and the second ack
+        a.tick().await.unwrap();
+        a.tick().await.unwrap();
+        assert!(a.received.is_empty());
+        drop(a);
+
+        let sent: Vec<_> = peer.map(|frame| decode(frame.unwrap())).collect().await;
+        let expected = [
+            Frame::Msg { id: 0, val: "a0" },
+            // buffered while sending
+            Frame::Ack(0),
+            Frame::Ack(0),
+            Frame::Ack(1),
+            // duplicate of the received
+            Frame::Ack(0),
+        ];
+        assert_eq!(sent, expected.map(|frame| decode(encode(frame))));
+    }
+
+    #[tokio::test]
+    async fn flooding_peer_fails_the_channel() {
+        let (a, mut peer) = DuplexChannel::pair();
+        let mut a = Channel::new(a);
+
+        // never waits for the acks `a` sends while sending itself
+        for id in 0..=MAX_BUFFERED_MESSAGES as u32 {
+            peer.send(encode(Frame::Msg { id, val: "spam" }))
+                .await
+                .unwrap();
+        }
+
+        let err = a.send("a0").await.unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with(&format!("peer sent {} messages", MAX_BUFFERED_MESSAGES + 1)),
+            "{err}"
+        );
+        assert_eq!(a.received.len(), MAX_BUFFERED_MESSAGES);
+    }
+
+    #[tokio::test]
+    async fn large_buffered_messages_fail_the_channel() {
+        let (a, mut peer) = DuplexChannel::pair();
+        let mut a = Channel::new(a);
+
+        let large = "x".repeat(DEFAULT_MAX_FRAME_BYTES - 100);
+        for id in 0..(MAX_BUFFERED_BYTES / large.len()) as u32 + 1 {
+            peer.send(encode(Frame::Msg { id, val: &large }))
+                .await
+                .unwrap();
+        }
+
+        let err = a.send("a0").await.unwrap_err();
+        assert!(err.to_string().contains("exceeding the limit"), "{err}");
+        assert!(a.received_bytes <= MAX_BUFFERED_BYTES);
+        assert!(a.received.len() < MAX_BUFFERED_MESSAGES);
+    }
+
+    #[tokio::test]
+    async fn replace_inner_keeps_the_sequence() {
+        let (mut a, mut b) = pair();
+
+        let (sent, received) = tokio::join!(a.send("before"), b.receive::<String>());
+        sent.unwrap();
+        assert_eq!(received.unwrap(), "before");
+
+        let (x, y) = DuplexChannel::pair();
+        let mut old_a = a.replace_inner(x);
+        let mut old_b = b.replace_inner(y);
+
+        let (sent, received) = tokio::join!(
+            async {
+                a.send("after").await?;
+                a.send("and more").await
+            },
+            async { anyhow::Ok([b.receive::<String>().await?, b.receive().await?]) },
+        );
+        sent.unwrap();
+        assert_eq!(received.unwrap(), ["after", "and more"]);
+        assert_eq!((a.next_id, b.last_received), (3, Some(2)));
+
+        // nothing goes through the old transport
+        assert!(old_a.next().now_or_never().is_none());
+        assert!(old_b.next().now_or_never().is_none());
+    }
+
+    /// Encodes and decodes a message frame with `C`, checking nothing is lost.
+    fn round_trip<C: Codec>() {
+        let val = serde_json::json!({ "name": "red", "bid": [-1, 2.5, null] });
+        let bytes = C::encode(&Frame::Msg { id: 7, val: &val }).unwrap();
+
+        let Frame::Msg { id, val: decoded } = C::decode::<Frame<C::Value>>(&bytes).unwrap() else {
+            panic!("not a msg frame");
+        };
+        assert_eq!(id, 7);
+        let decoded: serde_json::Value = C::decode(&C::encode(&decoded).unwrap()).unwrap();
+        assert_eq!(decoded, val);
+
+        let bytes = C::encode(&Frame::Ack::<()>(7)).unwrap();
+        assert!(matches!(
+            C::decode(&bytes).unwrap(),
+            Frame::<C::Value>::Ack(7)
+        ));
+    }
+
+    #[test]
+    fn json_codec_round_trips_frames() {
+        round_trip::<JsonCodec>();
+    }
+
+    #[cfg(feature = "msgpack")]
+    #[test]
+    fn msgpack_codec_round_trips_frames() {
+        use crate::codec::MsgpackCodec;
+
+        round_trip::<MsgpackCodec>();
+    }
+
+    /// Sends until it's cancelled, leaving the message in the transport.
+    async fn send_unacked(channel: &mut Channel<DuplexChannel>, msg: &str) {
+        let res = tokio::time::timeout(Duration::from_millis(20), channel.send(msg)).await;
+        assert!(res.is_err(), "message is acked");
+    }
+
+    #[tokio::test]
+    async fn resume_delivers_missed_messages_once_in_order() {
+        let (mut a, mut b) = pair();
+        let mut a_received = vec![];
+        let mut b_received = vec![];
+
+        let (sent, received) = tokio::join!(a.send("m0"), b.receive::<String>());
+        sent.unwrap();
+        b_received.push(received.unwrap());
+
+        // b receives m1 but the ack is lost
+        send_unacked(&mut a, "m1").await;
+        b_received.push(b.receive::<String>().await.unwrap());
+        // a never receives n0
+        send_unacked(&mut b, "n0").await;
+
+        let (x, y) = DuplexChannel::pair();
+        drop(a.replace_inner(x));
+        drop(b.replace_inner(y));
+        let (a_res, b_res) = tokio::join!(a.resume(), b.resume());
+        a_res.unwrap();
+        b_res.unwrap();
+
+        let (a_res, b_res) = tokio::join!(
+            async {
+                a.send("m2").await?;
+                a_received.push(a.receive::<String>().await?);
+                a.send("m3").await
+            },
+            async {
+                b_received.push(b.receive::<String>().await?);
+                b.send("n1").await?;
+                b_received.push(b.receive::<String>().await?);
+                anyhow::Ok(())
+            },
+        );
+        a_res.unwrap();
+        b_res.unwrap();
+        a_received.push(a.receive::<String>().await.unwrap());
 
-        anyhow::bail!("connection closed before receive complete")
+        assert_eq!(b_received, ["m0", "m1", "m2", "m3"]);
+        assert_eq!(a_received, ["n0", "n1"]);
+        assert!(a.unacked.is_empty() && b.unacked.is_empty());
+        assert!(a.received.is_empty() && b.received.is_empty());
     }
 }
@@ -0,0 +1,50 @@
+use std::fmt::Debug;
+
+use anyhow::Result;
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::value::RawValue;
+
+/// Format of `Channel` frames on the wire.
+pub trait Codec {
+    /// Message value kept as is until `Channel::receive` knows its type.
+    type Value: Serialize + DeserializeOwned + Debug + Send + Sync;
+
+    fn encode<T: Serialize + ?Sized>(val: &T) -> Result<Vec<u8>>;
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T>;
+}
+
+/// Frames as JSON texts, the default.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonCodec;
+
+impl Codec for JsonCodec {
+    type Value = Box<RawValue>;
+
+    fn encode<T: Serialize + ?Sized>(val: &T) -> Result<Vec<u8>> {
+        Ok(serde_json::to_vec(val)?)
+    }
+
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T> {
+        Ok(serde_json::from_slice(bytes)?)
+    }
+}
+
+/// Frames as MessagePack, smaller than JSON mostly on numbers and binary data.
+///
+/// Structs are encoded as maps with their field names, same shape as the JSON.
+#[cfg(feature = "msgpack")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MsgpackCodec;
+
+#[cfg(feature = "msgpack")]
+impl Codec for MsgpackCodec {
+    type Value = rmpv::Value;
+
+    fn encode<T: Serialize + ?Sized>(val: &T) -> Result<Vec<u8>> {
+        Ok(rmp_serde::to_vec_named(val)?)
+    }
+
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T> {
+        Ok(rmp_serde::from_slice(bytes)?)
+    }
+}
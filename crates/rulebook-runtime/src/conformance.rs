@@ -0,0 +1,313 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::time::Duration;
+
+use anyhow::Result;
+use serde_json::value::RawValue;
+
+use crate::determinism::ScenarioExhausted;
+use crate::{
+    Config, GameError, OutputHandler, PlayerId, RandomSource, Runtime, RuntimeError, Scenario,
+    SeededRandom, Session, TaskResult, TimedAction,
+};
+
+/// Outputs a full run of a game is expected to exercise.
+pub const EXPECTED_OUTPUTS: [&str; 4] = ["sessionStart", "action", "random", "sessionEnd"];
+
+/// Result of `Runtime::check_conformance`.
+#[derive(Debug)]
+pub struct ConformanceReport {
+    pub outcome: CheckOutcome,
+    /// Number of outputs the game sent, keyed by their `type`.
+    pub outputs: BTreeMap<String, usize>,
+}
+
+#[derive(Debug)]
+pub enum CheckOutcome {
+    /// Session ended normally.
+    Completed,
+    /// Scenario ran out of actions before the end, the game followed the protocol so far.
+    OutOfActions,
+    /// Game aborted itself, like by a panic.
+    GameError(GameError),
+    /// Game revealed something beyond the current scope.
+    VisibilityViolation(String),
+    /// Any other failure, like a malformed output or a trap.
+    Failed(String),
+}
+
+impl ConformanceReport {
+    pub fn passed(&self) -> bool {
+        matches!(
+            self.outcome,
+            CheckOutcome::Completed | CheckOutcome::OutOfActions
+        )
+    }
+
+    /// Outputs of `EXPECTED_OUTPUTS` the game never sent.
+    pub fn missing_outputs(&self) -> Vec<&'static str> {
+        EXPECTED_OUTPUTS
+            .into_iter()
+            .filter(|kind| !self.outputs.contains_key(*kind))
+            .collect()
+    }
+}
+
+impl Runtime {
+    /// Runs the game with the scenario against a mock room, to check it follows the protocol.
+    ///
+    /// The mock room tracks the scope like the server does, answers actions from the scenario
+    /// and random numbers from its seed. Failures of the game are reported, not returned.
+    pub async fn check_conformance(
+        &self,
+        game_key: &str,
+        scenario: &Scenario,
+    ) -> Result<ConformanceReport> {
+        let mut session = self
+            .new_session_with_config(
+                game_key,
+                Config {
+                    enable_state: true,
+                    record_trace: true,
+                    ..self.conf.clone()
+                },
+            )
+            .await?;
+
+        let handler = CheckHandler::new(scenario);
+        let res = session
+            .start(16 * 1024, true, scenario.room.clone(), handler)
+            .await;
+
+        Ok(ConformanceReport {
+            outcome: outcome(res),
+            outputs: count_outputs(&session),
+        })
+    }
+}
+
+fn outcome(res: Result<()>) -> CheckOutcome {
+    let Err(err) = res else {
+        return CheckOutcome::Completed;
+    };
+
+    if let Some(game_err) = err.downcast_ref::<GameError>() {
+        CheckOutcome::GameError(game_err.clone())
+    } else if err.downcast_ref::<RuntimeError>() == Some(&RuntimeError::VisibilityViolation) {
+        // without the wasm backtrace around it
+        CheckOutcome::VisibilityViolation(err.root_cause().to_string())
+    } else if err.downcast_ref::<ScenarioExhausted>().is_some() {
+        CheckOutcome::OutOfActions
+    } else {
+        CheckOutcome::Failed(format!("{err:#}"))
+    }
+}
+
+fn count_outputs(session: &Session) -> BTreeMap<String, usize> {
+    #[derive(serde::Deserialize)]
+    struct Tag {
+        r#type: String,
+    }
+
+    let mut outputs = BTreeMap::new();
+    for entry in session.trace().entries {
+        if let Ok(Tag { r#type }) = serde_json::from_str(&entry.output) {
+            *outputs.entry(r#type).or_default() += 1;
+        }
+    }
+    outputs
+}
+
+/// Players by their names like `[red, blue]`, rather than their ids.
+fn names(players: &[PlayerId]) -> String {
+    let names: Vec<_> = players.iter().map(PlayerId::to_string).collect();
+    format!("[{}]", names.join(", "))
+}
+
+/// Mock room which checks every output against the current scope.
+struct CheckHandler {
+    players: Vec<PlayerId>,
+    /// Scopes of `doTaskIf` blocks, innermost last.
+    visibility: Vec<Vec<PlayerId>>,
+    actions: VecDeque<Box<RawValue>>,
+    rng: SeededRandom,
+}
+
+impl CheckHandler {
+    fn new(scenario: &Scenario) -> Self {
+        CheckHandler {
+            players: scenario.room.players.clone(),
+            visibility: vec![],
+            actions: scenario.actions.iter().cloned().collect(),
+            rng: SeededRandom::new(scenario.seed),
+        }
+    }
+
+    fn scope(&self) -> &[PlayerId] {
+        self.visibility.last().unwrap_or(&self.players)
+    }
+
+    fn ensure_in_scope(&self, players: &[PlayerId], what: &str) -> Result<()> {
+        if let Some(player) = players.iter().find(|p| !self.scope().contains(p)) {
+            return Err(anyhow::anyhow!(
+                "game requested {what} from player {player} out of current scope {:?}",
+                self.scope()
+            )
+            .context(RuntimeError::VisibilityViolation));
+        }
+        Ok(())
+    }
+
+    fn next_action(&mut self) -> Result<Box<RawValue>> {
+        match self.actions.pop_front() {
+            Some(action) => Ok(action),
+            None => Err(ScenarioExhausted.into()),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl OutputHandler for CheckHandler {
+    fn state(&mut self, _json: &RawValue) -> Result<()> {
+        Ok(())
+    }
+
+    fn private_state(&mut self, _name: &str, _target: PlayerId, _json: &RawValue) -> Result<()> {
+        Ok(())
+    }
+
+    async fn do_task_if(&mut self, allowed: Vec<PlayerId>) -> Result<TaskResult<Box<RawValue>>> {
+        let scope = self.scope();
+        let beyond: Vec<_> = allowed
+            .iter()
+            .copied()
+            .filter(|p| !scope.contains(p))
+            .collect();
+        if !beyond.is_empty() {
+            return Err(anyhow::anyhow!(
+                "game tries to extend visibility to {} beyond current scope {}",
+                names(&beyond),
+                names(scope),
+            )
+            .context(RuntimeError::VisibilityViolation));
+        }
+
+        self.visibility.push(allowed);
+        Ok(TaskResult::DoTask)
+    }
+
+    async fn task_done(&mut self, _targets: Vec<PlayerId>, _value: &RawValue) -> Result<()> {
+        if self.visibility.pop().is_none() {
+            return Err(
+                anyhow::anyhow!("game requested taskDone event without previous doTaskIf")
+                    .context(RuntimeError::Protocol),
+            );
+        }
+        Ok(())
+    }
+
+    async fn notify(&mut self, _targets: Vec<PlayerId>, _value: &RawValue) -> Result<()> {
+        Ok(())
+    }
+
+    async fn random(&mut self, start: i32, end: i32) -> Result<i32> {
+        Ok(self.rng.next_in_range(start, end))
+    }
+
+    async fn random_bytes(&mut self, len: usize) -> Result<Vec<u8>> {
+        let mut bytes = vec![0; len];
+        self.rng.fill_bytes(&mut bytes);
+        Ok(bytes)
+    }
+
+    async fn action(&mut self, from: PlayerId, _param: &RawValue) -> Result<Box<RawValue>> {
+        self.ensure_in_scope(&[from], "action")?;
+        self.next_action()
+    }
+
+    async fn action_with_deadline(
+        &mut self,
+        from: PlayerId,
+        _param: &RawValue,
+        _deadline: Duration,
+    ) -> Result<TimedAction<Box<RawValue>>> {
+        self.ensure_in_scope(&[from], "action")?;
+        Ok(TimedAction::Acted(self.next_action()?))
+    }
+
+    async fn first_action(
+        &mut self,
+        from: Vec<PlayerId>,
+        _param: &RawValue,
+        _deadline: Option<Duration>,
+    ) -> Result<TimedAction<(PlayerId, Box<RawValue>)>> {
+        self.ensure_in_scope(&from, "first action")?;
+        // the first listed player always wins the race
+        Ok(TimedAction::Acted((from[0], self.next_action()?)))
+    }
+
+    async fn simultaneous_action(
+        &mut self,
+        from: Vec<PlayerId>,
+        _param: &RawValue,
+    ) -> Result<Box<RawValue>> {
+        self.ensure_in_scope(&from, "simultaneous action")?;
+
+        let mut values = BTreeMap::new();
+        for player in from {
+            values.insert(player, self.next_action()?);
+        }
+        Ok(serde_json::value::to_raw_value(&values)?)
+    }
+
+    async fn gather_actions(
+        &mut self,
+        requests: Vec<(PlayerId, Box<RawValue>)>,
+    ) -> Result<Box<RawValue>> {
+        let from: Vec<_> = requests.iter().map(|(player, _)| *player).collect();
+        self.ensure_in_scope(&from, "gathered action")?;
+
+        let mut values = vec![];
+        for _ in requests {
+            values.push(self.next_action()?);
+        }
+        Ok(serde_json::value::to_raw_value(&values)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_games, Color, RoomInfo};
+
+    #[tokio::test]
+    async fn nested_task_beyond_parent_scope_names_the_player() {
+        let code = test_games::game(&[
+            test_games::SESSION_START,
+            r#"{"type":"doTaskIf","data":{"allowed":["red"]}}"#,
+            r#"{"type":"doTaskIf","data":{"allowed":["red","blue"]}}"#,
+            test_games::SESSION_END,
+        ]);
+        let runtime = Runtime::new(Config::default()).unwrap();
+        runtime.add_game("game".into(), code.as_bytes()).unwrap();
+        let scenario = Scenario {
+            room: RoomInfo {
+                players: vec![
+                    PlayerId::new(Color::Red as u8),
+                    PlayerId::new(Color::Blue as u8),
+                ],
+                ..Default::default()
+            },
+            actions: vec![],
+            seed: 0,
+        };
+
+        let report = runtime.check_conformance("game", &scenario).await.unwrap();
+        let CheckOutcome::VisibilityViolation(msg) = report.outcome else {
+            panic!("not a visibility violation: {:?}", report.outcome);
+        };
+        assert_eq!(
+            msg,
+            "game tries to extend visibility to [blue] beyond current scope [red]"
+        );
+    }
+}
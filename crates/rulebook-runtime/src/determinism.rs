@@ -0,0 +1,363 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
+
+use crate::{
+    OutputHandler, PlayerId, RandomSource, RoomInfo, Runtime, SeededRandom, TaskResult, TimedAction,
+};
+
+/// Fixed inputs to run a game with.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Scenario {
+    pub room: RoomInfo,
+    /// Responses to action requests in order.
+    /// Simultaneous actions take one response per player in player order,
+    /// gathered actions one per request in request order.
+    /// Session stops when it runs out of actions. Actions with a deadline never time out.
+    pub actions: Vec<Box<RawValue>>,
+    pub seed: u64,
+}
+
+/// Every action of the scenario is taken, the run stops there.
+#[derive(Debug)]
+pub(crate) struct ScenarioExhausted;
+
+impl std::fmt::Display for ScenarioExhausted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("scenario ran out of actions")
+    }
+}
+
+impl std::error::Error for ScenarioExhausted {}
+
+impl Runtime {
+    /// Runs the game twice with same scenario and checks if every output are identical.
+    pub async fn check_determinism(&self, game_key: &str, scenario: &Scenario) -> Result<()> {
+        let first = self.run_scenario(game_key, scenario).await?;
+        let second = self.run_scenario(game_key, scenario).await?;
+        compare_traces(game_key, &first, &second)
+    }
+
+    async fn run_scenario(&self, game_key: &str, scenario: &Scenario) -> Result<Vec<String>> {
+        let mut session = self.new_session(game_key).await?;
+        session.conf.enable_state = true;
+
+        let (handler, trace) = TraceHandler::new(scenario);
+        match session
+            .start(16 * 1024, true, scenario.room.clone(), handler)
+            .await
+        {
+            Ok(()) => {}
+            Err(err) if err.downcast_ref::<ScenarioExhausted>().is_some() => {}
+            Err(err) => return Err(err),
+        }
+
+        let trace = std::mem::take(&mut *trace.lock().unwrap());
+        Ok(trace)
+    }
+}
+
+/// Fails at the first output the runs of the game differ.
+fn compare_traces(game_key: &str, first: &[String], second: &[String]) -> Result<()> {
+    if let Some((idx, (lhs, rhs))) = first
+        .iter()
+        .zip(second)
+        .enumerate()
+        .find(|(_, (lhs, rhs))| lhs != rhs)
+    {
+        anyhow::bail!("game {game_key} is not deterministic, output #{idx} differs:\n{lhs}\n{rhs}");
+    }
+    anyhow::ensure!(
+        first.len() == second.len(),
+        "game {game_key} is not deterministic, output count differs: {} and {}",
+        first.len(),
+        second.len(),
+    );
+
+    Ok(())
+}
+
+type Trace = Arc<Mutex<Vec<String>>>;
+
+/// Omniscient handler which records every output.
+struct TraceHandler {
+    actions: VecDeque<Box<RawValue>>,
+    rng: SeededRandom,
+    trace: Trace,
+}
+
+impl TraceHandler {
+    fn new(scenario: &Scenario) -> (Self, Trace) {
+        let trace = Trace::default();
+        let handler = TraceHandler {
+            actions: scenario.actions.iter().cloned().collect(),
+            rng: SeededRandom::new(scenario.seed),
+            trace: trace.clone(),
+        };
+
+        (handler, trace)
+    }
+
+    fn record(&self, entry: String) {
+        self.trace.lock().unwrap().push(entry);
+    }
+
+    fn next_action(&mut self) -> Result<Box<RawValue>> {
+        match self.actions.pop_front() {
+            Some(action) => Ok(action),
+            None => Err(ScenarioExhausted.into()),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl OutputHandler for TraceHandler {
+    fn state(&mut self, json: &RawValue) -> Result<()> {
+        self.record(format!("state {json}"));
+        Ok(())
+    }
+
+    fn private_state(&mut self, name: &str, target: PlayerId, json: &RawValue) -> Result<()> {
+        self.record(format!("privateState {name} {target} {json}"));
+        Ok(())
+    }
+
+    fn game_result(&mut self, json: &RawValue) -> Result<()> {
+        self.record(format!("gameResult {json}"));
+        Ok(())
+    }
+
+    async fn do_task_if(&mut self, allowed: Vec<PlayerId>) -> Result<TaskResult<Box<RawValue>>> {
+        self.record(format!("doTaskIf {allowed:?}"));
+        Ok(TaskResult::DoTask)
+    }
+
+    async fn task_done(&mut self, targets: Vec<PlayerId>, value: &RawValue) -> Result<()> {
+        self.record(format!("taskDone {targets:?} {value}"));
+        Ok(())
+    }
+
+    async fn notify(&mut self, targets: Vec<PlayerId>, value: &RawValue) -> Result<()> {
+        self.record(format!("notify {targets:?} {value}"));
+        Ok(())
+    }
+
+    async fn random(&mut self, start: i32, end: i32) -> Result<i32> {
+        let value = self.rng.next_in_range(start, end);
+        self.record(format!("random {start}..={end} {value}"));
+        Ok(value)
+    }
+
+    async fn random_bytes(&mut self, len: usize) -> Result<Vec<u8>> {
+        let mut bytes = vec![0; len];
+        self.rng.fill_bytes(&mut bytes);
+        self.record(format!("randomBytes {len} {bytes:?}"));
+        Ok(bytes)
+    }
+
+    async fn action(&mut self, from: PlayerId, param: &RawValue) -> Result<Box<RawValue>> {
+        let value = self.next_action()?;
+        self.record(format!("action {from} {param} {value}"));
+        Ok(value)
+    }
+
+    async fn action_with_deadline(
+        &mut self,
+        from: PlayerId,
+        param: &RawValue,
+        deadline: Duration,
+    ) -> Result<TimedAction<Box<RawValue>>> {
+        let value = self.next_action()?;
+        self.record(format!(
+            "actionWithDeadline {from} {param} {deadline:?} {value}"
+        ));
+        Ok(TimedAction::Acted(value))
+    }
+
+    async fn first_action(
+        &mut self,
+        from: Vec<PlayerId>,
+        param: &RawValue,
+        deadline: Option<Duration>,
+    ) -> Result<TimedAction<(PlayerId, Box<RawValue>)>> {
+        let value = self.next_action()?;
+        self.record(format!(
+            "firstAction {from:?} {param} {deadline:?} {} {value}",
+            from[0]
+        ));
+        Ok(TimedAction::Acted((from[0], value)))
+    }
+
+    async fn simultaneous_action(
+        &mut self,
+        from: Vec<PlayerId>,
+        param: &RawValue,
+    ) -> Result<Box<RawValue>> {
+        let mut values = BTreeMap::new();
+        for &player in &from {
+            values.insert(player, self.next_action()?);
+        }
+        let values = serde_json::value::to_raw_value(&values)?;
+        self.record(format!("simultaneousAction {from:?} {param} {values}"));
+        Ok(values)
+    }
+
+    async fn gather_actions(
+        &mut self,
+        requests: Vec<(PlayerId, Box<RawValue>)>,
+    ) -> Result<Box<RawValue>> {
+        let mut values = vec![];
+        for _ in &requests {
+            values.push(self.next_action()?);
+        }
+        let values = serde_json::value::to_raw_value(&values)?;
+        let requests = serde_json::to_string(&requests)?;
+        self.record(format!("gatherActions {requests} {values}"));
+        Ok(values)
+    }
+
+    async fn forfeited_players(&mut self) -> Result<Vec<PlayerId>> {
+        self.record("forfeitedPlayers []".into());
+        Ok(vec![])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_games, Color, Config};
+
+    const RED: PlayerId = PlayerId::new(Color::Red as u8);
+
+    fn scenario() -> Scenario {
+        Scenario {
+            room: RoomInfo {
+                players: vec![RED],
+                ..Default::default()
+            },
+            actions: vec![serde_json::value::to_raw_value("move").unwrap()],
+            seed: 7,
+        }
+    }
+
+    /// Draws a random number below `end`, then asks red to act.
+    fn game(end: i32) -> String {
+        test_games::game(&[
+            test_games::SESSION_START,
+            &format!(r#"{{"type":"random","data":{{"start":0,"end":{end}}}}}"#),
+            r#"{"type":"action","data":{"from":"red","param":null}}"#,
+            test_games::SESSION_END,
+        ])
+    }
+
+    #[tokio::test]
+    async fn deterministic_game_passes() {
+        let runtime = Runtime::new(Config::default()).unwrap();
+        runtime
+            .add_game("game".into(), game(100).as_bytes())
+            .unwrap();
+
+        runtime
+            .check_determinism("game", &scenario())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn running_out_of_actions_ends_the_runs() {
+        let runtime = Runtime::new(Config::default()).unwrap();
+        let code = test_games::game(&[
+            test_games::SESSION_START,
+            r#"{"type":"action","data":{"from":"red","param":null}}"#,
+            r#"{"type":"action","data":{"from":"red","param":null}}"#,
+            test_games::SESSION_END,
+        ]);
+        runtime.add_game("game".into(), code.as_bytes()).unwrap();
+
+        runtime
+            .check_determinism("game", &scenario())
+            .await
+            .unwrap();
+        let trace = runtime.run_scenario("game", &scenario()).await.unwrap();
+        assert_eq!(trace, [r#"action red null "move""#]);
+    }
+
+    #[tokio::test]
+    async fn nondeterministic_game_fails_the_check() {
+        // busy after its last output, long enough to replace it before the second run
+        let spin = "(i32.store (i32.const 200) (i32.const 200000000))
+    (loop $spin
+      (i32.store (i32.const 200) (i32.sub (i32.load (i32.const 200)) (i32.const 1)))
+      (br_if $spin (i32.load (i32.const 200))))";
+        let first = test_games::game_with(
+            &[
+                test_games::SESSION_START,
+                r#"{"type":"random","data":{"start":0,"end":100}}"#,
+                test_games::SESSION_END,
+            ],
+            spin,
+        );
+        let runtime = Arc::new(Runtime::new(Config::default()).unwrap());
+        runtime.add_game("game".into(), first.as_bytes()).unwrap();
+
+        // wasm can't tell the runs apart, so the game changes between them
+        let replacer = std::thread::spawn({
+            let runtime = runtime.clone();
+            move || loop {
+                let sessions = runtime.sessions();
+                if sessions.iter().any(|status| status.io_count == 3) {
+                    runtime.replace_game("game", game(50).as_bytes()).unwrap();
+                    return;
+                }
+                std::thread::sleep(Duration::from_millis(1));
+            }
+        });
+        let err = runtime
+            .check_determinism("game", &scenario())
+            .await
+            .unwrap_err();
+        replacer.join().unwrap();
+
+        let msg = err.to_string();
+        assert!(
+            msg.starts_with("game game is not deterministic, output #0 differs:\nrandom 0..=100 "),
+            "{msg}"
+        );
+        assert!(msg.contains("\nrandom 0..=50 "), "{msg}");
+    }
+
+    #[tokio::test]
+    async fn differing_runs_fail_at_the_first_difference() {
+        // wasm can't tell the runs apart, so the second run is of another game
+        let runtime = Runtime::new(Config::default()).unwrap();
+        runtime
+            .add_game("first".into(), game(100).as_bytes())
+            .unwrap();
+        runtime
+            .add_game("second".into(), game(50).as_bytes())
+            .unwrap();
+        let first = runtime.run_scenario("first", &scenario()).await.unwrap();
+        let second = runtime.run_scenario("second", &scenario()).await.unwrap();
+
+        let err = compare_traces("game", &first, &second).unwrap_err();
+        let msg = err.to_string();
+        assert!(
+            msg.starts_with("game game is not deterministic, output #0 differs:\nrandom 0..=100 "),
+            "{msg}"
+        );
+        assert!(msg.contains("\nrandom 0..=50 "), "{msg}");
+
+        let err = compare_traces("game", &first, &first[..1]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "game game is not deterministic, output count differs: {} and 1",
+                first.len()
+            )
+        );
+    }
+}
@@ -0,0 +1,31 @@
+use std::collections::VecDeque;
+
+use serde_json::value::RawValue;
+
+/// Max number of debug snapshots kept per session, older ones are dropped.
+const MAX_DEBUG_SNAPSHOTS: usize = 256;
+
+/// Labeled value the game captured with `rulebook::debug_snapshot`.
+#[derive(Debug, Clone)]
+pub struct DebugSnapshot {
+    pub label: String,
+    pub value: Box<RawValue>,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Diagnostics {
+    snapshots: VecDeque<DebugSnapshot>,
+}
+
+impl Diagnostics {
+    pub fn push(&mut self, snapshot: DebugSnapshot) {
+        if self.snapshots.len() == MAX_DEBUG_SNAPSHOTS {
+            self.snapshots.pop_front();
+        }
+        self.snapshots.push_back(snapshot);
+    }
+
+    pub fn snapshots(&self) -> Vec<DebugSnapshot> {
+        self.snapshots.iter().cloned().collect()
+    }
+}
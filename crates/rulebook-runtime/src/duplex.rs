@@ -0,0 +1,59 @@
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use anyhow::Result;
+use futures::channel::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use futures::sink::Sink;
+use futures::stream::Stream;
+
+/// One end of an in-memory transport, to run `Channel`s against each other without sockets.
+///
+/// Frames sent on one end are received by the other in order. Dropping an end ends
+/// the stream of the other and fails its sends, like a closed connection.
+#[derive(Debug)]
+pub struct DuplexChannel {
+    tx: UnboundedSender<Vec<u8>>,
+    rx: UnboundedReceiver<Vec<u8>>,
+}
+
+impl DuplexChannel {
+    /// Two ends connected to each other.
+    pub fn pair() -> (Self, Self) {
+        let (tx_a, rx_b) = mpsc::unbounded();
+        let (tx_b, rx_a) = mpsc::unbounded();
+        (
+            DuplexChannel { tx: tx_a, rx: rx_a },
+            DuplexChannel { tx: tx_b, rx: rx_b },
+        )
+    }
+}
+
+impl Stream for DuplexChannel {
+    type Item = Result<Vec<u8>>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.rx)
+            .poll_next(cx)
+            .map(|frame| frame.map(Ok))
+    }
+}
+
+impl Sink<Vec<u8>> for DuplexChannel {
+    type Error = anyhow::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.tx).poll_ready(cx).map_err(Into::into)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: Vec<u8>) -> Result<()> {
+        Pin::new(&mut self.tx).start_send(item).map_err(Into::into)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.tx).poll_flush(cx).map_err(Into::into)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.tx).poll_close(cx).map_err(Into::into)
+    }
+}
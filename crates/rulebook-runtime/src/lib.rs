@@ -1,44 +1,261 @@
 use std::collections::hash_map::{Entry, HashMap};
-use std::sync::{Arc, RwLock};
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex as StdMutex, RwLock};
+use std::time::{Duration, Instant};
 
 use anyhow::{Context, Result};
+use serde::Serialize;
+use serde_json::json;
 use serde_json::value::RawValue;
 use tokio::sync::Mutex;
-use wasmtime::{Caller, Engine, Extern, Func, Instance, Memory, Module, OptLevel, Store};
+use tracing::Instrument;
+use wasmtime::{
+    AsContextMut, Caller, Engine, Extern, ExternType, Func, FuncType, Linker, Memory, Module,
+    OptLevel, Store, Trap, ValType,
+};
 
 use rulebook_interface_types::Output;
 
-pub use rulebook_interface_types::{PlayerId, RoomInfo, SessionInfo, TaskResult};
+pub use rulebook_interface_types::ws_protocol;
+pub use rulebook_interface_types::{
+    ActionSchema, Color, EndReason, GameError, GameInfo, GameSchema, LobbyMessage, LobbyRequest,
+    LobbyUpdate, LogLevel, PlayerId, RoomInfo, RuntimeError, SessionInfo, TaskResult, TimedAction,
+    ABI_VERSION, PROTOCOL_VERSION, RECONNECT_TOKEN_HEADER,
+};
 
 pub mod channel;
+pub mod codec;
+#[cfg(feature = "deflate")]
+pub mod compress;
+mod conformance;
+mod determinism;
+mod diagnostics;
+#[cfg(any(test, feature = "testing"))]
+pub mod duplex;
+mod limits;
+mod log_limit;
+mod metrics;
+mod patch;
+mod random;
+mod registry;
+mod replay;
+mod scope;
+mod state_limit;
 pub mod task;
+#[cfg(any(test, feature = "testing"))]
+pub mod test_games;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+mod trace;
 
-#[derive(Debug, Default, Clone)]
+pub use conformance::{CheckOutcome, ConformanceReport, EXPECTED_OUTPUTS};
+pub use determinism::Scenario;
+pub use diagnostics::DebugSnapshot;
+pub use metrics::MetricsSnapshot;
+pub use random::{RandomSource, SeededRandom};
+pub use registry::{CancelHandle, SessionId, SessionStatus};
+pub use replay::{Event, EventKind, EventLog, RecordingHandler, ReplayHandler};
+pub use trace::{SessionTrace, TraceEntry};
+
+use diagnostics::Diagnostics;
+use limits::Limits;
+use log_limit::LogLimiter;
+use metrics::Metrics;
+use patch::StateMirror;
+use registry::{SessionControl, SessionRegistry};
+use scope::{ScopeChange, ScopeTracker};
+use state_limit::StateLimiter;
+use trace::{Recorder, SessionSnapshot};
+
+#[derive(Debug, Clone)]
 pub struct Config {
     pub enable_state: bool,
     pub enable_logging: bool,
+    pub unknown_imports: ImportPolicy,
+    /// Max number of guest log messages printed per second, excess are suppressed.
+    pub log_rate_limit: Option<u32>,
+    /// Max number of state updates passed to the handler per second.
+    /// Excess updates are coalesced, only the latest one is passed later.
+    /// Private states aren't limited.
+    pub state_rate_limit: Option<u32>,
+    /// Max number of epochs the game may run without performing IO.
+    /// Each epoch lasts `EPOCH_INTERVAL`.
+    pub max_execution_epochs: Option<u64>,
+    /// Amount of fuel, roughly the number of wasm instructions, the game may consume.
+    pub fuel_per_session: Option<u64>,
+    /// Max number of IO the game may perform in a session, like requesting an action or a random.
+    /// Catches games looping on IO which neither epochs nor fuel stop quickly.
+    pub max_io_calls: Option<u64>,
+    /// Max size of the game's linear memory.
+    pub max_memory_bytes: Option<usize>,
+    /// Max number of elements of each of the game's tables.
+    pub max_table_elements: Option<u32>,
+    /// Max number of sessions alive at once, `new_session` fails beyond it.
+    pub max_concurrent_sessions: Option<usize>,
+    /// Max time to wait for players to submit an action.
+    pub action_timeout: Option<Duration>,
+    /// Record every IO of sessions, retrieve them with `Session::trace`.
+    pub record_trace: bool,
+    /// Name the game exports its linear memory under.
+    pub memory_export_name: Arc<str>,
+    /// Compile functions of each game on multiple threads, faster to add games on multi-core machines.
+    /// The number of threads follows `RAYON_NUM_THREADS`, every core by default.
+    pub parallel_compilation: bool,
 }
 
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            enable_state: false,
+            enable_logging: false,
+            unknown_imports: ImportPolicy::default(),
+            log_rate_limit: None,
+            state_rate_limit: None,
+            max_execution_epochs: None,
+            fuel_per_session: None,
+            max_io_calls: None,
+            max_memory_bytes: None,
+            max_table_elements: None,
+            max_concurrent_sessions: None,
+            action_timeout: None,
+            record_trace: false,
+            memory_export_name: "memory".into(),
+            parallel_compilation: true,
+        }
+    }
+}
+
+pub const EPOCH_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How to treat games which import functions other than the rulebook host functions.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ImportPolicy {
+    /// Fail to add the game.
+    #[default]
+    Reject,
+    /// Log them on add, and link them to functions which trap when called.
+    Warn,
+}
+
+const IMPORT_MODULE: &str = "env";
+/// Name, params and results of the functions games may import.
+const HOST_FUNCTIONS: &[(&str, &[ValType], &[ValType])] = &[
+    ("rulebook_trigger_io", &[ValType::I32], &[ValType::I32]),
+    ("rulebook_log", &[ValType::I32, ValType::I32], &[]),
+    (
+        "rulebook_log_at",
+        &[ValType::I32, ValType::I32, ValType::I32],
+        &[],
+    ),
+    (
+        "rulebook_take_input",
+        &[ValType::I32, ValType::I32],
+        &[ValType::I32],
+    ),
+];
+
 pub struct Runtime {
     engine: Engine,
     modules: RwLock<HashMap<Arc<str>, Module>>,
     conf: Config,
+    stop_epoch_ticker: Arc<AtomicBool>,
+    sessions: Arc<SessionRegistry>,
+    metrics: Arc<Metrics>,
 }
 
 pub struct Session {
     game_key: Arc<str>,
-    store: Store<RoomInfo>,
+    store: Store<SessionData>,
     module: Module,
     conf: Config,
+    id: SessionId,
+    control: Arc<SessionControl>,
+    registry: Arc<SessionRegistry>,
+    metrics: Arc<Metrics>,
+    recorder: Arc<StdMutex<Recorder>>,
+    diagnostics: Arc<StdMutex<Diagnostics>>,
+    /// Reported by `Output::GameResult`.
+    result: Arc<StdMutex<Option<Box<RawValue>>>>,
+    /// Linear memory of the game, set once `start` instantiates it.
+    memory: Option<Memory>,
+}
+
+struct SessionData {
+    room: RoomInfo,
+    limits: Limits,
+    /// Input too large for the game's buffer, until it takes it with `rulebook_take_input`.
+    pending_input: Option<String>,
+    /// Epochs the game may still run until its next IO, see `Config::max_execution_epochs`.
+    epochs_left: Option<u64>,
 }
 
 #[async_trait::async_trait]
 pub trait OutputHandler: Send + 'static {
     fn state(&mut self, json: &RawValue) -> Result<()>;
+    /// State of the private store `name`, to be shown only to `target`.
+    fn private_state(&mut self, name: &str, target: PlayerId, json: &RawValue) -> Result<()>;
     async fn do_task_if(&mut self, allowed: Vec<PlayerId>) -> Result<TaskResult<Box<RawValue>>>;
     async fn task_done(&mut self, targets: Vec<PlayerId>, value: &RawValue) -> Result<()>;
+    /// Delivers the value to `targets` within the current scope, see `rulebook::notify`.
+    async fn notify(&mut self, targets: Vec<PlayerId>, value: &RawValue) -> Result<()>;
     async fn random(&mut self, start: i32, end: i32) -> Result<i32>;
+    /// Returns `len` random bytes, the entropy of `rulebook::shuffle` and its friends.
+    async fn random_bytes(&mut self, len: usize) -> Result<Vec<u8>>;
     async fn action(&mut self, from: PlayerId, param: &RawValue) -> Result<Box<RawValue>>;
+    /// Like `action`, but gives up once the deadline passes, see `rulebook::action_timed`.
+    async fn action_with_deadline(
+        &mut self,
+        from: PlayerId,
+        param: &RawValue,
+        deadline: Duration,
+    ) -> Result<TimedAction<Box<RawValue>>>;
+    /// Action of whoever of `from` acts first, see `rulebook::first_action`.
+    /// Times out only if `deadline` is given.
+    async fn first_action(
+        &mut self,
+        from: Vec<PlayerId>,
+        param: &RawValue,
+        deadline: Option<Duration>,
+    ) -> Result<TimedAction<(PlayerId, Box<RawValue>)>>;
+    /// Returns JSON object of each player's action keyed by the player.
+    async fn simultaneous_action(
+        &mut self,
+        from: Vec<PlayerId>,
+        param: &RawValue,
+    ) -> Result<Box<RawValue>>;
+    /// Like `simultaneous_action` with a param for each player,
+    /// returns JSON array of the actions in the order of `requests`.
+    async fn gather_actions(
+        &mut self,
+        requests: Vec<(PlayerId, Box<RawValue>)>,
+    ) -> Result<Box<RawValue>>;
+    /// Players removed from the game so far, see `rulebook::forfeited_players`.
+    /// Hosts which never remove players may keep the default.
+    async fn forfeited_players(&mut self) -> Result<Vec<PlayerId>> {
+        Ok(vec![])
+    }
+    /// Called on operations within the admin scope, which no player can check,
+    /// like `rulebook::do_if_admin` and random numbers drawn within it.
+    ///
+    /// `kind` is the type of the output, one of `doTaskIf`, `taskDone`, `random`
+    /// and `randomBytes`, with its detail. Failing it aborts the session.
+    fn admin_audit(&mut self, _kind: &str, _detail: &RawValue) -> Result<()> {
+        Ok(())
+    }
+    /// Called once the session ends, with the reason the game gave on `sessionEnd`
+    /// or one derived from the error the session failed with.
+    ///
+    /// Hosts may attach an `EndReason` to their errors to tell it instead, like
+    /// `EndReason::PlayerLeft` for a player who forfeited.
+    async fn session_end(&mut self, _reason: &EndReason) -> Result<()> {
+        Ok(())
+    }
+    /// Final result the game reported, see `rulebook::report_result`.
+    /// It's kept in `Session::game_result` too.
+    fn game_result(&mut self, _json: &RawValue) -> Result<()> {
+        Ok(())
+    }
 }
 
 impl Runtime {
@@ -46,25 +263,77 @@ impl Runtime {
         let engine = Engine::new(
             wasmtime::Config::new()
                 .async_support(true)
-                // .epoch_interruption(true) // TODO: enable to split long running wasm code
+                // cancelling sessions bumps the epoch too, see `CancelHandle::cancel`
+                .epoch_interruption(true)
+                .consume_fuel(conf.fuel_per_session.is_some())
+                .parallel_compilation(conf.parallel_compilation)
                 .cranelift_opt_level(OptLevel::Speed)
                 .cranelift_nan_canonicalization(true),
         )?;
 
+        let stop_epoch_ticker = Arc::new(AtomicBool::new(false));
+        if conf.max_execution_epochs.is_some() {
+            let engine = engine.clone();
+            let stop = stop_epoch_ticker.clone();
+            std::thread::Builder::new()
+                .name("rulebook-epoch-ticker".into())
+                .spawn(move || {
+                    while !stop.load(Ordering::Relaxed) {
+                        std::thread::sleep(EPOCH_INTERVAL);
+                        engine.increment_epoch();
+                    }
+                })?;
+        }
+
         Ok(Runtime {
-            engine,
+            engine: engine.clone(),
             modules: Default::default(),
             conf,
+            stop_epoch_ticker,
+            sessions: Arc::new(SessionRegistry::new(engine.clone())),
+            metrics: Default::default(),
         })
     }
 
     pub fn add_game(&self, key: Arc<str>, code: &[u8]) -> Result<()> {
         // fail fast on dupe
-        if self.modules.read().unwrap().contains_key(&key) {
+        if self.has_game(&key) {
             anyhow::bail!("game key {key} already exist")
         }
 
-        let module = Module::new(&self.engine, code)?;
+        let module = compile(&self.engine, code).with_context(|| format!("game {key}"))?;
+        self.insert_game(key, module)
+    }
+
+    /// Compiles the game ahead of time, so it can be loaded quickly
+    /// with `add_game_precompiled`.
+    pub fn precompile(&self, code: &[u8]) -> Result<Vec<u8>> {
+        compile(&self.engine, code)?.serialize()
+    }
+
+    /// Adds a game compiled with `precompile`, skipping compilation.
+    ///
+    /// Precompiled artifacts are only compatible with the same version of the runtime
+    /// configured with the same `Config`. Incompatible ones are rejected with an error.
+    ///
+    /// # Safety
+    ///
+    /// `bytes` must be produced by `precompile`. Loading arbitrary bytes
+    /// can result in arbitrary code execution, as it's machine code.
+    pub unsafe fn add_game_precompiled(&self, key: Arc<str>, bytes: &[u8]) -> Result<()> {
+        // fail fast on dupe
+        if self.has_game(&key) {
+            anyhow::bail!("game key {key} already exist")
+        }
+
+        let module = Module::deserialize(&self.engine, bytes).with_context(|| {
+            format!("precompiled game {key} is incompatible with this runtime, recompile it")
+        })?;
+        self.insert_game(key, module)
+    }
+
+    fn insert_game(&self, key: Arc<str>, module: Module) -> Result<()> {
+        self.check_linkage(&key, &module)?;
 
         match self.modules.write().unwrap().entry(key.clone()) {
             Entry::Occupied(_) => anyhow::bail!("game key {key} already exist"),
@@ -76,12 +345,259 @@ impl Runtime {
         Ok(())
     }
 
+    /// Checks both imports and exports, listing every problem found in a single error.
+    fn check_linkage(&self, key: &str, module: &Module) -> Result<()> {
+        let mut problems = self.import_problems(key, module);
+        problems.extend(export_problems(module, &self.conf.memory_export_name));
+
+        match &*problems {
+            [] => Ok(()),
+            [problem] => anyhow::bail!("game {key} {problem}"),
+            _ => anyhow::bail!("game {key} is incompatible: {}", problems.join(", ")),
+        }
+    }
+
+    fn import_problems(&self, key: &str, module: &Module) -> Vec<String> {
+        let mut problems = vec![];
+        let mut unexpected = vec![];
+        let mut mismatched = vec![];
+        for import in module.imports() {
+            let name = format!("{}::{}", import.module(), import.name());
+            let host_func = HOST_FUNCTIONS
+                .iter()
+                .find(|(func, ..)| import.module() == IMPORT_MODULE && import.name() == *func);
+            let Some((_, params, results)) = host_func else {
+                unexpected.push(name);
+                continue;
+            };
+
+            let expected = FuncType::new(params.iter().cloned(), results.iter().cloned());
+            match import.ty() {
+                ExternType::Func(ty) if ty == expected => {}
+                ty => mismatched.push(format!(
+                    "{name} as {}, expected {}",
+                    describe(&ty),
+                    describe(&expected.into())
+                )),
+            }
+        }
+
+        // these can't be linked regardless of the policy
+        if !mismatched.is_empty() {
+            problems.push(format!(
+                "imports host functions with wrong types: {mismatched:?}"
+            ));
+        }
+        if unexpected.is_empty() {
+            return problems;
+        }
+
+        match self.conf.unknown_imports {
+            ImportPolicy::Reject => {
+                problems.push(format!("imports unexpected functions: {unexpected:?}"))
+            }
+            ImportPolicy::Warn => {
+                tracing::warn!(
+                    game_key = %key,
+                    ?unexpected,
+                    "game imports unexpected functions, they will trap"
+                );
+            }
+        }
+        problems
+    }
+
+    /// Replaces the code of an existing game.
+    ///
+    /// Sessions created before the replace keep running the previous code,
+    /// new sessions run the new one.
+    pub fn replace_game(&self, key: &str, code: &[u8]) -> Result<()> {
+        let module = compile(&self.engine, code).with_context(|| format!("game {key}"))?;
+        self.check_linkage(key, &module)?;
+
+        match self.modules.write().unwrap().get_mut(key) {
+            Some(prev) => *prev = module,
+            None => anyhow::bail!("game key {key} not exist"),
+        }
+
+        Ok(())
+    }
+
+    /// Keys of every game added, in order.
+    pub fn game_keys(&self) -> Vec<Arc<str>> {
+        let mut keys: Vec<_> = self.modules.read().unwrap().keys().cloned().collect();
+        keys.sort();
+        keys
+    }
+
+    /// Whether a game of the key is added and not removed since.
+    pub fn has_game(&self, key: &str) -> bool {
+        self.modules.read().unwrap().contains_key(key)
+    }
+
     pub fn remove_game(&self, key: &str) -> bool {
         self.modules.write().unwrap().remove(key).is_some()
     }
 
+    /// Number of sessions currently alive.
+    pub fn active_sessions(&self) -> usize {
+        self.sessions.len()
+    }
+
+    /// Counters of every session created so far.
+    pub fn metrics_snapshot(&self) -> MetricsSnapshot {
+        self.metrics.snapshot()
+    }
+
+    /// Status of every session currently alive, ordered by creation.
+    pub fn sessions(&self) -> Vec<SessionStatus> {
+        self.sessions.list()
+    }
+
+    /// Cancels the session like `CancelHandle::cancel`.
+    ///
+    /// Returns `false` if the session doesn't exist.
+    pub fn cancel_session(&self, id: SessionId) -> bool {
+        self.sessions.cancel(id)
+    }
+
+    fn new_store(&self, conf: &Config, control: Arc<SessionControl>) -> Store<SessionData> {
+        let mut store = Store::new(
+            &self.engine,
+            SessionData {
+                room: RoomInfo::default(),
+                limits: Limits::new(conf.max_memory_bytes, conf.max_table_elements),
+                pending_input: None,
+                epochs_left: conf.max_execution_epochs,
+            },
+        );
+        store.limiter(|data| &mut data.limits);
+        // checked on every epoch, so the game notices cancel on the next one
+        store.epoch_deadline_callback(move |data| {
+            if control.cancelled.load(Ordering::Relaxed) {
+                return Err(anyhow::anyhow!("session cancelled while running the game")
+                    .context(RuntimeError::Cancelled));
+            }
+            match &mut data.epochs_left {
+                Some(left) => {
+                    *left = left.saturating_sub(1);
+                    if *left == 0 {
+                        return Err(Trap::Interrupt.into());
+                    }
+                    Ok(1)
+                }
+                None => Ok(1),
+            }
+        });
+        store.set_epoch_deadline(1);
+        store
+    }
+
+    /// Metadata the game declares, default if the game doesn't.
+    ///
+    /// It instantiates the game to ask, so cache it rather than calling repeatedly.
+    pub async fn game_info(&self, game_key: &str) -> Result<GameInfo> {
+        let Some(json) = self.read_export(game_key, "rulebook_game_info").await? else {
+            return Ok(GameInfo::default());
+        };
+
+        serde_json::from_slice(&json)
+            .context("invalid game info")
+            .context(RuntimeError::Protocol)
+    }
+
+    /// JSON schemas of the game's state and actions, see `GameSchema`.
+    ///
+    /// Fails if the game doesn't export it, i.e. built without the `schema` feature of `rulebook`.
+    pub async fn game_schema(&self, game_key: &str) -> Result<Box<RawValue>> {
+        let json = self
+            .read_export(game_key, "rulebook_schema")
+            .await?
+            .with_context(|| format!("game {game_key} doesn't export its schema"))?;
+
+        let json = String::from_utf8(json)
+            .context("invalid game schema")
+            .context(RuntimeError::Protocol)?;
+        // validate it here, so hosts can pass it through as is
+        serde_json::from_str::<GameSchema>(&json)
+            .context("invalid game schema")
+            .context(RuntimeError::Protocol)?;
+        Ok(RawValue::from_string(json)?)
+    }
+
+    /// Calls the export which returns a JSON packed in `u64`, `None` if not exported.
+    async fn read_export(&self, game_key: &str, name: &str) -> Result<Option<Vec<u8>>> {
+        let module = self
+            .modules
+            .read()
+            .unwrap()
+            .get(game_key)
+            .cloned()
+            .context("game key not exist")?;
+        if module.get_export(name).is_none() {
+            return Ok(None);
+        }
+
+        let mut store = self.new_store(&self.conf, Default::default());
+        if let Some(fuel) = self.conf.fuel_per_session {
+            store.add_fuel(fuel)?;
+        }
+
+        // host functions aren't available while asking the info
+        let mut linker = Linker::new(&self.engine);
+        linker.define_unknown_imports_as_traps(&module)?;
+        let instance = linker.instantiate_async(&mut store, &module).await?;
+
+        let packed = instance
+            .get_typed_func::<(), u64>(&mut store, name)?
+            .call_async(&mut store, ())
+            .await?;
+        let Some(Extern::Memory(memory)) =
+            instance.get_export(&mut store, &self.conf.memory_export_name)
+        else {
+            anyhow::bail!(
+                "wasm memory is not exported under the name `{}`",
+                self.conf.memory_export_name
+            )
+        };
+
+        let (ptr, len) = ((packed >> 32) as usize, packed as u32 as usize);
+        let json = memory
+            .data(&store)
+            .get(ptr..ptr + len)
+            .with_context(|| format!("{name} returned out of wasm memory"))
+            .context(RuntimeError::Protocol)?;
+        Ok(Some(json.to_vec()))
+    }
+
     pub async fn new_session(&self, game_key: &str) -> Result<Session> {
-        let store = Store::new(&self.engine, RoomInfo::default());
+        self.new_session_with_config(game_key, self.conf.clone())
+            .await
+    }
+
+    /// Like `new_session`, but the session runs with `conf` instead of the runtime's config.
+    ///
+    /// Options fixed on `Runtime::new` and `add_game` must be the same as the runtime's,
+    /// i.e. whether execution time and fuel are limited, `unknown_imports`
+    /// and `memory_export_name`. `max_concurrent_sessions` and `parallel_compilation` are ignored.
+    pub async fn new_session_with_config(&self, game_key: &str, conf: Config) -> Result<Session> {
+        anyhow::ensure!(
+            conf.max_execution_epochs.is_some() == self.conf.max_execution_epochs.is_some(),
+            "session config can't toggle the execution time limit of the runtime"
+        );
+        anyhow::ensure!(
+            conf.fuel_per_session.is_some() == self.conf.fuel_per_session.is_some(),
+            "session config can't toggle the fuel limit of the runtime"
+        );
+        anyhow::ensure!(
+            conf.unknown_imports == self.conf.unknown_imports,
+            "session config can't change unknown import policy of the runtime"
+        );
+        anyhow::ensure!(
+            conf.memory_export_name == self.conf.memory_export_name,
+            "session config can't change memory export name of the runtime"
+        );
+
         let (game_key, module) = self
             .modules
             .read()
@@ -90,20 +606,255 @@ impl Runtime {
             .map(|(k, v)| (k.clone(), v.clone()))
             .context("game key not exis")?;
 
+        let (id, control) = self
+            .sessions
+            .register(game_key.clone(), self.conf.max_concurrent_sessions)?;
+        self.metrics.session_created();
+
         Ok(Session {
+            store: self.new_store(&conf, control.clone()),
             game_key,
-            store,
             module,
-            conf: self.conf.clone(),
+            id,
+            control,
+            registry: self.sessions.clone(),
+            metrics: self.metrics.clone(),
+            recorder: Arc::new(StdMutex::new(Recorder::new(conf.record_trace))),
+            conf,
+            diagnostics: Default::default(),
+            result: Default::default(),
+            memory: None,
         })
     }
+
+    /// Rebuilds a session from `Session::snapshot`, possibly in another process.
+    ///
+    /// The game is fast-forwarded on `start`, pass `Session::room` to it.
+    pub async fn restore_session(&self, snapshot: &[u8]) -> Result<Session> {
+        let snapshot: SessionSnapshot =
+            serde_json::from_slice(snapshot).context("invalid session snapshot")?;
+
+        let mut session = self.new_session(&snapshot.game_key).await?;
+        session.store.data_mut().room = snapshot.room;
+        session.resume_from_trace(snapshot.trace);
+        Ok(session)
+    }
+}
+
+/// Passes the operation within the admin scope to `OutputHandler::admin_audit`.
+async fn audit<T: OutputHandler>(
+    handler: &Mutex<T>,
+    kind: &str,
+    detail: impl Serialize,
+) -> Result<()> {
+    let detail = serde_json::value::to_raw_value(&detail)?;
+    handler.lock().await.admin_audit(kind, &detail)
+}
+
+/// Fails with `RuntimeError::Timeout` if `action` doesn't finish in time.
+async fn with_timeout<T>(
+    timeout: Option<Duration>,
+    from: &[PlayerId],
+    action: impl std::future::Future<Output = Result<T>>,
+) -> Result<T> {
+    let Some(timeout) = timeout else {
+        return action.await;
+    };
+
+    match tokio::time::timeout(timeout, action).await {
+        Ok(res) => res,
+        Err(_) => Err(anyhow::Error::new(RuntimeError::Timeout).context(format!(
+            "players {from:?} didn't submit action in {timeout:?}"
+        ))),
+    }
+}
+
+/// Compiles the game, telling what's wrong with the code if it's not even a wasm module.
+fn compile(engine: &Engine, code: &[u8]) -> Result<Module> {
+    // wasmtime's errors on them are cryptic
+    if let Some(problem) = header_problem(code) {
+        anyhow::bail!("{problem}, {} bytes", code.len());
+    }
+    Module::new(engine, code)
+        .map_err(|err| err.context(format!("compilation failed, {} bytes", code.len())))
+}
+
+/// Checks the first 8 bytes, the magic and the version of wasm binaries.
+///
+/// `None` if they look right, or if it's the text format which wasmtime parses itself.
+fn header_problem(code: &[u8]) -> Option<String> {
+    const MAGIC: &[u8] = b"\0asm";
+    const VERSION: [u8; 4] = [1, 0, 0, 0];
+
+    if !code.is_empty() && MAGIC.starts_with(code) {
+        return Some("truncated wasm binary (incomplete header)".into());
+    }
+    if !code.starts_with(MAGIC) {
+        let text = code
+            .iter()
+            .find(|b| !b.is_ascii_whitespace())
+            .is_some_and(|&b| b == b'(');
+        return (!text).then(|| "not a wasm binary (bad magic)".into());
+    }
+    match code.get(4..8) {
+        None => Some("truncated wasm binary (incomplete header)".into()),
+        Some(version) if version == VERSION => None,
+        // components share the magic, but use another version and layer
+        Some([_, _, 1, 0]) => Some("wasm component, only core modules are supported".into()),
+        Some(version) => Some(format!("unsupported wasm binary version {version:?}")),
+    }
+}
+
+/// Checks the game exports everything the guest ABI requires with expected types.
+fn export_problems(module: &Module, memory_name: &str) -> Vec<String> {
+    let mut problems = vec![];
+    let mut expect_func = |name: &str, params: &[ValType], results: &[ValType], required| {
+        let expected = FuncType::new(params.iter().cloned(), results.iter().cloned());
+        match module.get_export(name) {
+            Some(ExternType::Func(ty)) if ty == expected => {}
+            Some(ty) => problems.push(format!(
+                "exports {name} as {}, expected {}",
+                describe(&ty),
+                describe(&expected.into())
+            )),
+            None if required => problems.push(format!("doesn't export {name}")),
+            None => {}
+        }
+    };
+
+    expect_func(
+        "rulebook_start_session",
+        &[ValType::I32, ValType::I32],
+        &[],
+        true,
+    );
+    // games built before the ABI is versioned don't export it, they're version 1
+    expect_func("rulebook_abi_version", &[], &[ValType::I32], false);
+    expect_func("rulebook_game_info", &[], &[ValType::I64], false);
+    expect_func("rulebook_schema", &[], &[ValType::I64], false);
+    if !matches!(module.get_export(memory_name), Some(ExternType::Memory(_))) {
+        problems.push(format!(
+            "doesn't export its memory under the name `{memory_name}`"
+        ));
+    }
+
+    problems
+}
+
+/// Formats the type for error messages, like `fn(i32, i32) -> ()`.
+fn describe(ty: &ExternType) -> String {
+    let join = |types: &mut dyn Iterator<Item = ValType>| {
+        types
+            .map(|ty| ty.to_string())
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    match ty {
+        ExternType::Func(ty) => format!(
+            "fn({}) -> ({})",
+            join(&mut ty.params()),
+            join(&mut ty.results())
+        ),
+        ExternType::Global(_) => "global".into(),
+        ExternType::Table(_) => "table".into(),
+        ExternType::Memory(_) => "memory".into(),
+    }
+}
+
+impl Drop for Runtime {
+    fn drop(&mut self) {
+        self.stop_epoch_ticker.store(true, Ordering::Relaxed);
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.registry.unregister(self.id);
+    }
 }
 
 impl Session {
+    pub fn id(&self) -> SessionId {
+        self.id
+    }
+
     pub fn game_key(&self) -> &str {
         &self.game_key
     }
 
+    /// Every IO recorded so far, requires `Config::record_trace`.
+    ///
+    /// Take it after `start` returns, like when the session is cancelled to pause it.
+    pub fn trace(&self) -> SessionTrace {
+        self.recorder.lock().unwrap().trace()
+    }
+
+    /// Makes the next `start` fast-forward the game through the trace, then continue live.
+    ///
+    /// Handler isn't called for replayed IO. Game must produce the same outputs as the trace,
+    /// otherwise the session fails with `RuntimeError::Protocol`.
+    pub fn resume_from_trace(&mut self, trace: SessionTrace) {
+        self.recorder.lock().unwrap().set_replay(trace);
+    }
+
+    /// Serializes the session to restore it later with `Runtime::restore_session`,
+    /// requires `Config::record_trace`.
+    ///
+    /// It records the IO rather than the wasm memory, so it survives runtime upgrades as long as
+    /// the game code doesn't change. Take it after `start` returns, like when the session
+    /// is cancelled to pause it. IO the cancel stopped isn't recorded, the restored game
+    /// performs it again live.
+    ///
+    /// Handler state isn't included. Restored sessions resume with a fresh handler,
+    /// so pause the session while the game isn't within `doTaskIf` to keep the handler consistent.
+    pub fn snapshot(&self) -> Result<Vec<u8>> {
+        let recorder = self.recorder.lock().unwrap();
+        anyhow::ensure!(
+            recorder.is_recording(),
+            "session snapshot requires Config::record_trace"
+        );
+
+        let snapshot = SessionSnapshot {
+            game_key: self.game_key.to_string(),
+            room: self.store.data().room.clone(),
+            trace: recorder.trace(),
+        };
+        Ok(serde_json::to_vec(&snapshot)?)
+    }
+
+    /// Debug snapshots the game captured, latest last.
+    pub fn debug_snapshots(&self) -> Vec<DebugSnapshot> {
+        self.diagnostics.lock().unwrap().snapshots()
+    }
+
+    /// Final result the game reported, `None` if it didn't report one (yet).
+    pub fn game_result(&self) -> Option<Box<RawValue>> {
+        self.result.lock().unwrap().clone()
+    }
+
+    pub fn room(&self) -> &RoomInfo {
+        &self.store.data().room
+    }
+
+    /// Returns a handle to cancel this session, even while `start` is running.
+    pub fn cancel_handle(&self) -> CancelHandle {
+        CancelHandle::new(self.control.clone())
+    }
+
+    /// Fuel left for this session, `None` if fuel metering is disabled.
+    pub fn fuel_remaining(&self) -> Option<u64> {
+        let fuel = self.conf.fuel_per_session?;
+        let consumed = self.store.fuel_consumed()?;
+        Some(fuel.saturating_sub(consumed))
+    }
+
+    /// Current size in bytes of the game's linear memory, `None` before `start` instantiates it.
+    ///
+    /// Linear memory never shrinks, so after `start` returns it's the peak of the session.
+    pub fn memory_size(&self) -> Option<usize> {
+        Some(self.memory?.data_size(&self.store))
+    }
+
     pub async fn start<T>(
         &mut self,
         input_caps: u32,
@@ -114,24 +865,81 @@ impl Session {
     where
         T: OutputHandler,
     {
-        *self.store.data_mut() = room;
+        self.store.data_mut().room = room;
 
         let Config {
             enable_state,
             enable_logging,
+            unknown_imports,
+            log_rate_limit,
+            state_rate_limit,
+            max_execution_epochs,
+            fuel_per_session,
+            max_io_calls,
+            action_timeout,
+            ..
         } = self.conf;
+        let memory_name = self.conf.memory_export_name.clone();
+        // games which import it grow their buffer on inputs exceeding `input_cap`
+        let grows_input = self.module.imports().any(|import| {
+            import.module() == IMPORT_MODULE && import.name() == "rulebook_take_input"
+        });
+
+        if let Some(fuel) = fuel_per_session {
+            self.store.add_fuel(fuel)?;
+        }
 
         let handler = Arc::new(Mutex::new(handler));
-        let func_trigger_io = Func::wrap1_async(
-            &mut self.store,
+        // whether the handler heard of the end from the game itself
+        let ended = Arc::new(AtomicBool::new(false));
+        let state_limiter = state_rate_limit
+            .filter(|_| enable_state)
+            .map(|limit| Arc::new(StdMutex::new(StateLimiter::new(limit))));
+        let state_mirror = Arc::new(StdMutex::new(StateMirror::default()));
+        let scope = Arc::new(StdMutex::new(ScopeTracker::default()));
+        let func_trigger_io = Func::wrap1_async(&mut self.store, {
+            let handler = handler.clone();
+            let ended = ended.clone();
+            let memory_name = memory_name.clone();
+            let control = self.control.clone();
+            let recorder = self.recorder.clone();
+            let diagnostics = self.diagnostics.clone();
+            let result = self.result.clone();
             move |mut caller: Caller<'_, _>, params_ptr: u32| {
                 let handler = handler.clone();
+                let ended = ended.clone();
+                let recorder = recorder.clone();
+                let diagnostics = diagnostics.clone();
+                let result = result.clone();
+                let state_limiter = state_limiter.clone();
+                let state_mirror = state_mirror.clone();
+                let scope = scope.clone();
+                let memory_name = memory_name.clone();
+                let io_count = control.io_count.fetch_add(1, Ordering::Relaxed) + 1;
+                let cancelled = control.cancelled.load(Ordering::Relaxed);
 
                 Box::new(async move {
-                    let Some(Extern::Memory(memory)) = caller.get_export("memory") else {
-                        anyhow::bail!("wasm memory is not exported under the name `memory`")
+                    // before the handler starts on it, so it's never left halfway
+                    if cancelled {
+                        return Err(anyhow::anyhow!("session cancelled before the game's IO")
+                            .context(RuntimeError::Cancelled));
+                    }
+                    if let Some(max) = max_io_calls.filter(|&max| io_count > max) {
+                        return Err(anyhow::anyhow!("game performed more than {max} IO")
+                            .context(RuntimeError::IoLimit));
+                    }
+                    let Some(Extern::Memory(memory)) = caller.get_export(&memory_name) else {
+                        return Err(anyhow::anyhow!(
+                            "wasm memory is not exported under the name `{memory_name}`"
+                        )
+                        .context(RuntimeError::Protocol));
                     };
-                    let (input_ptr, input_cap, output): (usize, usize, Output<Box<RawValue>>) = {
+                    let (input_ptr, input_cap, output_raw, output): (
+                        usize,
+                        usize,
+                        Option<String>,
+                        Output<Box<RawValue>>,
+                    ) = {
                         use bytes::Buf;
 
                         let params_len = 4 * std::mem::size_of::<u32>() as u32;
@@ -142,95 +950,1241 @@ impl Session {
                         let output_ptr = params.get_u32_ne();
                         let output_len = params.get_u32_ne();
 
-                        let output = slice_str(&memory, &caller, output_ptr, output_len)?;
-                        println!("got wasm output: {output}");
+                        let output = slice_str(&memory, &caller, output_ptr, output_len)
+                            .context(RuntimeError::Protocol)?;
+                        tracing::trace!(output, "got wasm output");
 
                         (
                             input_ptr as _,
                             input_cap as _,
-                            serde_json::from_str(output)?,
+                            recorder
+                                .lock()
+                                .unwrap()
+                                .is_active()
+                                .then(|| output.to_owned()),
+                            serde_json::from_str(output)
+                                .context("invalid game output")
+                                .context(RuntimeError::Protocol)?,
                         )
                     };
 
-                    let json = match output {
-                        Output::Error(msg) => anyhow::bail!("game logic error: {msg}"),
-                        Output::SessionStart => serde_json::to_string(caller.data())?,
-                        Output::SessionEnd => serde_json::to_string(&())?,
+                    // mirror the state even while replaying, later patches apply to it
+                    let output = match output {
                         Output::UpdateState(state) => {
-                            if enable_state {
-                                handler.lock().await.state(&state)?;
-                            }
-                            serde_json::to_string(&())?
+                            state_mirror.lock().unwrap().update(&state);
+                            Output::UpdateState(state)
                         }
-                        Output::DoTaskIf { allowed } => {
-                            let result = handler.lock().await.do_task_if(allowed).await?;
-                            serde_json::to_string(&result)?
+                        Output::PatchState(ops) => {
+                            Output::UpdateState(state_mirror.lock().unwrap().patch(ops)?)
                         }
-                        Output::TaskDone { targets, value } => {
-                            handler.lock().await.task_done(targets, &value).await?;
-                            serde_json::to_string(&())?
+                        // kept even while replaying, the restored session still has it
+                        Output::GameResult(value) => {
+                            let mut result = result.lock().unwrap();
+                            if result.is_some() {
+                                return Err(anyhow::anyhow!(
+                                    "game reported its result more than once"
+                                )
+                                .context(RuntimeError::Protocol));
+                            }
+                            *result = Some(value.clone());
+                            Output::GameResult(value)
                         }
-                        Output::Random { start, end } => {
-                            let result = handler.lock().await.random(start, end).await?;
-                            serde_json::to_string(&result)?
+                        output => output,
+                    };
+
+                    let scope_change = ScopeChange::of(&output);
+                    let in_admin = scope.lock().unwrap().in_admin();
+
+                    let replayed = match (&output_raw, &output) {
+                        (_, Output::Error(_)) | (None, _) => None,
+                        (Some(raw), _) => recorder.lock().unwrap().replay(raw)?,
+                    };
+                    let json = if let Some(input) = replayed {
+                        input
+                    } else {
+                        if let Some(limiter) = &state_limiter {
+                            let pending = match output {
+                                Output::UpdateState(_) => None,
+                                // don't hold the state while waiting for players or after the end
+                                Output::Action { .. }
+                                | Output::ActionWithDeadline { .. }
+                                | Output::FirstAction { .. }
+                                | Output::SimultaneousAction { .. }
+                                | Output::GatherActions { .. }
+                                | Output::SessionEnd { .. } => limiter.lock().unwrap().take(),
+                                _ => limiter.lock().unwrap().poll(),
+                            };
+                            if let Some(state) = pending {
+                                handler.lock().await.state(&state)?;
+                            }
+                        }
+
+                        match output {
+                            Output::Error(err) => {
+                                return Err(anyhow::Error::new(err).context(RuntimeError::GameLogic))
+                            }
+                            Output::SessionStart => serde_json::to_string(&caller.data().room)?,
+                            Output::SessionEnd { reason } => {
+                                ended.store(true, Ordering::Relaxed);
+                                handler.lock().await.session_end(&reason).await?;
+                                serde_json::to_string(&())?
+                            }
+                            Output::DebugSnapshot { label, value } => {
+                                diagnostics
+                                    .lock()
+                                    .unwrap()
+                                    .push(DebugSnapshot { label, value });
+                                serde_json::to_string(&())?
+                            }
+                            Output::PatchState(_) => unreachable!("patched into updateState"),
+                            Output::UpdateState(state) => {
+                                let state = match &state_limiter {
+                                    Some(limiter) => limiter.lock().unwrap().offer(state),
+                                    None => Some(state).filter(|_| enable_state),
+                                };
+                                if let Some(state) = state {
+                                    handler.lock().await.state(&state)?;
+                                }
+                                serde_json::to_string(&())?
+                            }
+                            Output::UpdatePrivateState {
+                                name,
+                                target,
+                                state,
+                            } => {
+                                if !caller.data().room.players.contains(&target) {
+                                    return Err(anyhow::anyhow!(
+                                        "private state {name} targets {target} not in the room"
+                                    )
+                                    .context(RuntimeError::Protocol));
+                                }
+                                if enable_state {
+                                    handler.lock().await.private_state(&name, target, &state)?;
+                                }
+                                serde_json::to_string(&())?
+                            }
+                            Output::DoTaskIf { allowed } => {
+                                let admin = allowed.is_empty();
+                                let result = handler.lock().await.do_task_if(allowed).await?;
+                                if admin && matches!(result, TaskResult::DoTask) {
+                                    audit(&handler, "doTaskIf", json!({})).await?;
+                                }
+                                serde_json::to_string(&result)?
+                            }
+                            Output::TaskDone { targets, value } => {
+                                if in_admin {
+                                    let detail = json!({ "targets": targets, "value": value });
+                                    audit(&handler, "taskDone", detail).await?;
+                                }
+                                handler.lock().await.task_done(targets, &value).await?;
+                                serde_json::to_string(&())?
+                            }
+                            Output::Notify { targets, value } => {
+                                handler.lock().await.notify(targets, &value).await?;
+                                serde_json::to_string(&())?
+                            }
+                            Output::Random { start, end } => {
+                                let result = handler.lock().await.random(start, end).await?;
+                                if in_admin {
+                                    let detail =
+                                        json!({ "start": start, "end": end, "value": result });
+                                    audit(&handler, "random", detail).await?;
+                                }
+                                serde_json::to_string(&result)?
+                            }
+                            Output::RandomBytes { len } => {
+                                let result = handler.lock().await.random_bytes(len).await?;
+                                if in_admin {
+                                    let detail = json!({ "len": len, "value": result });
+                                    audit(&handler, "randomBytes", detail).await?;
+                                }
+                                serde_json::to_string(&result)?
+                            }
+                            Output::Action { from, param } => {
+                                let mut handler = handler.lock().await;
+                                with_timeout(action_timeout, &[from], handler.action(from, &param))
+                                    .await?
+                                    .get()
+                                    .into()
+                            }
+                            Output::ActionWithDeadline {
+                                from,
+                                param,
+                                deadline_ms,
+                            } => {
+                                // the deadline bounds it already, no need of `action_timeout`
+                                let deadline = Duration::from_millis(deadline_ms);
+                                let result = handler
+                                    .lock()
+                                    .await
+                                    .action_with_deadline(from, &param, deadline)
+                                    .await?;
+                                serde_json::to_string(&result)?
+                            }
+                            Output::FirstAction {
+                                from,
+                                param,
+                                deadline_ms,
+                            } => {
+                                let unique = from.iter().collect::<HashSet<_>>().len();
+                                if from.is_empty() || unique != from.len() {
+                                    return Err(anyhow::anyhow!(
+                                        "game requested first action from players {from:?}, \
+                                         empty or with duplicates"
+                                    )
+                                    .context(RuntimeError::Protocol));
+                                }
+                                let mut handler = handler.lock().await;
+                                let result = match deadline_ms.map(Duration::from_millis) {
+                                    // the deadline bounds it already, no need of `action_timeout`
+                                    Some(deadline) => {
+                                        handler.first_action(from, &param, Some(deadline)).await?
+                                    }
+                                    None => {
+                                        let action =
+                                            handler.first_action(from.clone(), &param, None);
+                                        with_timeout(action_timeout, &from, action).await?
+                                    }
+                                };
+                                serde_json::to_string(&result)?
+                            }
+                            Output::SimultaneousAction { from, param } => {
+                                let mut handler = handler.lock().await;
+                                let action = handler.simultaneous_action(from.clone(), &param);
+                                with_timeout(action_timeout, &from, action)
+                                    .await?
+                                    .get()
+                                    .into()
+                            }
+                            Output::GatherActions { requests } => {
+                                let from: Vec<_> = requests.iter().map(|(p, _)| *p).collect();
+                                if from.iter().collect::<HashSet<_>>().len() != from.len() {
+                                    return Err(anyhow::anyhow!(
+                                        "game requested gather from players {from:?} with duplicates"
+                                    )
+                                    .context(RuntimeError::Protocol));
+                                }
+                                let mut handler = handler.lock().await;
+                                let actions = handler.gather_actions(requests);
+                                with_timeout(action_timeout, &from, actions)
+                                    .await?
+                                    .get()
+                                    .into()
+                            }
+                            Output::ForfeitedPlayers => {
+                                let players = handler.lock().await.forfeited_players().await?;
+                                serde_json::to_string(&players)?
+                            }
+                            Output::GameResult(value) => {
+                                handler.lock().await.game_result(&value)?;
+                                serde_json::to_string(&())?
+                            }
                         }
-                        Output::Action { from, param } => handler
-                            .lock()
-                            .await
-                            .action(from, &param)
-                            .await?
-                            .get()
-                            .into(),
                     };
+                    if let Some(change) = scope_change {
+                        scope.lock().unwrap().apply(change, &json);
+                    }
+                    if let Some(raw) = output_raw {
+                        recorder.lock().unwrap().record(raw, &json);
+                    }
+
+                    let input_len = json.len() as u32;
+                    if json.len() <= input_cap {
+                        memory.write(&mut caller, input_ptr, json.as_bytes())?;
+                    } else if grows_input {
+                        // the returned length tells the game how large a buffer it needs
+                        caller.data_mut().pending_input = Some(json);
+                    } else {
+                        return Err(anyhow::anyhow!(
+                            "input of {} bytes exceeds input_cap {input_cap} of the game, \
+                            which doesn't import rulebook_take_input to grow its buffer",
+                            json.len()
+                        )
+                        .context(RuntimeError::Protocol));
+                    }
+
+                    // time spent on waiting IO doesn't count
+                    caller.data_mut().epochs_left = max_execution_epochs;
+                    caller.as_context_mut().set_epoch_deadline(1);
 
-                    anyhow::ensure!(json.len() <= input_cap);
-                    memory.write(&mut caller, input_ptr, json.as_bytes())?;
-                    Ok(json.len() as u32)
+                    Ok(input_len)
                 })
-            },
-        );
-        let func_log = Func::wrap(
-            &mut self.store,
-            move |mut caller: Caller<'_, RoomInfo>, msg_ptr: u32, msg_len: u32| -> Result<()> {
+            }
+        });
+        let log_limiter =
+            log_rate_limit.map(|limit| Arc::new(StdMutex::new(LogLimiter::new(limit))));
+        let log_guest = {
+            let log_limiter = log_limiter.clone();
+            let memory_name = memory_name.clone();
+            move |caller: &mut Caller<'_, SessionData>,
+                  level: LogLevel,
+                  msg_ptr: u32,
+                  msg_len: u32|
+                  -> Result<()> {
                 if !enable_logging {
                     return Ok(());
                 };
+                if let Some(limiter) = &log_limiter {
+                    if !limiter.lock().unwrap().allow() {
+                        return Ok(());
+                    }
+                }
 
-                let Some(Extern::Memory(memory)) = caller.get_export("memory") else {
-                    anyhow::bail!("wasm memory is not exported under the name `memory`")
+                let Some(Extern::Memory(memory)) = caller.get_export(&memory_name) else {
+                    anyhow::bail!("wasm memory is not exported under the name `{memory_name}`")
                 };
-                let msg = slice_str(&memory, &caller, msg_ptr, msg_len)?;
+                let msg = slice_str(&memory, caller, msg_ptr, msg_len)?;
 
-                println!("LOG: {msg}");
+                // tracing needs the level at compile time
+                match level {
+                    LogLevel::Trace => tracing::trace!(target: "guest", "{msg}"),
+                    LogLevel::Debug => tracing::debug!(target: "guest", "{msg}"),
+                    LogLevel::Info => tracing::info!(target: "guest", "{msg}"),
+                    LogLevel::Warn => tracing::warn!(target: "guest", "{msg}"),
+                    LogLevel::Error => tracing::error!(target: "guest", "{msg}"),
+                }
                 Ok(())
+            }
+        };
+        let func_log = Func::wrap(&mut self.store, {
+            let log_guest = log_guest.clone();
+            move |mut caller: Caller<'_, SessionData>, msg_ptr: u32, msg_len: u32| {
+                log_guest(&mut caller, LogLevel::Info, msg_ptr, msg_len)
+            }
+        });
+        let func_log_at = Func::wrap(
+            &mut self.store,
+            move |mut caller: Caller<'_, SessionData>,
+                  level: u32,
+                  msg_ptr: u32,
+                  msg_len: u32|
+                  -> Result<()> {
+                let level = u8::try_from(level)
+                    .ok()
+                    .and_then(LogLevel::from_repr)
+                    .with_context(|| format!("invalid log level {level}"))
+                    .context(RuntimeError::Protocol)?;
+                log_guest(&mut caller, level, msg_ptr, msg_len)
             },
         );
 
-        let instance = Instance::new_async(
+        let func_take_input = Func::wrap(
             &mut self.store,
-            &self.module,
-            &[func_trigger_io.into(), func_log.into()],
-        )
-        .await?;
+            move |mut caller: Caller<'_, SessionData>,
+                  input_ptr: u32,
+                  input_cap: u32|
+                  -> Result<u32> {
+                let Some(input) = caller.data_mut().pending_input.take() else {
+                    return Err(anyhow::anyhow!("game took input while none is pending")
+                        .context(RuntimeError::Protocol));
+                };
+                if input.len() > input_cap as usize {
+                    return Err(anyhow::anyhow!(
+                        "input of {} bytes still exceeds the grown buffer of {input_cap} bytes",
+                        input.len()
+                    )
+                    .context(RuntimeError::Protocol));
+                }
 
-        instance
-            .get_typed_func::<(u32, u32), ()>(&mut self.store, "rulebook_start_session")?
-            .call_async(&mut self.store, (input_caps, print_state as u32))
-            .await?;
+                let Some(Extern::Memory(memory)) = caller.get_export(&memory_name) else {
+                    return Err(anyhow::anyhow!(
+                        "wasm memory is not exported under the name `{memory_name}`"
+                    )
+                    .context(RuntimeError::Protocol));
+                };
+                memory.write(&mut caller, input_ptr as usize, input.as_bytes())?;
+                Ok(input.len() as u32)
+            },
+        );
 
-        Ok(())
+        let mut linker = Linker::new(self.store.engine());
+        linker.define(
+            &self.store,
+            IMPORT_MODULE,
+            "rulebook_trigger_io",
+            func_trigger_io,
+        )?;
+        linker.define(&self.store, IMPORT_MODULE, "rulebook_log", func_log)?;
+        linker.define(&self.store, IMPORT_MODULE, "rulebook_log_at", func_log_at)?;
+        linker.define(
+            &self.store,
+            IMPORT_MODULE,
+            "rulebook_take_input",
+            func_take_input,
+        )?;
+        if unknown_imports == ImportPolicy::Warn {
+            linker.define_unknown_imports_as_traps(&self.module)?;
+        }
+
+        let span = tracing::info_span!("session", id = self.id, game_key = %self.game_key);
+        let run = async {
+            if self.control.cancelled.load(Ordering::Relaxed) {
+                return Err(anyhow::anyhow!("session cancelled before it started")
+                    .context(RuntimeError::Cancelled));
+            }
+            let instance = linker
+                .instantiate_async(&mut self.store, &self.module)
+                .await?;
+            self.memory = instance.get_memory(&mut self.store, &self.conf.memory_export_name);
+
+            // games without the export predate it
+            let version =
+                match instance.get_typed_func::<(), u32>(&mut self.store, "rulebook_abi_version") {
+                    Ok(abi_version) => abi_version.call_async(&mut self.store, ()).await?,
+                    Err(_) => 1,
+                };
+            if version != ABI_VERSION {
+                return Err(anyhow::anyhow!(
+                    "game uses ABI version {version}, but the runtime supports {ABI_VERSION}"
+                )
+                .context(RuntimeError::Protocol));
+            }
+
+            instance
+                .get_typed_func::<(u32, u32), ()>(&mut self.store, "rulebook_start_session")?
+                .call_async(&mut self.store, (input_caps, print_state as u32))
+                .await
+        }
+        .instrument(span);
+
+        let started = Instant::now();
+        self.control.running.store(true, Ordering::Relaxed);
+        let res = run.await;
+        self.control.running.store(false, Ordering::Relaxed);
+
+        if let Some(limiter) = log_limiter {
+            limiter.lock().unwrap().flush();
+        }
+
+        let res = res.map_err(|err| {
+            if let Some(exceeded) = self.store.data().limits.exceeded() {
+                return err.context(exceeded);
+            }
+            match err.downcast_ref::<Trap>() {
+                Some(Trap::Interrupt) => err.context(RuntimeError::ExecutionBudgetExceeded),
+                Some(Trap::OutOfFuel) => err.context(RuntimeError::OutOfFuel),
+                _ => err,
+            }
+        });
+        if let Err(err) = &res {
+            if !ended.load(Ordering::Relaxed) {
+                let reason = end_reason(err);
+                if let Err(err) = handler.lock().await.session_end(&reason).await {
+                    tracing::debug!("failed to notify the session end: {err:?}");
+                }
+            }
+        }
+        self.metrics.session_finished(started.elapsed(), &res);
+        res
     }
 }
 
-fn slice<'a>(memory: &Memory, caller: &'a Caller<'_, RoomInfo>, ptr: u32, len: u32) -> &'a [u8] {
+/// Why the session failed with `err`, told to the handler in place of the game.
+fn end_reason(err: &anyhow::Error) -> EndReason {
+    if let Some(reason) = err.downcast_ref::<EndReason>() {
+        return reason.clone();
+    }
+    match err.downcast_ref::<RuntimeError>() {
+        Some(RuntimeError::GameLogic) => EndReason::Error,
+        Some(category) => EndReason::Aborted(category.to_string()),
+        None => EndReason::Aborted(err.to_string()),
+    }
+}
+
+fn slice<'a>(memory: &Memory, caller: &'a Caller<'_, SessionData>, ptr: u32, len: u32) -> &'a [u8] {
     &memory.data(caller)[ptr as usize..][..len as usize]
 }
 
 fn slice_str<'a>(
     memory: &Memory,
-    caller: &'a Caller<'_, RoomInfo>,
+    caller: &'a Caller<'_, SessionData>,
     ptr: u32,
     len: u32,
 ) -> Result<&'a str> {
     std::str::from_utf8(slice(memory, caller, ptr, len)).context("wasm memory slice not a string")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::LocalRoom;
+
+    /// WAT module of the example in the ABI doc.
+    fn abi_doc_example() -> &'static str {
+        let doc = include_str!("../../../docs/abi.md");
+        let (_, example) = doc.split_once("```wat\n").expect("doc has a WAT example");
+        let (example, _) = example.split_once("```").expect("WAT example is closed");
+        example
+    }
+
+    #[tokio::test]
+    async fn abi_doc_example_completes_session() {
+        let runtime = Runtime::new(Config::default()).unwrap();
+        runtime
+            .add_game("example".into(), abi_doc_example().as_bytes())
+            .unwrap();
+
+        let mut session = runtime.new_session("example").await.unwrap();
+        session
+            .start(1024, false, RoomInfo::default(), LocalRoom::new(0))
+            .await
+            .unwrap();
+        assert_eq!(runtime.metrics_snapshot().sessions_completed, 1);
+    }
+
+    const SMUGGLED_WASI: &str = r#"(module
+  (import "wasi_snapshot_preview1" "fd_write" (func (param i32 i32 i32 i32) (result i32)))
+  (memory (export "memory") 1)
+  (func (export "rulebook_start_session") (param i32 i32)))"#;
+
+    #[test]
+    fn unexpected_imports_are_rejected() {
+        let runtime = Runtime::new(Config::default()).unwrap();
+        let err = runtime
+            .add_game("wasi".into(), SMUGGLED_WASI.as_bytes())
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            r#"game wasi imports unexpected functions: ["wasi_snapshot_preview1::fd_write"]"#
+        );
+        assert!(!runtime.has_game("wasi"));
+    }
+
+    #[test]
+    fn unexpected_imports_are_allowed_with_warn_policy() {
+        let runtime = Runtime::new(Config {
+            unknown_imports: ImportPolicy::Warn,
+            ..Config::default()
+        })
+        .unwrap();
+        runtime
+            .add_game("wasi".into(), SMUGGLED_WASI.as_bytes())
+            .unwrap();
+        assert!(runtime.has_game("wasi"));
+    }
+
+    #[test]
+    fn missing_start_export_is_rejected() {
+        let runtime = Runtime::new(Config::default()).unwrap();
+        let code = r#"(module (memory (export "memory") 1))"#;
+        let err = runtime
+            .add_game("empty".into(), code.as_bytes())
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "game empty doesn't export rulebook_start_session"
+        );
+    }
+
+    #[tokio::test]
+    async fn memory_is_found_under_the_configured_export_name() {
+        let code = abi_doc_example().replace(r#"(export "memory")"#, r#"(export "main_memory")"#);
+        let err = Runtime::new(Config::default())
+            .unwrap()
+            .add_game("example".into(), code.as_bytes())
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "game example doesn't export its memory under the name `memory`"
+        );
+
+        let runtime = Runtime::new(Config {
+            memory_export_name: "main_memory".into(),
+            ..Config::default()
+        })
+        .unwrap();
+        runtime.add_game("example".into(), code.as_bytes()).unwrap();
+        let mut session = runtime.new_session("example").await.unwrap();
+        session
+            .start(1024, false, RoomInfo::default(), LocalRoom::new(0))
+            .await
+            .unwrap();
+        assert!(session.memory_size().is_some());
+    }
+
+    #[tokio::test]
+    async fn memory_size_is_the_peak_of_the_run() {
+        const PAGE: usize = 64 * 1024;
+        let outputs = [test_games::SESSION_START, test_games::SESSION_END];
+        let plain = test_games::game(&outputs);
+        let growing = test_games::game_with(&outputs, "(drop (memory.grow (i32.const 2)))");
+        let runtime = Runtime::new(Config::default()).unwrap();
+        runtime.add_game("plain".into(), plain.as_bytes()).unwrap();
+        runtime
+            .add_game("growing".into(), growing.as_bytes())
+            .unwrap();
+
+        for (game_key, pages) in [("plain", 1), ("growing", 3)] {
+            let mut session = runtime.new_session(game_key).await.unwrap();
+            session
+                .start(1024, false, RoomInfo::default(), LocalRoom::new(0))
+                .await
+                .unwrap();
+            assert_eq!(session.memory_size(), Some(pages * PAGE), "{game_key}");
+        }
+    }
+
+    #[test]
+    fn every_linkage_problem_is_listed() {
+        let runtime = Runtime::new(Config::default()).unwrap();
+        let code = r#"(module
+  (import "env" "rulebook_log" (func (param i32)))
+  (import "env" "fetch" (func (param i32)))
+  (memory (export "mem") 1)
+  (func (export "rulebook_abi_version") (result i64) (i64.const 2)))"#;
+        let err = runtime
+            .add_game("broken".into(), code.as_bytes())
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "game broken is incompatible: \
+             imports host functions with wrong types: \
+             [\"env::rulebook_log as fn(i32) -> (), expected fn(i32, i32) -> ()\"], \
+             imports unexpected functions: [\"env::fetch\"], \
+             doesn't export rulebook_start_session, \
+             exports rulebook_abi_version as fn() -> (i64), expected fn() -> (i32), \
+             doesn't export its memory under the name `memory`"
+        );
+        assert!(!runtime.has_game("broken"));
+    }
+
+    /// Game growing its memory by `pages`, trapping if it's refused.
+    fn memory_grower(pages: u32) -> String {
+        test_games::game_with(
+            &[test_games::SESSION_START],
+            &format!(
+                "(if (i32.eq (memory.grow (i32.const {pages})) (i32.const -1)) (then unreachable))"
+            ),
+        )
+    }
+
+    async fn run_game(conf: Config, code: &str) -> Result<()> {
+        let runtime = Runtime::new(conf).unwrap();
+        runtime.add_game("game".into(), code.as_bytes()).unwrap();
+        let mut session = runtime.new_session("game").await.unwrap();
+        session
+            .start(1024, false, RoomInfo::default(), LocalRoom::new(0))
+            .await
+    }
+
+    #[tokio::test]
+    async fn memory_growth_past_limit_fails_with_memory_limit() {
+        let conf = || Config {
+            max_memory_bytes: Some(4 * 64 * 1024),
+            ..Config::default()
+        };
+
+        run_game(conf(), &memory_grower(3)).await.unwrap();
+
+        let err = run_game(conf(), &memory_grower(4)).await.unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<RuntimeError>(),
+            Some(&RuntimeError::MemoryLimit)
+        );
+    }
+
+    #[tokio::test]
+    async fn looping_game_is_cut_off_at_max_io_calls() {
+        let code = test_games::game_with(
+            &[
+                test_games::SESSION_START,
+                r#"{"type":"random","data":{"start":0,"end":100}}"#,
+            ],
+            "(loop $again (call $out1) (br $again))",
+        );
+        let runtime = Runtime::new(Config {
+            max_io_calls: Some(10),
+            ..Config::default()
+        })
+        .unwrap();
+        runtime.add_game("game".into(), code.as_bytes()).unwrap();
+        let mut session = runtime.new_session("game").await.unwrap();
+
+        let err = session
+            .start(1024, false, RoomInfo::default(), LocalRoom::new(0))
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<RuntimeError>(),
+            Some(&RuntimeError::IoLimit)
+        );
+        assert_eq!(runtime.sessions()[0].io_count, 11);
+    }
+
+    #[test]
+    fn garbage_is_not_compiled() {
+        assert_eq!(
+            header_problem(b"garbage!"),
+            Some("not a wasm binary (bad magic)".into())
+        );
+        assert_eq!(
+            header_problem(b"\0as"),
+            Some("truncated wasm binary (incomplete header)".into())
+        );
+        assert_eq!(header_problem(b"  (module)"), None);
+
+        let runtime = Runtime::new(Config::default()).unwrap();
+        let err = runtime.add_game("garbage".into(), b"garbage!").unwrap_err();
+        assert_eq!(
+            format!("{err:#}"),
+            "game garbage: not a wasm binary (bad magic), 8 bytes"
+        );
+    }
+
+    #[test]
+    fn unsupported_binary_version_is_not_compiled() {
+        // an empty module is valid as is
+        let empty = b"\0asm\x01\0\0\0";
+        assert_eq!(header_problem(empty), None);
+
+        let future = b"\0asm\x02\0\0\0";
+        assert_eq!(
+            header_problem(future),
+            Some("unsupported wasm binary version [2, 0, 0, 0]".into())
+        );
+        let component = b"\0asm\x0d\0\x01\0";
+        assert_eq!(
+            header_problem(component),
+            Some("wasm component, only core modules are supported".into())
+        );
+
+        let runtime = Runtime::new(Config::default()).unwrap();
+        let err = runtime.add_game("future".into(), future).unwrap_err();
+        assert_eq!(
+            format!("{err:#}"),
+            "game future: unsupported wasm binary version [2, 0, 0, 0], 8 bytes"
+        );
+    }
+
+    #[tokio::test]
+    async fn precompiled_game_runs_in_another_runtime() {
+        let bytes = Runtime::new(Config::default())
+            .unwrap()
+            .precompile(abi_doc_example().as_bytes())
+            .unwrap();
+
+        let runtime = Runtime::new(Config::default()).unwrap();
+        // SAFETY: made by `precompile` just above
+        unsafe { runtime.add_game_precompiled("example".into(), &bytes) }.unwrap();
+        let mut session = runtime.new_session("example").await.unwrap();
+        session
+            .start(1024, false, RoomInfo::default(), LocalRoom::new(0))
+            .await
+            .unwrap();
+        assert_eq!(runtime.metrics_snapshot().sessions_completed, 1);
+    }
+
+    #[test]
+    fn precompiled_game_of_other_config_is_rejected() {
+        let bytes = Runtime::new(Config::default())
+            .unwrap()
+            .precompile(abi_doc_example().as_bytes())
+            .unwrap();
+
+        // fuel metering changes the machine code
+        let runtime = Runtime::new(Config {
+            fuel_per_session: Some(1_000_000),
+            ..Config::default()
+        })
+        .unwrap();
+        // SAFETY: made by `precompile` just above
+        let err = unsafe { runtime.add_game_precompiled("example".into(), &bytes) }.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "precompiled game example is incompatible with this runtime, recompile it"
+        );
+        assert!(!runtime.has_game("example"));
+    }
+
+    #[tokio::test]
+    async fn unsupported_abi_version_fails_on_start() {
+        let code = test_games::game(&[test_games::SESSION_START, test_games::SESSION_END]).replace(
+            r#"(func (export "rulebook_abi_version") (result i32) (i32.const 2))"#,
+            r#"(func (export "rulebook_abi_version") (result i32) (i32.const 99))"#,
+        );
+        let err = run_game(Config::default(), &code).await.unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<RuntimeError>(),
+            Some(&RuntimeError::Protocol)
+        );
+        assert_eq!(
+            err.root_cause().to_string(),
+            format!("game uses ABI version 99, but the runtime supports {ABI_VERSION}")
+        );
+    }
+
+    #[test]
+    fn game_keys_follow_adds_and_removes() {
+        let runtime = Runtime::new(Config::default()).unwrap();
+        let code = test_games::game(&[test_games::SESSION_START, test_games::SESSION_END]);
+        for key in ["tic-tac-toe", "chess", "go"] {
+            runtime.add_game(key.into(), code.as_bytes()).unwrap();
+        }
+        assert!(runtime.remove_game("chess"));
+        assert!(!runtime.remove_game("chess"));
+        runtime
+            .add_game("backgammon".into(), code.as_bytes())
+            .unwrap();
+        assert!(runtime.add_game("go".into(), code.as_bytes()).is_err());
+
+        let keys: Vec<_> = runtime
+            .game_keys()
+            .iter()
+            .map(|key| key.to_string())
+            .collect();
+        assert_eq!(keys, ["backgammon", "go", "tic-tac-toe"]);
+        assert!(runtime.has_game("go"));
+        assert!(!runtime.has_game("chess"));
+        assert!(!runtime.has_game("g"));
+    }
+
+    #[tokio::test]
+    async fn replaced_game_runs_in_new_sessions_only() {
+        let version = |version: u32| {
+            let result = format!(r#"{{"type":"gameResult","data":{{"version":{version}}}}}"#);
+            test_games::game(&[test_games::SESSION_START, &result, test_games::SESSION_END])
+        };
+        let runtime = Runtime::new(Config::default()).unwrap();
+        runtime
+            .add_game("game".into(), version(1).as_bytes())
+            .unwrap();
+
+        let mut before = runtime.new_session("game").await.unwrap();
+        runtime.replace_game("game", version(2).as_bytes()).unwrap();
+        let mut after = runtime.new_session("game").await.unwrap();
+
+        for session in [&mut before, &mut after] {
+            session
+                .start(1024, false, RoomInfo::default(), LocalRoom::new(0))
+                .await
+                .unwrap();
+        }
+        let result = |session: &Session| session.game_result().unwrap().get().to_owned();
+        assert_eq!(result(&before), r#"{"version":1}"#);
+        assert_eq!(result(&after), r#"{"version":2}"#);
+
+        let err = runtime
+            .replace_game("chess", version(2).as_bytes())
+            .unwrap_err();
+        assert_eq!(err.to_string(), "game key chess not exist");
+        assert_eq!(runtime.game_keys(), [Arc::from("game")]);
+    }
+
+    #[tokio::test]
+    async fn sessions_over_max_concurrent_are_rejected() {
+        let runtime = Runtime::new(Config {
+            max_concurrent_sessions: Some(2),
+            ..Config::default()
+        })
+        .unwrap();
+        let code = test_games::game(&[test_games::SESSION_START, test_games::SESSION_END]);
+        runtime.add_game("game".into(), code.as_bytes()).unwrap();
+
+        let first = runtime.new_session("game").await.unwrap();
+        let _second = runtime.new_session("game").await.unwrap();
+        assert_eq!(runtime.active_sessions(), 2);
+
+        let Err(err) = runtime.new_session("game").await else {
+            panic!("session over the max is created");
+        };
+        assert_eq!(
+            err.downcast_ref::<RuntimeError>(),
+            Some(&RuntimeError::TooManySessions)
+        );
+        assert_eq!(runtime.active_sessions(), 2);
+
+        drop(first);
+        assert_eq!(runtime.active_sessions(), 1);
+        let _third = runtime.new_session("game").await.unwrap();
+        assert_eq!(runtime.active_sessions(), 2);
+    }
+
+    #[tokio::test]
+    async fn session_runs_with_its_override_config() {
+        let runtime = Runtime::new(Config::default()).unwrap();
+        let code = test_games::game(&[
+            test_games::SESSION_START,
+            r#"{"type":"random","data":{"start":0,"end":100}}"#,
+            test_games::SESSION_END,
+        ]);
+        runtime.add_game("game".into(), code.as_bytes()).unwrap();
+        let conf = || Config {
+            max_io_calls: Some(2),
+            ..Config::default()
+        };
+
+        let mut session = runtime
+            .new_session_with_config("game", conf())
+            .await
+            .unwrap();
+        let err = session
+            .start(1024, false, RoomInfo::default(), LocalRoom::new(0))
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<RuntimeError>(),
+            Some(&RuntimeError::IoLimit)
+        );
+
+        // others keep the runtime's
+        let mut session = runtime.new_session("game").await.unwrap();
+        session
+            .start(1024, false, RoomInfo::default(), LocalRoom::new(0))
+            .await
+            .unwrap();
+
+        let conf = Config {
+            unknown_imports: ImportPolicy::Warn,
+            ..conf()
+        };
+        let Err(err) = runtime.new_session_with_config("game", conf).await else {
+            panic!("session changed the import policy");
+        };
+        assert_eq!(
+            err.to_string(),
+            "session config can't change unknown import policy of the runtime"
+        );
+    }
+
+    /// Host whose players never act, like disconnected ones.
+    /// It gives up waiting on them once the session is cancelled, like the server does.
+    struct Unresponsive(LocalRoom, CancelHandle);
+
+    async fn wait_for_cancel<T>(cancel: &CancelHandle) -> Result<T> {
+        cancel.cancelled().await;
+        Err(anyhow::anyhow!("cancelled while waiting for players").context(RuntimeError::Cancelled))
+    }
+
+    #[async_trait::async_trait]
+    impl OutputHandler for Unresponsive {
+        fn state(&mut self, json: &RawValue) -> Result<()> {
+            self.0.state(json)
+        }
+
+        fn private_state(&mut self, name: &str, target: PlayerId, json: &RawValue) -> Result<()> {
+            self.0.private_state(name, target, json)
+        }
+
+        async fn do_task_if(
+            &mut self,
+            allowed: Vec<PlayerId>,
+        ) -> Result<TaskResult<Box<RawValue>>> {
+            self.0.do_task_if(allowed).await
+        }
+
+        async fn task_done(&mut self, targets: Vec<PlayerId>, value: &RawValue) -> Result<()> {
+            self.0.task_done(targets, value).await
+        }
+
+        async fn notify(&mut self, targets: Vec<PlayerId>, value: &RawValue) -> Result<()> {
+            self.0.notify(targets, value).await
+        }
+
+        async fn random(&mut self, start: i32, end: i32) -> Result<i32> {
+            self.0.random(start, end).await
+        }
+
+        async fn random_bytes(&mut self, len: usize) -> Result<Vec<u8>> {
+            self.0.random_bytes(len).await
+        }
+
+        async fn action(&mut self, _from: PlayerId, _param: &RawValue) -> Result<Box<RawValue>> {
+            wait_for_cancel(&self.1).await
+        }
+
+        async fn action_with_deadline(
+            &mut self,
+            _from: PlayerId,
+            _param: &RawValue,
+            _deadline: Duration,
+        ) -> Result<TimedAction<Box<RawValue>>> {
+            wait_for_cancel(&self.1).await
+        }
+
+        async fn first_action(
+            &mut self,
+            _from: Vec<PlayerId>,
+            _param: &RawValue,
+            _deadline: Option<Duration>,
+        ) -> Result<TimedAction<(PlayerId, Box<RawValue>)>> {
+            wait_for_cancel(&self.1).await
+        }
+
+        async fn simultaneous_action(
+            &mut self,
+            _from: Vec<PlayerId>,
+            _param: &RawValue,
+        ) -> Result<Box<RawValue>> {
+            wait_for_cancel(&self.1).await
+        }
+
+        async fn gather_actions(
+            &mut self,
+            _requests: Vec<(PlayerId, Box<RawValue>)>,
+        ) -> Result<Box<RawValue>> {
+            wait_for_cancel(&self.1).await
+        }
+    }
+
+    #[tokio::test]
+    async fn action_timeout_fires_on_unresponsive_players() {
+        let code = test_games::game(&[
+            test_games::SESSION_START,
+            r#"{"type":"action","data":{"from":"red","param":null}}"#,
+            test_games::SESSION_END,
+        ]);
+        let runtime = Runtime::new(Config {
+            action_timeout: Some(Duration::from_millis(50)),
+            ..Config::default()
+        })
+        .unwrap();
+        runtime.add_game("game".into(), code.as_bytes()).unwrap();
+        let mut session = runtime.new_session("game").await.unwrap();
+
+        let started = std::time::Instant::now();
+        let run = session.start(
+            1024,
+            false,
+            RoomInfo::default(),
+            Unresponsive(LocalRoom::new(0), CancelHandle::default()),
+        );
+        let err = tokio::time::timeout(Duration::from_secs(5), run)
+            .await
+            .expect("action timeout didn't fire")
+            .unwrap_err();
+        assert!(started.elapsed() >= Duration::from_millis(50));
+        assert_eq!(
+            err.downcast_ref::<RuntimeError>(),
+            Some(&RuntimeError::Timeout)
+        );
+        assert!(
+            format!("{err:#}").contains("didn't submit action in 50ms"),
+            "{err:#}"
+        );
+    }
+
+    #[tokio::test]
+    async fn busy_game_is_cut_off_at_execution_budget() {
+        let code = test_games::game_with(&[test_games::SESSION_START], "(loop $again (br $again))");
+        let conf = Config {
+            max_execution_epochs: Some(5),
+            ..Config::default()
+        };
+
+        let err = run_game(conf, &code).await.unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<RuntimeError>(),
+            Some(&RuntimeError::ExecutionBudgetExceeded),
+            "{err:#}"
+        );
+    }
+
+    #[tokio::test]
+    async fn cancel_stops_game_busy_computing() {
+        let code = test_games::game_with(&[test_games::SESSION_START], "(loop $again (br $again))");
+        // with the budget far away, and without any
+        for max_execution_epochs in [Some(100_000), None] {
+            let runtime = Runtime::new(Config {
+                max_execution_epochs,
+                ..Config::default()
+            })
+            .unwrap();
+            runtime.add_game("game".into(), code.as_bytes()).unwrap();
+            let mut session = runtime.new_session("game").await.unwrap();
+
+            // the game blocks the thread driving it
+            let cancel = session.cancel_handle();
+            std::thread::spawn(move || {
+                std::thread::sleep(Duration::from_millis(50));
+                cancel.cancel();
+            });
+            let started = Instant::now();
+            let err = session
+                .start(1024, false, RoomInfo::default(), LocalRoom::new(0))
+                .await
+                .unwrap_err();
+            assert_eq!(
+                err.downcast_ref::<RuntimeError>(),
+                Some(&RuntimeError::Cancelled),
+                "{err:#}"
+            );
+            assert!(started.elapsed() < Duration::from_secs(5));
+        }
+    }
+
+    #[tokio::test]
+    async fn session_cancelled_before_start_never_runs() {
+        let runtime = Runtime::new(Config::default()).unwrap();
+        runtime
+            .add_game("example".into(), abi_doc_example().as_bytes())
+            .unwrap();
+        let mut session = runtime.new_session("example").await.unwrap();
+
+        session.cancel_handle().cancel();
+        let err = session
+            .start(1024, false, RoomInfo::default(), LocalRoom::new(0))
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<RuntimeError>(),
+            Some(&RuntimeError::Cancelled)
+        );
+        assert_eq!(session.memory_size(), None, "game is instantiated");
+    }
+
+    #[tokio::test]
+    async fn running_session_is_found_and_cancelled_through_the_registry() {
+        let code = test_games::game_with(&[test_games::SESSION_START], "(loop $again (br $again))");
+        let runtime = Arc::new(Runtime::new(Config::default()).unwrap());
+        runtime.add_game("game".into(), code.as_bytes()).unwrap();
+        let mut session = runtime.new_session("game").await.unwrap();
+        let id = session.id();
+
+        // the game blocks the thread driving it
+        let operator = std::thread::spawn({
+            let runtime = runtime.clone();
+            move || loop {
+                let sessions = runtime.sessions();
+                let [status] = &*sessions else {
+                    panic!("session isn't registered: {sessions:?}");
+                };
+                // past `sessionStart`, busy computing
+                if status.running && status.io_count == 1 {
+                    assert_eq!((status.id, &*status.game_key), (id, "game"));
+                    assert!(runtime.cancel_session(id));
+                    return;
+                }
+                std::thread::sleep(Duration::from_millis(10));
+            }
+        });
+        let err = session
+            .start(1024, false, RoomInfo::default(), LocalRoom::new(0))
+            .await
+            .unwrap_err();
+        operator.join().unwrap();
+        assert_eq!(
+            err.downcast_ref::<RuntimeError>(),
+            Some(&RuntimeError::Cancelled)
+        );
+
+        drop(session);
+        assert!(runtime.sessions().is_empty());
+        assert!(!runtime.cancel_session(id));
+    }
+
+    #[tokio::test]
+    async fn session_paused_at_an_action_resumes_to_the_same_outcome() {
+        let code = test_games::game(&[
+            test_games::SESSION_START,
+            r#"{"type":"random","data":{"start":0,"end":1000000}}"#,
+            r#"{"type":"action","data":{"from":"red","param":null}}"#,
+            r#"{"type":"gameResult","data":{"winner":"red"}}"#,
+            test_games::SESSION_END,
+        ]);
+        let runtime = Runtime::new(Config {
+            record_trace: true,
+            ..Config::default()
+        })
+        .unwrap();
+        runtime.add_game("game".into(), code.as_bytes()).unwrap();
+        let red = PlayerId::new(Color::Red as u8);
+        let local = |seed| {
+            let mut local = LocalRoom::new(seed);
+            local.script(red, ["guess"]).unwrap();
+            local
+        };
+
+        let mut uninterrupted = runtime.new_session("game").await.unwrap();
+        uninterrupted
+            .start(1024, false, RoomInfo::default(), local(7))
+            .await
+            .unwrap();
+
+        // paused while red is yet to act
+        let mut paused = runtime.new_session("game").await.unwrap();
+        let id = paused.id();
+        let handler = Unresponsive(LocalRoom::new(7), paused.cancel_handle());
+        let (res, ()) = tokio::join!(
+            paused.start(1024, false, RoomInfo::default(), handler),
+            async {
+                while runtime.sessions()[0].io_count < 3 {
+                    tokio::time::sleep(Duration::from_millis(5)).await;
+                }
+                assert!(runtime.cancel_session(id));
+            },
+        );
+        assert_eq!(
+            res.unwrap_err().downcast_ref::<RuntimeError>(),
+            Some(&RuntimeError::Cancelled)
+        );
+        assert_eq!(paused.trace().entries.len(), 2, "{:?}", paused.trace());
+        let snapshot = paused.snapshot().unwrap();
+        drop(paused);
+
+        // the random number comes from the trace, not the new room's seed
+        let mut resumed = runtime.restore_session(&snapshot).await.unwrap();
+        let room = resumed.room().clone();
+        resumed.start(1024, false, room, local(99)).await.unwrap();
+
+        assert_eq!(resumed.trace().entries, uninterrupted.trace().entries);
+        assert_eq!(
+            resumed.game_result().map(|res| res.get().to_owned()),
+            Some(r#"{"winner":"red"}"#.to_owned())
+        );
+    }
+
+    #[tokio::test]
+    async fn debug_snapshots_reach_the_session_diagnostics() {
+        let outputs = [
+            test_games::SESSION_START,
+            r#"{"type":"debugSnapshot","data":{"label":"setup","value":{"deck":52}}}"#,
+            r#"{"type":"debugSnapshot","data":{"label":"turn","value":{"turn":1}}}"#,
+        ];
+        let labeled = |snapshots: &[DebugSnapshot]| -> Vec<(String, String)> {
+            snapshots
+                .iter()
+                .map(|snapshot| (snapshot.label.clone(), snapshot.value.get().to_owned()))
+                .collect()
+        };
+
+        let runtime = Runtime::new(Config::default()).unwrap();
+        let mut finished = outputs.to_vec();
+        finished.push(test_games::SESSION_END);
+        runtime
+            .add_game("game".into(), test_games::game(&finished).as_bytes())
+            .unwrap();
+        let mut session = runtime.new_session("game").await.unwrap();
+        session
+            .start(1024, false, RoomInfo::default(), LocalRoom::new(0))
+            .await
+            .unwrap();
+        assert_eq!(
+            labeled(&session.debug_snapshots()),
+            [
+                ("setup".to_string(), r#"{"deck":52}"#.to_string()),
+                ("turn".into(), r#"{"turn":1}"#.into()),
+            ]
+        );
+
+        // the oldest are dropped once too many are kept
+        let runtime = Runtime::new(Config {
+            max_io_calls: Some(1000),
+            ..Config::default()
+        })
+        .unwrap();
+        let code = test_games::game_with(&outputs, "(loop $again (call $out2) (br $again))");
+        runtime.add_game("game".into(), code.as_bytes()).unwrap();
+        let mut session = runtime.new_session("game").await.unwrap();
+        let err = session
+            .start(1024, false, RoomInfo::default(), LocalRoom::new(0))
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<RuntimeError>(),
+            Some(&RuntimeError::IoLimit)
+        );
+        let snapshots = labeled(&session.debug_snapshots());
+        assert_eq!(snapshots.len(), 256);
+        assert!(snapshots.iter().all(|(label, _)| label == "turn"));
+    }
+}
@@ -0,0 +1,44 @@
+use wasmtime::ResourceLimiter;
+
+use crate::RuntimeError;
+
+/// Caps linear memory and table growth of a session.
+#[derive(Debug, Default)]
+pub(crate) struct Limits {
+    max_memory_bytes: Option<usize>,
+    max_table_elements: Option<u32>,
+    exceeded: Option<RuntimeError>,
+}
+
+impl Limits {
+    pub fn new(max_memory_bytes: Option<usize>, max_table_elements: Option<u32>) -> Self {
+        Limits {
+            max_memory_bytes,
+            max_table_elements,
+            exceeded: None,
+        }
+    }
+
+    /// The limit hit by the game, if any.
+    pub fn exceeded(&self) -> Option<RuntimeError> {
+        self.exceeded
+    }
+}
+
+impl ResourceLimiter for Limits {
+    fn memory_growing(&mut self, _current: usize, desired: usize, _maximum: Option<usize>) -> bool {
+        if self.max_memory_bytes.is_some_and(|max| desired > max) {
+            self.exceeded = Some(RuntimeError::MemoryLimit);
+            return false;
+        }
+        true
+    }
+
+    fn table_growing(&mut self, _current: u32, desired: u32, _maximum: Option<u32>) -> bool {
+        if self.max_table_elements.is_some_and(|max| desired > max) {
+            self.exceeded = Some(RuntimeError::TableLimit);
+            return false;
+        }
+        true
+    }
+}
@@ -0,0 +1,77 @@
+use std::time::{Duration, Instant};
+
+const WINDOW: Duration = Duration::from_secs(1);
+
+/// Fixed window rate limiter for guest log messages.
+#[derive(Debug)]
+pub(crate) struct LogLimiter {
+    max_per_sec: u32,
+    window_start: Instant,
+    count: u32,
+    suppressed: u32,
+}
+
+impl LogLimiter {
+    pub fn new(max_per_sec: u32) -> Self {
+        LogLimiter {
+            max_per_sec,
+            window_start: Instant::now(),
+            count: 0,
+            suppressed: 0,
+        }
+    }
+
+    /// Returns whether the message should be printed.
+    pub fn allow(&mut self) -> bool {
+        if self.window_start.elapsed() >= WINDOW {
+            self.flush();
+            self.window_start = Instant::now();
+            self.count = 0;
+        }
+
+        if self.count < self.max_per_sec {
+            self.count += 1;
+            true
+        } else {
+            self.suppressed += 1;
+            false
+        }
+    }
+
+    /// Reports the number of suppressed messages, if any.
+    pub fn flush(&mut self) {
+        if self.suppressed > 0 {
+            tracing::warn!(target: "guest", "{} messages suppressed", self.suppressed);
+            self.suppressed = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn suppresses_messages_over_the_limit_until_next_window() {
+        let mut limiter = LogLimiter::new(2);
+
+        assert!(limiter.allow());
+        assert!(limiter.allow());
+        assert!(!limiter.allow());
+        assert!(!limiter.allow());
+        assert_eq!(limiter.suppressed, 2);
+
+        limiter.window_start -= WINDOW;
+        assert!(limiter.allow());
+        assert_eq!(
+            limiter.suppressed, 0,
+            "suppressed messages should be reported"
+        );
+        assert!(limiter.allow());
+        assert!(!limiter.allow());
+        assert_eq!(limiter.suppressed, 1);
+
+        limiter.flush();
+        assert_eq!(limiter.suppressed, 0);
+    }
+}
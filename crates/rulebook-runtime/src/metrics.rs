@@ -0,0 +1,97 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::RuntimeError;
+
+/// Upper bounds of the session duration buckets, sessions longer than the last one
+/// fall into an unbounded bucket.
+const DURATION_BUCKETS: [Duration; 7] = [
+    Duration::from_secs(1),
+    Duration::from_secs(10),
+    Duration::from_secs(60),
+    Duration::from_secs(5 * 60),
+    Duration::from_secs(15 * 60),
+    Duration::from_secs(30 * 60),
+    Duration::from_secs(60 * 60),
+];
+
+/// Counters of every session the runtime ran, see `Runtime::metrics_snapshot`.
+#[derive(Debug, Default)]
+pub(crate) struct Metrics {
+    sessions_created: AtomicU64,
+    sessions_completed: AtomicU64,
+    sessions_failed: AtomicU64,
+    total_duration_us: AtomicU64,
+    duration_buckets: [AtomicU64; DURATION_BUCKETS.len() + 1],
+    /// Only touched on failures, off the hot path.
+    failures: Mutex<BTreeMap<Option<RuntimeError>, u64>>,
+}
+
+/// Counters at a point in time.
+#[derive(Debug, Clone)]
+pub struct MetricsSnapshot {
+    pub sessions_created: u64,
+    pub sessions_completed: u64,
+    pub sessions_failed: u64,
+    /// Sum of the durations `Session::start` took, of sessions completed or failed.
+    pub total_duration: Duration,
+    /// Number of finished sessions which took at most the bound and longer than the previous one.
+    /// The last bound is `None`, for sessions longer than every other bound.
+    pub duration_buckets: Vec<(Option<Duration>, u64)>,
+    /// Number of failed sessions by the `RuntimeError` attached, `None` for uncategorized ones.
+    pub failures_by_category: BTreeMap<Option<RuntimeError>, u64>,
+}
+
+impl Metrics {
+    pub fn session_created(&self) {
+        self.sessions_created.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn session_finished(&self, duration: Duration, res: &anyhow::Result<()>) {
+        match res {
+            Ok(()) => {
+                self.sessions_completed.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(err) => {
+                self.sessions_failed.fetch_add(1, Ordering::Relaxed);
+                let category = err.downcast_ref::<RuntimeError>().copied();
+                *self.failures.lock().unwrap().entry(category).or_default() += 1;
+            }
+        }
+
+        self.total_duration_us
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+        let bucket = DURATION_BUCKETS
+            .iter()
+            .position(|&bound| duration <= bound)
+            .unwrap_or(DURATION_BUCKETS.len());
+        self.duration_buckets[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        let bounds = DURATION_BUCKETS.iter().copied().map(Some).chain([None]);
+        MetricsSnapshot {
+            sessions_created: self.sessions_created.load(Ordering::Relaxed),
+            sessions_completed: self.sessions_completed.load(Ordering::Relaxed),
+            sessions_failed: self.sessions_failed.load(Ordering::Relaxed),
+            total_duration: Duration::from_micros(self.total_duration_us.load(Ordering::Relaxed)),
+            duration_buckets: bounds
+                .zip(&self.duration_buckets)
+                .map(|(bound, count)| (bound, count.load(Ordering::Relaxed)))
+                .collect(),
+            failures_by_category: self.failures.lock().unwrap().clone(),
+        }
+    }
+}
+
+impl MetricsSnapshot {
+    /// Average duration of finished sessions, `None` if none has finished.
+    pub fn avg_duration(&self) -> Option<Duration> {
+        let finished = self.sessions_completed + self.sessions_failed;
+        (finished > 0).then(|| {
+            Duration::from_nanos((self.total_duration.as_nanos() / finished as u128) as u64)
+        })
+    }
+}
@@ -0,0 +1,108 @@
+use anyhow::{Context, Result};
+use serde_json::value::RawValue;
+use serde_json::Value;
+
+use rulebook_interface_types::PatchOp;
+
+use crate::RuntimeError;
+
+/// Last full state of the game, to apply `Output::PatchState` to.
+#[derive(Debug, Default)]
+pub(crate) struct StateMirror {
+    last: Option<Box<RawValue>>,
+}
+
+impl StateMirror {
+    pub fn update(&mut self, state: &RawValue) {
+        self.last = Some(state.to_owned());
+    }
+
+    /// Applies the patch to the last state, returns the patched state.
+    pub fn patch(&mut self, ops: Vec<PatchOp>) -> Result<Box<RawValue>> {
+        let last = self
+            .last
+            .as_ref()
+            .context("game sent patchState before any updateState")
+            .context(RuntimeError::Protocol)?;
+
+        let mut state: Value = serde_json::from_str(last.get())?;
+        for op in ops {
+            apply(&mut state, op).context(RuntimeError::Protocol)?;
+        }
+
+        let state = serde_json::value::to_raw_value(&state)?;
+        self.last = Some(state.clone());
+        Ok(state)
+    }
+}
+
+fn apply(target: &mut Value, op: PatchOp) -> Result<()> {
+    match op {
+        PatchOp::Add { path, value } => {
+            let Some((parent, key)) = split_pointer(&path)? else {
+                *target = value;
+                return Ok(());
+            };
+            match resolve(target, parent, &path)? {
+                Value::Object(map) => {
+                    map.insert(key, value);
+                }
+                Value::Array(arr) => {
+                    let idx = match &*key {
+                        "-" => arr.len(),
+                        _ => array_index(&key, arr.len() + 1, &path)?,
+                    };
+                    arr.insert(idx, value);
+                }
+                _ => anyhow::bail!("patch path {path} is not within a container"),
+            }
+        }
+        PatchOp::Remove { path } => {
+            let Some((parent, key)) = split_pointer(&path)? else {
+                anyhow::bail!("patch can't remove the root");
+            };
+            match resolve(target, parent, &path)? {
+                Value::Object(map) => {
+                    map.remove(&key)
+                        .with_context(|| format!("patch path {path} doesn't exist"))?;
+                }
+                Value::Array(arr) => {
+                    let idx = array_index(&key, arr.len(), &path)?;
+                    arr.remove(idx);
+                }
+                _ => anyhow::bail!("patch path {path} is not within a container"),
+            }
+        }
+        PatchOp::Replace { path, value } => {
+            *resolve(target, &path, &path)? = value;
+        }
+    }
+
+    Ok(())
+}
+
+/// Splits the pointer into its parent pointer and unescaped last token, `None` for the root.
+fn split_pointer(path: &str) -> Result<Option<(&str, String)>> {
+    if path.is_empty() {
+        return Ok(None);
+    }
+    let idx = path
+        .rfind('/')
+        .with_context(|| format!("invalid patch path {path}"))?;
+    let key = path[idx + 1..].replace("~1", "/").replace("~0", "~");
+    Ok(Some((&path[..idx], key)))
+}
+
+fn resolve<'a>(target: &'a mut Value, pointer: &str, path: &str) -> Result<&'a mut Value> {
+    target
+        .pointer_mut(pointer)
+        .with_context(|| format!("patch path {path} doesn't exist"))
+}
+
+/// Parses the array index, which must be less than `bound`.
+fn array_index(key: &str, bound: usize, path: &str) -> Result<usize> {
+    match key.parse() {
+        Ok(idx) if idx < bound => Ok(idx),
+        _ => anyhow::bail!("patch path {path} is out of the array"),
+    }
+}
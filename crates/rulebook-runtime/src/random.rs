@@ -0,0 +1,57 @@
+/// Source of the numbers hosts answer the game's `random` requests with.
+///
+/// Replaying a session with the same source and the same actions reproduces the same game.
+pub trait RandomSource: Send {
+    /// Returns a number within `start..=end`.
+    fn next_in_range(&mut self, start: i32, end: i32) -> i32;
+
+    /// Fills the buffer with random bytes.
+    fn fill_bytes(&mut self, buf: &mut [u8]) {
+        for byte in buf {
+            *byte = self.next_in_range(0, u8::MAX as i32) as u8;
+        }
+    }
+}
+
+/// Reproducible random source from a seed.
+#[derive(Debug, Clone)]
+pub struct SeededRandom {
+    seed: u64,
+    rng: fastrand::Rng,
+}
+
+impl SeededRandom {
+    pub fn new(seed: u64) -> Self {
+        SeededRandom {
+            seed,
+            rng: fastrand::Rng::with_seed(seed),
+        }
+    }
+
+    /// Seeded with a random seed, which can be retrieved with `seed` to replay later.
+    pub fn from_entropy() -> Self {
+        Self::new(fastrand::u64(..))
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+}
+
+impl Default for SeededRandom {
+    fn default() -> Self {
+        Self::from_entropy()
+    }
+}
+
+impl RandomSource for SeededRandom {
+    fn next_in_range(&mut self, start: i32, end: i32) -> i32 {
+        self.rng.i32(start..=end)
+    }
+
+    fn fill_bytes(&mut self, buf: &mut [u8]) {
+        for byte in buf {
+            *byte = self.rng.u8(..);
+        }
+    }
+}
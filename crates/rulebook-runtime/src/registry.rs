@@ -0,0 +1,176 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::sync::Notify;
+use wasmtime::Engine;
+
+use crate::RuntimeError;
+
+pub type SessionId = u64;
+
+/// Snapshot of a session alive in the runtime.
+#[derive(Debug, Clone)]
+pub struct SessionStatus {
+    pub id: SessionId,
+    pub game_key: Arc<str>,
+    /// Time since the session is created.
+    pub age: Duration,
+    pub running: bool,
+    /// Number of IO the game performed so far.
+    pub io_count: u64,
+}
+
+/// Stops a session from anywhere, see `Session::cancel_handle`.
+///
+/// The default one belongs to no session, for handlers run without one like in tests.
+#[derive(Debug, Clone, Default)]
+pub struct CancelHandle {
+    control: Arc<SessionControl>,
+}
+
+impl CancelHandle {
+    pub(crate) fn new(control: Arc<SessionControl>) -> Self {
+        CancelHandle { control }
+    }
+
+    /// Makes the session's `start` return `RuntimeError::Cancelled`.
+    ///
+    /// Game busy computing traps right away, and IO the game performs afterwards fails
+    /// before reaching the handler. IO already in the handler runs to its end, so the session
+    /// never leaves the handler halfway. Handlers waiting on players should give up
+    /// at a safe point once `cancelled` resolves.
+    ///
+    /// If the session hasn't started yet, it's cancelled as soon as it starts.
+    pub fn cancel(&self) {
+        self.control.cancelled.store(true, Ordering::Relaxed);
+        self.control.cancel_notify.notify_waiters();
+        // the game checks the flag once it reaches its epoch deadline
+        if let Some(engine) = &self.control.engine {
+            engine.increment_epoch();
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.control.cancelled.load(Ordering::Relaxed)
+    }
+
+    /// Resolves once the session is cancelled, right away if it is already.
+    pub async fn cancelled(&self) {
+        let notified = self.control.cancel_notify.notified();
+        tokio::pin!(notified);
+        // registered before checking the flag, so a cancel in between isn't missed
+        notified.as_mut().enable();
+        if !self.is_cancelled() {
+            notified.await;
+        }
+    }
+}
+
+/// Shared between the registry and the session it controls.
+#[derive(Default)]
+pub(crate) struct SessionControl {
+    /// Its epoch is bumped on cancel, `None` for handles of no session.
+    pub engine: Option<Engine>,
+    pub cancelled: AtomicBool,
+    pub cancel_notify: Notify,
+    pub running: AtomicBool,
+    pub io_count: AtomicU64,
+}
+
+impl std::fmt::Debug for SessionControl {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SessionControl")
+            .field("cancelled", &self.cancelled)
+            .field("running", &self.running)
+            .field("io_count", &self.io_count)
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    game_key: Arc<str>,
+    created_at: Instant,
+    control: Arc<SessionControl>,
+}
+
+pub(crate) struct SessionRegistry {
+    engine: Engine,
+    next_id: AtomicU64,
+    sessions: Mutex<HashMap<SessionId, Entry>>,
+}
+
+impl SessionRegistry {
+    pub fn new(engine: Engine) -> Self {
+        SessionRegistry {
+            engine,
+            next_id: AtomicU64::new(0),
+            sessions: Default::default(),
+        }
+    }
+
+    pub fn register(
+        &self,
+        game_key: Arc<str>,
+        max: Option<usize>,
+    ) -> Result<(SessionId, Arc<SessionControl>), RuntimeError> {
+        let mut sessions = self.sessions.lock().unwrap();
+        if max.is_some_and(|max| sessions.len() >= max) {
+            return Err(RuntimeError::TooManySessions);
+        }
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let control = Arc::new(SessionControl {
+            engine: Some(self.engine.clone()),
+            ..Default::default()
+        });
+        sessions.insert(
+            id,
+            Entry {
+                game_key,
+                created_at: Instant::now(),
+                control: control.clone(),
+            },
+        );
+
+        Ok((id, control))
+    }
+
+    pub fn unregister(&self, id: SessionId) {
+        self.sessions.lock().unwrap().remove(&id);
+    }
+
+    pub fn len(&self) -> usize {
+        self.sessions.lock().unwrap().len()
+    }
+
+    pub fn list(&self) -> Vec<SessionStatus> {
+        let mut list: Vec<_> = self
+            .sessions
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(&id, entry)| SessionStatus {
+                id,
+                game_key: entry.game_key.clone(),
+                age: entry.created_at.elapsed(),
+                running: entry.control.running.load(Ordering::Relaxed),
+                io_count: entry.control.io_count.load(Ordering::Relaxed),
+            })
+            .collect();
+        list.sort_by_key(|status| status.id);
+        list
+    }
+
+    pub fn cancel(&self, id: SessionId) -> bool {
+        match self.sessions.lock().unwrap().get(&id) {
+            Some(entry) => {
+                CancelHandle::new(entry.control.clone()).cancel();
+                true
+            }
+            None => false,
+        }
+    }
+}
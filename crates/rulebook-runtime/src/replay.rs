@@ -0,0 +1,505 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
+
+use crate::{OutputHandler, PlayerId, RoomInfo, Runtime, RuntimeError, TaskResult, TimedAction};
+
+/// Host side input the game received, recorded by `RecordingHandler`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Event {
+    /// Position of the event in the log, starts from 0.
+    pub index: u64,
+    pub kind: EventKind,
+}
+
+/// Request of the game and the input replied to it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", content = "data", rename_all = "camelCase")]
+pub enum EventKind {
+    DoTaskIf {
+        allowed: Vec<PlayerId>,
+        result: TaskResult<Box<RawValue>>,
+    },
+    Random {
+        start: i32,
+        end: i32,
+        value: i32,
+    },
+    RandomBytes {
+        len: usize,
+        value: Vec<u8>,
+    },
+    Action {
+        from: PlayerId,
+        param: Box<RawValue>,
+        value: Box<RawValue>,
+    },
+    ActionWithDeadline {
+        from: PlayerId,
+        param: Box<RawValue>,
+        deadline_ms: u64,
+        value: TimedAction<Box<RawValue>>,
+    },
+    FirstAction {
+        from: Vec<PlayerId>,
+        param: Box<RawValue>,
+        deadline_ms: Option<u64>,
+        value: TimedAction<(PlayerId, Box<RawValue>)>,
+    },
+    SimultaneousAction {
+        from: Vec<PlayerId>,
+        param: Box<RawValue>,
+        value: Box<RawValue>,
+    },
+    GatherActions {
+        requests: Vec<(PlayerId, Box<RawValue>)>,
+        value: Box<RawValue>,
+    },
+    ForfeitedPlayers {
+        value: Vec<PlayerId>,
+    },
+}
+
+impl EventKind {
+    /// Whether both are the same request, regardless of the input.
+    fn same_request(&self, other: &EventKind) -> bool {
+        use EventKind::*;
+
+        match (self, other) {
+            (DoTaskIf { allowed: a, .. }, DoTaskIf { allowed: b, .. }) => a == b,
+            (
+                Random { start, end, .. },
+                Random {
+                    start: s, end: e, ..
+                },
+            ) => start == s && end == e,
+            (RandomBytes { len, .. }, RandomBytes { len: l, .. }) => len == l,
+            (
+                Action { from, param, .. },
+                Action {
+                    from: f, param: p, ..
+                },
+            ) => from == f && param.get() == p.get(),
+            (
+                ActionWithDeadline {
+                    from,
+                    param,
+                    deadline_ms,
+                    ..
+                },
+                ActionWithDeadline {
+                    from: f,
+                    param: p,
+                    deadline_ms: d,
+                    ..
+                },
+            ) => from == f && param.get() == p.get() && deadline_ms == d,
+            (
+                FirstAction {
+                    from,
+                    param,
+                    deadline_ms,
+                    ..
+                },
+                FirstAction {
+                    from: f,
+                    param: p,
+                    deadline_ms: d,
+                    ..
+                },
+            ) => from == f && param.get() == p.get() && deadline_ms == d,
+            (
+                SimultaneousAction { from, param, .. },
+                SimultaneousAction {
+                    from: f, param: p, ..
+                },
+            ) => from == f && param.get() == p.get(),
+            (GatherActions { requests, .. }, GatherActions { requests: r, .. }) => {
+                requests.len() == r.len()
+                    && requests
+                        .iter()
+                        .zip(r)
+                        .all(|((from, param), (f, p))| from == f && param.get() == p.get())
+            }
+            (ForfeitedPlayers { .. }, ForfeitedPlayers { .. }) => true,
+            _ => false,
+        }
+    }
+}
+
+/// Events recorded so far, shared with the `RecordingHandler` which fills it.
+#[derive(Debug, Clone, Default)]
+pub struct EventLog {
+    events: Arc<Mutex<Vec<Event>>>,
+}
+
+impl EventLog {
+    pub fn events(&self) -> Vec<Event> {
+        self.events.lock().unwrap().clone()
+    }
+
+    fn push(&self, kind: EventKind) {
+        let mut events = self.events.lock().unwrap();
+        let index = events.len() as u64;
+        events.push(Event { index, kind });
+    }
+}
+
+/// Forwards every output to the inner handler, and records every input it returns
+/// so the session can be replayed offline with `ReplayHandler`.
+pub struct RecordingHandler<H> {
+    inner: H,
+    log: EventLog,
+}
+
+impl<H: OutputHandler> RecordingHandler<H> {
+    pub fn new(inner: H) -> (Self, EventLog) {
+        let log = EventLog::default();
+        let handler = RecordingHandler {
+            inner,
+            log: log.clone(),
+        };
+
+        (handler, log)
+    }
+}
+
+#[async_trait::async_trait]
+impl<H: OutputHandler> OutputHandler for RecordingHandler<H> {
+    fn state(&mut self, json: &RawValue) -> Result<()> {
+        self.inner.state(json)
+    }
+
+    fn private_state(&mut self, name: &str, target: PlayerId, json: &RawValue) -> Result<()> {
+        self.inner.private_state(name, target, json)
+    }
+
+    fn admin_audit(&mut self, kind: &str, detail: &RawValue) -> Result<()> {
+        self.inner.admin_audit(kind, detail)
+    }
+
+    fn game_result(&mut self, json: &RawValue) -> Result<()> {
+        self.inner.game_result(json)
+    }
+
+    async fn do_task_if(&mut self, allowed: Vec<PlayerId>) -> Result<TaskResult<Box<RawValue>>> {
+        let result = self.inner.do_task_if(allowed.clone()).await?;
+        self.log.push(EventKind::DoTaskIf {
+            allowed,
+            result: result.clone(),
+        });
+        Ok(result)
+    }
+
+    async fn task_done(&mut self, targets: Vec<PlayerId>, value: &RawValue) -> Result<()> {
+        self.inner.task_done(targets, value).await
+    }
+
+    async fn notify(&mut self, targets: Vec<PlayerId>, value: &RawValue) -> Result<()> {
+        self.inner.notify(targets, value).await
+    }
+
+    async fn random(&mut self, start: i32, end: i32) -> Result<i32> {
+        let value = self.inner.random(start, end).await?;
+        self.log.push(EventKind::Random { start, end, value });
+        Ok(value)
+    }
+
+    async fn random_bytes(&mut self, len: usize) -> Result<Vec<u8>> {
+        let value = self.inner.random_bytes(len).await?;
+        self.log.push(EventKind::RandomBytes {
+            len,
+            value: value.clone(),
+        });
+        Ok(value)
+    }
+
+    async fn action(&mut self, from: PlayerId, param: &RawValue) -> Result<Box<RawValue>> {
+        let value = self.inner.action(from, param).await?;
+        self.log.push(EventKind::Action {
+            from,
+            param: param.to_owned(),
+            value: value.clone(),
+        });
+        Ok(value)
+    }
+
+    async fn action_with_deadline(
+        &mut self,
+        from: PlayerId,
+        param: &RawValue,
+        deadline: Duration,
+    ) -> Result<TimedAction<Box<RawValue>>> {
+        let value = self
+            .inner
+            .action_with_deadline(from, param, deadline)
+            .await?;
+        self.log.push(EventKind::ActionWithDeadline {
+            from,
+            param: param.to_owned(),
+            deadline_ms: deadline.as_millis() as u64,
+            value: value.clone(),
+        });
+        Ok(value)
+    }
+
+    async fn first_action(
+        &mut self,
+        from: Vec<PlayerId>,
+        param: &RawValue,
+        deadline: Option<Duration>,
+    ) -> Result<TimedAction<(PlayerId, Box<RawValue>)>> {
+        let value = self
+            .inner
+            .first_action(from.clone(), param, deadline)
+            .await?;
+        self.log.push(EventKind::FirstAction {
+            from,
+            param: param.to_owned(),
+            deadline_ms: deadline.map(|deadline| deadline.as_millis() as u64),
+            value: value.clone(),
+        });
+        Ok(value)
+    }
+
+    async fn simultaneous_action(
+        &mut self,
+        from: Vec<PlayerId>,
+        param: &RawValue,
+    ) -> Result<Box<RawValue>> {
+        let value = self.inner.simultaneous_action(from.clone(), param).await?;
+        self.log.push(EventKind::SimultaneousAction {
+            from,
+            param: param.to_owned(),
+            value: value.clone(),
+        });
+        Ok(value)
+    }
+
+    async fn gather_actions(
+        &mut self,
+        requests: Vec<(PlayerId, Box<RawValue>)>,
+    ) -> Result<Box<RawValue>> {
+        let value = self.inner.gather_actions(requests.clone()).await?;
+        self.log.push(EventKind::GatherActions {
+            requests,
+            value: value.clone(),
+        });
+        Ok(value)
+    }
+
+    async fn forfeited_players(&mut self) -> Result<Vec<PlayerId>> {
+        let value = self.inner.forfeited_players().await?;
+        self.log.push(EventKind::ForfeitedPlayers {
+            value: value.clone(),
+        });
+        Ok(value)
+    }
+}
+
+/// Replies every input from the log recorded by `RecordingHandler`, without any network.
+///
+/// Fails with `RuntimeError::Protocol` once the game requests something else than recorded.
+#[derive(Debug)]
+pub struct ReplayHandler {
+    events: Arc<Mutex<VecDeque<Event>>>,
+}
+
+impl ReplayHandler {
+    pub fn new(events: Vec<Event>) -> Self {
+        ReplayHandler {
+            events: Arc::new(Mutex::new(events.into())),
+        }
+    }
+
+    fn next(&self, request: EventKind) -> Result<EventKind> {
+        let Some(event) = self.events.lock().unwrap().pop_front() else {
+            return Err(
+                anyhow::anyhow!("game requested {request:?} beyond the end of the log")
+                    .context(RuntimeError::Protocol),
+            );
+        };
+
+        if !event.kind.same_request(&request) {
+            return Err(anyhow::anyhow!(
+                "game diverged from the log at event #{}, expected {:?} but got {request:?}",
+                event.index,
+                event.kind,
+            )
+            .context(RuntimeError::Protocol));
+        }
+
+        Ok(event.kind)
+    }
+}
+
+fn null() -> Box<RawValue> {
+    RawValue::from_string("null".into()).unwrap()
+}
+
+#[async_trait::async_trait]
+impl OutputHandler for ReplayHandler {
+    fn state(&mut self, _json: &RawValue) -> Result<()> {
+        Ok(())
+    }
+
+    fn private_state(&mut self, _name: &str, _target: PlayerId, _json: &RawValue) -> Result<()> {
+        Ok(())
+    }
+
+    async fn do_task_if(&mut self, allowed: Vec<PlayerId>) -> Result<TaskResult<Box<RawValue>>> {
+        let request = EventKind::DoTaskIf {
+            allowed,
+            result: TaskResult::DoTask,
+        };
+        match self.next(request)? {
+            EventKind::DoTaskIf { result, .. } => Ok(result),
+            _ => unreachable!("checked by same_request"),
+        }
+    }
+
+    async fn task_done(&mut self, _targets: Vec<PlayerId>, _value: &RawValue) -> Result<()> {
+        Ok(())
+    }
+
+    async fn notify(&mut self, _targets: Vec<PlayerId>, _value: &RawValue) -> Result<()> {
+        Ok(())
+    }
+
+    async fn random(&mut self, start: i32, end: i32) -> Result<i32> {
+        let request = EventKind::Random {
+            start,
+            end,
+            value: 0,
+        };
+        match self.next(request)? {
+            EventKind::Random { value, .. } => Ok(value),
+            _ => unreachable!("checked by same_request"),
+        }
+    }
+
+    async fn random_bytes(&mut self, len: usize) -> Result<Vec<u8>> {
+        let request = EventKind::RandomBytes { len, value: vec![] };
+        match self.next(request)? {
+            EventKind::RandomBytes { value, .. } => Ok(value),
+            _ => unreachable!("checked by same_request"),
+        }
+    }
+
+    async fn action(&mut self, from: PlayerId, param: &RawValue) -> Result<Box<RawValue>> {
+        let request = EventKind::Action {
+            from,
+            param: param.to_owned(),
+            value: null(),
+        };
+        match self.next(request)? {
+            EventKind::Action { value, .. } => Ok(value),
+            _ => unreachable!("checked by same_request"),
+        }
+    }
+
+    async fn action_with_deadline(
+        &mut self,
+        from: PlayerId,
+        param: &RawValue,
+        deadline: Duration,
+    ) -> Result<TimedAction<Box<RawValue>>> {
+        let request = EventKind::ActionWithDeadline {
+            from,
+            param: param.to_owned(),
+            deadline_ms: deadline.as_millis() as u64,
+            value: TimedAction::TimedOut,
+        };
+        match self.next(request)? {
+            EventKind::ActionWithDeadline { value, .. } => Ok(value),
+            _ => unreachable!("checked by same_request"),
+        }
+    }
+
+    async fn first_action(
+        &mut self,
+        from: Vec<PlayerId>,
+        param: &RawValue,
+        deadline: Option<Duration>,
+    ) -> Result<TimedAction<(PlayerId, Box<RawValue>)>> {
+        let request = EventKind::FirstAction {
+            from,
+            param: param.to_owned(),
+            deadline_ms: deadline.map(|deadline| deadline.as_millis() as u64),
+            value: TimedAction::TimedOut,
+        };
+        match self.next(request)? {
+            EventKind::FirstAction { value, .. } => Ok(value),
+            _ => unreachable!("checked by same_request"),
+        }
+    }
+
+    async fn simultaneous_action(
+        &mut self,
+        from: Vec<PlayerId>,
+        param: &RawValue,
+    ) -> Result<Box<RawValue>> {
+        let request = EventKind::SimultaneousAction {
+            from,
+            param: param.to_owned(),
+            value: null(),
+        };
+        match self.next(request)? {
+            EventKind::SimultaneousAction { value, .. } => Ok(value),
+            _ => unreachable!("checked by same_request"),
+        }
+    }
+
+    async fn gather_actions(
+        &mut self,
+        requests: Vec<(PlayerId, Box<RawValue>)>,
+    ) -> Result<Box<RawValue>> {
+        let request = EventKind::GatherActions {
+            requests,
+            value: null(),
+        };
+        match self.next(request)? {
+            EventKind::GatherActions { value, .. } => Ok(value),
+            _ => unreachable!("checked by same_request"),
+        }
+    }
+
+    async fn forfeited_players(&mut self) -> Result<Vec<PlayerId>> {
+        let request = EventKind::ForfeitedPlayers { value: vec![] };
+        match self.next(request)? {
+            EventKind::ForfeitedPlayers { value } => Ok(value),
+            _ => unreachable!("checked by same_request"),
+        }
+    }
+}
+
+impl Runtime {
+    /// Runs the game with inputs from the log, and checks it consumes the whole log.
+    pub async fn replay_events(
+        &self,
+        game_key: &str,
+        room: RoomInfo,
+        events: Vec<Event>,
+    ) -> Result<()> {
+        let total = events.len();
+        let handler = ReplayHandler::new(events);
+        let remaining = handler.events.clone();
+
+        let mut session = self.new_session(game_key).await?;
+        session.start(16 * 1024, false, room, handler).await?;
+
+        let remaining = remaining.lock().unwrap().len();
+        anyhow::ensure!(
+            remaining == 0,
+            "game {game_key} ended after {} of {total} events",
+            total - remaining,
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,59 @@
+use serde::de::IgnoredAny;
+use serde_json::value::RawValue;
+
+use rulebook_interface_types::Output;
+
+use crate::TaskResult;
+
+/// `doTaskIf` blocks the game is running, to tell if it's within the admin scope.
+///
+/// Tracked for replayed IO too, so it stays right once the session continues live.
+#[derive(Debug, Default)]
+pub(crate) struct ScopeTracker {
+    /// Whether each block is admin scoped, innermost last.
+    blocks: Vec<bool>,
+}
+
+/// How an output may change the scope, taken before the output is handled.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum ScopeChange {
+    /// Enters the block if the input is `DoTask`.
+    Enter {
+        admin: bool,
+    },
+    Exit,
+}
+
+impl ScopeChange {
+    pub fn of(output: &Output<Box<RawValue>>) -> Option<Self> {
+        match output {
+            Output::DoTaskIf { allowed } => Some(ScopeChange::Enter {
+                admin: allowed.is_empty(),
+            }),
+            Output::TaskDone { .. } => Some(ScopeChange::Exit),
+            _ => None,
+        }
+    }
+}
+
+impl ScopeTracker {
+    /// Whether the game is within a block no player runs but the host.
+    pub fn in_admin(&self) -> bool {
+        self.blocks.last() == Some(&true)
+    }
+
+    /// Applies the change with the input replied to its output.
+    pub fn apply(&mut self, change: ScopeChange, input: &str) {
+        match change {
+            ScopeChange::Enter { admin } => {
+                let result = serde_json::from_str::<TaskResult<IgnoredAny>>(input);
+                if matches!(result, Ok(TaskResult::DoTask)) {
+                    self.blocks.push(admin);
+                }
+            }
+            ScopeChange::Exit => {
+                self.blocks.pop();
+            }
+        }
+    }
+}
@@ -0,0 +1,85 @@
+use std::time::{Duration, Instant};
+
+use serde_json::value::RawValue;
+
+/// Coalesces guest state updates to at most one per interval, keeping only the latest.
+#[derive(Debug)]
+pub(crate) struct StateLimiter {
+    interval: Duration,
+    last_sent: Option<Instant>,
+    pending: Option<Box<RawValue>>,
+}
+
+impl StateLimiter {
+    pub fn new(max_per_sec: u32) -> Self {
+        StateLimiter {
+            interval: Duration::from_secs(1) / max_per_sec.max(1),
+            last_sent: None,
+            pending: None,
+        }
+    }
+
+    fn ready(&self) -> bool {
+        self.last_sent
+            .is_none_or(|last| last.elapsed() >= self.interval)
+    }
+
+    fn sent(&mut self) {
+        self.last_sent = Some(Instant::now());
+        self.pending = None;
+    }
+
+    /// Returns the state if it should be sent now, otherwise holds it until later.
+    pub fn offer(&mut self, state: Box<RawValue>) -> Option<Box<RawValue>> {
+        if self.ready() {
+            self.sent();
+            Some(state)
+        } else {
+            self.pending = Some(state);
+            None
+        }
+    }
+
+    /// Returns the held state if the interval has passed.
+    pub fn poll(&mut self) -> Option<Box<RawValue>> {
+        if self.ready() {
+            self.take()
+        } else {
+            None
+        }
+    }
+
+    /// Returns the held state regardless of the interval.
+    pub fn take(&mut self) -> Option<Box<RawValue>> {
+        let state = self.pending.take()?;
+        self.sent();
+        Some(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(n: u32) -> Box<RawValue> {
+        serde_json::value::to_raw_value(&n).unwrap()
+    }
+
+    #[test]
+    fn coalesces_updates_within_the_interval_to_the_latest() {
+        let mut limiter = StateLimiter::new(1);
+
+        assert_eq!(limiter.offer(state(1)).unwrap().get(), "1");
+        assert!(limiter.offer(state(2)).is_none());
+        assert!(limiter.offer(state(3)).is_none());
+        assert!(limiter.poll().is_none(), "interval hasn't passed");
+
+        limiter.last_sent = limiter.last_sent.map(|last| last - limiter.interval);
+        assert_eq!(limiter.poll().unwrap().get(), "3");
+        assert!(limiter.poll().is_none(), "only the latest state is held");
+
+        assert!(limiter.offer(state(4)).is_none());
+        assert_eq!(limiter.take().unwrap().get(), "4");
+        assert!(limiter.take().is_none());
+    }
+}
@@ -0,0 +1,86 @@
+//! Games written in WAT for tests, performing a fixed sequence of outputs.
+//!
+//! Runtimes take the text format as is, so they're added without compiling them first.
+
+use std::fmt::Write as _;
+
+use crate::GameInfo;
+
+/// First output of every session.
+pub const SESSION_START: &str = r#"{"type":"sessionStart"}"#;
+pub const SESSION_END: &str = r#"{"type":"sessionEnd","data":{"reason":{"type":"completed"}}}"#;
+
+/// Where outputs are placed in the memory, below the input buffer.
+const OUTPUTS_OFFSET: usize = 1024;
+const INPUT_PTR: usize = 32 * 1024;
+const INPUT_CAP: usize = 32 * 1024;
+
+/// Game performing `outputs` in order, each the JSON of an `Output`.
+pub fn game(outputs: &[&str]) -> String {
+    game_with(outputs, "")
+}
+
+/// Like `game`, running the instructions of `tail` after the outputs.
+///
+/// Within it `(call $out<N>)` performs the Nth output again,
+/// and `(call $log)` logs the message `hello`.
+pub fn game_with(outputs: &[&str], tail: &str) -> String {
+    game_with_info(&GameInfo::default(), outputs, tail)
+}
+
+/// Like `game_with`, telling the host the info on `rulebook_game_info`.
+pub fn game_with_info(info: &GameInfo, outputs: &[&str], tail: &str) -> String {
+    let mut data = String::new();
+    let mut funcs = String::new();
+    let mut body = String::new();
+    let mut offset = OUTPUTS_OFFSET;
+    for (idx, output) in outputs.iter().enumerate() {
+        writeln!(
+            data,
+            r#"  (data (i32.const {offset}) "{}")"#,
+            escape(output)
+        )
+        .unwrap();
+        writeln!(
+            funcs,
+            "  (func $out{idx} (call $io (i32.const {offset}) (i32.const {})))",
+            output.len()
+        )
+        .unwrap();
+        writeln!(body, "    (call $out{idx})").unwrap();
+        offset += output.len();
+    }
+    let info = serde_json::to_string(info).unwrap();
+    let (info_offset, info_len) = (offset, info.len());
+    writeln!(data, r#"  (data (i32.const {offset}) "{}")"#, escape(&info)).unwrap();
+    offset += info_len;
+    assert!(offset <= INPUT_PTR, "outputs overlap the input buffer");
+
+    format!(
+        r#"(module
+  (import "env" "rulebook_trigger_io" (func $trigger_io (param i32) (result i32)))
+  (import "env" "rulebook_log" (func $rulebook_log (param i32 i32)))
+  (memory (export "memory") 1)
+  (data (i32.const 96) "hello")
+{data}
+  (func $io (param $output_ptr i32) (param $output_len i32)
+    (i32.store (i32.const 0) (i32.const {INPUT_PTR}))
+    (i32.store (i32.const 4) (i32.const {INPUT_CAP}))
+    (i32.store (i32.const 8) (local.get $output_ptr))
+    (i32.store (i32.const 12) (local.get $output_len))
+    (drop (call $trigger_io (i32.const 0))))
+  (func $log (call $rulebook_log (i32.const 96) (i32.const 5)))
+{funcs}
+  (func (export "rulebook_abi_version") (result i32) (i32.const 2))
+  (func (export "rulebook_game_info") (result i64)
+    (i64.or (i64.shl (i64.const {info_offset}) (i64.const 32)) (i64.const {info_len})))
+  (func (export "rulebook_start_session") (param $input_cap i32) (param $print_state i32)
+{body}    {tail}))
+"#
+    )
+}
+
+/// Text as the content of a WAT string.
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
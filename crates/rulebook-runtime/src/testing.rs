@@ -0,0 +1,366 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use serde_json::value::RawValue;
+
+use crate::{
+    Event, OutputHandler, PlayerId, RandomSource, RecordingHandler, RoomInfo, Runtime,
+    SeededRandom, TaskResult, TimedAction,
+};
+
+/// In-process room which plays every player from scripted actions, without any network.
+///
+/// Run it with `Runtime::run_local`.
+#[derive(Debug)]
+pub struct LocalRoom {
+    actions: HashMap<PlayerId, VecDeque<TimedAction<Box<RawValue>>>>,
+    rng: SeededRandom,
+    /// Shared to read it back after the session consumed the room.
+    state: Arc<Mutex<Option<Box<RawValue>>>>,
+    private_states: Arc<Mutex<PrivateStates>>,
+}
+
+/// Last private state of each store, keyed by the target and then the name of the store.
+pub type PrivateStates = BTreeMap<PlayerId, BTreeMap<String, Box<RawValue>>>;
+
+/// Result of a session run by `Runtime::run_local`.
+#[derive(Debug)]
+pub struct LocalOutcome {
+    /// Last state the game sent, `None` if it never did.
+    pub state: Option<Box<RawValue>>,
+    /// Last private states the game sent.
+    pub private_states: PrivateStates,
+    /// Every input the game received, replayable with `Runtime::replay_events`.
+    pub events: Vec<Event>,
+}
+
+impl LocalRoom {
+    pub fn new(seed: u64) -> Self {
+        LocalRoom {
+            actions: HashMap::new(),
+            rng: SeededRandom::new(seed),
+            state: Default::default(),
+            private_states: Default::default(),
+        }
+    }
+
+    /// Queues actions the player submits in order, after the ones queued before.
+    pub fn script<T: Serialize>(
+        &mut self,
+        player: PlayerId,
+        actions: impl IntoIterator<Item = T>,
+    ) -> Result<()> {
+        let queue = self.actions.entry(player).or_default();
+        for action in actions {
+            let action = serde_json::value::to_raw_value(&action)?;
+            queue.push_back(TimedAction::Acted(action));
+        }
+
+        Ok(())
+    }
+
+    /// Queues the player letting the deadline of the next action pass without acting.
+    pub fn script_timeout(&mut self, player: PlayerId) {
+        let queue = self.actions.entry(player).or_default();
+        queue.push_back(TimedAction::TimedOut);
+    }
+
+    fn next_timed_action(&mut self, player: PlayerId) -> Result<TimedAction<Box<RawValue>>> {
+        self.actions
+            .get_mut(&player)
+            .and_then(VecDeque::pop_front)
+            .with_context(|| format!("player {player} ran out of scripted actions"))
+    }
+
+    fn next_action(&mut self, player: PlayerId) -> Result<Box<RawValue>> {
+        match self.next_timed_action(player)? {
+            TimedAction::Acted(action) => Ok(action),
+            TimedAction::TimedOut => {
+                anyhow::bail!(
+                    "player {player} is scripted to time out on an action without deadline"
+                )
+            }
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl OutputHandler for LocalRoom {
+    fn state(&mut self, json: &RawValue) -> Result<()> {
+        *self.state.lock().unwrap() = Some(json.to_owned());
+        Ok(())
+    }
+
+    fn private_state(&mut self, name: &str, target: PlayerId, json: &RawValue) -> Result<()> {
+        let mut private_states = self.private_states.lock().unwrap();
+        let states = private_states.entry(target).or_default();
+        states.insert(name.into(), json.to_owned());
+        Ok(())
+    }
+
+    async fn do_task_if(&mut self, _allowed: Vec<PlayerId>) -> Result<TaskResult<Box<RawValue>>> {
+        // the host runs the task on behalf of everyone
+        Ok(TaskResult::DoTask)
+    }
+
+    async fn task_done(&mut self, _targets: Vec<PlayerId>, _value: &RawValue) -> Result<()> {
+        Ok(())
+    }
+
+    async fn notify(&mut self, _targets: Vec<PlayerId>, _value: &RawValue) -> Result<()> {
+        Ok(())
+    }
+
+    async fn random(&mut self, start: i32, end: i32) -> Result<i32> {
+        Ok(self.rng.next_in_range(start, end))
+    }
+
+    async fn random_bytes(&mut self, len: usize) -> Result<Vec<u8>> {
+        let mut bytes = vec![0; len];
+        self.rng.fill_bytes(&mut bytes);
+        Ok(bytes)
+    }
+
+    async fn action(&mut self, from: PlayerId, _param: &RawValue) -> Result<Box<RawValue>> {
+        self.next_action(from)
+    }
+
+    async fn action_with_deadline(
+        &mut self,
+        from: PlayerId,
+        _param: &RawValue,
+        _deadline: Duration,
+    ) -> Result<TimedAction<Box<RawValue>>> {
+        self.next_timed_action(from)
+    }
+
+    /// The first of `from` whose next scripted action isn't a timeout wins,
+    /// the others' scripts are left for later. Without such a player it times out,
+    /// taking the scripted timeouts of `from`, which needs a deadline.
+    async fn first_action(
+        &mut self,
+        from: Vec<PlayerId>,
+        _param: &RawValue,
+        deadline: Option<Duration>,
+    ) -> Result<TimedAction<(PlayerId, Box<RawValue>)>> {
+        let acts = |queue: &VecDeque<_>| matches!(queue.front(), Some(TimedAction::Acted(_)));
+        let winner = from
+            .iter()
+            .copied()
+            .find(|player| self.actions.get(player).is_some_and(acts));
+        if let Some(player) = winner {
+            return Ok(match self.next_timed_action(player)? {
+                TimedAction::Acted(action) => TimedAction::Acted((player, action)),
+                TimedAction::TimedOut => unreachable!("checked by acts"),
+            });
+        }
+
+        anyhow::ensure!(
+            deadline.is_some(),
+            "none of {from:?} is scripted to act on a first action without deadline"
+        );
+        for player in from {
+            if let Some(queue) = self.actions.get_mut(&player) {
+                if matches!(queue.front(), Some(TimedAction::TimedOut)) {
+                    queue.pop_front();
+                }
+            }
+        }
+        Ok(TimedAction::TimedOut)
+    }
+
+    async fn simultaneous_action(
+        &mut self,
+        from: Vec<PlayerId>,
+        _param: &RawValue,
+    ) -> Result<Box<RawValue>> {
+        let mut values = BTreeMap::new();
+        for player in from {
+            values.insert(player, self.next_action(player)?);
+        }
+
+        Ok(serde_json::value::to_raw_value(&values)?)
+    }
+
+    async fn gather_actions(
+        &mut self,
+        requests: Vec<(PlayerId, Box<RawValue>)>,
+    ) -> Result<Box<RawValue>> {
+        let mut values = vec![];
+        for (player, _) in requests {
+            values.push(self.next_action(player)?);
+        }
+
+        Ok(serde_json::value::to_raw_value(&values)?)
+    }
+}
+
+impl Runtime {
+    /// Runs the game to the end within the local room.
+    ///
+    /// Every state update reaches the room regardless of `Config::state_rate_limit`.
+    pub async fn run_local(
+        &self,
+        game_key: &str,
+        room: RoomInfo,
+        local: LocalRoom,
+    ) -> Result<LocalOutcome> {
+        let mut session = self.new_session(game_key).await?;
+        session.conf.enable_state = true;
+        session.conf.state_rate_limit = None;
+
+        let state = local.state.clone();
+        let private_states = local.private_states.clone();
+        let (handler, log) = RecordingHandler::new(local);
+        session.start(16 * 1024, true, room, handler).await?;
+
+        let state = state.lock().unwrap().take();
+        let private_states = std::mem::take(&mut *private_states.lock().unwrap());
+        Ok(LocalOutcome {
+            state,
+            private_states,
+            events: log.events(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_games::{game, SESSION_END, SESSION_START};
+    use crate::{Color, Config, EventKind};
+
+    const RED: PlayerId = PlayerId::new(Color::Red as u8);
+    const BLUE: PlayerId = PlayerId::new(Color::Blue as u8);
+
+    #[tokio::test]
+    async fn run_local_plays_scripted_actions() {
+        let runtime = Runtime::new(Config::default()).unwrap();
+        let code = game(&[
+            SESSION_START,
+            r#"{"type":"action","data":{"from":"red","param":null}}"#,
+            r#"{"type":"random","data":{"start":0,"end":100}}"#,
+            r#"{"type":"updateState","data":{"round":1}}"#,
+            r#"{"type":"updatePrivateState","data":{"name":"hand","target":"blue","state":[3]}}"#,
+            SESSION_END,
+        ]);
+        runtime.add_game("game".into(), code.as_bytes()).unwrap();
+
+        let room = RoomInfo {
+            players: vec![RED, BLUE],
+            ..Default::default()
+        };
+        let mut local = LocalRoom::new(42);
+        local.script(RED, ["guess"]).unwrap();
+        let outcome = runtime.run_local("game", room, local).await.unwrap();
+
+        assert_eq!(outcome.state.unwrap().get(), r#"{"round":1}"#);
+        assert_eq!(outcome.private_states[&BLUE]["hand"].get(), "[3]");
+        let expected_random = SeededRandom::new(42).next_in_range(0, 100);
+        match &*outcome.events {
+            [action, random] => {
+                assert!(matches!(
+                    &action.kind,
+                    EventKind::Action { from: RED, value, .. } if value.get() == r#""guess""#
+                ));
+                assert!(matches!(
+                    random.kind,
+                    EventKind::Random { value, .. } if value == expected_random
+                ));
+            }
+            events => panic!("unexpected events {events:?}"),
+        }
+    }
+
+    /// Players take turns guessing a random digit, whoever guesses it first wins.
+    const GUESSING_GAME: &str = r#"(module
+  (import "env" "rulebook_trigger_io" (func $trigger_io (param i32) (result i32)))
+  (memory (export "memory") 1)
+  (data (i32.const 1024) "{\"type\":\"sessionStart\"}")
+  (data (i32.const 1100) "{\"type\":\"random\",\"data\":{\"start\":1,\"end\":9}}")
+  (data (i32.const 1200) "{\"type\":\"action\",\"data\":{\"from\":\"red\",\"param\":null}}")
+  (data (i32.const 1300) "{\"type\":\"action\",\"data\":{\"from\":\"blue\",\"param\":null}}")
+  (data (i32.const 1400) "{\"type\":\"updateState\",\"data\":{\"winner\":\"red\"}}")
+  (data (i32.const 1500) "{\"type\":\"updateState\",\"data\":{\"winner\":\"blue\"}}")
+  (data (i32.const 1600) "{\"type\":\"sessionEnd\",\"data\":{\"reason\":{\"type\":\"completed\"}}}")
+  (func $io (param $output_ptr i32) (param $output_len i32)
+    (i32.store (i32.const 0) (i32.const 4096))
+    (i32.store (i32.const 4) (i32.const 1024))
+    (i32.store (i32.const 8) (local.get $output_ptr))
+    (i32.store (i32.const 12) (local.get $output_len))
+    (drop (call $trigger_io (i32.const 0))))
+  (func (export "rulebook_abi_version") (result i32) (i32.const 2))
+  (func (export "rulebook_start_session") (param $input_cap i32) (param $print_state i32)
+    (local $secret i32)
+    (local $blue_turn i32)
+    (call $io (i32.const 1024) (i32.const 23))
+    (call $io (i32.const 1100) (i32.const 44))
+    ;; a single digit, so its JSON is a single byte
+    (local.set $secret (i32.load8_u (i32.const 4096)))
+    (block $guessed
+      (loop $turn
+        (if (local.get $blue_turn)
+          (then (call $io (i32.const 1300) (i32.const 53)))
+          (else (call $io (i32.const 1200) (i32.const 52))))
+        (br_if $guessed (i32.eq (i32.load8_u (i32.const 4096)) (local.get $secret)))
+        (local.set $blue_turn (i32.eqz (local.get $blue_turn)))
+        (br $turn)))
+    (if (local.get $blue_turn)
+      (then (call $io (i32.const 1500) (i32.const 47)))
+      (else (call $io (i32.const 1400) (i32.const 46))))
+    (call $io (i32.const 1600) (i32.const 60))))"#;
+
+    #[tokio::test]
+    async fn run_local_plays_a_game_to_its_winner() {
+        const SEED: u64 = 7;
+        let runtime = Runtime::new(Config::default()).unwrap();
+        runtime
+            .add_game("guessing".into(), GUESSING_GAME.as_bytes())
+            .unwrap();
+
+        let room = RoomInfo {
+            players: vec![RED, BLUE],
+            ..Default::default()
+        };
+        let secret = SeededRandom::new(SEED).next_in_range(1, 9);
+        let mut local = LocalRoom::new(SEED);
+        // red misses on the first turn, then blue gets it right
+        local.script(RED, [secret % 9 + 1]).unwrap();
+        local.script(BLUE, [secret]).unwrap();
+        let outcome = runtime
+            .run_local("guessing", room.clone(), local)
+            .await
+            .unwrap();
+
+        assert_eq!(outcome.state.unwrap().get(), r#"{"winner":"blue"}"#);
+        assert_eq!(outcome.events.len(), 3, "{:?}", outcome.events);
+        runtime
+            .replay_events("guessing", room, outcome.events)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn run_local_fails_once_script_runs_out() {
+        let runtime = Runtime::new(Config::default()).unwrap();
+        let code = game(&[
+            SESSION_START,
+            r#"{"type":"action","data":{"from":"red","param":null}}"#,
+            SESSION_END,
+        ]);
+        runtime.add_game("game".into(), code.as_bytes()).unwrap();
+
+        let room = RoomInfo {
+            players: vec![RED],
+            ..Default::default()
+        };
+        let err = runtime
+            .run_local("game", room, LocalRoom::new(0))
+            .await
+            .unwrap_err();
+        assert!(format!("{err:#}").contains("player red ran out of scripted actions"));
+    }
+}
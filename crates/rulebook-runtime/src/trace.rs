@@ -0,0 +1,94 @@
+use std::collections::VecDeque;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::{RoomInfo, RuntimeError};
+
+/// Every IO a session performed, to resume it later with `Session::resume_from_trace`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SessionTrace {
+    pub entries: Vec<TraceEntry>,
+}
+
+/// JSON output of the game and the input replied to it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TraceEntry {
+    pub output: String,
+    pub input: String,
+}
+
+/// Everything needed to rebuild a session, see `Session::snapshot`.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct SessionSnapshot {
+    pub game_key: String,
+    pub room: RoomInfo,
+    pub trace: SessionTrace,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Recorder {
+    record: bool,
+    recorded: Vec<TraceEntry>,
+    replay: VecDeque<TraceEntry>,
+    replayed: usize,
+}
+
+impl Recorder {
+    pub fn new(record: bool) -> Self {
+        Recorder {
+            record,
+            ..Default::default()
+        }
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.record
+    }
+
+    /// Whether outputs should be passed to `replay` and `record`.
+    pub fn is_active(&self) -> bool {
+        self.record || !self.replay.is_empty()
+    }
+
+    pub fn trace(&self) -> SessionTrace {
+        SessionTrace {
+            entries: self.recorded.clone(),
+        }
+    }
+
+    pub fn set_replay(&mut self, trace: SessionTrace) {
+        self.replay = trace.entries.into();
+        self.replayed = 0;
+    }
+
+    /// Returns the recorded input if the output is still being replayed.
+    pub fn replay(&mut self, output: &str) -> Result<Option<String>> {
+        let Some(entry) = self.replay.pop_front() else {
+            return Ok(None);
+        };
+
+        if entry.output != output {
+            self.replay.clear();
+            return Err(anyhow::anyhow!(
+                "game diverged from the trace at output #{}, expected {} but got {output}",
+                self.replayed,
+                entry.output,
+            )
+            .context(RuntimeError::Protocol));
+        }
+
+        self.replayed += 1;
+        Ok(Some(entry.input))
+    }
+
+    pub fn record(&mut self, output: String, input: &str) {
+        if self.record {
+            self.recorded.push(TraceEntry {
+                output,
+                input: input.into(),
+            });
+        }
+    }
+}
@@ -0,0 +1,148 @@
+//! Extension point to check who may connect to a room, before the websocket is accepted.
+//!
+//! The server allows everyone by default, so anyone knowing a room id may take any free seat.
+//! With `--auth-tokens` it only allows clients sending one of the tokens, see `BearerTokens`.
+//! Operators who need more implement an `Authenticator` of their own, like one checking a JWT,
+//! and choose it in `authenticator` next to the built-in ones.
+
+use std::path::Path;
+
+use anyhow::{Context as _, Result};
+use axum::http::header::AUTHORIZATION;
+use axum::http::{HeaderMap, StatusCode};
+
+use rulebook_runtime::PlayerId;
+
+/// Decides whether a connect request may take the seat of `player` in the room.
+///
+/// Called on every player's `/connect`, with or without a reconnect token. `/reconnect` carries
+/// a token issued to a connect allowed already, and spectators and watchers only see
+/// what's public, so neither is checked.
+#[async_trait::async_trait]
+pub trait Authenticator: Send + Sync {
+    /// Refuses the request with the returned status, like `UNAUTHORIZED` or `FORBIDDEN`.
+    async fn authorize(
+        &self,
+        room_id: &str,
+        player: PlayerId,
+        headers: &HeaderMap,
+    ) -> Result<(), StatusCode>;
+}
+
+/// Authenticator the server is configured with, `AllowAll` unless `tokens_file` is given.
+pub fn authenticator(tokens_file: Option<&Path>) -> Result<Box<dyn Authenticator>> {
+    Ok(match tokens_file {
+        Some(path) => Box::new(BearerTokens::load(path)?),
+        None => Box::new(AllowAll),
+    })
+}
+
+/// Allows every request, the default.
+#[derive(Debug, Default)]
+pub struct AllowAll;
+
+#[async_trait::async_trait]
+impl Authenticator for AllowAll {
+    async fn authorize(
+        &self,
+        _room_id: &str,
+        _player: PlayerId,
+        _headers: &HeaderMap,
+    ) -> Result<(), StatusCode> {
+        Ok(())
+    }
+}
+
+/// Allows requests with `Authorization: Bearer <token>` of any of the tokens.
+///
+/// A token allows any seat of any room, it's a secret shared with the operator's clients
+/// rather than a player's identity. Requests without one are `UNAUTHORIZED`,
+/// those with an unknown one `FORBIDDEN`.
+#[derive(Debug)]
+pub struct BearerTokens {
+    tokens: Vec<String>,
+}
+
+impl BearerTokens {
+    pub fn new(tokens: impl IntoIterator<Item = String>) -> Self {
+        BearerTokens {
+            tokens: tokens.into_iter().collect(),
+        }
+    }
+
+    /// Reads the tokens from the file, one per line. Blank lines are skipped.
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read auth tokens from {}", path.display()))?;
+        let tokens = BearerTokens::new(
+            text.lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty())
+                .map(String::from),
+        );
+        anyhow::ensure!(
+            !tokens.tokens.is_empty(),
+            "no auth token in {}, which would refuse every player",
+            path.display()
+        );
+        Ok(tokens)
+    }
+}
+
+#[async_trait::async_trait]
+impl Authenticator for BearerTokens {
+    async fn authorize(
+        &self,
+        _room_id: &str,
+        _player: PlayerId,
+        headers: &HeaderMap,
+    ) -> Result<(), StatusCode> {
+        let token = headers
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .ok_or(StatusCode::UNAUTHORIZED)?;
+
+        let known = |known: &String| constant_time_eq(known.as_bytes(), token.as_bytes());
+        match self.tokens.iter().any(known) {
+            true => Ok(()),
+            false => Err(StatusCode::FORBIDDEN),
+        }
+    }
+}
+
+/// Compares every byte, so the time taken doesn't tell how much of a token matched.
+fn constant_time_eq(lhs: &[u8], rhs: &[u8]) -> bool {
+    lhs.len() == rhs.len() && lhs.iter().zip(rhs).fold(0, |acc, (l, r)| acc | (l ^ r)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use rulebook_runtime::Color;
+
+    use super::*;
+
+    const RED: PlayerId = PlayerId::new(Color::Red as u8);
+
+    async fn authorize(auth: &BearerTokens, authorization: Option<&str>) -> Result<(), StatusCode> {
+        let mut headers = HeaderMap::new();
+        if let Some(value) = authorization {
+            headers.insert(AUTHORIZATION, value.parse().unwrap());
+        }
+        auth.authorize("room", RED, &headers).await
+    }
+
+    #[tokio::test]
+    async fn bearer_tokens_allow_only_known_tokens() {
+        let auth = BearerTokens::new(["first".into(), "second".into()]);
+
+        assert_eq!(authorize(&auth, Some("Bearer first")).await, Ok(()));
+        assert_eq!(authorize(&auth, Some("Bearer second")).await, Ok(()));
+        let forbidden = Err(StatusCode::FORBIDDEN);
+        assert_eq!(authorize(&auth, Some("Bearer third")).await, forbidden);
+        assert_eq!(authorize(&auth, Some("Bearer firs")).await, forbidden);
+        let unauthorized = Err(StatusCode::UNAUTHORIZED);
+        assert_eq!(authorize(&auth, Some("first")).await, unauthorized);
+        assert_eq!(authorize(&auth, None).await, unauthorized);
+    }
+}
@@ -0,0 +1,130 @@
+use std::sync::Arc;
+
+use axum::extract::ws::{Message, WebSocket};
+use serde::Serialize;
+use serde_json::value::RawValue;
+use tokio::sync::broadcast;
+
+use rulebook_runtime::PlayerId;
+
+/// Number of events buffered for slow subscribers before they start to miss events.
+pub(crate) const EVENT_BUFFER: usize = 256;
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub(crate) enum ServerEvent {
+    RoomCreated {
+        room: String,
+        game: Arc<str>,
+    },
+    PlayerJoined {
+        room: String,
+        game: Arc<str>,
+        player: PlayerId,
+    },
+    /// Removed without being started, see `Server::room_idle_timeout`.
+    RoomExpired {
+        room: String,
+        game: Arc<str>,
+    },
+    SessionStarted {
+        room: String,
+        game: Arc<str>,
+        players: Vec<PlayerId>,
+    },
+    /// Removed from the running session by the host.
+    PlayerForfeited {
+        room: String,
+        game: Arc<str>,
+        player: PlayerId,
+    },
+    SessionEnded {
+        room: String,
+        game: Arc<str>,
+        error: Option<String>,
+        /// What the game reported, see `rulebook::report_result`.
+        result: Option<Box<RawValue>>,
+        /// Every player disconnected, see `ReconnectPolicy::terminate_abandoned`.
+        abandoned: bool,
+    },
+}
+
+impl ServerEvent {
+    fn game(&self) -> &str {
+        match self {
+            ServerEvent::RoomCreated { game, .. }
+            | ServerEvent::PlayerJoined { game, .. }
+            | ServerEvent::RoomExpired { game, .. }
+            | ServerEvent::SessionStarted { game, .. }
+            | ServerEvent::PlayerForfeited { game, .. }
+            | ServerEvent::SessionEnded { game, .. } => game,
+        }
+    }
+}
+
+/// Forwards server events to the websocket until either side closes.
+pub(crate) async fn stream_events(
+    mut sock: WebSocket,
+    mut events: broadcast::Receiver<ServerEvent>,
+    game: Option<String>,
+) {
+    loop {
+        let event = match events.recv().await {
+            Ok(event) => event,
+            Err(broadcast::error::RecvError::Lagged(count)) => {
+                tracing::warn!("event subscriber lagged, {count} events skipped");
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => break,
+        };
+
+        if game.as_deref().is_some_and(|game| game != event.game()) {
+            continue;
+        }
+
+        let msg = match serde_json::to_string(&event) {
+            Ok(msg) => msg,
+            Err(err) => {
+                tracing::error!("event serialize err: {err:?}");
+                continue;
+            }
+        };
+        if sock.send(Message::Text(msg)).await.is_err() {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::StreamExt;
+    use rulebook_runtime::test_games::{game, SESSION_END, SESSION_START};
+    use tokio_tungstenite::connect_async;
+
+    use crate::test_util::{request, serve, server};
+
+    #[tokio::test]
+    async fn room_created_event_reaches_subscribers_of_the_game() {
+        let code = game(&[SESSION_START, SESSION_END]);
+        let addr = serve(server(&[("game", &code), ("other", &code)]));
+        let (mut events, _) = connect_async(format!("ws://{addr}/events")).await.unwrap();
+        let (mut other_events, _) = connect_async(format!("ws://{addr}/events?game=other"))
+            .await
+            .unwrap();
+
+        let (status, body) = request(addr, "POST", "/room", r#"{"game":"game"}"#).await;
+        assert!(status.is_success(), "{status}: {body}");
+        let room: serde_json::Value = serde_json::from_str(&body).unwrap();
+
+        let event = events.next().await.unwrap().unwrap();
+        let event: serde_json::Value = serde_json::from_str(event.to_text().unwrap()).unwrap();
+        assert_eq!(
+            event,
+            serde_json::json!({ "type": "roomCreated", "room": room["room"], "game": "game" })
+        );
+        let other = tokio::time::timeout(Duration::from_millis(50), other_events.next()).await;
+        assert!(other.is_err(), "event of another game: {other:?}");
+    }
+}
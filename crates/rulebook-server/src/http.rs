@@ -1,53 +1,128 @@
 use std::collections::hash_map::Entry;
+use std::collections::{BTreeSet, HashMap};
 use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
+use std::time::Duration;
 
-use axum::extract::ws::WebSocketUpgrade;
-use axum::extract::{Json, Path, Query, State};
-use axum::http::StatusCode;
-use axum::response::IntoResponse;
-use axum::routing::{get, post};
+use axum::extract::ws::{close_code, CloseFrame, Message, WebSocket, WebSocketUpgrade};
+use axum::extract::{ConnectInfo, Json, Path, Query, State};
+use axum::http::{header, HeaderMap, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{delete, get, post};
 use axum::Router;
+use axum_server::tls_rustls::RustlsConfig;
 use serde::{Deserialize, Serialize};
-use tokio::sync::{oneshot, Mutex};
+use serde_json::value::RawValue;
+use tokio::sync::{oneshot, watch, Mutex};
+use tokio::time::Instant;
+use tracing::Instrument;
 
-use rulebook_runtime::{PlayerId, RoomInfo};
+use rulebook_runtime::{
+    channel::{self, Channel},
+    compress::{COMPRESSION_HEADER, DEFLATE},
+    ws_protocol, GameInfo, PlayerId, RoomInfo, RuntimeError, SeededRandom, SessionId,
+    RECONNECT_TOKEN_HEADER,
+};
 
-use crate::{new_id, Connection, Lobby, Room, Server};
+use crate::events::{self, ServerEvent};
+use crate::lobby::{self, Handover, LobbyStatus};
+use crate::queue;
+use crate::websocket::{ConnStats, WebSocketStream};
+use crate::{
+    new_id, Connection, LatestState, Lobby, Reconnects, Room, Server, ROOM_DELETED_NOTICE,
+    ROOM_EXPIRED_NOTICE, SHUTDOWN_NOTICE,
+};
 
-pub(crate) async fn run_server(server: Arc<Server>, addr: SocketAddr) {
-    let app = Router::new()
+/// Serves https rather than http if `tls` is given.
+pub(crate) async fn run_server(server: Arc<Server>, addr: SocketAddr, tls: Option<RustlsConfig>) {
+    tokio::spawn(sweep_idle_rooms(server.clone()));
+
+    let app = router(&server);
+
+    let shutdown = {
+        let server = server.clone();
+        async move {
+            shutdown_signal().await;
+            tracing::info!("shutting down");
+            server.shutdown.send_replace(true);
+        }
+    };
+    let app = app.into_make_service_with_connect_info::<SocketAddr>();
+    match tls {
+        None => axum::Server::bind(&addr)
+            .serve(app)
+            .with_graceful_shutdown(shutdown)
+            .await
+            .unwrap(),
+        Some(tls) => {
+            let handle = axum_server::Handle::new();
+            tokio::spawn({
+                let handle = handle.clone();
+                let timeout = server.shutdown_timeout;
+                async move {
+                    shutdown.await;
+                    handle.graceful_shutdown(Some(timeout));
+                }
+            });
+            axum_server::bind_rustls(addr, tls)
+                .handle(handle)
+                .serve(app)
+                .await
+                .unwrap();
+        }
+    }
+
+    drain_rooms(&server).await;
+}
+
+/// Every endpoint of the server.
+pub(crate) fn router(server: &Arc<Server>) -> Router {
+    Router::new()
         .route(
             "/room",
-            post(
-                |State(server): State<Arc<Server>>, Json(req): Json<CreateRoomRequest>| async move {
-                    println!("/room, req: {req:?}");
-                    let room_id = new_id();
-                    let session = match server.runtime.new_session(&req.game).await {
-                        Ok(s) => s,
-                        Err(err) => {
-                            return (
-                                StatusCode::INTERNAL_SERVER_ERROR,
-                                format!("failed to create session: {err}"),
-                            )
-                                .into_response()
-                        }
-                    };
+            get(|State(server): State<Arc<Server>>| async move {
+                let rooms: Vec<_> = server
+                    .rooms
+                    .read()
+                    .unwrap()
+                    .iter()
+                    .map(|(room_id, room)| (room_id.clone(), room.clone()))
+                    .collect();
 
-                    match server.rooms.write().unwrap().entry(room_id.clone()) {
-                        Entry::Occupied(_) => {
-                            return (StatusCode::INTERNAL_SERVER_ERROR, "UnluckyError")
-                                .into_response()
-                        }
-                        Entry::Vacant(entry) => {
-                            entry.insert(Arc::new(Mutex::new(Lobby {
-                                session: Some(session),
-                                connections: Vec::new(),
-                            })));
+                // started rooms are listed until their session ends
+                let mut res = Vec::with_capacity(rooms.len());
+                for (room_id, room) in rooms {
+                    let room = room.lock().await;
+                    res.push(RoomResponse {
+                        room_id,
+                        game: room.game.clone(),
+                        connected_players: room.players.iter().map(|(p, _)| *p).collect(),
+                        started: room.session.is_none(),
+                        min_players: room.info.min_players,
+                        max_players: room.info.max_players,
+                        roles: room.info.roles.clone(),
+                        capacity: room.capacity(server.max_seats),
+                    });
+                }
+                Json(res)
+            })
+            .post(
+                |State(server): State<Arc<Server>>,
+                 ConnectInfo(client): ConnectInfo<SocketAddr>,
+                 Json(req): Json<CreateRoomRequest>| async move {
+                    tracing::debug!(?req, %client, "/room");
+                    if let Some(limiter) = &server.room_limiter {
+                        if let Err(err) = limiter.check(client.ip()) {
+                            return err.into_response();
                         }
                     }
-
-                    Json(CreateRoomResponse { room: room_id }).into_response()
+                    match create_room(&server, &req.game, req.options, req.init_state, req.seats)
+                        .await
+                    {
+                        Ok(room_id) => Json(CreateRoomResponse { room: room_id }).into_response(),
+                        Err(err) => err.into_response(),
+                    }
                 },
             ),
         )
@@ -55,90 +130,930 @@ pub(crate) async fn run_server(server: Arc<Server>, addr: SocketAddr) {
             "/room/:room_id/connect",
             get(
                 |State(server): State<Arc<Server>>,
+                 ConnectInfo(client): ConnectInfo<SocketAddr>,
                  Path(room_id): Path<String>,
                  Query(query): Query<ConnectQuery>,
+                 headers: HeaderMap,
+                 ws_conn: WebSocketUpgrade| async move {
+                    if let Some(limiter) = &server.connect_limiter {
+                        if let Err(err) = limiter.check(client.ip()) {
+                            return err.into_response();
+                        }
+                    }
+                    if let Err(reason) = check_protocol(&headers) {
+                        return refuse_protocol(ws_conn, reason);
+                    }
+                    if let (Some(color), false) = (query.color, query.spectator) {
+                        let auth = server.authenticator.authorize(&room_id, color, &headers);
+                        if let Err(status) = auth.await {
+                            tracing::info!(%room_id, %color, %status, "connect refused");
+                            return (status, "not authorized to connect").into_response();
+                        }
+                    }
+                    let ws_conn = ws_conn.protocols([ws_protocol()]);
+                    let compress = negotiate_compression(&server, &headers);
+                    connect_room(server, room_id, query, ws_conn, compress).await
+                },
+            ),
+        )
+        .route(
+            "/room/:room_id/reconnect",
+            get(
+                |State(server): State<Arc<Server>>,
+                 Path(room_id): Path<String>,
+                 Query(query): Query<ReconnectQuery>,
+                 headers: HeaderMap,
+                 ws_conn: WebSocketUpgrade| async move {
+                    if let Err(reason) = check_protocol(&headers) {
+                        return refuse_protocol(ws_conn, reason);
+                    }
+                    let ws_conn = ws_conn.protocols([ws_protocol()]);
+                    let compress = negotiate_compression(&server, &headers);
+                    reconnect_room(server, room_id, query, ws_conn, compress).await
+                },
+            ),
+        )
+        .route(
+            "/room/:room_id/watch",
+            get(
+                |State(server): State<Arc<Server>>,
+                 Path(room_id): Path<String>,
                  ws_conn: WebSocketUpgrade| async move {
-                    println!("/room/{room_id}/connect, q: {query:?}");
+                    watch_room(server, room_id, ws_conn).await
+                },
+            ),
+        )
+        .route(
+            "/room/:room_id/start",
+            post(
+                |State(server): State<Arc<Server>>, Path(room_id): Path<String>| {
+                    start_room(server, room_id)
+                },
+            ),
+        )
+        .route(
+            "/room/:room_id",
+            delete(
+                |State(server): State<Arc<Server>>, Path(room_id): Path<String>| async move {
                     let Some(room) = server.rooms.read().unwrap().get(&room_id).cloned() else {
-                        return (StatusCode::NOT_FOUND, "room not found").into_response();
+                        return StatusCode::NOT_FOUND;
                     };
                     let mut room = room.lock().await;
 
-                    if room.session.is_none() {
-                        return (StatusCode::NOT_FOUND, "room not found").into_response();
-                    }
-                    if room.connections.len() == PlayerId::candidates().len() {
-                        println!("room full");
-                        return (StatusCode::CONFLICT, "room is full").into_response();
-                    }
-                    let colors: Vec<_> = room.connections.iter().map(|c| c.player_id).collect();
-                    if colors.contains(&query.color) {
-                        println!("color dupe, current: {colors:?}");
-                        return (StatusCode::CONFLICT, "requested color already taken")
-                            .into_response();
+                    room.cancel.cancel();
+                    // started rooms are removed when their session ends
+                    if room.session.is_some() {
+                        server.rooms.write().unwrap().remove(&room_id);
+                        room.close(Some(ROOM_DELETED_NOTICE));
                     }
 
-                    let (sender, receiver) = oneshot::channel();
-                    room.connections.push(Connection {
-                        player_id: query.color,
-                        ws: receiver,
-                    });
+                    StatusCode::NO_CONTENT
+                },
+            ),
+        )
+        .route(
+            "/room/:room_id/players",
+            get(
+                |State(server): State<Arc<Server>>, Path(room_id): Path<String>| async move {
+                    let Some(room) = server.rooms.read().unwrap().get(&room_id).cloned() else {
+                        return (StatusCode::NOT_FOUND, "room not found").into_response();
+                    };
+                    let room = room.lock().await;
 
-                    ws_conn.on_upgrade(|sock| async {
-                        if let Err(err) = sender.send(sock) {
-                            println!("sock send failed: {err:?}")
-                        }
+                    let players: Vec<_> = room
+                        .players
+                        .iter()
+                        .map(|(player, stats)| PlayerStatusResponse {
+                            player: *player,
+                            bytes_sent: stats.bytes_sent.load(Ordering::Relaxed),
+                            bytes_received: stats.bytes_received.load(Ordering::Relaxed),
+                            payload_bytes_sent: stats.payload_bytes_sent.load(Ordering::Relaxed),
+                            payload_bytes_received: stats
+                                .payload_bytes_received
+                                .load(Ordering::Relaxed),
+                            compression_ratio: stats.compression_ratio(),
+                            frames_sent: stats.frames_sent.load(Ordering::Relaxed),
+                            frames_received: stats.frames_received.load(Ordering::Relaxed),
+                        })
+                        .collect();
+                    Json(players).into_response()
+                },
+            ),
+        )
+        .route(
+            "/room/:room_id/debug",
+            get(
+                |State(server): State<Arc<Server>>, Path(room_id): Path<String>| async move {
+                    let Some(room) = server.rooms.read().unwrap().get(&room_id).cloned() else {
+                        return (StatusCode::NOT_FOUND, "room not found").into_response();
+                    };
+                    let room = room.lock().await;
+
+                    let status = server
+                        .runtime
+                        .sessions()
+                        .into_iter()
+                        .find(|status| status.id == room.session_id);
+                    let debug = room.debug.read().unwrap().clone();
+                    Json(RoomDebugResponse {
+                        session: room.session_id,
+                        started: room.session.is_none(),
+                        running: status.as_ref().is_some_and(|status| status.running),
+                        io_count: status.map_or(0, |status| status.io_count),
+                        visibility: debug.visibility,
+                        scope: debug.scope,
+                        waiting_for: debug.waiting_for,
                     })
+                    .into_response()
                 },
             ),
         )
         .route(
-            "/room/:room_id/start",
+            "/room/:room_id/players/:player/forfeit",
             post(
-                |State(server): State<Arc<Server>>, Path(room_id): Path<String>| async move {
-                    let Some(room) = server.rooms.write().unwrap().remove(&room_id) else {
-                    return (StatusCode::NOT_FOUND, "room not found").into_response();
-                };
-                    let mut room = room.lock().await;
+                |State(server): State<Arc<Server>>,
+                 Path((room_id, player)): Path<(String, PlayerId)>| {
+                    forfeit_player(server, room_id, player)
+                },
+            ),
+        )
+        .route(
+            "/games",
+            get(|State(server): State<Arc<Server>>| async move {
+                let keys = server.runtime.game_keys();
+                let keys: Vec<&str> = keys.iter().map(|key| &**key).collect();
+                Json(keys).into_response()
+            }),
+        )
+        .route(
+            "/games/:game/schema",
+            get(
+                |State(server): State<Arc<Server>>, Path(game): Path<String>| async move {
+                    match server.runtime.game_schema(&game).await {
+                        Ok(schema) => Json(schema).into_response(),
+                        Err(err) => (StatusCode::NOT_FOUND, format!("{err:#}")).into_response(),
+                    }
+                },
+            ),
+        )
+        .route(
+            "/queue",
+            post(
+                |State(server): State<Arc<Server>>, Query(query): Query<QueueQuery>| async move {
+                    tracing::debug!(?query, "/queue");
+                    match queue::enqueue(&server, &query.game).await {
+                        Ok(seat) => Json(seat).into_response(),
+                        Err(err) => err.into_response(),
+                    }
+                },
+            ),
+        )
+        .route(
+            "/events",
+            get(
+                |State(server): State<Arc<Server>>,
+                 Query(query): Query<EventsQuery>,
+                 ws_conn: WebSocketUpgrade| async move {
+                    let events = server.events.subscribe();
+                    ws_conn.on_upgrade(|sock| events::stream_events(sock, events, query.game))
+                },
+            ),
+        )
+        .route(
+            "/sessions",
+            get(|State(server): State<Arc<Server>>| async move {
+                let sessions: Vec<_> = server
+                    .runtime
+                    .sessions()
+                    .into_iter()
+                    .map(|status| SessionStatusResponse {
+                        id: status.id,
+                        game: status.game_key,
+                        age_ms: status.age.as_millis() as u64,
+                        running: status.running,
+                        io_count: status.io_count,
+                    })
+                    .collect();
+                Json(sessions)
+            }),
+        )
+        .route(
+            "/metrics",
+            get(|State(server): State<Arc<Server>>| async move {
+                let metrics = server.runtime.metrics_snapshot();
+                Json(MetricsResponse {
+                    active_sessions: server.runtime.active_sessions(),
+                    sessions_created: metrics.sessions_created,
+                    sessions_completed: metrics.sessions_completed,
+                    sessions_failed: metrics.sessions_failed,
+                    avg_duration_ms: metrics.avg_duration().map(|avg| avg.as_millis() as u64),
+                    duration_buckets: metrics
+                        .duration_buckets
+                        .iter()
+                        .map(|&(bound, count)| DurationBucket {
+                            le_ms: bound.map(|bound| bound.as_millis() as u64),
+                            count,
+                        })
+                        .collect(),
+                    failures_by_category: metrics
+                        .failures_by_category
+                        .into_iter()
+                        .map(|(category, count)| CategoryCount { category, count })
+                        .collect(),
+                })
+            }),
+        )
+        .route(
+            "/sessions/:id",
+            delete(
+                |State(server): State<Arc<Server>>, Path(id): Path<SessionId>| async move {
+                    if server.runtime.cancel_session(id) {
+                        StatusCode::NO_CONTENT
+                    } else {
+                        StatusCode::NOT_FOUND
+                    }
+                },
+            ),
+        )
+        .with_state(server.clone())
+}
+
+/// Resolves on ctrl-c, or SIGTERM on unix.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("failed to listen for ctrl-c");
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        signal(SignalKind::terminate())
+            .expect("failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        () = ctrl_c => {}
+        () = terminate => {}
+    }
+}
+
+/// Minimum interval between sweeps of idle rooms.
+const SWEEP_INTERVAL_MIN: Duration = Duration::from_secs(1);
+
+/// Removes rooms not started within `room_idle_timeout`, noticing their connected players.
+async fn sweep_idle_rooms(server: Arc<Server>) {
+    let mut interval =
+        tokio::time::interval((server.room_idle_timeout / 4).max(SWEEP_INTERVAL_MIN));
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+
+        let rooms: Vec<_> = server.rooms.read().unwrap().clone().into_iter().collect();
+        for (room_id, room) in rooms {
+            let mut room = room.lock().await;
+            if room.session.is_none() || room.created_at.elapsed() < server.room_idle_timeout {
+                continue;
+            }
+
+            tracing::info!(
+                %room_id,
+                game = %room.game,
+                connections = room.connections.len(),
+                "removing room not started in time"
+            );
+            room.cancel.cancel();
+            room.session = None;
+            server.rooms.write().unwrap().remove(&room_id);
+            room.close(Some(ROOM_EXPIRED_NOTICE));
+            server.emit(ServerEvent::RoomExpired {
+                room: room_id,
+                game: room.game.clone(),
+            });
+        }
+    }
+}
+
+/// Cancels rooms not started yet, and gives running sessions `shutdown_timeout` to end
+/// before cancelling them too.
+async fn drain_rooms(server: &Server) {
+    let rooms: Vec<_> = server.rooms.read().unwrap().clone().into_iter().collect();
+    for (room_id, room) in rooms {
+        let mut room = room.lock().await;
+        if room.session.is_some() {
+            room.cancel.cancel();
+            server.rooms.write().unwrap().remove(&room_id);
+            room.close(Some(SHUTDOWN_NOTICE));
+        }
+    }
+
+    if wait_rooms_ended(server, server.shutdown_timeout).await {
+        return;
+    }
+
+    let rooms: Vec<_> = server.rooms.read().unwrap().values().cloned().collect();
+    tracing::warn!("cancelling {} sessions still running", rooms.len());
+    for room in rooms {
+        room.lock().await.cancel.cancel();
+    }
+    // busy games trap at once and rooms stop waiting on players, but IO in flight
+    // like a send to a slow player still runs to its end
+    if !wait_rooms_ended(server, CANCEL_TIMEOUT).await {
+        let left = server.rooms.read().unwrap().len();
+        tracing::warn!("{left} sessions didn't end in time after cancelled");
+    }
+}
+
+/// Max time to wait for cancelled sessions to end on shutdown.
+const CANCEL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Returns `false` if some rooms are still running after the timeout.
+async fn wait_rooms_ended(server: &Server, timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    while !server.rooms.read().unwrap().is_empty() {
+        if Instant::now() >= deadline {
+            return false;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    true
+}
+
+/// Creates a room of the game waiting for players, returns its id.
+///
+/// If `seats` isn't empty, only those players may join and the room starts once all of them do.
+pub(crate) async fn create_room(
+    server: &Server,
+    game: &str,
+    options: Option<Box<RawValue>>,
+    init_state: Option<Box<RawValue>>,
+    seats: Vec<PlayerId>,
+) -> Result<String, (StatusCode, String)> {
+    if !server.runtime.has_game(game) {
+        return Err((StatusCode::NOT_FOUND, format!("game {game} not found")));
+    }
+    let room_id = new_id();
+    let session = match server.runtime.new_session(game).await {
+        Ok(s) => s,
+        Err(err) => {
+            let status = match err.downcast_ref::<RuntimeError>() {
+                Some(RuntimeError::TooManySessions) => StatusCode::SERVICE_UNAVAILABLE,
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            return Err((status, format!("failed to create session: {err}")));
+        }
+    };
+    tracing::debug!(active_sessions = server.runtime.active_sessions());
+    let game: Arc<str> = session.game_key().into();
+    let info = match server.runtime.game_info(&game).await {
+        Ok(info) => info,
+        Err(err) => {
+            return Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("failed to get game info: {err}"),
+            ));
+        }
+    };
+    if let Some(problem) = seats_problem(&info, &seats, server.max_seats) {
+        return Err((StatusCode::BAD_REQUEST, problem));
+    }
+
+    let mut rooms = server.rooms.write().unwrap();
+    // checked along with the insert, so concurrent requests can't exceed it
+    if server
+        .max_rooms
+        .is_some_and(|max_rooms| rooms.len() >= max_rooms)
+    {
+        let msg = format!("server already has {} rooms", rooms.len());
+        return Err((StatusCode::SERVICE_UNAVAILABLE, msg));
+    }
+    match rooms.entry(room_id.clone()) {
+        Entry::Occupied(_) => {
+            return Err((StatusCode::INTERNAL_SERVER_ERROR, "UnluckyError".into()));
+        }
+        Entry::Vacant(entry) => {
+            entry.insert(Arc::new(Mutex::new(Lobby {
+                game: game.clone(),
+                created_at: Instant::now(),
+                info,
+                options: options.unwrap_or_else(|| RoomInfo::default().options),
+                init_state,
+                cancel: session.cancel_handle(),
+                session_id: session.id(),
+                session: Some(session),
+                connections: Vec::new(),
+                ready: BTreeSet::new(),
+                lobby: watch::channel(LobbyStatus::Open(Default::default())).0,
+                next_conn: 0,
+                players: Vec::new(),
+                seats,
+                reconnect_tokens: HashMap::new(),
+                reconnects: Arc::new(Reconnects::new(server.reconnect)),
+                forfeits: watch::channel(BTreeSet::new()).0,
+                state: None,
+                debug: Default::default(),
+            })));
+        }
+    }
+    drop(rooms);
+    server.emit(ServerEvent::RoomCreated {
+        room: room_id.clone(),
+        game,
+    });
+
+    Ok(room_id)
+}
+
+/// Why the game doesn't allow a player of the color, if it doesn't.
+fn color_problem(info: &GameInfo, color: PlayerId, max_seats: usize) -> Option<String> {
+    match &info.roster {
+        Some(roster) if !roster.contains(&color) => Some(format!(
+            "player {color} not allowed by the game, valid players: {roster:?}"
+        )),
+        None if usize::from(color.index()) >= max_seats => {
+            Some(format!("player {color} exceeds the max seats {max_seats}"))
+        }
+        _ => None,
+    }
+}
+
+/// Why the seats reserved on creation don't fit the game, if they don't.
+fn seats_problem(info: &GameInfo, seats: &[PlayerId], max_seats: usize) -> Option<String> {
+    for (idx, &seat) in seats.iter().enumerate() {
+        if seats[..idx].contains(&seat) {
+            return Some(format!("seat {seat} reserved more than once"));
+        }
+        if let Some(problem) = color_problem(info, seat, max_seats) {
+            return Some(problem);
+        }
+    }
+
+    // open rooms are checked on start instead
+    let count = seats.len();
+    match (info.min_players, info.max_players, &info.roles) {
+        _ if count == 0 => None,
+        (Some(min), _, _) if count < min => Some(format!(
+            "game requires at least {min} players, {count} seats reserved"
+        )),
+        (_, Some(max), _) if count > max => Some(format!(
+            "game allows at most {max} players, {count} seats reserved"
+        )),
+        (_, _, Some(roles)) if count != roles.len() => Some(format!(
+            "game requires a seat for each of {roles:?}, {count} seats reserved"
+        )),
+        _ => None,
+    }
+}
+
+/// Checks the client requested our subprotocol, returns why not if it didn't.
+fn check_protocol(headers: &HeaderMap) -> Result<(), String> {
+    let protocol = ws_protocol();
+    let requested: Vec<_> = headers
+        .get_all(header::SEC_WEBSOCKET_PROTOCOL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .collect();
+    if requested.contains(&&*protocol) {
+        return Ok(());
+    }
+
+    tracing::debug!(?requested, "incompatible client protocol");
+    Err(match requested.is_empty() {
+        true => format!("protocol required: {protocol}"),
+        false => format!("incompatible protocol {requested:?}, required: {protocol}"),
+    })
+}
+
+/// Whether to deflate frames of the connection, if the client asks for it and we allow it.
+fn negotiate_compression(server: &Server, headers: &HeaderMap) -> bool {
+    server.compress
+        && headers
+            .get(COMPRESSION_HEADER)
+            .is_some_and(|value| value == DEFLATE)
+}
+
+/// Tells the client frames are deflated from now on.
+fn agree_compression(res: &mut Response) {
+    res.headers_mut()
+        .insert(COMPRESSION_HEADER, HeaderValue::from_static(DEFLATE));
+}
+
+/// Closes the websocket right after the upgrade, telling the client it speaks another protocol.
+fn refuse_protocol(ws_conn: WebSocketUpgrade, reason: String) -> Response {
+    ws_conn.on_upgrade(|mut sock| async move {
+        let frame = CloseFrame {
+            code: close_code::PROTOCOL,
+            // close reason is limited to 123 bytes
+            reason: reason.chars().take(100).collect::<String>().into(),
+        };
+        if let Err(err) = sock.send(Message::Close(Some(frame))).await {
+            tracing::debug!("close frame send failed: {err:?}");
+        }
+    })
+}
 
-                    let Some(mut session) = room.session.take() else {
+/// Joins the room as a player or a spectator, or takes the seat again with the reconnect token.
+async fn connect_room(
+    server: Arc<Server>,
+    room_id: String,
+    query: ConnectQuery,
+    ws_conn: WebSocketUpgrade,
+    compress: bool,
+) -> Response {
+    tracing::debug!(%room_id, ?query, "/room/:room_id/connect");
+    let Some(lobby) = server.rooms.read().unwrap().get(&room_id).cloned() else {
+        return (StatusCode::NOT_FOUND, "room not found").into_response();
+    };
+    let mut room = lobby.lock().await;
+
+    if room.session.is_none() {
+        return (StatusCode::NOT_FOUND, "room not found").into_response();
+    }
+    let (handover, chan) = oneshot::channel();
+    let conn_id = room.next_conn;
+    room.next_conn += 1;
+    let mut reconnect_token = None;
+    let (player, stats) = if query.spectator {
+        let stats = Arc::new(ConnStats::default());
+        room.connections.push(Connection {
+            player_id: None,
+            id: conn_id,
+            chan,
+            stats: stats.clone(),
+            connected: true,
+        });
+        (None, stats)
+    } else {
+        let Some(color) = query.color else {
+            return (StatusCode::BAD_REQUEST, "color is required for players").into_response();
+        };
+
+        let stats = match query.token {
+            Some(token) => {
+                if room.reconnect_tokens.get(&color) != Some(&token) {
+                    return (StatusCode::FORBIDDEN, "invalid reconnect token").into_response();
+                }
+                let Some(conn) = room
+                    .connections
+                    .iter_mut()
+                    .find(|conn| conn.player_id == Some(color))
+                else {
                     return (StatusCode::NOT_FOUND, "room not found").into_response();
                 };
+                // the previous lobby task sees it's replaced and lets its connection go
+                conn.id = conn_id;
+                conn.chan = chan;
+                conn.connected = true;
+                reconnect_token = Some(token);
+                conn.stats.clone()
+            }
+            None => match join_player(&server, &mut room, &room_id, color, conn_id, chan) {
+                Ok(stats) => {
+                    reconnect_token = room.reconnect_tokens.get(&color).cloned();
+                    stats
+                }
+                Err(err) => return err.into_response(),
+            },
+        };
+        (Some(color), stats)
+    };
+    room.publish();
+    let status = room.lobby.subscribe();
+    drop(room);
 
-                    let players = room.connections.iter().map(|conn| conn.player_id).collect();
-                    let conns = std::mem::take(&mut room.connections);
-
-                    tokio::spawn(async move {
-                        let room = match Room::new(conns).await {
-                            Ok(r) => r,
-                            Err(err) => {
-                                println!("room init err: {err:?}");
-                                return;
-                            }
-                        };
-                        let res = session
-                            .start(16384, false, RoomInfo { players }, room)
-                            .await;
-                        if let Err(err) = res {
-                            println!("session run err: {err:?}");
-                        }
-                    });
+    // oversized messages are refused by the websocket before buffered whole
+    let ws_conn = ws_conn.max_message_size(channel::DEFAULT_MAX_FRAME_BYTES);
+    let keepalive = server.keepalive;
+    let mut res = ws_conn.on_upgrade(move |sock| async move {
+        let max_frame_bytes = channel::DEFAULT_MAX_FRAME_BYTES;
+        let stream = WebSocketStream::new(sock, stats, compress, max_frame_bytes);
+        let mut chan = Channel::with_limit(stream, max_frame_bytes);
+        if let Some(interval) = keepalive {
+            chan.set_keepalive(interval, interval);
+        }
+        lobby::drive(lobby, conn_id, player, chan, status, handover).await;
+    });
+    if let Some(token) = reconnect_token {
+        let token = HeaderValue::from_str(&token).expect("token should be base64");
+        res.headers_mut().insert(RECONNECT_TOKEN_HEADER, token);
+    }
+    if compress {
+        agree_compression(&mut res);
+    }
+    res
+}
 
-                    Json(StartRoomResponse { ok: true }).into_response()
-                },
-            ),
-        )
-        .with_state(server);
+/// Takes a seat of the room for the first time.
+fn join_player(
+    server: &Arc<Server>,
+    room: &mut Lobby,
+    room_id: &str,
+    color: PlayerId,
+    conn_id: u64,
+    chan: Handover,
+) -> Result<Arc<ConnStats>, (StatusCode, String)> {
+    if let Some(problem) = color_problem(&room.info, color, server.max_seats) {
+        return Err((StatusCode::BAD_REQUEST, problem));
+    }
+    let capacity = room.capacity(server.max_seats);
+    if !room.seats.is_empty() && !room.seats.contains(&color) {
+        let msg = format!(
+            "player {color} has no reserved seat, seats: {:?}",
+            room.seats
+        );
+        return Err((StatusCode::CONFLICT, msg));
+    }
+    if room.players.len() >= capacity {
+        tracing::debug!("room full");
+        return Err((StatusCode::CONFLICT, "room is full".into()));
+    }
+    let colors: Vec<_> = room.players.iter().map(|(p, _)| *p).collect();
+    if colors.contains(&color) {
+        tracing::debug!(current = ?colors, "player dupe");
+        return Err((
+            StatusCode::CONFLICT,
+            "requested player already taken".into(),
+        ));
+    }
+
+    let stats = Arc::new(ConnStats::default());
+    room.connections.push(Connection {
+        player_id: Some(color),
+        id: conn_id,
+        chan,
+        stats: stats.clone(),
+        connected: true,
+    });
+    room.players.push((color, stats.clone()));
+    room.reconnect_tokens.insert(color, new_id());
+    server.emit(ServerEvent::PlayerJoined {
+        room: room_id.to_owned(),
+        game: room.game.clone(),
+        player: color,
+    });
+
+    // rooms with reserved seats start once every seat is taken
+    if !room.seats.is_empty() && room.players.len() == room.seats.len() {
+        tokio::spawn(start_room(server.clone(), room_id.to_owned()));
+    }
+
+    Ok(stats)
+}
+
+/// Hands the new websocket of a player to the running session, see `reconnect` module.
+async fn reconnect_room(
+    server: Arc<Server>,
+    room_id: String,
+    query: ReconnectQuery,
+    ws_conn: WebSocketUpgrade,
+    compress: bool,
+) -> Response {
+    tracing::debug!(%room_id, "/room/:room_id/reconnect");
+    let Some(room) = server.rooms.read().unwrap().get(&room_id).cloned() else {
+        return (StatusCode::NOT_FOUND, "room not found").into_response();
+    };
+    let room = room.lock().await;
+
+    let Some(player) = room
+        .reconnect_tokens
+        .iter()
+        .find(|(_, token)| **token == query.token)
+        .map(|(player, _)| *player)
+    else {
+        return (StatusCode::FORBIDDEN, "invalid reconnect token").into_response();
+    };
+    if room.forfeits.borrow().contains(&player) {
+        return (StatusCode::GONE, "player forfeited").into_response();
+    }
+    if room.session.is_some() {
+        let msg = "session not started yet, connect with the token instead";
+        return (StatusCode::CONFLICT, msg).into_response();
+    }
+
+    let stats = room
+        .players
+        .iter()
+        .find(|(p, _)| *p == player)
+        .map(|(_, stats)| stats.clone())
+        .unwrap_or_default();
+    let reconnects = room.reconnects.clone();
+    let ws_conn = ws_conn.max_message_size(channel::DEFAULT_MAX_FRAME_BYTES);
+    let mut res = ws_conn.on_upgrade(move |sock| async move {
+        let max_frame_bytes = channel::DEFAULT_MAX_FRAME_BYTES;
+        let stream = WebSocketStream::new(sock, stats, compress, max_frame_bytes);
+        reconnects.offer(player, stream);
+    });
+    if compress {
+        agree_compression(&mut res);
+    }
+    res
+}
+
+/// Removes the player from the running session, the room drops them once it notices.
+async fn forfeit_player(server: Arc<Server>, room_id: String, player: PlayerId) -> Response {
+    let Some(room) = server.rooms.read().unwrap().get(&room_id).cloned() else {
+        return (StatusCode::NOT_FOUND, "room not found").into_response();
+    };
+    let room = room.lock().await;
+
+    if room.session.is_some() {
+        return (StatusCode::CONFLICT, "session not started yet").into_response();
+    }
+    if !room.players.iter().any(|(p, _)| *p == player) {
+        return (StatusCode::NOT_FOUND, "player not in the room").into_response();
+    }
+
+    if room
+        .forfeits
+        .send_if_modified(|forfeits| forfeits.insert(player))
+    {
+        server.emit(ServerEvent::PlayerForfeited {
+            room: room_id,
+            game: room.game.clone(),
+            player,
+        });
+    }
+    StatusCode::NO_CONTENT.into_response()
+}
 
-    axum::Server::bind(&addr)
-        .serve(app.into_make_service())
+/// Streams the latest state of the running session, for spectators who join after it started.
+///
+/// Unlike connected spectators, they don't run the game but see its state as JSON text frames,
+/// the current one first.
+async fn watch_room(server: Arc<Server>, room_id: String, ws_conn: WebSocketUpgrade) -> Response {
+    tracing::debug!(%room_id, "/room/:room_id/watch");
+    let Some(room) = server.rooms.read().unwrap().get(&room_id).cloned() else {
+        return (StatusCode::NOT_FOUND, "room not found").into_response();
+    };
+    let Some(state) = room.lock().await.state.clone() else {
+        let msg = "session not started, connect as a spectator instead";
+        return (StatusCode::CONFLICT, msg).into_response();
+    };
+
+    ws_conn.on_upgrade(|sock| stream_state(sock, state))
+}
+
+async fn stream_state(mut sock: WebSocket, mut state: watch::Receiver<LatestState>) {
+    loop {
+        let latest = state.borrow_and_update().clone();
+        if let Some(latest) = latest {
+            if sock.send(Message::Text(latest.get().into())).await.is_err() {
+                return;
+            }
+        }
+        // the room is gone along with the session
+        if state.changed().await.is_err() {
+            break;
+        }
+    }
+    _ = sock.close().await;
+}
+
+/// Starts the session of the room with players connected so far.
+async fn start_room(server: Arc<Server>, room_id: String) -> Response {
+    // room is kept until the session ends to report its players
+    let Some(room) = server.rooms.read().unwrap().get(&room_id).cloned() else {
+        return (StatusCode::NOT_FOUND, "room not found").into_response();
+    };
+    let mut room = room.lock().await;
+
+    // started rooms never fall below the minimum
+    if let Some(min) = room.info.min_players {
+        if room.players.len() < min {
+            let msg = format!(
+                "game requires at least {min} players, {} connected",
+                room.players.len()
+            );
+            return (StatusCode::CONFLICT, msg).into_response();
+        }
+    }
+    if room.players.len() < room.seats.len() {
+        let msg = format!(
+            "room reserved seats {:?}, {} connected",
+            room.seats,
+            room.players.len()
+        );
+        return (StatusCode::CONFLICT, msg).into_response();
+    }
+    if let Some(roles) = &room.info.roles {
+        if room.players.len() < roles.len() {
+            let msg = format!(
+                "game requires every seat of {roles:?} taken, {} connected",
+                room.players.len()
+            );
+            return (StatusCode::CONFLICT, msg).into_response();
+        }
+    }
+    let disconnected: Vec<_> = room
+        .connections
+        .iter()
+        .filter(|conn| !conn.connected)
+        .filter_map(|conn| conn.player_id)
+        .collect();
+    if !disconnected.is_empty() {
+        let msg =
+            format!("players {disconnected:?} disconnected, waiting for them to connect again");
+        return (StatusCode::CONFLICT, msg).into_response();
+    }
+    let Some(mut session) = room.session.take() else {
+        return (StatusCode::NOT_FOUND, "room not found").into_response();
+    };
+
+    let players: Vec<_> = room.players.iter().map(|(p, _)| *p).collect();
+    let conns = std::mem::take(&mut room.connections);
+    let reconnects = room.reconnects.clone();
+    let forfeits = room.forfeits.subscribe();
+    let debug = room.debug.clone();
+    let game = room.game.clone();
+    server.emit(ServerEvent::SessionStarted {
+        room: room_id.clone(),
+        game: game.clone(),
+        players: players.clone(),
+    });
+    let info = RoomInfo {
+        roles: room.info.assign_roles(&players),
+        players,
+        options: room.options.clone(),
+        init_state: room.init_state.clone(),
+    };
+    room.lobby.send_replace(LobbyStatus::Started(info.clone()));
+
+    let span = tracing::info_span!("room", %room_id);
+    // logged to replay the session on desync reports
+    let rng = SeededRandom::from_entropy();
+    tracing::info!(parent: &span, seed = rng.seed(), "session random seed");
+
+    tokio::spawn(async move {
+        let res = match Room::new(
+            conns,
+            info.clone(),
+            Box::new(rng),
+            reconnects,
+            server.shutdown.subscribe(),
+            forfeits,
+            debug,
+        )
         .await
-        .unwrap();
+        {
+            Ok(mut room) => {
+                room.cancel = session.cancel_handle();
+                let lobby = server.rooms.read().unwrap().get(&room_id).cloned();
+                if let Some(lobby) = lobby {
+                    lobby.lock().await.state = Some(room.watch_state());
+                }
+                session.start(16384, true, info, room).await
+            }
+            Err(err) => Err(err.context("room init failed")),
+        };
+        let mut abandoned = false;
+        if let Err(err) = &res {
+            tracing::error!("session run err: {err:?}");
+            let (category, count) = server.count_error(err);
+            abandoned = category == Some(RuntimeError::Abandoned);
+            tracing::warn!(?category, count, "session err category");
+            for snapshot in session.debug_snapshots() {
+                tracing::info!(label = snapshot.label.as_str(), value = %snapshot.value, "debug snapshot");
+            }
+        }
+        if let Some(fuel) = session.fuel_remaining() {
+            tracing::debug!(fuel, "session fuel remaining");
+        }
+        let result = session.game_result();
+        if let Some(result) = &result {
+            tracing::info!(%result, "game result");
+        }
+        server.rooms.write().unwrap().remove(&room_id);
+        server.emit(ServerEvent::SessionEnded {
+            room: room_id,
+            game,
+            error: res.err().map(|err| format!("{err:#}")),
+            result,
+            abandoned,
+        });
+    }.instrument(span));
+
+    Json(StartRoomResponse { ok: true }).into_response()
 }
 
 #[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 struct CreateRoomRequest {
     game: String,
+    /// Passed to the game as `RoomInfo::options`.
+    options: Option<Box<RawValue>>,
+    /// Reserves the room for these players, like those invited to a match.
+    /// Others can't join it and it starts once all of them do.
+    #[serde(default)]
+    seats: Vec<PlayerId>,
+    /// Passed to the game as `RoomInfo::init_state`, for games which take one.
+    init_state: Option<Box<RawValue>>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -146,12 +1061,197 @@ struct CreateRoomResponse {
     room: String,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+struct RoomResponse {
+    room_id: String,
+    game: Arc<str>,
+    connected_players: Vec<PlayerId>,
+    started: bool,
+    min_players: Option<usize>,
+    max_players: Option<usize>,
+    /// Roles of the seats, taken in the order players join.
+    roles: Option<Vec<String>>,
+    /// Max number of players who may join this room.
+    capacity: usize,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 struct ConnectQuery {
-    color: PlayerId,
+    /// Player to join as, like `red` or `player8`.
+    color: Option<PlayerId>,
+    /// Connect as a spectator, who only sees what's visible to every player.
+    #[serde(default)]
+    spectator: bool,
+    /// Token returned on the first connect, to connect again to the same seat.
+    token: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ReconnectQuery {
+    token: String,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 struct StartRoomResponse {
     ok: bool,
 }
+
+#[derive(Debug, Serialize, Deserialize)]
+struct QueueQuery {
+    game: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct EventsQuery {
+    /// Only stream events of this game.
+    game: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SessionStatusResponse {
+    id: SessionId,
+    game: Arc<str>,
+    age_ms: u64,
+    running: bool,
+    io_count: u64,
+}
+
+/// What the room's game is at, see `DebugState`.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RoomDebugResponse {
+    session: SessionId,
+    started: bool,
+    running: bool,
+    /// Number of IO the game performed so far.
+    io_count: u64,
+    /// Nested scopes of the game's tasks, the innermost last.
+    visibility: Vec<Vec<PlayerId>>,
+    scope: Vec<PlayerId>,
+    /// Players whose action the game waits for.
+    waiting_for: Vec<PlayerId>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct MetricsResponse {
+    active_sessions: usize,
+    sessions_created: u64,
+    sessions_completed: u64,
+    sessions_failed: u64,
+    /// `None` until a session finishes.
+    avg_duration_ms: Option<u64>,
+    duration_buckets: Vec<DurationBucket>,
+    failures_by_category: Vec<CategoryCount>,
+}
+
+/// Number of sessions which took at most `le_ms`, and longer than the previous bucket.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DurationBucket {
+    /// `None` for the last bucket, which is unbounded.
+    le_ms: Option<u64>,
+    count: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CategoryCount {
+    /// `None` for errors without a category.
+    category: Option<RuntimeError>,
+    count: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PlayerStatusResponse {
+    player: PlayerId,
+    bytes_sent: u64,
+    bytes_received: u64,
+    /// Bytes before deflated and after inflated, see `ConnStats`.
+    payload_bytes_sent: u64,
+    payload_bytes_received: u64,
+    /// Bytes on the wire per byte of payload, `None` before any traffic.
+    compression_ratio: Option<f64>,
+    frames_sent: u64,
+    frames_received: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use rulebook_runtime::test_games::{game, game_with_info, SESSION_END, SESSION_START};
+    use rulebook_runtime::Color;
+    use tokio_tungstenite::tungstenite::Error as WsError;
+
+    use super::*;
+    use crate::test_util::{connect, request, serve, server};
+
+    const RED: PlayerId = PlayerId::new(Color::Red as u8);
+    const BLUE: PlayerId = PlayerId::new(Color::Blue as u8);
+
+    /// Creates a room of the game, returns its id.
+    async fn create_room(addr: SocketAddr, game: &str) -> String {
+        let req = format!(r#"{{"game":"{game}"}}"#);
+        let (status, body) = request(addr, "POST", "/room", &req).await;
+        assert!(status.is_success(), "{status}: {body}");
+        serde_json::from_str::<CreateRoomResponse>(&body)
+            .unwrap()
+            .room
+    }
+
+    #[tokio::test]
+    async fn players_outside_the_roster_are_refused() {
+        let info = GameInfo {
+            roster: Some(vec![RED, BLUE]),
+            ..Default::default()
+        };
+        let code = game_with_info(&info, &[SESSION_START, SESSION_END], "");
+        let addr = serve(server(&[("duel", &code)]));
+        let room = create_room(addr, "duel").await;
+
+        let res = connect(addr, &format!("/room/{room}/connect?color=green")).await;
+        let Err(WsError::Http(res)) = res else {
+            panic!("player outside the roster connected: {res:?}");
+        };
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let body = String::from_utf8(res.into_body().unwrap()).unwrap();
+        assert!(
+            body.starts_with("player green not allowed by the game"),
+            "{body}"
+        );
+
+        connect(addr, &format!("/room/{room}/connect?color=red"))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn seat_is_taken_again_only_with_its_token() {
+        let addr = serve(server(&[("game", &game(&[SESSION_START, SESSION_END]))]));
+        let room = create_room(addr, "game").await;
+        let connect_red = |token: &str| format!("/room/{room}/connect?color=red{token}");
+
+        let (_red, res) = connect(addr, &connect_red("")).await.unwrap();
+        let token = res.headers()[RECONNECT_TOKEN_HEADER]
+            .to_str()
+            .unwrap()
+            .to_owned();
+
+        for path in [
+            connect_red("&token=forged"),
+            format!("/room/{room}/reconnect?token=forged"),
+        ] {
+            let res = connect(addr, &path).await;
+            let Err(WsError::Http(res)) = res else {
+                panic!("{path} connected with a forged token: {res:?}");
+            };
+            assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        }
+
+        let (_red, res) = connect(addr, &connect_red(&format!("&token={token}")))
+            .await
+            .unwrap();
+        assert_eq!(res.headers()[RECONNECT_TOKEN_HEADER], token.as_str());
+    }
+}
@@ -0,0 +1,183 @@
+//! Connections of a room waiting to start.
+//!
+//! Each connection is driven by its own task from the upgrade on, which tells the client
+//! who's in the room with `LobbyMessage::Update` on every change, and takes the player's
+//! `LobbyRequest`s. Once the room starts the task sends `LobbyMessage::Start` with the
+//! `SessionInfo`, where clients used to get the bare `SessionInfo` as their first message.
+//! Clients answer it with `LobbyRequest::Start`, discarding requests they sent meanwhile,
+//! then the task hands the `Channel` over to the `Room`. Message ids go on from the lobby's,
+//! so the session's messages and reconnects work like the channel was the room's all along.
+//!
+//! A player whose connection drops keeps the seat, to connect again with the reconnect token,
+//! but is left out of updates until then and the room can't start without them.
+
+use std::sync::Arc;
+
+use anyhow::Result;
+use tokio::sync::{oneshot, watch, Mutex};
+
+use rulebook_runtime::{
+    channel::Channel, LobbyMessage, LobbyRequest, LobbyUpdate, PlayerId, RoomInfo, SessionInfo,
+};
+
+use crate::websocket::WebSocketStream;
+use crate::Lobby;
+
+/// What the room tells its lobby tasks, the latest one wins.
+#[derive(Debug, Clone)]
+pub(crate) enum LobbyStatus {
+    Open(LobbyUpdate),
+    /// Start the session of the room and hand the channel over to it.
+    Started(RoomInfo),
+    /// Removed before it started, with the notice to send if any.
+    Closed(Option<&'static str>),
+}
+
+/// Handed over by the lobby task once the room starts.
+pub(crate) type Handover = oneshot::Receiver<Channel<WebSocketStream>>;
+
+impl Lobby {
+    /// Tells every connection who's in the room now if it changed,
+    /// unless the room is started or closed already.
+    pub(crate) fn publish(&self) {
+        let players: Vec<_> = self
+            .players
+            .iter()
+            .map(|(player, _)| *player)
+            .filter(|player| {
+                let conn = self
+                    .connections
+                    .iter()
+                    .find(|c| c.player_id == Some(*player));
+                conn.is_some_and(|conn| conn.connected)
+            })
+            .collect();
+        let ready = players
+            .iter()
+            .copied()
+            .filter(|player| self.ready.contains(player))
+            .collect();
+        let update = LobbyUpdate { players, ready };
+        self.lobby.send_if_modified(|status| match status {
+            LobbyStatus::Open(prev) if *prev != update => {
+                *prev = update;
+                true
+            }
+            _ => false,
+        });
+    }
+
+    /// Closes every connection, sending them the notice if any.
+    pub(crate) fn close(&mut self, notice: Option<&'static str>) {
+        self.connections.clear();
+        self.lobby.send_replace(LobbyStatus::Closed(notice));
+    }
+
+    /// Whether the connection is still the one of its player, or a spectator still connected.
+    fn is_current(&self, conn_id: u64) -> bool {
+        self.connections.iter().any(|conn| conn.id == conn_id)
+    }
+}
+
+/// Drives the connection until the room starts or it drops.
+pub(crate) async fn drive(
+    lobby: Arc<Mutex<Lobby>>,
+    conn_id: u64,
+    player: Option<PlayerId>,
+    mut chan: Channel<WebSocketStream>,
+    mut status: watch::Receiver<LobbyStatus>,
+    handover: oneshot::Sender<Channel<WebSocketStream>>,
+) {
+    let err = loop {
+        let current = status.borrow_and_update().clone();
+        match current {
+            LobbyStatus::Open(update) => {
+                if let Err(err) = chan.send(&LobbyMessage::Update(update)).await {
+                    break err;
+                }
+            }
+            LobbyStatus::Started(room) => {
+                if let Err(err) = start(&mut chan, room, player).await {
+                    break err;
+                }
+                // dropped if the player connected again meanwhile
+                _ = handover.send(chan);
+                return;
+            }
+            LobbyStatus::Closed(notice) => {
+                if let Some(notice) = notice {
+                    if let Err(err) = chan.send_notice(notice).await {
+                        tracing::debug!(?player, "failed to notice room closed: {err:?}");
+                    }
+                }
+                return;
+            }
+        }
+
+        let res = loop {
+            tokio::select! {
+                res = status.changed() => break res.map_err(anyhow::Error::from),
+                req = chan.receive::<LobbyRequest>() => match req {
+                    Ok(req) => handle_request(&lobby, conn_id, player, req).await,
+                    Err(err) => break Err(err),
+                },
+            }
+        };
+        if let Err(err) = res {
+            break err;
+        }
+    };
+
+    tracing::debug!(?player, "lobby connection dropped: {err:?}");
+    let mut lobby = lobby.lock().await;
+    if !lobby.is_current(conn_id) {
+        return;
+    }
+    match player {
+        Some(player) => {
+            let conn = lobby.connections.iter_mut().find(|c| c.id == conn_id);
+            conn.expect("checked by is_current").connected = false;
+            lobby.ready.remove(&player);
+        }
+        None => lobby.connections.retain(|conn| conn.id != conn_id),
+    }
+    lobby.publish();
+}
+
+/// Sends the start and waits for the client to take it.
+async fn start(
+    chan: &mut Channel<WebSocketStream>,
+    room: RoomInfo,
+    player: Option<PlayerId>,
+) -> Result<()> {
+    chan.send(&LobbyMessage::Start(SessionInfo { room, player }))
+        .await?;
+    loop {
+        match chan.receive().await? {
+            LobbyRequest::Start => return Ok(()),
+            // too late to tell anyone
+            LobbyRequest::Ready(_) => {}
+        }
+    }
+}
+
+async fn handle_request(
+    lobby: &Mutex<Lobby>,
+    conn_id: u64,
+    player: Option<PlayerId>,
+    req: LobbyRequest,
+) {
+    let mut lobby = lobby.lock().await;
+    let Some(player) = player.filter(|_| lobby.is_current(conn_id)) else {
+        return;
+    };
+    match req {
+        LobbyRequest::Ready(true) => lobby.ready.insert(player),
+        LobbyRequest::Ready(false) => lobby.ready.remove(&player),
+        LobbyRequest::Start => {
+            tracing::debug!(%player, "start taken before the room started");
+            return;
+        }
+    };
+    lobby.publish();
+}
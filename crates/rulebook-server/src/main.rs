@@ -1,43 +1,182 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::net::SocketAddr;
-use std::path::PathBuf;
-use std::sync::{Arc, RwLock};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex as StdMutex, RwLock};
+use std::time::Duration;
 
 use anyhow::{Context as _, Result};
-use axum::extract::ws::WebSocket;
+use axum_server::tls_rustls::RustlsConfig;
 use clap::Parser;
-use futures::stream::{self, StreamExt, TryStreamExt};
+use futures::future::{self, FutureExt};
+use futures::stream::{FuturesUnordered, StreamExt};
+use serde::{de::DeserializeOwned, Serialize};
 use serde_json::value::RawValue;
-use tokio::sync::{oneshot, Mutex};
+use tokio::sync::{broadcast, watch, Mutex};
+use tokio::time::Instant;
+use tracing_subscriber::EnvFilter;
 
 use rulebook_runtime::{
-    channel::Channel, OutputHandler, PlayerId, RoomInfo, Runtime, Session, SessionInfo, TaskResult,
+    channel::Channel, CancelHandle, CheckOutcome, EndReason, GameInfo, OutputHandler, PlayerId,
+    RandomSource, RoomInfo, Runtime, RuntimeError, Scenario, Session, SessionId, TaskResult,
+    TimedAction,
 };
 
+mod auth;
+mod events;
 mod http;
+mod lobby;
+mod queue;
+mod rate_limit;
+mod reconnect;
+#[cfg(test)]
+mod test_util;
 mod websocket;
 
-use websocket::WebSocketStream;
+use auth::Authenticator;
+use events::ServerEvent;
+use lobby::{Handover, LobbyStatus};
+use rate_limit::RateLimiter;
+use reconnect::{ReconnectPolicy, Reconnects};
+use websocket::{ConnStats, WebSocketStream};
 
-#[derive(Debug, Parser)]
+#[derive(Debug, Clone, Parser)]
 struct Args {
     #[arg(short, long)]
     game: Vec<PathBuf>,
     #[arg(short, long)]
     addr: SocketAddr,
+    /// Max number of game log messages printed per second for each session.
+    #[arg(long)]
+    log_rate_limit: Option<u32>,
+    /// Max time in milliseconds a game may run without performing IO.
+    #[arg(long)]
+    max_execution_ms: Option<u64>,
+    /// Amount of fuel, roughly the number of wasm instructions, each session may consume.
+    #[arg(long)]
+    fuel_per_session: Option<u64>,
+    /// Max number of IO, like requesting an action or a random, each session may perform.
+    #[arg(long)]
+    max_io_calls: Option<u64>,
+    /// Max size in bytes of each session's wasm linear memory.
+    #[arg(long)]
+    max_memory_bytes: Option<usize>,
+    /// Max number of sessions alive at once, new rooms are refused beyond it.
+    #[arg(long)]
+    max_concurrent_sessions: Option<usize>,
+    /// Max number of rooms waiting or running at once, new rooms are refused beyond it.
+    #[arg(long)]
+    max_rooms: Option<usize>,
+    /// Max number of rooms each client IP may create per minute.
+    #[arg(long)]
+    max_rooms_per_min: Option<u32>,
+    /// Max number of room connections each client IP may open per minute.
+    #[arg(long)]
+    max_connects_per_min: Option<u32>,
+    /// Max number of players in a room, even if the game allows more.
+    #[arg(long, default_value_t = PlayerId::DEFAULT_COUNT)]
+    max_seats: usize,
+    /// Max time in milliseconds to wait for players to submit an action.
+    #[arg(long)]
+    action_timeout_ms: Option<u64>,
+    /// Max time in milliseconds to wait for a disconnected player to reconnect.
+    #[arg(long, default_value_t = 30_000)]
+    reconnect_timeout_ms: u64,
+    /// End sessions as abandoned once every player disconnects, without waiting for reconnects.
+    #[arg(long)]
+    terminate_abandoned: bool,
+    /// Max time in milliseconds to wait for running sessions to end on shutdown,
+    /// before cancelling them.
+    #[arg(long, default_value_t = 30_000)]
+    shutdown_timeout_ms: u64,
+    /// Max time in milliseconds a room may wait to be started, it's removed after that.
+    #[arg(long, default_value_t = 600_000)]
+    room_idle_timeout_ms: u64,
+    /// Interval in milliseconds to ping idle connections.
+    /// Connections not answering within another interval are dropped.
+    #[arg(long)]
+    keepalive_ms: Option<u64>,
+    /// Write precompiled `.cwasm` of each game to this directory and exit.
+    /// Load them back with `--game` to skip compilation on startup.
+    #[arg(long)]
+    precompile_to: Option<PathBuf>,
+    /// Run each game against a mock room to check it follows the protocol, print the results
+    /// and exit, failing if any game does.
+    #[arg(long)]
+    check: bool,
+    /// JSON of the `Scenario` to play on `--check`, with the room, actions and random seed.
+    /// Without it, games are played by the players they require without any action.
+    #[arg(long, requires = "check")]
+    check_scenario: Option<PathBuf>,
+    /// Deflate large frames for clients which ask for it, trading CPU for bandwidth.
+    #[arg(long)]
+    compress: bool,
+    /// PEM file of the certificate chain to serve https and wss with, see README for a self-signed one.
+    #[arg(long, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+    /// PEM file of the private key of `--tls-cert`.
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+    /// File of tokens, one per line, players must send one of as `Authorization: Bearer <token>`
+    /// to connect. Everyone may connect without it.
+    #[arg(long)]
+    auth_tokens: Option<PathBuf>,
 }
 
+/// Used without `RUST_LOG`, keeps wasmtime's compilation logs out.
+const DEFAULT_LOG_FILTER: &str =
+    "warn,rulebook_server=info,rulebook_runtime=info,guest=info,admin_audit=info";
+
 #[tokio::main]
 async fn main() -> Result<()> {
     let args = Args::parse();
-    println!("ARGS: {args:?}");
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            EnvFilter::try_from_default_env()
+                .unwrap_or_else(|_| EnvFilter::new(DEFAULT_LOG_FILTER)),
+        )
+        .init();
+    tracing::info!(?args, "starting");
+
+    if let Some(dir) = &args.precompile_to {
+        return precompile_games(&args, dir);
+    }
+    if args.check {
+        return check_games(&args).await;
+    }
 
     let server = Arc::new(Server {
-        runtime: new_runtime(&args.game)?,
+        runtime: new_runtime(&args)?,
         rooms: Default::default(),
+        events: broadcast::channel(events::EVENT_BUFFER).0,
+        error_counts: Default::default(),
+        keepalive: args.keepalive_ms.map(Duration::from_millis),
+        compress: args.compress,
+        max_seats: args.max_seats,
+        queue: Default::default(),
+        reconnect: ReconnectPolicy {
+            timeout: Duration::from_millis(args.reconnect_timeout_ms),
+            terminate_abandoned: args.terminate_abandoned,
+        },
+        shutdown: watch::channel(false).0,
+        shutdown_timeout: Duration::from_millis(args.shutdown_timeout_ms),
+        room_idle_timeout: Duration::from_millis(args.room_idle_timeout_ms),
+        max_rooms: args.max_rooms,
+        room_limiter: args.max_rooms_per_min.map(RateLimiter::new),
+        connect_limiter: args.max_connects_per_min.map(RateLimiter::new),
+        authenticator: auth::authenticator(args.auth_tokens.as_deref())?,
     });
 
-    http::run_server(server, args.addr).await;
+    let tls = match (&args.tls_cert, &args.tls_key) {
+        (Some(cert), Some(key)) => Some(
+            RustlsConfig::from_pem_file(cert, key)
+                .await
+                .context("failed to load the TLS certificate")?,
+        ),
+        _ => None,
+    };
+
+    http::run_server(server, args.addr, tls).await;
 
     Ok(())
 }
@@ -45,40 +184,277 @@ async fn main() -> Result<()> {
 struct Server {
     runtime: Runtime,
     rooms: RwLock<HashMap<String, Arc<Mutex<Lobby>>>>,
+    events: broadcast::Sender<ServerEvent>,
+    /// Number of sessions ended with each category of error, `None` for uncategorized ones.
+    error_counts: StdMutex<BTreeMap<Option<RuntimeError>, u64>>,
+    keepalive: Option<Duration>,
+    /// Whether to agree on compression clients ask for, see `rulebook_runtime::compress`.
+    compress: bool,
+    /// Players a room may have without the game's roster, see `PlayerId::candidates`.
+    max_seats: usize,
+    queue: queue::MatchQueue,
+    reconnect: ReconnectPolicy,
+    /// Set once the server starts shutting down, rooms notice their players on it.
+    shutdown: watch::Sender<bool>,
+    shutdown_timeout: Duration,
+    /// Rooms not started within this are removed.
+    room_idle_timeout: Duration,
+    max_rooms: Option<usize>,
+    room_limiter: Option<RateLimiter>,
+    connect_limiter: Option<RateLimiter>,
+    /// Checks players before they connect, see `auth`.
+    authenticator: Box<dyn Authenticator>,
+}
+
+impl Server {
+    fn emit(&self, event: ServerEvent) {
+        // no subscriber is not an error
+        _ = self.events.send(event);
+    }
+
+    /// Counts the error by its category, returns the category and its count so far.
+    fn count_error(&self, err: &anyhow::Error) -> (Option<RuntimeError>, u64) {
+        let category = err.downcast_ref::<RuntimeError>().copied();
+        let mut counts = self.error_counts.lock().unwrap();
+        let count = counts.entry(category).or_default();
+        *count += 1;
+        (category, *count)
+    }
 }
 
 struct Lobby {
+    game: Arc<str>,
+    created_at: Instant,
+    info: GameInfo,
+    /// Passed to the game as `RoomInfo::options`.
+    options: Box<RawValue>,
+    /// Passed to the game as `RoomInfo::init_state`.
+    init_state: Option<Box<RawValue>>,
     session: Option<Session>,
+    /// Id of `session`, kept after it starts to look up its status.
+    session_id: SessionId,
+    cancel: CancelHandle,
     connections: Vec<Connection>,
+    /// Kept after the session starts to report each player's connection.
+    players: Vec<(PlayerId, Arc<ConnStats>)>,
+    /// Players reserved on creation or by matchmaking, empty for open rooms.
+    /// Only these players may join and the room starts once all of them do.
+    seats: Vec<PlayerId>,
+    /// Players who said they're ready, see `lobby`.
+    ready: BTreeSet<PlayerId>,
+    /// Told to every connection until the room starts, see `lobby`.
+    lobby: watch::Sender<LobbyStatus>,
+    /// Id of the next connection, to tell apart connections of the same player.
+    next_conn: u64,
+    /// Issued to each player on connect, required to connect again as the same player.
+    reconnect_tokens: HashMap<PlayerId, String>,
+    /// Shared with the room once the session starts.
+    reconnects: Arc<Reconnects>,
+    /// Players removed by the host, the room drops them once it notices.
+    forfeits: watch::Sender<BTreeSet<PlayerId>>,
+    /// Latest state of the running session, `None` until its room is set up.
+    state: Option<watch::Receiver<LatestState>>,
+    /// Shared with the room once the session starts.
+    debug: Arc<RwLock<DebugState>>,
+}
+
+impl Lobby {
+    /// Max number of players who may join, limited by the game's info,
+    /// matchmaking seats and the server's `max_seats`.
+    fn capacity(&self, max_seats: usize) -> usize {
+        let mut capacity = self.info.max_players.unwrap_or(usize::MAX).min(max_seats);
+        if let Some(roster) = &self.info.roster {
+            capacity = capacity.min(roster.len());
+        }
+        if let Some(roles) = &self.info.roles {
+            capacity = capacity.min(roles.len());
+        }
+        if !self.seats.is_empty() {
+            capacity = capacity.min(self.seats.len());
+        }
+        capacity
+    }
 }
 
 struct Connection {
-    player_id: PlayerId,
-    ws: oneshot::Receiver<WebSocket>,
+    /// `None` for spectators.
+    player_id: Option<PlayerId>,
+    /// Unique within the lobby, replaced when the player connects again.
+    id: u64,
+    chan: Handover,
+    stats: Arc<ConnStats>,
+    /// Whether the lobby task still has the player's connection, see `lobby::drive`.
+    connected: bool,
 }
 
-fn new_runtime(games: &[PathBuf]) -> Result<Runtime> {
+fn new_runtime(args: &Args) -> Result<Runtime> {
     let runtime = Runtime::new(rulebook_runtime::Config {
-        enable_state: false,
+        // kept for late spectators, see `Room::watch_state`
+        enable_state: true,
         enable_logging: true,
+        log_rate_limit: args.log_rate_limit,
+        max_execution_epochs: args
+            .max_execution_ms
+            .map(|ms| ms.div_ceil(rulebook_runtime::EPOCH_INTERVAL.as_millis() as u64)),
+        fuel_per_session: args.fuel_per_session,
+        max_io_calls: args.max_io_calls,
+        max_memory_bytes: args.max_memory_bytes,
+        max_concurrent_sessions: args.max_concurrent_sessions,
+        action_timeout: args.action_timeout_ms.map(Duration::from_millis),
+        ..Default::default()
+    })?;
+
+    // compiling is CPU bound and games are independent, so they're loaded on every core
+    let threads = std::thread::available_parallelism().map_or(1, usize::from);
+    let per_thread = args.game.len().div_ceil(threads).max(1);
+    std::thread::scope(|scope| {
+        let loads: Vec<_> = args
+            .game
+            .chunks(per_thread)
+            .map(|games| {
+                let runtime = &runtime;
+                scope.spawn(move || {
+                    games
+                        .iter()
+                        .try_for_each(|game| add_game_file(runtime, game).map(drop))
+                })
+            })
+            .collect();
+        loads
+            .into_iter()
+            .try_for_each(|load| load.join().expect("loading games panicked"))
     })?;
 
-    for game in games {
-        let file = std::fs::read(game)?;
+    Ok(runtime)
+}
 
-        let name = game
-            .file_name()
-            .with_context(|| format!("filename not exist on {}", game.display()))?;
-        let name = name.to_str().with_context(|| {
-            format!("filename not a valid unicode string on {}", game.display())
-        })?;
-        let name = name.strip_suffix(".wasm").unwrap_or(name);
-        println!("game added: {name}");
+/// Adds the game keyed by its file name without the extension, returns the key.
+fn add_game_file(runtime: &Runtime, game: &Path) -> Result<String> {
+    let file = std::fs::read(game)?;
 
+    let name = game
+        .file_name()
+        .with_context(|| format!("filename not exist on {}", game.display()))?;
+    let name = name
+        .to_str()
+        .with_context(|| format!("filename not a valid unicode string on {}", game.display()))?;
+    if let Some(name) = name.strip_suffix(".cwasm") {
+        // SAFETY: operator is responsible to only pass `.cwasm` files made by `--precompile-to`
+        unsafe { runtime.add_game_precompiled(name.into(), &file)? };
+        tracing::info!("precompiled game added: {name}");
+        Ok(name.into())
+    } else {
+        let name = name.strip_suffix(".wasm").unwrap_or(name);
         runtime.add_game(name.into(), &file)?;
+        tracing::info!("game added: {name}");
+        Ok(name.into())
     }
+}
 
-    Ok(runtime)
+fn precompile_games(args: &Args, dir: &Path) -> Result<()> {
+    let runtime = new_runtime(&Args {
+        game: vec![],
+        ..args.clone()
+    })?;
+
+    for game in &args.game {
+        let code = std::fs::read(game)?;
+        let out = dir.join(
+            game.with_extension("cwasm")
+                .file_name()
+                .with_context(|| format!("filename not exist on {}", game.display()))?,
+        );
+
+        std::fs::write(&out, runtime.precompile(&code)?)?;
+        tracing::info!("game precompiled: {}", out.display());
+    }
+
+    Ok(())
+}
+
+async fn check_games(args: &Args) -> Result<()> {
+    // games are added one by one to report those failed to load
+    let runtime = new_runtime(&Args {
+        game: vec![],
+        ..args.clone()
+    })?;
+    let scenario: Option<Scenario> = match &args.check_scenario {
+        Some(path) => {
+            let scenario = serde_json::from_slice(&std::fs::read(path)?)
+                .with_context(|| format!("invalid scenario {}", path.display()))?;
+            Some(scenario)
+        }
+        None => None,
+    };
+
+    let mut failed = 0;
+    for path in &args.game {
+        let game = match add_game_file(&runtime, path) {
+            Ok(game) => game,
+            Err(err) => {
+                println!("{}: FAIL, not loaded: {err:#}", path.display());
+                failed += 1;
+                continue;
+            }
+        };
+        let scenario = match &scenario {
+            Some(scenario) => scenario.clone(),
+            None => default_scenario(runtime.game_info(&game).await?),
+        };
+        let report = runtime.check_conformance(&game, &scenario).await?;
+
+        let verdict = if report.passed() { "PASS" } else { "FAIL" };
+        let outcome = match &report.outcome {
+            CheckOutcome::Completed => "completed".into(),
+            CheckOutcome::OutOfActions => "ran out of scenario actions before the end".into(),
+            CheckOutcome::GameError(err) => format!("game error: {err}"),
+            CheckOutcome::VisibilityViolation(msg) => format!("visibility violated: {msg}"),
+            CheckOutcome::Failed(msg) => format!("failed: {msg}"),
+        };
+        println!("{game}: {verdict}, {outcome}");
+        let outputs: Vec<_> = report
+            .outputs
+            .iter()
+            .map(|(kind, count)| format!("{kind} x{count}"))
+            .collect();
+        println!("  outputs: {}", outputs.join(", "));
+        let missing = report.missing_outputs();
+        if !missing.is_empty() {
+            println!("  not exercised: {}", missing.join(", "));
+        }
+
+        if !report.passed() {
+            failed += 1;
+        }
+    }
+
+    anyhow::ensure!(failed == 0, "{failed} of {} games failed", args.game.len());
+    Ok(())
+}
+
+/// Room of the players the game requires, without any action.
+fn default_scenario(info: GameInfo) -> Scenario {
+    let count = match &info.roles {
+        Some(roles) => roles.len(),
+        None => info.min_players.unwrap_or(2),
+    };
+    let mut players = match &info.roster {
+        Some(roster) => roster.clone(),
+        None => PlayerId::candidates(count).collect(),
+    };
+    if let Some(max) = info.max_players {
+        players.truncate(max);
+    }
+
+    Scenario {
+        room: RoomInfo {
+            roles: info.assign_roles(&players),
+            players,
+            ..Default::default()
+        },
+        actions: vec![],
+        seed: 0,
+    }
 }
 
 fn new_id() -> String {
@@ -88,68 +464,538 @@ fn new_id() -> String {
     URL_SAFE.encode(bytes)
 }
 
-#[derive(Debug)]
 struct Room {
     chans: HashMap<PlayerId, Channel<websocket::WebSocketStream>>,
+    /// Seat order of `RoomInfo::players`, loops over players follow it
+    /// so they go the same way every session.
+    players: Vec<PlayerId>,
+    /// Receive what's visible to every player, but never act.
+    spectators: Vec<Channel<websocket::WebSocketStream>>,
+    visibility: Vec<Vec<PlayerId>>,
+    rng: Box<dyn RandomSource>,
+    reconnects: Arc<Reconnects>,
+    shutdown: watch::Receiver<bool>,
+    /// Whether players are noticed about the shutdown already.
+    shutdown_noticed: bool,
+    /// Of the session, waiting on players gives up once it's cancelled.
+    /// Set once the session starts on the room.
+    cancel: CancelHandle,
+    /// Number of messages each player owes for actions which timed out,
+    /// the late action or a filler, discarded before their next message.
+    stale: HashMap<PlayerId, usize>,
+    forfeits: watch::Receiver<BTreeSet<PlayerId>>,
+    /// Forfeited players whose channels are dropped already, see `Room::apply_forfeits`.
+    forfeited: BTreeSet<PlayerId>,
+    /// Kept for those who watch the game after it started, see `Room::watch_state`.
+    state: watch::Sender<LatestState>,
+    debug: Arc<RwLock<DebugState>>,
+}
+
+/// Latest state the game sent, `None` before the first one.
+type LatestState = Option<Box<RawValue>>;
+
+/// Where the game is at, for operators to tell what a stuck game waits on.
+#[derive(Debug, Clone, Default)]
+struct DebugState {
+    /// Frames of `Room::visibility`, the innermost last.
     visibility: Vec<Vec<PlayerId>>,
+    /// Players the game may talk to now, see `Room::scope`.
+    scope: Vec<PlayerId>,
+    /// Players whose action the game waits for, empty while it waits on no one.
+    waiting_for: Vec<PlayerId>,
+}
+
+/// Sent to every player once the server starts shutting down.
+const SHUTDOWN_NOTICE: &str = "server shutting down";
+/// Sent to players connected to a room removed for not being started in time.
+const ROOM_EXPIRED_NOTICE: &str = "room expired before it started";
+/// Sent to players connected to a room deleted before it started.
+const ROOM_DELETED_NOTICE: &str = "room deleted before it started";
+/// Sent to players removed from the game by the host, right before they're disconnected.
+const FORFEIT_NOTICE: &str = "you're removed from the game";
+
+/// Resolves once the server starts shutting down, or the server is gone.
+async fn wait_shutdown(mut shutdown: watch::Receiver<bool>) {
+    while !*shutdown.borrow_and_update() {
+        if shutdown.changed().await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Resolves with the first of `players` to forfeit, never if the lobby is gone.
+async fn wait_forfeit(
+    mut forfeits: watch::Receiver<BTreeSet<PlayerId>>,
+    players: Vec<PlayerId>,
+) -> PlayerId {
+    loop {
+        let found = {
+            let forfeited = forfeits.borrow_and_update();
+            players.iter().copied().find(|p| forfeited.contains(p))
+        };
+        if let Some(player) = found {
+            return player;
+        }
+        if forfeits.changed().await.is_err() {
+            return future::pending().await;
+        }
+    }
+}
+
+/// Channels of `chans` in the order of `players`, rather than the order of the map.
+fn in_seat_order<'a, C>(
+    players: &[PlayerId],
+    chans: &'a mut HashMap<PlayerId, C>,
+) -> Vec<(PlayerId, &'a mut C)> {
+    let mut chans: Vec<_> = chans.iter_mut().map(|(&p, chan)| (p, chan)).collect();
+    chans.sort_by_key(|(player, _)| players.iter().position(|p| p == player));
+    chans
+}
+
+/// Players by their names like `[red, blue]`, rather than their ids.
+fn names(players: &[PlayerId]) -> String {
+    let names: Vec<_> = players.iter().map(PlayerId::to_string).collect();
+    format!("[{}]", names.join(", "))
+}
+
+fn forfeited_while_waiting(player: PlayerId) -> anyhow::Error {
+    anyhow::anyhow!("player {player} forfeited while the game waits for them")
+        .context(RuntimeError::Forfeited)
+        .context(EndReason::PlayerLeft(player))
+}
+
+fn cancelled_while_waiting() -> anyhow::Error {
+    anyhow::anyhow!("session cancelled while the game waits for players")
+        .context(RuntimeError::Cancelled)
 }
 
 impl Room {
-    async fn new(conns: Vec<Connection>) -> Result<Self> {
-        let players: Vec<_> = conns.iter().map(|conn| conn.player_id).collect();
-        let player_count = players.len();
-        let conns: Result<HashMap<_, _>> = stream::iter(conns)
-            .map(|conn| async {
-                let conn = conn;
-                println!("got pid: {}", conn.player_id);
-                let mut chan = Channel::new(WebSocketStream::new(conn.ws.await?));
-                chan.send(&SessionInfo {
-                    room: RoomInfo {
-                        players: players.clone(),
-                    },
-                    player: conn.player_id,
-                })
-                .await?;
+    /// Takes over the connections once their lobby tasks started the session on them.
+    async fn new(
+        conns: Vec<Connection>,
+        info: RoomInfo,
+        rng: Box<dyn RandomSource>,
+        reconnects: Arc<Reconnects>,
+        shutdown: watch::Receiver<bool>,
+        forfeits: watch::Receiver<BTreeSet<PlayerId>>,
+        debug: Arc<RwLock<DebugState>>,
+    ) -> Result<Self> {
+        let handovers = conns.into_iter().map(|conn| async move {
+            let chan = conn.chan.await;
+            tracing::debug!(player = ?conn.player_id, ok = chan.is_ok(), "got connection");
+            (conn.player_id, chan)
+        });
 
-                Ok((conn.player_id, chan))
-            })
-            .buffer_unordered(player_count)
-            .try_collect()
-            .await;
+        let mut chans = HashMap::new();
+        let mut spectators = vec![];
+        for (player, chan) in future::join_all(handovers).await {
+            match (player, chan) {
+                (Some(player), Ok(chan)) => {
+                    chans.insert(player, chan);
+                }
+                (Some(player), Err(_)) => {
+                    anyhow::bail!("player {player} disconnected before the session started")
+                }
+                (None, Ok(chan)) => spectators.push(chan),
+                // like spectators failing to receive later
+                (None, Err(_)) => tracing::info!("spectator dropped before the session started"),
+            }
+        }
 
-        Ok(Room {
-            chans: conns?,
+        let mut room = Room {
+            chans,
+            players: info.players,
+            spectators,
             visibility: vec![],
-        })
+            rng,
+            reconnects,
+            shutdown,
+            shutdown_noticed: false,
+            cancel: CancelHandle::default(),
+            stale: HashMap::new(),
+            forfeits,
+            forfeited: BTreeSet::new(),
+            state: watch::channel(None).0,
+            debug,
+        };
+        room.update_debug(&[]);
+        Ok(room)
+    }
+
+    /// Latest state of the game, starting with the current one.
+    ///
+    /// Only the state everyone sees, for spectators joining late who can't run the game
+    /// without the inputs so far. Ends once the session does.
+    fn watch_state(&self) -> watch::Receiver<LatestState> {
+        self.state.subscribe()
+    }
+
+    /// Players the game may talk to now, forfeited players are out of every scope.
+    fn scope(&mut self) -> Vec<PlayerId> {
+        self.apply_forfeits();
+        match self.visibility.last() {
+            Some(frame) => frame
+                .iter()
+                .filter(|player| self.chans.contains_key(player))
+                .copied()
+                .collect(),
+            None => self
+                .players
+                .iter()
+                .filter(|player| self.chans.contains_key(player))
+                .copied()
+                .collect(),
+        }
+    }
+
+    /// Updates the debug state with the current scope and `waiting_for`.
+    fn update_debug(&mut self, waiting_for: &[PlayerId]) {
+        let scope = self.scope();
+        let mut debug = self.debug.write().unwrap();
+        debug.visibility = self.visibility.clone();
+        debug.scope = scope;
+        debug.waiting_for = waiting_for.to_vec();
+    }
+
+    /// Drops channels of players forfeited since the last call.
+    fn apply_forfeits(&mut self) {
+        let forfeits = self.forfeits.borrow().clone();
+        for &player in forfeits.difference(&self.forfeited) {
+            tracing::info!("player {player} forfeited");
+            self.reconnects.forget(player);
+            if let Some(mut chan) = self.chans.remove(&player) {
+                tokio::spawn(async move {
+                    if let Err(err) = chan.send_notice(FORFEIT_NOTICE).await {
+                        tracing::debug!("failed to notice forfeit to {player}: {err:?}");
+                    }
+                });
+            }
+        }
+        self.forfeited = forfeits;
+    }
+
+    /// Fails with `RuntimeError::Forfeited` if any of `players` is removed from the game.
+    fn ensure_present(&mut self, players: &[PlayerId]) -> Result<()> {
+        self.apply_forfeits();
+        if let Some(player) = players.iter().find(|p| self.forfeited.contains(p)) {
+            return Err(
+                anyhow::anyhow!("game requested action from forfeited player {player}")
+                    .context(RuntimeError::Forfeited)
+                    .context(EndReason::PlayerLeft(*player)),
+            );
+        }
+        Ok(())
     }
 
-    fn scope(&self) -> Vec<PlayerId> {
-        self.visibility
-            .last()
-            .cloned()
-            .unwrap_or_else(|| self.chans.keys().cloned().collect())
+    /// Sends the message to spectators if the game is at the top level scope.
+    /// Spectators which fail to receive are dropped rather than failing the game.
+    async fn send_spectators<M: Serialize + ?Sized>(&mut self, msg: &M) -> Result<()> {
+        if !self.visibility.is_empty() {
+            return Ok(());
+        }
+
+        let sends = self.spectators.iter_mut().map(|chan| chan.send(msg));
+        let mut results = future::join_all(sends).await.into_iter();
+        self.spectators.retain(|_| match results.next().unwrap() {
+            Ok(()) => true,
+            Err(err) => {
+                tracing::info!("spectator dropped: {err:?}");
+                false
+            }
+        });
+
+        Ok(())
+    }
+
+    /// Sends each of `players` its message at once, so a slow player doesn't hold up others.
+    ///
+    /// Each message is on its own channel, so they keep their order per player.
+    /// Every send runs to its end even if another one fails, then the first error fails
+    /// the game, which can't go on in lockstep without the player anyway.
+    async fn broadcast<M: Serialize>(
+        &mut self,
+        players: &[PlayerId],
+        msg_for: impl Fn(PlayerId) -> M,
+    ) -> Result<()> {
+        let reconnects = &self.reconnects;
+        let forfeits = &self.forfeits;
+        let player_count = self.chans.len();
+        let sends = in_seat_order(&self.players, &mut self.chans)
+            .into_iter()
+            .filter(|(player, _)| players.contains(player))
+            .map(|(player, chan)| {
+                let msg = msg_for(player);
+                let forfeit = wait_forfeit(forfeits.clone(), vec![player]);
+                async move {
+                    let res = tokio::select! {
+                        res = reconnects.send(player, chan, player_count, &msg) => res,
+                        // nothing to deliver to players gone
+                        _ = forfeit => Ok(()),
+                    };
+                    (player, res)
+                }
+            });
+
+        let mut first_err = None;
+        for (player, res) in future::join_all(sends).await {
+            match res {
+                Ok(()) => {}
+                Err(err) if first_err.is_none() => first_err = Some(err),
+                Err(err) => tracing::warn!("failed to send to {player} too: {err:?}"),
+            }
+        }
+        first_err.map_or(Ok(()), Err)
     }
 
     fn chan(&mut self, player: PlayerId) -> Result<&mut Channel<WebSocketStream>> {
         self.chans
             .get_mut(&player)
             .context("game tried to grab not existing player channel")
+            .context(RuntimeError::Protocol)
+    }
+
+    /// Receives from the player, noticing every player on shutdown meanwhile.
+    ///
+    /// Safe to cancel, messages are only taken once they're returned or discarded.
+    async fn receive_from<M: DeserializeOwned>(&mut self, player: PlayerId) -> Result<M> {
+        while self.stale.get(&player).is_some_and(|&count| count > 0) {
+            let _: serde::de::IgnoredAny = self.receive_fresh(player).await?;
+            *self.stale.get_mut(&player).unwrap() -= 1;
+        }
+
+        self.receive_fresh(player).await
+    }
+
+    /// Receives an action from each of `from` at once, all of them must be within the scope.
+    async fn receive_all(
+        &mut self,
+        from: &[PlayerId],
+    ) -> Result<BTreeMap<PlayerId, Box<RawValue>>> {
+        self.ensure_present(from)?;
+        let scope = self.scope();
+        if let Some(player) = from.iter().find(|p| !scope.contains(p)) {
+            return Err(anyhow::anyhow!(
+                "game requested action from player {player} out of current scope"
+            )
+            .context(RuntimeError::VisibilityViolation));
+        }
+
+        self.update_debug(from);
+
+        // discarded within each player's own future like `receive_first` does
+        let owed: HashMap<_, _> = from
+            .iter()
+            .map(|&player| {
+                (
+                    player,
+                    AtomicUsize::new(self.stale.remove(&player).unwrap_or(0)),
+                )
+            })
+            .collect();
+        let res = {
+            let owed = &owed;
+            let forfeit = wait_forfeit(self.forfeits.clone(), from.to_vec());
+            let reconnects = &self.reconnects;
+            let player_count = self.chans.len();
+            let receives = in_seat_order(&self.players, &mut self.chans)
+                .into_iter()
+                .filter(|(player, _)| from.contains(player))
+                .map(|(player, chan)| async move {
+                    let stale = &owed[&player];
+                    while stale.load(Ordering::Relaxed) > 0 {
+                        let _: serde::de::IgnoredAny =
+                            reconnects.receive(player, chan, player_count).await?;
+                        stale.fetch_sub(1, Ordering::Relaxed);
+                    }
+                    let value: Box<RawValue> =
+                        reconnects.receive(player, chan, player_count).await?;
+                    anyhow::Ok((player, value))
+                });
+            tokio::select! {
+                res = future::try_join_all(receives) => res,
+                player = forfeit => Err(forfeited_while_waiting(player)),
+                () = self.cancel.cancelled() => Err(cancelled_while_waiting()),
+            }
+        };
+        for (player, stale) in owed {
+            let count = stale.into_inner();
+            if count > 0 {
+                self.stale.insert(player, count);
+            }
+        }
+        let values: BTreeMap<_, _> = res?.into_iter().collect();
+        self.update_debug(&[]);
+        if values.len() != from.len() {
+            return Err(
+                anyhow::anyhow!("game requested action from not existing player")
+                    .context(RuntimeError::Protocol),
+            );
+        }
+
+        Ok(values)
+    }
+
+    /// Races `racers`, all within the scope, returning whoever acts first
+    /// or `None` once the deadline passes or every racer forfeited.
+    ///
+    /// Every racer sends exactly one message for the race, the winner its action and others
+    /// a late action or a filler, so losers who haven't sent theirs yet owe it as stale.
+    async fn receive_first(
+        &mut self,
+        racers: &[PlayerId],
+        deadline: Option<Duration>,
+    ) -> Result<Option<(PlayerId, Box<RawValue>)>> {
+        // discarded within each racer's own future, as they may not finish
+        let owed: HashMap<_, _> = racers
+            .iter()
+            .map(|&player| {
+                (
+                    player,
+                    AtomicUsize::new(self.stale.remove(&player).unwrap_or(0)),
+                )
+            })
+            .collect();
+        let mut owing = racers.to_vec();
+
+        let res = {
+            let owed = &owed;
+            let reconnects = &self.reconnects;
+            let player_count = self.chans.len();
+            let mut receives: FuturesUnordered<_> = in_seat_order(&self.players, &mut self.chans)
+                .into_iter()
+                .filter(|(player, _)| racers.contains(player))
+                .map(|(player, chan)| async move {
+                    let stale = &owed[&player];
+                    while stale.load(Ordering::Relaxed) > 0 {
+                        let _: serde::de::IgnoredAny =
+                            reconnects.receive(player, chan, player_count).await?;
+                        stale.fetch_sub(1, Ordering::Relaxed);
+                    }
+                    let value: Box<RawValue> =
+                        reconnects.receive(player, chan, player_count).await?;
+                    anyhow::Ok((player, value))
+                })
+                .collect();
+
+            let timeout = async {
+                match deadline {
+                    Some(deadline) => tokio::time::sleep(deadline).await,
+                    None => future::pending().await,
+                }
+            };
+            // the others may still act while some of them are gone
+            let forfeits = self.forfeits.clone();
+            let all_forfeited = async {
+                let mut last = None;
+                for &player in racers {
+                    last = Some(wait_forfeit(forfeits.clone(), vec![player]).await);
+                }
+                last.expect("racers are nonempty")
+            };
+
+            let res = tokio::select! {
+                res = receives.next() => res.expect("racers are nonempty").map(Some),
+                () = timeout => Ok(None),
+                player = all_forfeited => match deadline {
+                    Some(_) => Ok(None),
+                    None => Err(forfeited_while_waiting(player)),
+                },
+                () = self.cancel.cancelled() => Err(cancelled_while_waiting()),
+            };
+            // those who acted at about the same time lost, but owe nothing anymore
+            while let Some(Some(other)) = receives.next().now_or_never() {
+                let (player, _) = other?;
+                owing.retain(|&p| p != player);
+            }
+            res
+        };
+
+        if let Ok(Some((winner, _))) = &res {
+            owing.retain(|p| p != winner);
+        }
+        for (player, stale) in owed {
+            let count = stale.into_inner() + usize::from(owing.contains(&player));
+            if count > 0 {
+                self.stale.insert(player, count);
+            }
+        }
+
+        res
+    }
+
+    async fn receive_fresh<M: DeserializeOwned>(&mut self, player: PlayerId) -> Result<M> {
+        let reconnects = self.reconnects.clone();
+        let player_count = self.chans.len();
+        loop {
+            let noticed = self.shutdown_noticed;
+            let shutdown = wait_shutdown(self.shutdown.clone());
+            let forfeit = wait_forfeit(self.forfeits.clone(), vec![player]);
+            let cancel = self.cancel.clone();
+            tokio::select! {
+                res = reconnects.receive(player, self.chan(player)?, player_count) => return res,
+                () = shutdown, if !noticed => {}
+                player = forfeit => return Err(forfeited_while_waiting(player)),
+                () = cancel.cancelled() => return Err(cancelled_while_waiting()),
+            }
+            self.notice_shutdown().await;
+        }
+    }
+
+    /// Players failed to receive it are left to reconnect, the game goes on until it's cancelled.
+    async fn notice_shutdown(&mut self) {
+        self.shutdown_noticed = true;
+        for (player, chan) in in_seat_order(&self.players, &mut self.chans) {
+            if let Err(err) = chan.send_notice(SHUTDOWN_NOTICE).await {
+                tracing::warn!("failed to notice shutdown to {player}: {err:?}");
+            }
+        }
+        for chan in &mut self.spectators {
+            if let Err(err) = chan.send_notice(SHUTDOWN_NOTICE).await {
+                tracing::warn!("failed to notice shutdown to spectator: {err:?}");
+            }
+        }
     }
 }
 
 #[async_trait::async_trait]
 impl OutputHandler for Room {
-    fn state(&mut self, _state: &RawValue) -> Result<()> {
+    fn state(&mut self, state: &RawValue) -> Result<()> {
+        self.state.send_replace(Some(state.to_owned()));
+        Ok(())
+    }
+
+    fn private_state(&mut self, _name: &str, _target: PlayerId, _state: &RawValue) -> Result<()> {
+        Ok(())
+    }
+
+    fn admin_audit(&mut self, kind: &str, detail: &RawValue) -> Result<()> {
+        tracing::info!(target: "admin_audit", kind, detail = detail.get(), "admin operation");
         Ok(())
     }
 
-    async fn do_task_if(&mut self, allowed: Vec<PlayerId>) -> Result<TaskResult<Box<RawValue>>> {
+    async fn do_task_if(
+        &mut self,
+        mut allowed: Vec<PlayerId>,
+    ) -> Result<TaskResult<Box<RawValue>>> {
         let current_scope = self.scope();
-        if allowed.iter().any(|p| !current_scope.contains(p)) {
-            anyhow::bail!("game tries to extend visibility");
+        // the game may not know they're gone yet
+        allowed.retain(|player| !self.forfeited.contains(player));
+        let beyond: Vec<_> = allowed
+            .iter()
+            .copied()
+            .filter(|p| !current_scope.contains(p))
+            .collect();
+        if !beyond.is_empty() {
+            return Err(anyhow::anyhow!(
+                "game tries to extend visibility to {} beyond current scope {}",
+                names(&beyond),
+                names(&current_scope),
+            )
+            .context(RuntimeError::VisibilityViolation));
         }
 
         self.visibility.push(allowed);
+        self.update_debug(&[]);
 
         Ok(TaskResult::DoTask)
     }
@@ -158,46 +1004,450 @@ impl OutputHandler for Room {
         let last_frame = self
             .visibility
             .pop()
-            .context("game requested taskDone event without previous doTaskIf")?;
+            .context("game requested taskDone event without previous doTaskIf")
+            .context(RuntimeError::Protocol)?;
+        self.update_debug(&[]);
         let scope = self.scope();
 
-        for player in scope {
-            let chan = self.chan(player)?;
-
-            let res = if last_frame.contains(&player) {
+        self.broadcast(&scope, |player| {
+            if last_frame.contains(&player) {
                 TaskResult::DoTask
             } else if targets.contains(&player) {
                 TaskResult::SyncResult(value)
             } else {
                 TaskResult::Restricted
-            };
-            chan.send(&res).await?;
-        }
+            }
+        })
+        .await?;
+
+        // results revealed to every player are public
+        let all_players = self.chans.keys().all(|player| targets.contains(player));
+        let res = if all_players {
+            TaskResult::SyncResult(value)
+        } else {
+            TaskResult::Restricted
+        };
+        self.send_spectators(&res).await?;
+
+        Ok(())
+    }
+
+    async fn notify(&mut self, targets: Vec<PlayerId>, value: &RawValue) -> Result<()> {
+        let mut scope = self.scope();
+        scope.retain(|player| targets.contains(player));
+
+        // private by nature, spectators never receive it
+        self.broadcast(&scope, |_| value).await?;
 
         Ok(())
     }
 
     async fn random(&mut self, start: i32, end: i32) -> Result<i32> {
-        let value = fastrand::i32(start..=end);
+        let value = self.rng.next_in_range(start, end);
         let scope = self.scope();
 
-        for player in scope {
-            self.chan(player)?.send(&value).await?;
-        }
+        self.broadcast(&scope, |_| value).await?;
+        self.send_spectators(&value).await?;
 
         Ok(value)
     }
 
+    async fn random_bytes(&mut self, len: usize) -> Result<Vec<u8>> {
+        let mut bytes = vec![0; len];
+        self.rng.fill_bytes(&mut bytes);
+        let scope = self.scope();
+
+        self.broadcast(&scope, |_| &bytes).await?;
+        self.send_spectators(&bytes).await?;
+
+        Ok(bytes)
+    }
+
     async fn action(&mut self, from: PlayerId, _param: &RawValue) -> Result<Box<RawValue>> {
-        println!("action from {from} with {_param:?}");
-        let value: Box<RawValue> = self.chan(from)?.receive().await?;
+        tracing::debug!(%from, param = _param.get(), "action");
+        self.ensure_present(&[from])?;
+        self.update_debug(&[from]);
+        let value: Box<RawValue> = self.receive_from(from).await?;
+        self.update_debug(&[]);
         let mut scope = self.scope();
         scope.retain(|&p| p != from);
 
-        for player in scope {
-            self.chan(player)?.send(&*value).await?;
-        }
+        self.broadcast(&scope, |_| &*value).await?;
+        self.send_spectators(&*value).await?;
 
         Ok(value)
     }
+
+    async fn action_with_deadline(
+        &mut self,
+        from: PlayerId,
+        _param: &RawValue,
+        deadline: Duration,
+    ) -> Result<TimedAction<Box<RawValue>>> {
+        tracing::debug!(%from, param = _param.get(), ?deadline, "action with deadline");
+        self.apply_forfeits();
+        self.update_debug(&[from]);
+        let result = if self.forfeited.contains(&from) {
+            // forfeited players never act, like they let every deadline pass
+            TimedAction::TimedOut
+        } else {
+            match tokio::time::timeout(deadline, self.receive_from(from)).await {
+                Ok(Err(err)) if err.downcast_ref() == Some(&RuntimeError::Forfeited) => {
+                    TimedAction::TimedOut
+                }
+                Ok(value) => TimedAction::Acted(value?),
+                Err(_) => {
+                    // the player sends one anyway, a late action or a filler on this result
+                    *self.stale.entry(from).or_default() += 1;
+                    TimedAction::TimedOut
+                }
+            }
+        };
+        self.update_debug(&[]);
+
+        // including the acting player, the host decides whether it was in time
+        let scope = self.scope();
+        self.broadcast(&scope, |_| &result).await?;
+        self.send_spectators(&result).await?;
+
+        Ok(result)
+    }
+
+    async fn first_action(
+        &mut self,
+        mut from: Vec<PlayerId>,
+        _param: &RawValue,
+        deadline: Option<Duration>,
+    ) -> Result<TimedAction<(PlayerId, Box<RawValue>)>> {
+        tracing::debug!(?from, param = _param.get(), ?deadline, "first action");
+        let scope = self.scope();
+        if deadline.is_none() && from.iter().all(|p| self.forfeited.contains(p)) {
+            self.ensure_present(&from)?;
+        }
+        // forfeited players never act, like they're always too slow
+        from.retain(|player| !self.forfeited.contains(player));
+        if let Some(player) = from.iter().find(|p| !scope.contains(p)) {
+            return Err(anyhow::anyhow!(
+                "game requested action from player {player} out of current scope"
+            )
+            .context(RuntimeError::VisibilityViolation));
+        }
+
+        self.update_debug(&from);
+        let result = if from.is_empty() {
+            TimedAction::TimedOut
+        } else {
+            match self.receive_first(&from, deadline).await? {
+                Some(acted) => TimedAction::Acted(acted),
+                None => TimedAction::TimedOut,
+            }
+        };
+        self.update_debug(&[]);
+
+        // including every racer, the host decides who was first
+        let scope = self.scope();
+        self.broadcast(&scope, |_| &result).await?;
+        self.send_spectators(&result).await?;
+
+        Ok(result)
+    }
+
+    async fn simultaneous_action(
+        &mut self,
+        from: Vec<PlayerId>,
+        _param: &RawValue,
+    ) -> Result<Box<RawValue>> {
+        tracing::debug!(?from, param = _param.get(), "simultaneous action");
+        let values = self.receive_all(&from).await?;
+
+        let values = serde_json::value::to_raw_value(&values)?;
+        let scope = self.scope();
+        self.broadcast(&scope, |_| &*values).await?;
+        self.send_spectators(&*values).await?;
+
+        Ok(values)
+    }
+
+    async fn gather_actions(
+        &mut self,
+        requests: Vec<(PlayerId, Box<RawValue>)>,
+    ) -> Result<Box<RawValue>> {
+        let from: Vec<_> = requests.iter().map(|(player, _)| *player).collect();
+        tracing::debug!(?from, "gather actions");
+        let mut values = self.receive_all(&from).await?;
+
+        // in the requested order rather than the order they arrived
+        let values: Vec<_> = from.iter().map(|p| values.remove(p).unwrap()).collect();
+        let values = serde_json::value::to_raw_value(&values)?;
+        let scope = self.scope();
+        self.broadcast(&scope, |_| &*values).await?;
+        self.send_spectators(&*values).await?;
+
+        Ok(values)
+    }
+
+    /// Players who finished the game may have closed their connection already.
+    async fn session_end(&mut self, reason: &EndReason) -> Result<()> {
+        tracing::info!(%reason, "session end");
+        for (player, chan) in in_seat_order(&self.players, &mut self.chans) {
+            if let Err(err) = chan.send_end(reason).await {
+                tracing::debug!("failed to send the session end to {player}: {err:?}");
+            }
+        }
+        for chan in &mut self.spectators {
+            if let Err(err) = chan.send_end(reason).await {
+                tracing::debug!("failed to send the session end to spectator: {err:?}");
+            }
+        }
+        Ok(())
+    }
+
+    async fn forfeited_players(&mut self) -> Result<Vec<PlayerId>> {
+        let scope = self.scope();
+        let players: Vec<_> = self.forfeited.iter().copied().collect();
+
+        self.broadcast(&scope, |_| &players).await?;
+        self.send_spectators(&players).await?;
+
+        Ok(players)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rulebook_runtime::{Color, SeededRandom};
+    use tokio::sync::oneshot;
+
+    use super::*;
+    use crate::test_util::{chan_pair, ClientStream};
+
+    const RED: PlayerId = PlayerId::new(Color::Red as u8);
+    const BLUE: PlayerId = PlayerId::new(Color::Blue as u8);
+
+    struct TestRoom {
+        room: Room,
+        clients: HashMap<PlayerId, Channel<ClientStream>>,
+        // the room takes them closed as the server shutting down
+        _shutdown: watch::Sender<bool>,
+        _forfeits: watch::Sender<BTreeSet<PlayerId>>,
+    }
+
+    /// Room of the players connected over localhost, with the client end of each.
+    async fn room(players: &[PlayerId]) -> TestRoom {
+        let policy = ReconnectPolicy {
+            timeout: Duration::ZERO,
+            terminate_abandoned: false,
+        };
+        room_with(players, policy).await
+    }
+
+    async fn room_with(players: &[PlayerId], policy: ReconnectPolicy) -> TestRoom {
+        let mut conns = vec![];
+        let mut clients = HashMap::new();
+        for (id, &player) in players.iter().enumerate() {
+            let (chan, client) = chan_pair().await;
+            let (tx, rx) = oneshot::channel();
+            _ = tx.send(chan);
+            conns.push(Connection {
+                player_id: Some(player),
+                id: id as u64,
+                chan: rx,
+                stats: Default::default(),
+                connected: true,
+            });
+            clients.insert(player, client);
+        }
+        let info = RoomInfo {
+            players: players.to_vec(),
+            ..Default::default()
+        };
+        let shutdown = watch::channel(false);
+        let forfeits = watch::channel(BTreeSet::new());
+        let room = Room::new(
+            conns,
+            info,
+            Box::new(SeededRandom::new(0)),
+            Arc::new(Reconnects::new(policy)),
+            shutdown.1,
+            forfeits.1,
+            Default::default(),
+        )
+        .await
+        .unwrap();
+
+        TestRoom {
+            room,
+            clients,
+            _shutdown: shutdown.0,
+            _forfeits: forfeits.0,
+        }
+    }
+
+    fn null() -> Box<RawValue> {
+        serde_json::value::to_raw_value(&()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn simultaneous_action_skips_filler_of_timed_out_action() {
+        let mut test = room(&[RED, BLUE]).await;
+        let mut red = test.clients.remove(&RED).unwrap();
+        let mut blue = test.clients.remove(&BLUE).unwrap();
+        let red = tokio::spawn(async move {
+            let _: serde_json::Value = red.receive().await?;
+            // the filler of the action red let time out, then the actual one
+            red.send(&()).await?;
+            red.send("red action").await?;
+            red.receive::<serde_json::Value>().await
+        });
+        let blue = tokio::spawn(async move {
+            let _: serde_json::Value = blue.receive().await?;
+            blue.send("blue action").await?;
+            blue.receive::<serde_json::Value>().await
+        });
+
+        let param = null();
+        let deadline = Duration::from_millis(50);
+        let timed = test.room.action_with_deadline(RED, &param, deadline);
+        assert!(matches!(timed.await.unwrap(), TimedAction::TimedOut));
+        let values = test.room.simultaneous_action(vec![RED, BLUE], &param);
+        let values = values.await.unwrap();
+
+        let expected = r#"{"red":"red action","blue":"blue action"}"#;
+        assert_eq!(values.get(), expected);
+        assert_eq!(red.await.unwrap().unwrap().to_string(), expected);
+        assert_eq!(blue.await.unwrap().unwrap().to_string(), expected);
+    }
+
+    #[tokio::test]
+    async fn broadcast_is_not_held_up_by_a_slow_player() {
+        let mut test = room(&[RED, BLUE]).await;
+        let mut red = test.clients.remove(&RED).unwrap();
+        let mut blue = test.clients.remove(&BLUE).unwrap();
+
+        let broadcast = test
+            .room
+            .broadcast(&[RED, BLUE], |player| format!("to {player}"));
+        let clients = async {
+            // red is first in seat order but doesn't read until blue got its message
+            let to_blue = tokio::time::timeout(Duration::from_secs(5), blue.receive::<String>())
+                .await
+                .expect("blue waits for red")?;
+            let to_red = red.receive::<String>().await?;
+            anyhow::Ok((to_red, to_blue))
+        };
+        let (sent, received) = tokio::join!(broadcast, clients);
+
+        sent.unwrap();
+        let (to_red, to_blue) = received.unwrap();
+        assert_eq!((&*to_red, &*to_blue), ("to red", "to blue"));
+    }
+
+    #[tokio::test]
+    async fn notify_reaches_only_its_targets() {
+        let mut test = room(&[RED, BLUE]).await;
+        let mut red = test.clients.remove(&RED).unwrap();
+        let mut blue = test.clients.remove(&BLUE).unwrap();
+        let red = tokio::spawn(async move {
+            let notified: String = red.receive().await?;
+            let random: i32 = red.receive().await?;
+            anyhow::Ok((notified, random))
+        });
+        let blue = tokio::spawn(async move { blue.receive::<serde_json::Value>().await });
+
+        let value = serde_json::value::to_raw_value("you drew a card").unwrap();
+        test.room.notify(vec![RED], &value).await.unwrap();
+        let random = test.room.random(1, 6).await.unwrap();
+
+        let (notified, red_random) = red.await.unwrap().unwrap();
+        assert_eq!((&*notified, red_random), ("you drew a card", random));
+        // the first message blue gets is the random everyone sees
+        assert_eq!(blue.await.unwrap().unwrap(), serde_json::json!(random));
+    }
+
+    #[tokio::test]
+    async fn waiting_on_players_gives_up_once_cancelled() {
+        let mut test = room(&[RED, BLUE]).await;
+        let cancel = CancelHandle::default();
+        test.room.cancel = cancel.clone();
+
+        let param = null();
+        let action = test.room.action(RED, &param);
+        // red never acts
+        let cancelling = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            cancel.cancel();
+        };
+        let (res, ()) = tokio::time::timeout(Duration::from_secs(5), async {
+            tokio::join!(action, cancelling)
+        })
+        .await
+        .expect("room waits on red after cancelled");
+
+        let err = res.unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<RuntimeError>(),
+            Some(&RuntimeError::Cancelled)
+        );
+    }
+
+    #[tokio::test]
+    async fn room_is_abandoned_once_every_player_disconnects() {
+        let policy = ReconnectPolicy {
+            // long enough that only abandoning the room ends the wait
+            timeout: Duration::from_secs(60),
+            terminate_abandoned: true,
+        };
+        let mut test = room_with(&[RED, BLUE], policy).await;
+        test.clients.clear();
+
+        let param = null();
+        let action = test.room.simultaneous_action(vec![RED, BLUE], &param);
+        let res = tokio::time::timeout(Duration::from_secs(5), action)
+            .await
+            .expect("room waits for players to reconnect");
+
+        let err = res.unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<RuntimeError>(),
+            Some(&RuntimeError::Abandoned)
+        );
+        assert_eq!(err.to_string(), "every player disconnected");
+    }
+
+    #[tokio::test]
+    async fn nested_task_beyond_parent_scope_names_the_player() {
+        let mut test = room(&[RED, BLUE]).await;
+
+        test.room.do_task_if(vec![RED]).await.unwrap();
+        let err = test.room.do_task_if(vec![RED, BLUE]).await.unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<RuntimeError>(),
+            Some(&RuntimeError::VisibilityViolation)
+        );
+        assert_eq!(
+            err.root_cause().to_string(),
+            "game tries to extend visibility to [blue] beyond current scope [red]"
+        );
+    }
+
+    #[test]
+    fn seat_order_is_the_same_across_sessions() {
+        let green = PlayerId::new(Color::Green as u8);
+        let players = [BLUE, green, RED];
+        // each map has its own hash seed, and so its own iteration order
+        let mut first: HashMap<_, _> = players.iter().map(|&p| (p, p.to_string())).collect();
+        let mut second: HashMap<_, _> = players.iter().rev().map(|&p| (p, p.to_string())).collect();
+
+        let order = |chans| -> Vec<PlayerId> {
+            in_seat_order(&players, chans)
+                .into_iter()
+                .map(|(p, _)| p)
+                .collect()
+        };
+        assert_eq!(order(&mut first), players);
+        assert_eq!(order(&mut second), players);
+        assert!(in_seat_order(&players, &mut first)
+            .into_iter()
+            .all(|(p, name)| *name == p.to_string()));
+    }
 }
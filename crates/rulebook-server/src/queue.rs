@@ -0,0 +1,169 @@
+use std::collections::HashMap;
+use std::sync::Mutex as StdMutex;
+
+use axum::http::StatusCode;
+use serde::Serialize;
+use tokio::sync::oneshot;
+
+use rulebook_runtime::PlayerId;
+
+use crate::{http, Server};
+
+/// Number of players matched at once for games without `min_players`.
+pub(crate) const DEFAULT_MATCH_SIZE: usize = 2;
+
+type Ticket = oneshot::Sender<Result<Seat, (StatusCode, String)>>;
+
+/// Players waiting to be matched, per game.
+#[derive(Debug, Default)]
+pub(crate) struct MatchQueue {
+    waiting: StdMutex<HashMap<String, Vec<Ticket>>>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct Seat {
+    pub room: String,
+    pub color: PlayerId,
+}
+
+impl MatchQueue {
+    /// Queues the ticket if any, and takes the first `match_size` tickets of the game
+    /// once there are as many players still waiting.
+    fn take_match(
+        &self,
+        game: &str,
+        ticket: Option<Ticket>,
+        match_size: usize,
+    ) -> Option<Vec<Ticket>> {
+        let mut waiting = self.waiting.lock().unwrap();
+        let queue = waiting.entry(game.to_owned()).or_default();
+        // players who stopped waiting
+        queue.retain(|ticket| !ticket.is_closed());
+        queue.extend(ticket);
+
+        (queue.len() >= match_size).then(|| queue.drain(..match_size).collect())
+    }
+
+    /// Returns the tickets of players still waiting to the front of the game's queue.
+    fn put_back(&self, game: &str, mut tickets: Vec<Ticket>) {
+        tickets.retain(|ticket| !ticket.is_closed());
+        let mut waiting = self.waiting.lock().unwrap();
+        let queue = waiting.entry(game.to_owned()).or_default();
+        queue.splice(..0, tickets);
+    }
+}
+
+/// Waits in the game's queue until enough players are queued, then seats them in a new room.
+///
+/// Players connect to the room with the color of their seat, and it starts once everyone does.
+pub(crate) async fn enqueue(server: &Server, game: &str) -> Result<Seat, (StatusCode, String)> {
+    let info = server.runtime.game_info(game).await.map_err(|err| {
+        (
+            StatusCode::BAD_REQUEST,
+            format!("failed to get game info: {err}"),
+        )
+    })?;
+    let match_size = match &info.roles {
+        // every seat must be taken
+        Some(roles) => roles.len(),
+        None => info.min_players.unwrap_or(DEFAULT_MATCH_SIZE),
+    }
+    .max(1);
+    let colors: Vec<_> = match info.roster {
+        Some(roster) => roster,
+        None => PlayerId::candidates(server.max_seats).collect(),
+    };
+    if colors.len() < match_size {
+        let msg = format!("game requires {match_size} players but only {colors:?} can join");
+        return Err((StatusCode::CONFLICT, msg));
+    }
+
+    let (sender, receiver) = oneshot::channel();
+    let mut ticket = Some(sender);
+    while let Some(tickets) = server.queue.take_match(game, ticket.take(), match_size) {
+        let seats = colors[..match_size].to_vec();
+        let seated = http::create_room(server, game, None, None, seats.clone()).await;
+
+        // players may stop waiting while the room is created, the others keep waiting
+        // rather than taking seats of a room which never fills
+        if let Ok(room) = &seated {
+            if tickets.iter().any(Ticket::is_closed) {
+                server.rooms.write().unwrap().remove(room);
+                server.queue.put_back(game, tickets);
+                continue;
+            }
+        }
+
+        for (idx, ticket) in tickets.into_iter().enumerate() {
+            let seat = seated.clone().map(|room| Seat {
+                room,
+                color: seats[idx],
+            });
+            // player might have stopped waiting meanwhile
+            _ = ticket.send(seat);
+        }
+        break;
+    }
+
+    receiver.await.unwrap_or_else(|_| {
+        Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "matchmaking dropped the ticket".into(),
+        ))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use rulebook_runtime::test_games::{game, SESSION_END, SESSION_START};
+
+    use super::*;
+    use crate::test_util::server;
+
+    #[tokio::test]
+    async fn queued_players_are_seated_in_one_room() {
+        let server = server(&[("game", &game(&[SESSION_START, SESSION_END]))]);
+
+        // stops waiting before anyone else queues
+        let gone = tokio::time::timeout(Duration::from_millis(20), enqueue(&server, "game")).await;
+        assert!(gone.is_err(), "matched alone: {gone:?}");
+
+        let (red, blue) = tokio::join!(enqueue(&server, "game"), enqueue(&server, "game"));
+        let (red, blue) = (red.unwrap(), blue.unwrap());
+        assert_eq!(red.room, blue.room);
+        assert_ne!(red.color, blue.color);
+        assert_eq!(server.rooms.read().unwrap().len(), 1);
+        assert!(server.queue.waiting.lock().unwrap()["game"].is_empty());
+    }
+
+    #[test]
+    fn tickets_put_back_wait_ahead_of_later_ones() {
+        let queue = MatchQueue::default();
+        let (first, mut first_rx) = oneshot::channel();
+        let (gone, gone_rx) = oneshot::channel();
+        let (later, mut later_rx) = oneshot::channel();
+        let tickets = queue.take_match("game", Some(first), 1).unwrap();
+        assert!(queue.take_match("game", Some(gone), 2).is_none());
+        drop(gone_rx);
+
+        queue.put_back("game", tickets);
+        let matched = queue.take_match("game", Some(later), 2).unwrap();
+        for (ticket, color) in matched.into_iter().zip([1, 2]) {
+            let seat = Seat {
+                room: "room".into(),
+                color: PlayerId::new(color),
+            };
+            ticket.send(Ok(seat)).unwrap();
+        }
+        assert_eq!(
+            first_rx.try_recv().unwrap().unwrap().color,
+            PlayerId::new(1)
+        );
+        assert_eq!(
+            later_rx.try_recv().unwrap().unwrap().color,
+            PlayerId::new(2)
+        );
+    }
+}
@@ -0,0 +1,94 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex as StdMutex;
+use std::time::Instant;
+
+use axum::http::StatusCode;
+
+/// Number of clients tracked before forgetting the ones whose bucket refilled.
+const PRUNE_THRESHOLD: usize = 10_000;
+
+/// Token bucket per client IP, each holds up to `per_min` tokens and refills at that rate.
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    per_min: u32,
+    buckets: StdMutex<HashMap<IpAddr, Bucket>>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(per_min: u32) -> Self {
+        RateLimiter {
+            per_min,
+            buckets: Default::default(),
+        }
+    }
+
+    /// Takes a token of the client, fails with `429 Too Many Requests` if it has none.
+    pub fn check(&self, ip: IpAddr) -> Result<(), (StatusCode, String)> {
+        let now = Instant::now();
+        let capacity = f64::from(self.per_min);
+        let refill = |bucket: &Bucket| {
+            let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+            (bucket.tokens + elapsed * capacity / 60.0).min(capacity)
+        };
+
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= PRUNE_THRESHOLD {
+            // full buckets are the same as new ones
+            buckets.retain(|_, bucket| refill(bucket) < capacity);
+        }
+
+        let bucket = buckets.entry(ip).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+        });
+        bucket.tokens = refill(bucket);
+        bucket.updated = now;
+
+        if bucket.tokens < 1.0 {
+            let msg = format!("rate limited to {} requests per minute", self.per_min);
+            return Err((StatusCode::TOO_MANY_REQUESTS, msg));
+        }
+        bucket.tokens -= 1.0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn rejects_client_over_the_limit_until_refilled() {
+        let limiter = RateLimiter::new(2);
+        let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let other = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+
+        assert!(limiter.check(ip).is_ok());
+        assert!(limiter.check(ip).is_ok());
+        let (status, _) = limiter.check(ip).unwrap_err();
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        assert!(limiter.check(other).is_ok(), "each client has own bucket");
+
+        // half a minute refills a token of two per minute
+        limiter
+            .buckets
+            .lock()
+            .unwrap()
+            .get_mut(&ip)
+            .unwrap()
+            .updated -= Duration::from_secs(30);
+        assert!(limiter.check(ip).is_ok());
+        let (status, _) = limiter.check(ip).unwrap_err();
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    }
+}
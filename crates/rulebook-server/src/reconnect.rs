@@ -0,0 +1,154 @@
+//! Players reconnecting mid-game with the token issued on their first connect.
+//!
+//! When a player's connection fails, the room waits for the player to connect again
+//! to `/room/:id/reconnect` and swaps the new websocket into the player's `Channel`.
+//! Both sides then `Channel::resume`, exchanging the id of the last message they received.
+//! Since `Channel::send` waits for the ack, at most the last message of each side may be lost,
+//! which is sent again only if the other side reports it hasn't received it.
+//! Messages received twice are acked again and dropped, so the game sees each message once.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex as StdMutex;
+use std::time::Duration;
+
+use anyhow::Result;
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::sync::Notify;
+
+use rulebook_runtime::{channel::Channel, PlayerId, RuntimeError};
+
+use crate::websocket::WebSocketStream;
+
+/// What to do when players disconnect mid-game.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ReconnectPolicy {
+    /// Time to wait for a player to reconnect before failing the session.
+    pub timeout: Duration,
+    /// End the session with `RuntimeError::Abandoned` as soon as every player is known
+    /// to be disconnected, rather than waiting for them to reconnect.
+    ///
+    /// Connections are found dead only when the room talks to them, so players dropped
+    /// while the room waits for another one aren't counted until it talks to them.
+    pub terminate_abandoned: bool,
+}
+
+/// Websockets of reconnected players, until their room picks them up.
+#[derive(Debug)]
+pub(crate) struct Reconnects {
+    policy: ReconnectPolicy,
+    pending: StdMutex<HashMap<PlayerId, WebSocketStream>>,
+    notify: Notify,
+    /// Players whose connection failed and haven't resumed yet.
+    disconnected: StdMutex<HashSet<PlayerId>>,
+}
+
+impl Reconnects {
+    pub fn new(policy: ReconnectPolicy) -> Self {
+        Reconnects {
+            policy,
+            pending: Default::default(),
+            notify: Notify::new(),
+            disconnected: Default::default(),
+        }
+    }
+
+    /// Stops counting the player who is never coming back, like the one forfeited.
+    pub fn forget(&self, player: PlayerId) {
+        self.pending.lock().unwrap().remove(&player);
+        self.disconnected.lock().unwrap().remove(&player);
+    }
+
+    pub fn offer(&self, player: PlayerId, ws: WebSocketStream) {
+        // replaces the previous one if the player reconnected again before picked up
+        self.pending.lock().unwrap().insert(player, ws);
+        self.notify.notify_waiters();
+    }
+
+    async fn wait(&self, player: PlayerId) -> Option<WebSocketStream> {
+        let wait = async {
+            loop {
+                let notified = self.notify.notified();
+                tokio::pin!(notified);
+                notified.as_mut().enable();
+
+                if let Some(ws) = self.pending.lock().unwrap().remove(&player) {
+                    return ws;
+                }
+                notified.await;
+            }
+        };
+
+        tokio::time::timeout(self.policy.timeout, wait).await.ok()
+    }
+
+    /// Swaps the player's reconnected websocket into the channel after it failed with `err`.
+    async fn recover(
+        &self,
+        player: PlayerId,
+        chan: &mut Channel<WebSocketStream>,
+        player_count: usize,
+        err: anyhow::Error,
+    ) -> Result<()> {
+        tracing::warn!("player {player} connection lost, waiting reconnect: {err:?}");
+
+        let disconnected = {
+            let mut disconnected = self.disconnected.lock().unwrap();
+            disconnected.insert(player);
+            disconnected.len()
+        };
+        if self.policy.terminate_abandoned && disconnected >= player_count {
+            return Err(err.context(RuntimeError::Abandoned));
+        }
+
+        loop {
+            let Some(ws) = self.wait(player).await else {
+                let msg = format!(
+                    "player {player} didn't reconnect in {:?}",
+                    self.policy.timeout
+                );
+                return Err(err.context(msg));
+            };
+            chan.replace_inner(ws);
+
+            match chan.resume().await {
+                Ok(()) => {
+                    tracing::info!("player {player} reconnected");
+                    self.disconnected.lock().unwrap().remove(&player);
+                    return Ok(());
+                }
+                Err(err) => tracing::warn!("player {player} resume failed: {err:?}"),
+            }
+        }
+    }
+
+    /// `Channel::send` which survives the player reconnecting.
+    pub async fn send<M: Serialize + ?Sized>(
+        &self,
+        player: PlayerId,
+        chan: &mut Channel<WebSocketStream>,
+        player_count: usize,
+        msg: &M,
+    ) -> Result<()> {
+        if let Err(err) = chan.send(msg).await {
+            // resume completes the failed send
+            self.recover(player, chan, player_count, err).await?;
+        }
+
+        Ok(())
+    }
+
+    /// `Channel::receive` which survives the player reconnecting.
+    pub async fn receive<M: DeserializeOwned>(
+        &self,
+        player: PlayerId,
+        chan: &mut Channel<WebSocketStream>,
+        player_count: usize,
+    ) -> Result<M> {
+        loop {
+            match chan.receive().await {
+                Ok(msg) => return Ok(msg),
+                Err(err) => self.recover(player, chan, player_count, err).await?,
+            }
+        }
+    }
+}
@@ -0,0 +1,198 @@
+//! Websockets for tests, connected over localhost since axum hands them out only on upgrades.
+
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex as StdMutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use anyhow::Result;
+use axum::extract::ws::{WebSocket, WebSocketUpgrade};
+use axum::http::StatusCode;
+use axum::routing::get;
+use axum::Router;
+use futures::{ready, sink::Sink, stream::Stream};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::{broadcast, oneshot, watch};
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::handshake::client::Response;
+use tokio_tungstenite::tungstenite::http::header::SEC_WEBSOCKET_PROTOCOL;
+use tokio_tungstenite::tungstenite::protocol::Message;
+use tokio_tungstenite::MaybeTlsStream;
+
+use rulebook_runtime::channel::{Channel, DEFAULT_MAX_FRAME_BYTES};
+use rulebook_runtime::{ws_protocol, PlayerId, Runtime};
+
+use crate::auth::AllowAll;
+use crate::reconnect::ReconnectPolicy;
+use crate::websocket::{ConnStats, WebSocketStream};
+use crate::{events, http, Server};
+
+pub(crate) type ClientWs = tokio_tungstenite::WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// Server end of a fresh websocket and its client end.
+pub(crate) async fn ws_pair() -> (WebSocket, ClientWs) {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (tx, rx) = oneshot::channel();
+    let tx = Arc::new(StdMutex::new(Some(tx)));
+    let app = Router::new().route(
+        "/",
+        get(|ws: WebSocketUpgrade| async move {
+            ws.on_upgrade(move |ws| async move {
+                if let Some(tx) = tx.lock().unwrap().take() {
+                    _ = tx.send(ws);
+                }
+            })
+        }),
+    );
+    let server = axum::Server::from_tcp(listener).unwrap();
+    tokio::spawn(server.serve(app.into_make_service()));
+
+    let (client, _) = tokio_tungstenite::connect_async(format!("ws://{addr}/"))
+        .await
+        .unwrap();
+    (rx.await.unwrap(), client)
+}
+
+/// Server of the games in WAT keyed by their names, with the defaults of the command line.
+pub(crate) fn server(games: &[(&str, &str)]) -> Server {
+    let runtime = Runtime::new(rulebook_runtime::Config {
+        enable_state: true,
+        ..Default::default()
+    })
+    .unwrap();
+    for (key, code) in games {
+        runtime.add_game((*key).into(), code.as_bytes()).unwrap();
+    }
+
+    Server {
+        runtime,
+        rooms: Default::default(),
+        events: broadcast::channel(events::EVENT_BUFFER).0,
+        error_counts: Default::default(),
+        keepalive: None,
+        compress: false,
+        max_seats: PlayerId::DEFAULT_COUNT,
+        queue: Default::default(),
+        reconnect: ReconnectPolicy {
+            timeout: Duration::from_secs(30),
+            terminate_abandoned: false,
+        },
+        shutdown: watch::channel(false).0,
+        shutdown_timeout: Duration::from_secs(30),
+        room_idle_timeout: Duration::from_secs(600),
+        max_rooms: None,
+        room_limiter: None,
+        connect_limiter: None,
+        authenticator: Box::new(AllowAll),
+    }
+}
+
+/// Serves every endpoint of the server over localhost, returns its address.
+pub(crate) fn serve(server: Server) -> SocketAddr {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let app = http::router(&Arc::new(server));
+    let server = axum::Server::from_tcp(listener).unwrap();
+    tokio::spawn(server.serve(app.into_make_service_with_connect_info::<SocketAddr>()));
+    addr
+}
+
+/// Sends a request with the JSON body over a fresh connection,
+/// returns the status and the body of the response.
+pub(crate) async fn request(
+    addr: SocketAddr,
+    method: &str,
+    path: &str,
+    body: &str,
+) -> (StatusCode, String) {
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let req = format!(
+        "{method} {path} HTTP/1.1\r\nhost: {addr}\r\ncontent-type: application/json\r\n\
+        content-length: {}\r\nconnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(req.as_bytes()).await.unwrap();
+    let mut res = String::new();
+    stream.read_to_string(&mut res).await.unwrap();
+
+    let (head, body) = res.split_once("\r\n\r\n").expect("response has no body");
+    let status = head.split(' ').nth(1).expect("response has no status");
+    (
+        StatusCode::from_bytes(status.as_bytes()).unwrap(),
+        body.into(),
+    )
+}
+
+/// Connects to the endpoint speaking the protocol of the server,
+/// the error carries the response if the server refused it.
+pub(crate) async fn connect(
+    addr: SocketAddr,
+    path: &str,
+) -> tokio_tungstenite::tungstenite::Result<(ClientWs, Response)> {
+    let mut req = format!("ws://{addr}{path}").into_client_request()?;
+    req.headers_mut()
+        .insert(SEC_WEBSOCKET_PROTOCOL, ws_protocol().parse().unwrap());
+    tokio_tungstenite::connect_async(req).await
+}
+
+/// Channels of both ends of a fresh websocket, the server's one without compression.
+pub(crate) async fn chan_pair() -> (Channel<WebSocketStream>, Channel<ClientStream>) {
+    let (ws, client) = ws_pair().await;
+    let stats = Arc::new(ConnStats::default());
+    (
+        Channel::new(WebSocketStream::new(
+            ws,
+            stats,
+            false,
+            DEFAULT_MAX_FRAME_BYTES,
+        )),
+        Channel::new(ClientStream(client)),
+    )
+}
+
+/// Client end as a `Channel` transport, like the test client's without compression.
+#[derive(Debug)]
+pub(crate) struct ClientStream(pub ClientWs);
+
+impl Stream for ClientStream {
+    type Item = Result<Vec<u8>>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            match ready!(Pin::new(&mut self.0).poll_next(cx)?) {
+                Some(Message::Text(msg)) => return Poll::Ready(Some(Ok(msg.into_bytes()))),
+                Some(Message::Binary(bytes)) => return Poll::Ready(Some(Ok(bytes))),
+                // tungstenite answers pings by itself
+                Some(Message::Ping(_) | Message::Pong(_) | Message::Frame(_)) => {}
+                Some(Message::Close(_)) | None => return Poll::Ready(None),
+            }
+        }
+    }
+}
+
+impl Sink<Vec<u8>> for ClientStream {
+    type Error = anyhow::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.0).poll_ready(cx).map_err(Into::into)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: Vec<u8>) -> Result<()> {
+        let msg = match String::from_utf8(item) {
+            Ok(text) => Message::Text(text),
+            Err(err) => Message::Binary(err.into_bytes()),
+        };
+        Pin::new(&mut self.0).start_send(msg).map_err(Into::into)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx).map_err(Into::into)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.0).poll_close(cx).map_err(Into::into)
+    }
+}
@@ -1,43 +1,120 @@
 use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 use std::task::{Context, Poll};
 
 use anyhow::Result;
 use axum::extract::ws::{Message, WebSocket};
 use futures::{ready, sink::Sink, stream::Stream};
 
+use rulebook_runtime::compress;
+
 #[derive(Debug)]
 pub struct WebSocketStream {
     ws: WebSocket,
+    stats: Arc<ConnStats>,
+    /// Deflate frames sent, received ones are inflated regardless.
+    compress: bool,
+    /// Received frames fail to inflate beyond it.
+    max_frame_bytes: usize,
+}
+
+/// Traffic of a connection, counted on message frame payloads as on the wire.
+#[derive(Debug, Default)]
+pub struct ConnStats {
+    pub bytes_sent: AtomicU64,
+    pub bytes_received: AtomicU64,
+    /// Bytes of frames before deflated, same as `bytes_sent` without compression.
+    pub payload_bytes_sent: AtomicU64,
+    /// Bytes of frames after inflated, same as `bytes_received` without compression.
+    pub payload_bytes_received: AtomicU64,
+    pub frames_sent: AtomicU64,
+    pub frames_received: AtomicU64,
+}
+
+impl ConnStats {
+    /// Bytes on the wire per byte of frames both ways, `None` before any traffic.
+    pub fn compression_ratio(&self) -> Option<f64> {
+        let wire =
+            self.bytes_sent.load(Ordering::Relaxed) + self.bytes_received.load(Ordering::Relaxed);
+        let payload = self.payload_bytes_sent.load(Ordering::Relaxed)
+            + self.payload_bytes_received.load(Ordering::Relaxed);
+        (payload > 0).then(|| wire as f64 / payload as f64)
+    }
 }
 
 impl WebSocketStream {
-    pub fn new(ws: WebSocket) -> Self {
-        WebSocketStream { ws }
+    pub fn new(
+        ws: WebSocket,
+        stats: Arc<ConnStats>,
+        compress: bool,
+        max_frame_bytes: usize,
+    ) -> Self {
+        WebSocketStream {
+            ws,
+            stats,
+            compress,
+            max_frame_bytes,
+        }
+    }
+
+    fn received(&self, msg: Vec<u8>) -> Result<Vec<u8>> {
+        self.stats.frames_received.fetch_add(1, Ordering::Relaxed);
+        self.stats
+            .bytes_received
+            .fetch_add(msg.len() as u64, Ordering::Relaxed);
+        let frame = compress::inflate(msg, self.max_frame_bytes)?;
+        self.stats
+            .payload_bytes_received
+            .fetch_add(frame.len() as u64, Ordering::Relaxed);
+        Ok(frame)
     }
 }
 
 impl Stream for WebSocketStream {
-    type Item = Result<String>;
+    type Item = Result<Vec<u8>>;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        match ready!(Pin::new(&mut self.ws).poll_next(cx)?) {
-            Some(Message::Text(msg)) => Poll::Ready(Some(Ok(msg))),
-            Some(_) => Poll::Pending,
-            None => Poll::Ready(None),
+        loop {
+            match ready!(Pin::new(&mut self.ws).poll_next(cx)?) {
+                Some(Message::Text(msg)) => {
+                    return Poll::Ready(Some(self.received(msg.into_bytes())))
+                }
+                // the codec tells them apart, not the framing
+                Some(Message::Binary(bytes)) => return Poll::Ready(Some(self.received(bytes))),
+                // pong is queued by the websocket, flush to send it without waiting for next send
+                Some(Message::Ping(_)) => {
+                    if let Poll::Ready(Err(err)) = Pin::new(&mut self.ws).poll_flush(cx) {
+                        return Poll::Ready(Some(Err(err.into())));
+                    }
+                }
+                Some(Message::Pong(_)) => {}
+                Some(Message::Close(_)) | None => return Poll::Ready(None),
+            }
         }
     }
 }
 
-impl Sink<String> for WebSocketStream {
+impl Sink<Vec<u8>> for WebSocketStream {
     type Error = anyhow::Error;
 
     fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
         Pin::new(&mut self.ws).poll_ready(cx).map_err(Into::into)
     }
 
-    fn start_send(mut self: Pin<&mut Self>, item: String) -> Result<()> {
+    fn start_send(mut self: Pin<&mut Self>, mut item: Vec<u8>) -> Result<()> {
+        self.stats
+            .payload_bytes_sent
+            .fetch_add(item.len() as u64, Ordering::Relaxed);
+        if self.compress {
+            item = compress::deflate(item);
+        }
+        self.stats.frames_sent.fetch_add(1, Ordering::Relaxed);
+        self.stats
+            .bytes_sent
+            .fetch_add(item.len() as u64, Ordering::Relaxed);
         Pin::new(&mut self.ws)
-            .start_send(Message::Text(item))
+            .start_send(frame_message(item))
             .map_err(Into::into)
     }
 
@@ -49,3 +126,116 @@ impl Sink<String> for WebSocketStream {
         Pin::new(&mut self.ws).poll_close(cx).map_err(Into::into)
     }
 }
+
+/// Texts like JSON go as text frames as browsers expect, binary codecs as binary frames.
+fn frame_message(frame: Vec<u8>) -> Message {
+    match String::from_utf8(frame) {
+        Ok(text) => Message::Text(text),
+        Err(err) => Message::Binary(err.into_bytes()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::{SinkExt, StreamExt};
+    use tokio_tungstenite::tungstenite::protocol::Message as ClientMessage;
+
+    use rulebook_runtime::channel::DEFAULT_MAX_FRAME_BYTES;
+
+    use super::*;
+    use crate::test_util::ws_pair;
+
+    async fn pair() -> (WebSocketStream, crate::test_util::ClientWs) {
+        let (ws, client) = ws_pair().await;
+        (
+            WebSocketStream::new(ws, Default::default(), false, DEFAULT_MAX_FRAME_BYTES),
+            client,
+        )
+    }
+
+    #[tokio::test]
+    async fn binary_frames_go_both_ways() {
+        let (mut server, mut client) = pair().await;
+        // like a MessagePack map, not valid utf-8
+        let frame = vec![0x81, 0xa2, b'i', b'd', 0x07];
+
+        client
+            .send(ClientMessage::Binary(frame.clone()))
+            .await
+            .unwrap();
+        assert_eq!(server.next().await.unwrap().unwrap(), frame);
+
+        server.send(frame.clone()).await.unwrap();
+        server.send(b"{}".to_vec()).await.unwrap();
+        assert_eq!(
+            client.next().await.unwrap().unwrap(),
+            ClientMessage::Binary(frame)
+        );
+        assert_eq!(
+            client.next().await.unwrap().unwrap(),
+            ClientMessage::Text("{}".into())
+        );
+        assert_eq!(server.stats.frames_received.load(Ordering::Relaxed), 1);
+        assert_eq!(server.stats.frames_sent.load(Ordering::Relaxed), 2);
+
+        client.send(ClientMessage::Close(None)).await.unwrap();
+        assert!(server.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn compressed_traffic_is_counted_both_before_and_after() {
+        let (ws, mut client) = ws_pair().await;
+        let stats = Arc::new(ConnStats::default());
+        let mut server = WebSocketStream::new(ws, stats.clone(), true, DEFAULT_MAX_FRAME_BYTES);
+        let frame = format!(r#"{{"deck":[{}]}}"#, "1,".repeat(1000)).into_bytes();
+        assert_eq!(stats.compression_ratio(), None);
+
+        server.send(frame.clone()).await.unwrap();
+        let deflated = client.next().await.unwrap().unwrap().into_data();
+        assert_eq!(
+            compress::inflate(deflated.clone(), DEFAULT_MAX_FRAME_BYTES).unwrap(),
+            frame
+        );
+        client
+            .send(ClientMessage::Binary(deflated.clone()))
+            .await
+            .unwrap();
+        assert_eq!(server.next().await.unwrap().unwrap(), frame);
+
+        let wire = deflated.len() as u64;
+        let payload = frame.len() as u64;
+        assert_eq!(stats.bytes_sent.load(Ordering::Relaxed), wire);
+        assert_eq!(stats.bytes_received.load(Ordering::Relaxed), wire);
+        assert_eq!(stats.payload_bytes_sent.load(Ordering::Relaxed), payload);
+        assert_eq!(
+            stats.payload_bytes_received.load(Ordering::Relaxed),
+            payload
+        );
+        let ratio = stats.compression_ratio().unwrap();
+        assert!(ratio < 0.1, "repetitive frame should shrink, ratio {ratio}");
+    }
+
+    #[tokio::test]
+    async fn ping_is_answered_while_waiting_for_messages() {
+        let (mut server, mut client) = pair().await;
+        let server = tokio::spawn(async move { server.next().await.map(|res| res.unwrap()) });
+
+        client
+            .send(ClientMessage::Ping(b"ping".to_vec()))
+            .await
+            .unwrap();
+        // the server never sends anything itself
+        let pong = tokio::time::timeout(Duration::from_secs(5), client.next())
+            .await
+            .expect("ping not answered");
+        assert_eq!(
+            pong.unwrap().unwrap(),
+            ClientMessage::Pong(b"ping".to_vec())
+        );
+
+        client.send(ClientMessage::Close(None)).await.unwrap();
+        assert_eq!(server.await.unwrap(), None);
+    }
+}
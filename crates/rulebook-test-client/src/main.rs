@@ -1,12 +1,18 @@
 use std::path::PathBuf;
+use std::time::Duration;
 
 use anyhow::{Context, Result};
 use clap::Parser;
+use serde::de::DeserializeOwned;
 use serde_json::value::RawValue;
 use tokio_tungstenite::connect_async;
+use tokio_tungstenite::tungstenite::http::header::AUTHORIZATION;
+use tracing_subscriber::EnvFilter;
 
 use rulebook_runtime::{
-    channel::Channel, Config, OutputHandler, PlayerId, Runtime, SessionInfo, TaskResult,
+    channel::{Channel, DEFAULT_MAX_FRAME_BYTES},
+    Config, EndReason, LobbyMessage, LobbyRequest, OutputHandler, PlayerId, Runtime, SessionInfo,
+    TaskResult, TimedAction, RECONNECT_TOKEN_HEADER,
 };
 
 mod websocket;
@@ -17,26 +23,145 @@ struct Args {
     game: PathBuf,
     #[arg(short, long)]
     addr: String,
-    #[arg(short, long)]
-    player: PlayerId,
+    #[arg(short, long, required_unless_present_any = ["spectator", "watch"])]
+    player: Option<PlayerId>,
+    /// Watch the game without playing.
+    #[arg(long, conflicts_with = "player")]
+    spectator: bool,
+    /// Prints the state of the running session as it changes, without running the game.
+    ///
+    /// Unlike a spectator it can join after the session has started.
+    #[arg(long, conflicts_with_all = ["player", "spectator"])]
+    watch: bool,
+    /// Max number of state updates printed per second, excess are coalesced.
+    #[arg(long)]
+    state_rate_limit: Option<u32>,
+    /// Sent as `Authorization: Bearer <token>` on connect, for servers run with `--auth-tokens`.
+    #[arg(long)]
+    auth_token: Option<String>,
+    /// Token printed on the previous connect, to take the same seat again.
+    #[arg(long, requires = "player")]
+    reconnect_token: Option<String>,
+    /// Reads actions from the file instead of stdin, one JSON per line.
+    ///
+    /// Blank lines are skipped. The client exits once the game asks for more actions.
+    #[arg(long)]
+    script: Option<PathBuf>,
+    /// Asks the server to deflate large frames, see `rulebook_runtime::compress`.
+    #[arg(long)]
+    compress: bool,
+    /// Tells others in the room the player is ready, once connected.
+    #[arg(long, requires = "player")]
+    ready: bool,
+}
+
+/// Every action given is taken, by stdin closed or by the end of the script.
+#[derive(Debug)]
+struct InputExhausted;
+
+impl std::fmt::Display for InputExhausted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("no more actions to input")
+    }
+}
+
+impl std::error::Error for InputExhausted {}
+
+/// Used without `RUST_LOG`, keeps wasmtime's compilation logs out.
+const DEFAULT_LOG_FILTER: &str = "warn,rulebook_test_client=info,rulebook_runtime=info,guest=info";
+
+/// Streams the state from the `/watch` route next to the room's `/connect` one.
+async fn watch(addr: &str) -> Result<()> {
+    use futures::StreamExt;
+    use tokio_tungstenite::tungstenite::protocol::Message;
+
+    let url = addr
+        .strip_suffix("/connect")
+        .map(|room| format!("{room}/watch"))
+        .with_context(|| format!("not a room connect url: {addr}"))?;
+    let (mut ws, _resp) = connect_async(url).await.context("ws connect failed")?;
+    while let Some(msg) = ws.next().await {
+        if let Message::Text(state) = msg.context("ws receive failed")? {
+            println!("STATE: {state}");
+        }
+    }
+
+    Ok(())
+}
+
+/// Prints who's in the room until it starts, then takes the start.
+async fn wait_start(
+    chan: &mut Channel<websocket::WebSocketStream>,
+    ready: bool,
+) -> Result<SessionInfo> {
+    if ready {
+        chan.send(&LobbyRequest::Ready(true)).await?;
+    }
+    loop {
+        match chan.receive().await? {
+            LobbyMessage::Update(update) => {
+                println!(
+                    "LOBBY: players {:?}, ready {:?}",
+                    update.players, update.ready
+                );
+            }
+            LobbyMessage::Start(info) => {
+                chan.send(&LobbyRequest::Start).await?;
+                return Ok(info);
+            }
+        }
+    }
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let args = Args::parse();
+    // stdout is for the game, logs go to stderr
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            EnvFilter::try_from_default_env()
+                .unwrap_or_else(|_| EnvFilter::new(DEFAULT_LOG_FILTER)),
+        )
+        .with_writer(std::io::stderr)
+        .init();
+
+    if args.watch {
+        return watch(&args.addr).await;
+    }
 
     let (sender, receiver) = async_channel::unbounded();
-    std::thread::spawn(move || {
-        use std::io::BufRead;
+    match &args.script {
+        Some(script) => {
+            let content = std::fs::read_to_string(script)
+                .with_context(|| format!("failed to read script {}", script.display()))?;
+            for (idx, line) in content.lines().enumerate() {
+                if line.trim().is_empty() {
+                    continue;
+                }
+                // fail now rather than in the middle of the game
+                serde_json::from_str::<serde::de::IgnoredAny>(line)
+                    .with_context(|| format!("invalid JSON on line {} of the script", idx + 1))?;
+                sender.try_send(line.to_owned())?;
+            }
+            // closed once drained
+            drop(sender);
+        }
+        None => {
+            std::thread::spawn(move || {
+                use std::io::BufRead;
 
-        for line in std::io::stdin().lock().lines() {
-            sender.send_blocking(line.unwrap()).unwrap();
+                for line in std::io::stdin().lock().lines() {
+                    sender.send_blocking(line.unwrap()).unwrap();
+                }
+            });
         }
-    });
+    }
 
     let runtime = Runtime::new(Config {
         enable_state: true,
         enable_logging: true,
+        state_rate_limit: args.state_rate_limit,
+        ..Default::default()
     })?;
 
     let game_name = args
@@ -52,15 +177,53 @@ async fn main() -> Result<()> {
     runtime.add_game(game_name.into(), &std::fs::read(&args.game)?)?;
 
     // TODO: use url crate
-    let addr = format!("{}?color={}", args.addr, args.player);
-    let (ws, _resp) = connect_async(addr).await.context("ws connect failed")?;
+    let addr = match (args.player, &args.reconnect_token) {
+        (Some(player), Some(token)) => format!("{}?color={player}&token={token}", args.addr),
+        (Some(player), None) => format!("{}?color={player}", args.addr),
+        (None, _) => format!("{}?spectator=true", args.addr),
+    };
+    let mut req = websocket::request(&addr, args.compress)?;
+    if let Some(token) = &args.auth_token {
+        req.headers_mut()
+            .insert(AUTHORIZATION, format!("Bearer {token}").parse()?);
+    }
+    let (ws, _resp) = connect_async(req).await.context("ws connect failed")?;
     anyhow::ensure!(_resp.status().as_u16() < 300, "err resp: {_resp:?}");
-    let mut chan = Channel::new(websocket::WebSocketStream::new(ws));
+    let mut reconnect_url = None;
+    if let Some(token) = _resp.headers().get(RECONNECT_TOKEN_HEADER) {
+        let token = token.to_str()?;
+        println!("RECONNECT TOKEN: {token}");
+        reconnect_url = args
+            .addr
+            .strip_suffix("/connect")
+            .map(|room| format!("{room}/reconnect?token={token}"));
+    }
+    let mut chan = Channel::new(websocket::WebSocketStream::new(
+        ws,
+        &_resp,
+        DEFAULT_MAX_FRAME_BYTES,
+    ));
 
-    let session_info: SessionInfo = chan.receive().await?;
+    let session_info = match wait_start(&mut chan, args.ready).await {
+        Ok(info) => info,
+        Err(err) => {
+            // like when the room expired before it started
+            for notice in chan.take_notices() {
+                println!("NOTICE: {notice}");
+            }
+            return Err(err);
+        }
+    };
+
+    if let Some(role) = session_info
+        .player
+        .and_then(|player| session_info.room.role(player))
+    {
+        println!("ROLE: {role}");
+    }
 
     let mut session = runtime.new_session(game_name).await?;
-    session
+    let res = session
         .start(
             16 * 1024,
             true,
@@ -69,18 +232,105 @@ async fn main() -> Result<()> {
                 player_id: session_info.player,
                 chan,
                 receiver,
+                reconnect_url,
+                compress: args.compress,
             },
         )
-        .await?;
-
-    Ok(())
+        .await;
+    match res {
+        Err(err) if err.downcast_ref::<InputExhausted>().is_some() => {
+            println!("INPUT EXHAUSTED");
+            Ok(())
+        }
+        res => res,
+    }
 }
 
 #[derive(Debug)]
 struct Agent {
-    player_id: PlayerId,
+    /// `None` for spectators.
+    player_id: Option<PlayerId>,
     chan: Channel<websocket::WebSocketStream>,
     receiver: async_channel::Receiver<String>,
+    /// Where to connect again when the connection fails mid-game.
+    reconnect_url: Option<String>,
+    /// Whether to ask for compression, on reconnect too.
+    compress: bool,
+}
+
+impl Agent {
+    /// Next line of the input, fails with `InputExhausted` once there's no more.
+    async fn next_line(receiver: &async_channel::Receiver<String>) -> Result<String> {
+        receiver
+            .recv()
+            .await
+            .map_err(|_| anyhow::Error::new(InputExhausted))
+    }
+
+    /// Connects again after the connection failed with `err`, and resumes the channel.
+    async fn reconnect(&mut self, err: anyhow::Error) -> Result<()> {
+        // like why the server dropped us
+        for notice in self.chan.take_notices() {
+            println!("NOTICE: {notice}");
+        }
+        // nothing to come back to
+        if self.chan.end_reason().is_some() {
+            return Err(err.context("session ended by the server"));
+        }
+        let Some(url) = &self.reconnect_url else {
+            return Err(err);
+        };
+        tracing::warn!("connection lost, reconnecting: {err:?}");
+
+        let (ws, resp) = connect_async(websocket::request(url, self.compress)?)
+            .await
+            .context("ws reconnect failed")?;
+        anyhow::ensure!(resp.status().as_u16() < 300, "err resp: {resp:?}");
+        self.chan.replace_inner(websocket::WebSocketStream::new(
+            ws,
+            &resp,
+            DEFAULT_MAX_FRAME_BYTES,
+        ));
+        self.chan.resume().await
+    }
+
+    async fn send(&mut self, msg: &RawValue) -> Result<()> {
+        if let Err(err) = self.chan.send(msg).await {
+            // resume completes the failed send
+            self.reconnect(err).await?;
+        }
+        Ok(())
+    }
+
+    async fn receive<M: DeserializeOwned>(&mut self) -> Result<M> {
+        let res = self.chan.receive().await;
+        // notices may come right before the server goes away
+        for notice in self.chan.take_notices() {
+            println!("NOTICE: {notice}");
+        }
+
+        match res {
+            Ok(msg) => Ok(msg),
+            Err(err) => {
+                self.reconnect(err).await?;
+                self.chan.receive().await
+            }
+        }
+    }
+
+    /// Reads a line from stdin, answering server pings meanwhile.
+    async fn read_input(&mut self) -> Result<String> {
+        let ticks = async {
+            loop {
+                self.chan.tick().await?;
+            }
+        };
+
+        tokio::select! {
+            line = Self::next_line(&self.receiver) => line,
+            res = ticks => res,
+        }
+    }
 }
 
 #[async_trait::async_trait]
@@ -90,14 +340,34 @@ impl OutputHandler for Agent {
         Ok(())
     }
 
+    fn private_state(&mut self, name: &str, target: PlayerId, json: &RawValue) -> Result<()> {
+        // every copy of the game sends it, only the target's is meaningful
+        if Some(target) == self.player_id {
+            println!("PRIVATE STATE {name}: {json}");
+        }
+        Ok(())
+    }
+
+    fn game_result(&mut self, json: &RawValue) -> Result<()> {
+        println!("RESULT: {json}");
+        Ok(())
+    }
+
+    async fn session_end(&mut self, reason: &EndReason) -> Result<()> {
+        // the server knows better why a session it aborted ended
+        let reason = self.chan.end_reason().unwrap_or(reason);
+        println!("SESSION END: {reason}");
+        Ok(())
+    }
+
     async fn do_task_if(&mut self, targets: Vec<PlayerId>) -> Result<TaskResult<Box<RawValue>>> {
-        println!("doTaskIf, targets: {targets:?}, me: {}", self.player_id);
+        println!("doTaskIf, targets: {targets:?}, me: {:?}", self.player_id);
 
-        if targets.contains(&self.player_id) {
+        if self.player_id.is_some_and(|me| targets.contains(&me)) {
             Ok(TaskResult::DoTask)
         } else {
             println!("waiting sync msg...");
-            let res: TaskResult<Box<RawValue>> = self.chan.receive().await?;
+            let res: TaskResult<Box<RawValue>> = self.receive().await?;
             anyhow::ensure!(!matches!(res, TaskResult::DoTask));
             println!("escaped doTaskIf block");
             Ok(res)
@@ -106,28 +376,173 @@ impl OutputHandler for Agent {
 
     async fn task_done(&mut self, _targets: Vec<PlayerId>, _value: &RawValue) -> Result<()> {
         println!("waiting sync msg...");
-        let res: TaskResult<()> = self.chan.receive().await?;
+        let res: TaskResult<()> = self.receive().await?;
         anyhow::ensure!(matches!(res, TaskResult::DoTask));
         println!("escaped doTaskIf block");
         Ok(())
     }
 
+    async fn notify(&mut self, targets: Vec<PlayerId>, _value: &RawValue) -> Result<()> {
+        // the game only notifies players within the scope, which we're in if we got here
+        if self.player_id.is_some_and(|me| targets.contains(&me)) {
+            let msg: Box<RawValue> = self.receive().await?;
+            println!("NOTIFY: {msg}");
+        }
+        Ok(())
+    }
+
     async fn random(&mut self, _start: i32, _end: i32) -> Result<i32> {
         println!("waiting random number");
-        Ok(self.chan.receive().await?)
+        Ok(self.receive().await?)
+    }
+
+    async fn random_bytes(&mut self, _len: usize) -> Result<Vec<u8>> {
+        println!("waiting random bytes");
+        Ok(self.receive().await?)
     }
 
     async fn action(&mut self, from: PlayerId, param: &RawValue) -> Result<Box<RawValue>> {
-        if from == self.player_id {
+        if Some(from) == self.player_id {
             println!("action requested, param:\n{param}\nINPUT ACTION:");
-            let input = RawValue::from_string(self.receiver.recv().await?)?;
-            self.chan.send(&input).await?;
+            let input = RawValue::from_string(self.read_input().await?)?;
+            self.send(&input).await?;
             Ok(input)
         } else {
             println!("waiting action from player {from}");
-            let msg = self.chan.receive().await?;
+            let msg = self.receive().await?;
             println!("received {msg}");
             Ok(msg)
         }
     }
+
+    async fn action_with_deadline(
+        &mut self,
+        from: PlayerId,
+        param: &RawValue,
+        deadline: Duration,
+    ) -> Result<TimedAction<Box<RawValue>>> {
+        if Some(from) != self.player_id {
+            println!("waiting action from player {from} within {deadline:?}");
+            let result = self.receive().await?;
+            println!("received {result:?}");
+            return Ok(result);
+        }
+
+        println!("action requested within {deadline:?}, param:\n{param}\nINPUT ACTION:");
+        // the server tells whether it was in time, even if we send it
+        let received = tokio::select! {
+            line = Self::next_line(&self.receiver) => Ok(line?),
+            res = self.chan.receive::<TimedAction<Box<RawValue>>>() => Err(res),
+        };
+        let result = match received {
+            Ok(line) => {
+                let input = RawValue::from_string(line)?;
+                self.send(&input).await?;
+                self.receive().await?
+            }
+            Err(res) => {
+                let result = match res {
+                    Ok(result) => result,
+                    Err(err) => {
+                        self.reconnect(err).await?;
+                        self.chan.receive().await?
+                    }
+                };
+                println!("deadline passed");
+                // the server discards one message of ours for it
+                self.send(&RawValue::from_string("null".into())?).await?;
+                result
+            }
+        };
+        println!("received {result:?}");
+        Ok(result)
+    }
+
+    async fn first_action(
+        &mut self,
+        from: Vec<PlayerId>,
+        param: &RawValue,
+        deadline: Option<Duration>,
+    ) -> Result<TimedAction<(PlayerId, Box<RawValue>)>> {
+        if !self.player_id.is_some_and(|me| from.contains(&me)) {
+            println!("waiting first action of players {from:?} within {deadline:?}");
+            let result = self.receive().await?;
+            println!("received {result:?}");
+            return Ok(result);
+        }
+
+        println!("first action requested within {deadline:?}, param:\n{param}\nINPUT ACTION:");
+        // the server tells who was first, even if we send ours
+        let received = tokio::select! {
+            line = Self::next_line(&self.receiver) => Ok(line?),
+            res = self.chan.receive::<TimedAction<(PlayerId, Box<RawValue>)>>() => Err(res),
+        };
+        let result = match received {
+            Ok(line) => {
+                let input = RawValue::from_string(line)?;
+                self.send(&input).await?;
+                self.receive().await?
+            }
+            Err(res) => {
+                let result = match res {
+                    Ok(result) => result,
+                    Err(err) => {
+                        self.reconnect(err).await?;
+                        self.chan.receive().await?
+                    }
+                };
+                println!("action cancelled");
+                // the server discards one message of ours for it
+                self.send(&RawValue::from_string("null".into())?).await?;
+                result
+            }
+        };
+        println!("received {result:?}");
+        Ok(result)
+    }
+
+    async fn simultaneous_action(
+        &mut self,
+        from: Vec<PlayerId>,
+        param: &RawValue,
+    ) -> Result<Box<RawValue>> {
+        if self.player_id.is_some_and(|me| from.contains(&me)) {
+            println!("simultaneous action requested, param:\n{param}\nINPUT ACTION:");
+            let input = RawValue::from_string(self.read_input().await?)?;
+            self.send(&input).await?;
+        }
+
+        println!("waiting actions from players {from:?}");
+        let msg = self.receive().await?;
+        println!("received {msg}");
+        Ok(msg)
+    }
+
+    async fn gather_actions(
+        &mut self,
+        requests: Vec<(PlayerId, Box<RawValue>)>,
+    ) -> Result<Box<RawValue>> {
+        let from: Vec<_> = requests.iter().map(|(player, _)| *player).collect();
+        let mine = requests
+            .iter()
+            .find(|(player, _)| Some(*player) == self.player_id);
+        if let Some((_, param)) = mine {
+            println!("gathered action requested, param:\n{param}\nINPUT ACTION:");
+            let input = RawValue::from_string(self.read_input().await?)?;
+            self.send(&input).await?;
+        }
+
+        println!("waiting actions from players {from:?}");
+        let msg = self.receive().await?;
+        println!("received {msg}");
+        Ok(msg)
+    }
+
+    async fn forfeited_players(&mut self) -> Result<Vec<PlayerId>> {
+        let players: Vec<PlayerId> = self.receive().await?;
+        if !players.is_empty() {
+            println!("FORFEITED: {players:?}");
+        }
+        Ok(players)
+    }
 }
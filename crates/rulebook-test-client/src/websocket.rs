@@ -4,43 +4,110 @@ use std::task::{Context, Poll};
 use anyhow::Result;
 use futures::{ready, sink::Sink, stream::Stream};
 use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::handshake::client::{Request, Response};
+use tokio_tungstenite::tungstenite::http::header::SEC_WEBSOCKET_PROTOCOL;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
 use tokio_tungstenite::tungstenite::protocol::Message;
 use tokio_tungstenite::{MaybeTlsStream, WebSocketStream as WSStream};
 
+use rulebook_runtime::compress::{self, COMPRESSION_HEADER, DEFLATE};
+use rulebook_runtime::ws_protocol;
+
+/// Connect request to the url, speaking the protocol of this client.
+pub fn request(url: &str, compress: bool) -> Result<Request> {
+    let mut req = url.into_client_request()?;
+    req.headers_mut()
+        .insert(SEC_WEBSOCKET_PROTOCOL, ws_protocol().parse()?);
+    if compress {
+        req.headers_mut()
+            .insert(COMPRESSION_HEADER, DEFLATE.parse()?);
+    }
+    Ok(req)
+}
+
 #[derive(Debug)]
 pub struct WebSocketStream {
     ws: WSStream<MaybeTlsStream<TcpStream>>,
+    /// Deflate frames sent, received ones are inflated regardless.
+    compress: bool,
+    /// Received frames fail to inflate beyond it.
+    max_frame_bytes: usize,
 }
 
 impl WebSocketStream {
-    pub fn new(ws: WSStream<MaybeTlsStream<TcpStream>>) -> Self {
-        WebSocketStream { ws }
+    /// Deflates frames only if the server agreed to in its response.
+    pub fn new(
+        ws: WSStream<MaybeTlsStream<TcpStream>>,
+        resp: &Response,
+        max_frame_bytes: usize,
+    ) -> Self {
+        let compress = resp
+            .headers()
+            .get(COMPRESSION_HEADER)
+            .is_some_and(|value| value == DEFLATE);
+        WebSocketStream {
+            ws,
+            compress,
+            max_frame_bytes,
+        }
     }
 }
 
 impl Stream for WebSocketStream {
-    type Item = Result<String>;
+    type Item = Result<Vec<u8>>;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        match ready!(Pin::new(&mut self.ws).poll_next(cx)?) {
-            Some(Message::Text(msg)) => Poll::Ready(Some(Ok(msg))),
-            Some(_) => Poll::Pending,
-            None => Poll::Ready(None),
+        loop {
+            match ready!(Pin::new(&mut self.ws).poll_next(cx)?) {
+                Some(Message::Text(msg)) => {
+                    return Poll::Ready(Some(compress::inflate(
+                        msg.into_bytes(),
+                        self.max_frame_bytes,
+                    )))
+                }
+                // the codec tells them apart, not the framing
+                Some(Message::Binary(bytes)) => {
+                    return Poll::Ready(Some(compress::inflate(bytes, self.max_frame_bytes)))
+                }
+                // pong is queued by the websocket, flush to send it without waiting for next send
+                Some(Message::Ping(_)) => {
+                    if let Poll::Ready(Err(err)) = Pin::new(&mut self.ws).poll_flush(cx) {
+                        return Poll::Ready(Some(Err(err.into())));
+                    }
+                }
+                Some(Message::Pong(_) | Message::Frame(_)) => {}
+                // like when the server speaks another protocol
+                Some(Message::Close(Some(frame))) if frame.code != CloseCode::Normal => {
+                    return Poll::Ready(Some(Err(anyhow::anyhow!(
+                        "closed by the server: {} ({})",
+                        frame.reason,
+                        u16::from(frame.code)
+                    ))))
+                }
+                Some(Message::Close(_)) | None => return Poll::Ready(None),
+            }
         }
     }
 }
 
-impl Sink<String> for WebSocketStream {
+impl Sink<Vec<u8>> for WebSocketStream {
     type Error = anyhow::Error;
 
     fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
         Pin::new(&mut self.ws).poll_ready(cx).map_err(Into::into)
     }
 
-    fn start_send(mut self: Pin<&mut Self>, item: String) -> Result<()> {
-        Pin::new(&mut self.ws)
-            .start_send(Message::Text(item))
-            .map_err(Into::into)
+    fn start_send(mut self: Pin<&mut Self>, mut item: Vec<u8>) -> Result<()> {
+        if self.compress {
+            item = compress::deflate(item);
+        }
+        // text frames for JSON, like the server sends
+        let msg = match String::from_utf8(item) {
+            Ok(text) => Message::Text(text),
+            Err(err) => Message::Binary(err.into_bytes()),
+        };
+        Pin::new(&mut self.ws).start_send(msg).map_err(Into::into)
     }
 
     fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
@@ -0,0 +1,122 @@
+//! Cost of the game's IO, run natively against a stub host in place of the runtime.
+//!
+//! `cargo bench` prints the time and allocations per IO of a tight loop of each kind.
+//! The traced loop logs every IO like `IoParams::new` did before it only traced in debug builds.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::OnceLock;
+use std::time::Instant;
+
+use rulebook::serde::Serialize;
+use rulebook::{
+    notify, random, request_action, trace, Action, Color, IoParams, PlayerId, RoomInfo, State,
+    Store,
+};
+
+const IO_COUNT: usize = 200_000;
+const RED: PlayerId = PlayerId::new(Color::Red as u8);
+
+/// Counts every allocation, the IO itself shouldn't make any.
+struct Counting;
+
+static ALLOCS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+/// JSON of the room the stub host starts the session with.
+static ROOM: OnceLock<Vec<u8>> = OnceLock::new();
+
+#[derive(Serialize)]
+struct Board;
+
+impl State for Board {
+    fn from_room_info(_room_info: &RoomInfo) -> Self {
+        Board
+    }
+}
+
+struct Guess;
+
+impl Action for Guess {
+    const NAME: &'static str = "guess";
+    type Param = ();
+    type Response = String;
+}
+
+fn main() {
+    ROOM.get_or_init(|| rulebook::serde_json::to_vec(&RoomInfo::default()).unwrap());
+    rulebook::start_session(1024, false, |_room, _store: &mut Store<Board>| {
+        measure("random", || {
+            random(1, 100);
+        });
+        measure("random, traced", || {
+            let n = random(1, 100);
+            trace!("ioparam, output: {n}");
+        });
+        measure("action", || {
+            request_action::<Guess>(RED, ());
+        });
+        measure("notify", || notify(vec![RED], &()));
+        Ok(())
+    });
+}
+
+fn measure(name: &str, mut io: impl FnMut()) {
+    let allocs = ALLOCS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    for _ in 0..IO_COUNT {
+        io();
+    }
+    let elapsed = start.elapsed();
+    let allocs = ALLOCS.load(Ordering::Relaxed) - allocs;
+
+    println!(
+        "{name:<16} {:>6} ns/io {:>3} allocs/io",
+        elapsed.as_nanos() / IO_COUNT as u128,
+        allocs / IO_COUNT,
+    );
+}
+
+/// Answers each output with a fixed input, without allocating.
+#[no_mangle]
+extern "C" fn rulebook_trigger_io(params: *const IoParams) -> usize {
+    let params = unsafe { &*params };
+    let output = unsafe { std::slice::from_raw_parts(params.output_ptr, params.output_len) };
+
+    let input: &[u8] = if output.starts_with(br#"{"type":"sessionStart""#) {
+        ROOM.get().unwrap()
+    } else if output.starts_with(br#"{"type":"random""#) {
+        b"42"
+    } else if output.starts_with(br#"{"type":"action""#) {
+        br#""50""#
+    } else {
+        b"null"
+    };
+    assert!(input.len() <= params.input_cap);
+    unsafe { std::ptr::copy_nonoverlapping(input.as_ptr(), params.input_ptr, input.len()) };
+    input.len()
+}
+
+#[no_mangle]
+extern "C" fn rulebook_take_input(_input_ptr: *mut u8, _input_cap: usize) -> usize {
+    unreachable!("inputs of the stub host always fit")
+}
+
+#[no_mangle]
+extern "C" fn rulebook_log(_msg_ptr: *const u8, _msg_len: usize) {}
+
+#[no_mangle]
+extern "C" fn rulebook_log_at(_level: u32, _msg_ptr: *const u8, _msg_len: usize) {}
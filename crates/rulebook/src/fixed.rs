@@ -0,0 +1,289 @@
+use std::fmt;
+use std::ops;
+
+use serde::{Deserialize, Serialize};
+
+/// Integer backed fixed-point number with 4 decimal digits.
+///
+/// Game logic can't use floats as their arithmetic isn't guaranteed to be
+/// deterministic across hosts. Use this type for probabilities, ratios etc.
+///
+/// Operators panic on overflow. Multiplication and division round
+/// half away from zero. Use `checked_*` methods to handle overflow manually.
+///
+/// It's serialized as its raw bits, i.e. `1.5` is serialized as `15000`.
+#[derive(
+    Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+#[serde(transparent)]
+pub struct Fixed(i64);
+
+impl Fixed {
+    pub const SCALE: i64 = 10_000;
+
+    pub const ZERO: Fixed = Fixed(0);
+    pub const ONE: Fixed = Fixed(Self::SCALE);
+    pub const MIN: Fixed = Fixed(i64::MIN);
+    pub const MAX: Fixed = Fixed(i64::MAX);
+
+    pub const fn from_bits(bits: i64) -> Self {
+        Fixed(bits)
+    }
+
+    pub const fn to_bits(self) -> i64 {
+        self.0
+    }
+
+    pub fn from_int(value: i64) -> Self {
+        Self::checked_from_int(value).expect("fixed-point overflow")
+    }
+
+    pub fn checked_from_int(value: i64) -> Option<Self> {
+        value.checked_mul(Self::SCALE).map(Fixed)
+    }
+
+    /// `numer / denom`, e.g. `Fixed::from_ratio(1, 3)` is `0.3333`.
+    pub fn from_ratio(numer: i64, denom: i64) -> Self {
+        Self::from_int(numer) / Self::from_int(denom)
+    }
+
+    /// `Fixed::from_percent(30)` is `0.3`.
+    pub fn from_percent(percent: i64) -> Self {
+        Self::from_ratio(percent, 100)
+    }
+
+    /// Integer part, rounded toward zero.
+    pub fn trunc(self) -> i64 {
+        self.0 / Self::SCALE
+    }
+
+    /// Nearest integer, rounded half away from zero.
+    pub fn round(self) -> i64 {
+        div_round(self.0 as i128, Self::SCALE as i128) as i64
+    }
+
+    pub fn abs(self) -> Self {
+        Fixed(self.0.checked_abs().expect("fixed-point overflow"))
+    }
+
+    pub fn checked_add(self, rhs: Self) -> Option<Self> {
+        self.0.checked_add(rhs.0).map(Fixed)
+    }
+
+    pub fn checked_sub(self, rhs: Self) -> Option<Self> {
+        self.0.checked_sub(rhs.0).map(Fixed)
+    }
+
+    pub fn checked_mul(self, rhs: Self) -> Option<Self> {
+        let res = div_round(self.0 as i128 * rhs.0 as i128, Self::SCALE as i128);
+        i64::try_from(res).ok().map(Fixed)
+    }
+
+    /// Returns `None` on overflow or division by zero.
+    pub fn checked_div(self, rhs: Self) -> Option<Self> {
+        if rhs.0 == 0 {
+            return None;
+        }
+        let res = div_round(self.0 as i128 * Self::SCALE as i128, rhs.0 as i128);
+        i64::try_from(res).ok().map(Fixed)
+    }
+
+    pub fn saturating_add(self, rhs: Self) -> Self {
+        Fixed(self.0.saturating_add(rhs.0))
+    }
+
+    pub fn saturating_sub(self, rhs: Self) -> Self {
+        Fixed(self.0.saturating_sub(rhs.0))
+    }
+
+    pub fn saturating_mul(self, rhs: Self) -> Self {
+        match self.checked_mul(rhs) {
+            Some(res) => res,
+            None if (self.0 < 0) == (rhs.0 < 0) => Self::MAX,
+            None => Self::MIN,
+        }
+    }
+}
+
+/// `lhs / rhs` rounded half away from zero. `rhs` must not be zero.
+fn div_round(lhs: i128, rhs: i128) -> i128 {
+    let quot = lhs / rhs;
+    let rem = lhs % rhs;
+
+    if rem.abs() * 2 >= rhs.abs() {
+        if (lhs < 0) == (rhs < 0) {
+            quot + 1
+        } else {
+            quot - 1
+        }
+    } else {
+        quot
+    }
+}
+
+impl From<i32> for Fixed {
+    fn from(value: i32) -> Self {
+        Fixed(value as i64 * Self::SCALE)
+    }
+}
+
+impl fmt::Display for Fixed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sign = if self.0 < 0 { "-" } else { "" };
+        let abs = self.0.unsigned_abs();
+        let scale = Self::SCALE as u64;
+
+        write!(f, "{sign}{}.{:04}", abs / scale, abs % scale)
+    }
+}
+
+impl ops::Add for Fixed {
+    type Output = Fixed;
+
+    fn add(self, rhs: Self) -> Self::Output {
+        self.checked_add(rhs).expect("fixed-point overflow")
+    }
+}
+
+impl ops::Sub for Fixed {
+    type Output = Fixed;
+
+    fn sub(self, rhs: Self) -> Self::Output {
+        self.checked_sub(rhs).expect("fixed-point overflow")
+    }
+}
+
+impl ops::Mul for Fixed {
+    type Output = Fixed;
+
+    fn mul(self, rhs: Self) -> Self::Output {
+        self.checked_mul(rhs).expect("fixed-point overflow")
+    }
+}
+
+impl ops::Div for Fixed {
+    type Output = Fixed;
+
+    fn div(self, rhs: Self) -> Self::Output {
+        assert!(rhs.0 != 0, "fixed-point division by zero");
+        self.checked_div(rhs).expect("fixed-point overflow")
+    }
+}
+
+impl ops::Neg for Fixed {
+    type Output = Fixed;
+
+    fn neg(self) -> Self::Output {
+        Fixed(self.0.checked_neg().expect("fixed-point overflow"))
+    }
+}
+
+impl ops::AddAssign for Fixed {
+    fn add_assign(&mut self, rhs: Self) {
+        *self = *self + rhs;
+    }
+}
+
+impl ops::SubAssign for Fixed {
+    fn sub_assign(&mut self, rhs: Self) {
+        *self = *self - rhs;
+    }
+}
+
+impl ops::MulAssign for Fixed {
+    fn mul_assign(&mut self, rhs: Self) {
+        *self = *self * rhs;
+    }
+}
+
+impl ops::DivAssign for Fixed {
+    fn div_assign(&mut self, rhs: Self) {
+        *self = *self / rhs;
+    }
+}
+
+/// Returns `true` with given probability, e.g. `chance(Fixed::from_percent(30))`.
+///
+/// Like [`random`](crate::random), everyone in current scope gets the same result.
+pub fn chance(probability: Fixed) -> bool {
+    (crate::random(0, (Fixed::SCALE - 1) as i32) as i64) < probability.to_bits()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn div_round_rounds_half_away_from_zero() {
+        assert_eq!(div_round(5, 2), 3);
+        assert_eq!(div_round(-5, 2), -3);
+        assert_eq!(div_round(5, -2), -3);
+        assert_eq!(div_round(-5, -2), 3);
+        assert_eq!(div_round(4, 3), 1);
+        assert_eq!(div_round(-4, 3), -1);
+        assert_eq!(div_round(6, 4), 2);
+        assert_eq!(div_round(-6, 4), -2);
+    }
+
+    #[test]
+    fn mul_and_div_round_half_away_from_zero() {
+        let tiny = Fixed::from_bits(5);
+        let half = Fixed::from_ratio(1, 2);
+        // 0.0005 * 0.5 is 0.00025, rounded to 0.0003
+        assert_eq!(tiny * half, Fixed::from_bits(3));
+        assert_eq!(-tiny * half, Fixed::from_bits(-3));
+        assert_eq!(Fixed::from_ratio(2, 3), Fixed::from_bits(6667));
+        assert_eq!(Fixed::from_ratio(-2, 3), Fixed::from_bits(-6667));
+        assert_eq!(Fixed::from_bits(25_000).round(), 3);
+        assert_eq!(Fixed::from_bits(-25_000).round(), -3);
+        assert_eq!(Fixed::from_bits(-25_000).trunc(), -2);
+    }
+
+    #[test]
+    fn from_ratio_keeps_four_digits() {
+        assert_eq!(Fixed::from_ratio(1, 3), Fixed::from_bits(3333));
+        assert_eq!(Fixed::from_ratio(1, 3).to_string(), "0.3333");
+        assert_eq!(Fixed::from_percent(30), Fixed::from_bits(3000));
+    }
+
+    #[test]
+    fn checked_ops_catch_overflow_and_division_by_zero() {
+        assert_eq!(Fixed::MAX.checked_add(Fixed::from_bits(1)), None);
+        assert_eq!(Fixed::MIN.checked_sub(Fixed::from_bits(1)), None);
+        assert_eq!(Fixed::MAX.checked_mul(Fixed::from_int(2)), None);
+        assert_eq!(Fixed::MAX.checked_mul(Fixed::ONE), Some(Fixed::MAX));
+        assert_eq!(Fixed::MAX.checked_div(Fixed::from_ratio(1, 2)), None);
+        assert_eq!(Fixed::ONE.checked_div(Fixed::ZERO), None);
+        assert_eq!(Fixed::checked_from_int(i64::MAX), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "fixed-point division by zero")]
+    fn div_by_zero_panics() {
+        let _ = Fixed::ONE / Fixed::ZERO;
+    }
+
+    #[test]
+    #[should_panic(expected = "fixed-point overflow")]
+    fn mul_overflow_panics() {
+        let _ = Fixed::MAX * Fixed::from_int(2);
+    }
+
+    #[test]
+    fn saturating_mul_saturates_toward_the_sign_of_result() {
+        let two = Fixed::from_int(2);
+        assert_eq!(Fixed::MAX.saturating_mul(two), Fixed::MAX);
+        assert_eq!(Fixed::MAX.saturating_mul(-two), Fixed::MIN);
+        assert_eq!(Fixed::MIN.saturating_mul(two), Fixed::MIN);
+        assert_eq!(Fixed::MIN.saturating_mul(-two), Fixed::MAX);
+        assert_eq!(two.saturating_mul(two), Fixed::from_int(4));
+    }
+
+    #[test]
+    fn display_negative_values() {
+        assert_eq!(Fixed::from_bits(-5).to_string(), "-0.0005");
+        assert_eq!(Fixed::from_bits(-15_000).to_string(), "-1.5000");
+        assert_eq!(Fixed::MIN.to_string(), "-922337203685477.5808");
+        assert_eq!(Fixed::ZERO.to_string(), "0.0000");
+    }
+}
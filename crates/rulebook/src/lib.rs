@@ -1,24 +1,46 @@
 #![deny(clippy::float_arithmetic)]
 
 use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
 use std::fmt::Debug;
+use std::time::Duration;
 
 use anyhow::Result;
 use scoped_tls::scoped_thread_local;
-use serde::{de::DeserializeOwned, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::value::RawValue;
 
-use rulebook_interface_types::{Output, TaskResult};
+#[cfg(feature = "schema")]
+use rulebook_interface_types::{ActionSchema, GameSchema};
+use rulebook_interface_types::{EndReason, GameError, Output, TaskResult, TimedAction};
+
+mod fixed;
+mod patch;
+mod rng;
+#[cfg(test)]
+mod test_host;
 
 pub use {anyhow, serde, serde_json};
 
-pub use rulebook_interface_types::{PlayerId, RoomInfo};
+#[cfg(feature = "schema")]
+pub use schemars;
+
+pub use fixed::{chance, Fixed};
+pub use rng::{choose, random_bytes, roll, shuffle};
+
+pub use rulebook_interface_types::{Color, GameInfo, LogLevel, PlayerId, RoomInfo, ABI_VERSION};
 
 struct Context {
     input: Box<[u8]>,
     output: Vec<u8>,
     print_state: bool,
+    room: RoomInfo,
+    /// Allowed players of each `do_if` currently running.
+    scope: Vec<Vec<PlayerId>>,
 }
 
+/// Parameter of `rulebook_trigger_io`, its layout is part of the guest ABI.
+/// See `docs/abi.md` for details.
 #[repr(C)]
 #[derive(Debug)]
 pub struct IoParams {
@@ -30,7 +52,10 @@ pub struct IoParams {
 
 impl IoParams {
     pub fn new(input: &mut [u8], output: &[u8]) -> Self {
-        log!(
+        // formatting and logging it costs several times the IO itself, which is otherwise
+        // free of allocations, so only debug builds of the game trace every IO
+        #[cfg(debug_assertions)]
+        trace!(
             "ioparam, input: {:p}-{}, output: {:p}-{}",
             input.as_ptr(),
             input.len(),
@@ -54,8 +79,16 @@ extern "C" {
 
     #[doc(hidden)]
     pub fn rulebook_log(msg_ptr: *const u8, msg_len: usize);
+
+    #[doc(hidden)]
+    pub fn rulebook_log_at(level: u32, msg_ptr: *const u8, msg_len: usize);
+
+    #[doc(hidden)]
+    pub fn rulebook_take_input(input_ptr: *mut u8, input_cap: usize) -> usize;
 }
 
+/// Reuses the buffers of the context, so only deserializing the input may allocate.
+/// `benches/io.rs` measures it.
 fn perform_io_raw<I, O>(out: Output<O>) -> Result<I>
 where
     I: DeserializeOwned + Debug,
@@ -67,8 +100,18 @@ where
         ctx.output.clear();
         serde_json::to_writer(&mut ctx.output, &out)?;
 
-        let input_len = unsafe { rulebook_trigger_io(&IoParams::new(&mut ctx.input, &ctx.output)) };
-        assert!(input_len <= ctx.input.len());
+        let mut input_len =
+            unsafe { rulebook_trigger_io(&IoParams::new(&mut ctx.input, &ctx.output)) };
+        if input_len > ctx.input.len() {
+            // grown just enough and kept for later inputs, bounded by the host's memory limit
+            ctx.input = vec![0; input_len].into_boxed_slice();
+            input_len = unsafe { rulebook_take_input(ctx.input.as_mut_ptr(), ctx.input.len()) };
+        }
+        assert!(
+            input_len <= ctx.input.len(),
+            "input of {input_len} bytes exceeds the buffer of {} bytes",
+            ctx.input.len()
+        );
 
         let input = serde_json::from_slice(&ctx.input[..input_len])?;
 
@@ -81,21 +124,65 @@ fn report_error<T>(f: impl FnOnce() -> Result<T>) -> T {
 
     let err = match catch_unwind(AssertUnwindSafe(f)) {
         Ok(Ok(v)) => return v,
-        Ok(Err(err)) => err,
+        Ok(Err(err)) => GameError {
+            message: format!("{err:?}"),
+            location: None,
+            backtrace: None,
+        },
         Err(err) => {
-            if let Some(err) = err.downcast_ref::<String>() {
-                anyhow::anyhow!("{err}")
+            let message = if let Some(err) = err.downcast_ref::<String>() {
+                err.clone()
             } else if let Some(err) = err.downcast_ref::<&'static str>() {
-                anyhow::anyhow!("{err}")
+                err.to_string()
             } else {
-                anyhow::anyhow!("unknown panic msg")
+                "unknown panic msg".into()
+            };
+            let (location, backtrace) = PANIC_DETAILS
+                .with(|details| details.borrow_mut().take())
+                .unwrap_or_default();
+            GameError {
+                message,
+                location,
+                backtrace,
             }
         }
     };
-    _ = perform_io_raw::<(), ()>(Output::Error(format!("{err:?}")));
+    _ = perform_io_raw::<(), ()>(Output::Error(err));
     unreachable!("rulebook_trigger_io imported function should not return after error output");
 }
 
+/// Location and backtrace of a panic.
+type PanicDetails = (Option<String>, Option<String>);
+
+thread_local! {
+    /// Details of the last panic, recorded by the hook for `report_error`.
+    static PANIC_DETAILS: RefCell<Option<PanicDetails>> = const { RefCell::new(None) };
+}
+
+fn install_panic_hook() {
+    static INSTALL: std::sync::Once = std::sync::Once::new();
+
+    INSTALL.call_once(|| {
+        std::panic::set_hook(Box::new(|info| {
+            let location = info.location().map(|location| location.to_string());
+            PANIC_DETAILS.with(|details| {
+                *details.borrow_mut() = Some((location, capture_backtrace()));
+            });
+        }))
+    });
+}
+
+/// Capturing is slow, so it's opt-in with the `backtrace` feature.
+#[cfg(feature = "backtrace")]
+fn capture_backtrace() -> Option<String> {
+    Some(std::backtrace::Backtrace::force_capture().to_string())
+}
+
+#[cfg(not(feature = "backtrace"))]
+fn capture_backtrace() -> Option<String> {
+    None
+}
+
 fn perform_io<I, O>(out: Output<O>) -> I
 where
     I: DeserializeOwned + Debug,
@@ -104,6 +191,11 @@ where
     report_error(|| perform_io_raw(out))
 }
 
+/// Exports the game to the host.
+///
+/// `setup!(run)` exports `run` as the game, and `setup!(run, info)` its `GameInfo` too.
+/// With the `schema` feature, `setup!(run, info, schema: State, Action1, Action2)`
+/// exports JSON schemas of the state and each `Action` as well.
 #[macro_export]
 macro_rules! setup {
     ($game:ident) => {
@@ -112,6 +204,11 @@ macro_rules! setup {
             $crate::start_session(input_cap, print_state != 0, $game)
         }
 
+        #[no_mangle]
+        pub extern "C" fn rulebook_abi_version() -> u32 {
+            $crate::ABI_VERSION
+        }
+
         #[doc(hidden)]
         #[no_mangle]
         pub unsafe extern "C" fn rulebook_dummy_function_to_enforce_linkage() {
@@ -119,8 +216,69 @@ macro_rules! setup {
 
             $crate::rulebook_trigger_io(ptr::null());
             $crate::rulebook_log(ptr::null(), 0);
+            $crate::rulebook_log_at(0, ptr::null(), 0);
+        }
+    };
+    ($game:ident, $info:expr) => {
+        $crate::setup!($game);
+
+        #[no_mangle]
+        pub extern "C" fn rulebook_game_info() -> u64 {
+            $crate::export_game_info($info)
         }
     };
+    ($game:ident, $info:expr, schema: $state:ty $(, $action:ty)* $(,)?) => {
+        $crate::setup!($game, $info);
+
+        #[no_mangle]
+        pub extern "C" fn rulebook_schema() -> u64 {
+            $crate::export_schema::<$state>(vec![$($crate::action_schema::<$action>()),*])
+        }
+    };
+}
+
+/// Leaks the JSON of the info, returns its pointer in upper 32 bits and its length in lower.
+#[doc(hidden)]
+pub fn export_game_info(info: GameInfo) -> u64 {
+    export_json(&info)
+}
+
+/// Like `export_game_info`, for `GameSchema`.
+#[cfg(feature = "schema")]
+#[doc(hidden)]
+pub fn export_schema<S: schemars::JsonSchema>(actions: Vec<(&'static str, ActionSchema)>) -> u64 {
+    export_json(&GameSchema {
+        state: schema_of::<S>(),
+        actions: actions
+            .into_iter()
+            .map(|(name, schema)| (name.into(), schema))
+            .collect(),
+    })
+}
+
+#[cfg(feature = "schema")]
+#[doc(hidden)]
+pub fn action_schema<A>() -> (&'static str, ActionSchema)
+where
+    A: Action,
+    A::Param: schemars::JsonSchema,
+    A::Response: schemars::JsonSchema,
+{
+    let schema = ActionSchema {
+        param: schema_of::<A::Param>(),
+        response: schema_of::<A::Response>(),
+    };
+    (A::NAME, schema)
+}
+
+#[cfg(feature = "schema")]
+fn schema_of<T: schemars::JsonSchema>() -> Box<serde_json::value::RawValue> {
+    serde_json::value::to_raw_value(&schemars::schema_for!(T)).unwrap()
+}
+
+fn export_json<T: Serialize>(value: &T) -> u64 {
+    let json: &'static str = Box::leak(serde_json::to_string(value).unwrap().into_boxed_str());
+    (json.as_ptr() as u64) << 32 | json.len() as u64
 }
 
 #[macro_export]
@@ -130,9 +288,47 @@ macro_rules! log {
     };
 }
 
+#[macro_export]
+macro_rules! trace {
+    ($($t:tt)*) => {
+        $crate::log_at($crate::LogLevel::Trace, &format!($($t)*))
+    };
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($t:tt)*) => {
+        $crate::log_at($crate::LogLevel::Debug, &format!($($t)*))
+    };
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($t:tt)*) => {
+        $crate::log_at($crate::LogLevel::Info, &format!($($t)*))
+    };
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($t:tt)*) => {
+        $crate::log_at($crate::LogLevel::Warn, &format!($($t)*))
+    };
+}
+
+#[macro_export]
+macro_rules! error {
+    ($($t:tt)*) => {
+        $crate::log_at($crate::LogLevel::Error, &format!($($t)*))
+    };
+}
+
 #[derive(Debug)]
 pub struct Store<T> {
     state: T,
+    patch_mode: bool,
+    /// Last state sent to the host in patch mode.
+    sent: Option<serde_json::Value>,
 }
 
 impl<T: Serialize> Store<T> {
@@ -140,15 +336,109 @@ impl<T: Serialize> Store<T> {
         &self.state
     }
 
+    /// Sends only the changes of the state on each mutation, as JSON Patch.
+    ///
+    /// Worth it for large states mostly unchanged by each mutation.
+    /// Full state is sent instead if it's smaller than the patch.
+    pub fn set_patch_mode(&mut self, enabled: bool) {
+        self.patch_mode = enabled;
+        self.sent = None;
+    }
+
     pub fn mutate(&mut self, f: impl FnOnce(&mut T)) {
         f(&mut self.state);
 
-        CONTEXT.with(|ctx| {
-            let print_state = ctx.borrow().print_state;
+        let print_state = CONTEXT.with(|ctx| ctx.borrow().print_state);
+        if print_state {
+            self.send_state();
+        }
+    }
 
-            if print_state {
-                let () = perform_io(Output::UpdateState(&self.state));
+    fn send_state(&mut self) {
+        if !self.patch_mode {
+            let () = perform_io(Output::UpdateState(&self.state));
+            return;
+        }
+
+        let state =
+            serde_json::to_value(&self.state).expect("state should be serializable to JSON");
+        match self.sent.as_ref().map(|sent| patch::diff(sent, &state)) {
+            Some(ops) if ops.is_empty() => {}
+            Some(ops) if json_len(&ops) < json_len(&state) => {
+                let () = perform_io(Output::PatchState::<()>(ops));
+            }
+            _ => {
+                let () = perform_io(Output::UpdateState(&state));
             }
+        }
+        self.sent = Some(state);
+    }
+
+    pub fn set(&mut self, new_state: T) {
+        self.mutate(|inner| *inner = new_state)
+    }
+
+    /// Hash of current state, see `state_hash`.
+    pub fn state_hash(&self) -> u64 {
+        state_hash(&self.state)
+    }
+}
+
+fn json_len<T: Serialize>(value: &T) -> usize {
+    serde_json::to_vec(value).unwrap().len()
+}
+
+/// Stable hash of the value, equal on every machine for equal values.
+///
+/// Compare it between players to detect desync.
+/// Hashed over the JSON with sorted object keys, so map ordering doesn't matter.
+pub fn state_hash<T: Serialize>(value: &T) -> u64 {
+    const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+    const FNV_PRIME: u64 = 0x0100_0000_01b3;
+
+    let value = serde_json::to_value(value).expect("state should be serializable to JSON");
+    let json = serde_json::to_vec(&sort_keys(value)).unwrap();
+
+    json.iter().fold(FNV_OFFSET, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(FNV_PRIME)
+    })
+}
+
+/// State only one player sees, like their hand of cards.
+///
+/// The host shows its updates only to the target, but other players' copies of the game
+/// run it too. Compute the state within `do_if` of the target so they never learn it.
+#[derive(Debug)]
+pub struct PrivateStore<T> {
+    name: &'static str,
+    target: PlayerId,
+    state: T,
+}
+
+impl<T: Serialize> PrivateStore<T> {
+    /// Player who sees the state.
+    pub fn target(&self) -> PlayerId {
+        self.target
+    }
+
+    pub fn get(&self) -> &T {
+        &self.state
+    }
+
+    pub fn mutate(&mut self, f: impl FnOnce(&mut T)) {
+        f(&mut self.state);
+
+        let print_state = CONTEXT.with(|ctx| ctx.borrow().print_state);
+        if print_state {
+            self.send_state();
+        }
+    }
+
+    fn send_state(&self) {
+        let () = perform_io(Output::UpdatePrivateState {
+            name: self.name.into(),
+            target: self.target,
+            state: &self.state,
         });
     }
 
@@ -159,29 +449,110 @@ impl<T: Serialize> Store<T> {
 
 pub trait State: Serialize {
     fn from_room_info(room_info: &RoomInfo) -> Self;
+
+    /// Starts from `RoomInfo::init_state` the host gave instead, like a preset board
+    /// or a resumed game. Games taking one override it, typically deserializing `init`.
+    ///
+    /// The default panics, failing the session rather than ignoring what the host asked for.
+    fn from_init(room_info: &RoomInfo, init: &RawValue) -> Self
+    where
+        Self: Sized,
+    {
+        _ = room_info;
+        panic!("game doesn't take an initial state, but the host gave {init}")
+    }
 }
 
+/// State of `PrivateStore`, one for each player.
+pub trait PrivateState: Serialize {
+    /// Tells the store apart from other private stores of the player.
+    const NAME: &'static str;
+    fn from_room_info(room_info: &RoomInfo, player: PlayerId) -> Self;
+}
+
+/// Stores the game takes, like `Store<S>` or a tuple of stores.
+///
+/// A map of `PrivateStore` keyed by every player in the room is a store too.
+/// Keep at most one `Store` per game, the host only tracks the latest of them.
+pub trait Stores: Sized {
+    /// Creates the stores from the room and sends their initial states.
+    fn init(room_info: &RoomInfo) -> Self;
+}
+
+impl<S: State> Stores for Store<S> {
+    fn init(room_info: &RoomInfo) -> Self {
+        let state = match &room_info.init_state {
+            Some(init) => S::from_init(room_info, init),
+            None => S::from_room_info(room_info),
+        };
+        let store = Store {
+            state,
+            patch_mode: false,
+            sent: None,
+        };
+        let () = perform_io(Output::UpdateState(store.get()));
+        store
+    }
+}
+
+impl<S: PrivateState> Stores for BTreeMap<PlayerId, PrivateStore<S>> {
+    fn init(room_info: &RoomInfo) -> Self {
+        room_info
+            .players
+            .iter()
+            .map(|&player| {
+                let store = PrivateStore {
+                    name: S::NAME,
+                    target: player,
+                    state: S::from_room_info(room_info, player),
+                };
+                store.send_state();
+                (player, store)
+            })
+            .collect()
+    }
+}
+
+macro_rules! impl_stores_for_tuple {
+    ($($store:ident),*) => {
+        impl<$($store: Stores),*> Stores for ($($store,)*) {
+            fn init(room_info: &RoomInfo) -> Self {
+                ($($store::init(room_info),)*)
+            }
+        }
+    };
+}
+
+impl_stores_for_tuple!(A, B);
+impl_stores_for_tuple!(A, B, C);
+impl_stores_for_tuple!(A, B, C, D);
+
 pub fn start_session<F, S>(input_cap: usize, print_state: bool, game: F)
 where
-    F: FnOnce(&RoomInfo, &mut Store<S>) -> Result<()>,
-    S: State,
+    F: FnOnce(&RoomInfo, &mut S) -> Result<()>,
+    S: Stores,
 {
+    install_panic_hook();
     let ctx = RefCell::new(Context {
         input: vec![0; input_cap].into_boxed_slice(),
         output: serde_json::to_vec(&()).unwrap(),
         print_state,
+        room: RoomInfo::default(),
+        scope: vec![],
     });
 
     CONTEXT.set(&ctx, || {
         let room: RoomInfo = perform_io(Output::SessionStart::<()>);
-        let mut store = Store {
-            state: S::from_room_info(&room),
-        };
-        let () = perform_io(Output::UpdateState(store.get()));
+        ctx.borrow_mut().room = room.clone();
+        // the game may not take the initial state the host gave
+        let mut stores = report_error(|| Ok(S::init(&room)));
 
-        report_error(|| game(&room, &mut store));
+        report_error(|| game(&room, &mut stores));
 
-        let () = perform_io(Output::SessionEnd::<()>);
+        // errors never get here, the host tells clients of them
+        let () = perform_io(Output::SessionEnd::<()> {
+            reason: EndReason::Completed,
+        });
     })
 }
 
@@ -189,13 +560,65 @@ pub fn log(msg: &str) {
     unsafe { rulebook_log(msg.as_ptr(), msg.len()) }
 }
 
+/// Logs the message at the level, so the host can filter it out.
+pub fn log_at(level: LogLevel, msg: &str) {
+    unsafe { rulebook_log_at(level as u32, msg.as_ptr(), msg.len()) }
+}
+
+/// Captures the value under the label for debugging, kept in the host's session diagnostics.
+///
+/// It doesn't affect the game, neither shown to players.
+pub fn debug_snapshot<T: Serialize>(label: &str, value: &T) {
+    let () = perform_io(Output::DebugSnapshot {
+        label: label.into(),
+        value,
+    });
+}
+
+/// Reports the final result of the game like its winner, for the host to record the match.
+///
+/// Call it at most once, at top level once the game is decided.
+pub fn report_result<T: Serialize>(result: &T) {
+    let () = perform_io(Output::GameResult(result));
+}
+
+/// Sends the value to the `targets` within `visible_players()`, without touching the state.
+///
+/// Useful for private events like "you drew a card". Other players receive nothing.
+pub fn notify<T: Serialize>(targets: Vec<PlayerId>, value: &T) {
+    let () = perform_io(Output::Notify { targets, value });
+}
+
 pub fn random(start: i32, end: i32) -> i32 {
     assert!(start <= end, "start > end");
     perform_io(Output::Random::<()> { start, end })
 }
 
+/// Players who can see what the game is doing now.
+///
+/// Every player in the room at top level, and `allowed` players within `do_if` and its variants.
+/// Empty within admin-only tasks.
+pub fn visible_players() -> Vec<PlayerId> {
+    CONTEXT.with(|ctx| {
+        let ctx = ctx.borrow();
+        ctx.scope
+            .last()
+            .cloned()
+            .unwrap_or_else(|| ctx.room.players.clone())
+    })
+}
+
+fn with_scope<T>(allowed: Vec<PlayerId>, f: impl FnOnce() -> T) -> T {
+    CONTEXT.with(|ctx| ctx.borrow_mut().scope.push(allowed));
+    let res = f();
+    CONTEXT.with(|ctx| ctx.borrow_mut().scope.pop());
+    res
+}
+
 pub fn do_if<F: FnOnce() -> T, T>(targets: Vec<PlayerId>, f: F) -> Option<T> {
-    match perform_io(Output::DoTaskIf::<()> { allowed: targets }) {
+    match perform_io(Output::DoTaskIf::<()> {
+        allowed: targets.clone(),
+    }) {
         TaskResult::DoTask => {} // proceed
         TaskResult::SyncResult(()) => {
             report_error(|| Err::<(), _>(anyhow::anyhow!("unexpected syncResult response")));
@@ -204,7 +627,7 @@ pub fn do_if<F: FnOnce() -> T, T>(targets: Vec<PlayerId>, f: F) -> Option<T> {
         TaskResult::Restricted => return None,
     }
 
-    let res = f();
+    let res = with_scope(targets, f);
     let () = perform_io(Output::TaskDone {
         targets: vec![],
         value: (),
@@ -213,6 +636,18 @@ pub fn do_if<F: FnOnce() -> T, T>(targets: Vec<PlayerId>, f: F) -> Option<T> {
     Some(res)
 }
 
+/// Like `do_if`, but the result is sent along with `TaskDone` to exactly `targets`.
+///
+/// Unlike `sync_admin_if`, `f` runs on `targets` rather than admin,
+/// so only they and admin ever learn the value. Others get `None`.
+pub fn do_if_result<F, T>(targets: Vec<PlayerId>, f: F) -> Option<T>
+where
+    F: FnOnce() -> T,
+    T: Serialize + DeserializeOwned + Debug,
+{
+    sync_if(targets.clone(), targets, f)
+}
+
 pub fn do_if_admin<F: FnOnce() -> T, T>(f: F) -> Option<T> {
     do_if(vec![], f)
 }
@@ -222,21 +657,94 @@ where
     F: FnOnce() -> T,
     T: Serialize + DeserializeOwned + Clone + Debug,
 {
-    match perform_io(Output::DoTaskIf::<()> { allowed: vec![] }) {
+    sync_if(vec![], targets, f)
+}
+
+/// Runs `f` within `allowed` scope and sends its result to `targets`.
+fn sync_if<F, T>(allowed: Vec<PlayerId>, targets: Vec<PlayerId>, f: F) -> Option<T>
+where
+    F: FnOnce() -> T,
+    T: Serialize + DeserializeOwned + Debug,
+{
+    match perform_io(Output::DoTaskIf::<()> {
+        allowed: allowed.clone(),
+    }) {
         TaskResult::DoTask => {} // proceed
         TaskResult::SyncResult(v) => return Some(v),
         TaskResult::Restricted => return None,
     }
 
-    let res = f();
+    let res = with_scope(allowed, f);
     let () = perform_io(Output::TaskDone {
         targets,
-        value: res.clone(),
+        value: &res,
     });
 
     Some(res)
 }
 
+/// Moves `value` from `from` to `to`, without revealing it to anyone else.
+///
+/// `value` only needs to be `Some` where it's visible, i.e. for `from` and admin.
+/// Returns the value for `from`, `to` and admin, `None` for others.
+pub fn transfer<T>(from: PlayerId, to: PlayerId, value: Option<T>) -> Option<T>
+where
+    T: Serialize + DeserializeOwned + Debug,
+{
+    sync_if(vec![from], vec![to], || {
+        value.expect("transfer source doesn't hold the value")
+    })
+}
+
+/// How a game played with `turn_limit` ended.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", content = "data", rename_all = "camelCase")]
+pub enum Outcome<T> {
+    Finished(T),
+    /// Turn limit is reached without a result.
+    Draw,
+}
+
+/// Plays `turn` with the turn number starting from 0 until it returns a result,
+/// at most `max_turns` times.
+pub fn turn_limit<T>(
+    max_turns: u32,
+    mut turn: impl FnMut(u32) -> Result<Option<T>>,
+) -> Result<Outcome<T>> {
+    for turn_number in 0..max_turns {
+        if let Some(res) = turn(turn_number)? {
+            return Ok(Outcome::Finished(res));
+        }
+    }
+
+    Ok(Outcome::Draw)
+}
+
+/// Schema of an action, to request it with `request_action`.
+pub trait Action {
+    /// Sent along with the param so players know which action is requested.
+    const NAME: &'static str;
+    type Param: Serialize;
+    type Response: DeserializeOwned + Debug;
+}
+
+#[derive(Debug, Serialize)]
+struct ActionRequest<P> {
+    action: &'static str,
+    param: P,
+}
+
+/// `action` typed by `A`, the param is sent as `{"action":A::NAME,"param":param}`.
+pub fn request_action<A: Action>(from: PlayerId, param: A::Param) -> A::Response {
+    action(
+        from,
+        ActionRequest {
+            action: A::NAME,
+            param,
+        },
+    )
+}
+
 pub fn action<I, O>(from: PlayerId, param: O) -> I
 where
     I: DeserializeOwned + Debug,
@@ -244,3 +752,565 @@ where
 {
     perform_io(Output::Action { from, param })
 }
+
+/// `request_action` which gives up when `from` doesn't act within `deadline`.
+pub fn request_action_timed<A: Action>(
+    from: PlayerId,
+    param: A::Param,
+    deadline: Duration,
+) -> Option<A::Response> {
+    action_timed(
+        from,
+        ActionRequest {
+            action: A::NAME,
+            param,
+        },
+        deadline,
+    )
+}
+
+/// `action` which returns `None` if `from` doesn't act within `deadline`.
+///
+/// The host measures the deadline, so every player agrees on whether it passed.
+pub fn action_timed<I, O>(from: PlayerId, param: O, deadline: Duration) -> Option<I>
+where
+    I: DeserializeOwned + Debug,
+    O: Serialize,
+{
+    let res = perform_io(Output::ActionWithDeadline {
+        from,
+        param,
+        deadline_ms: deadline.as_millis() as u64,
+    });
+    match res {
+        TimedAction::Acted(value) => Some(value),
+        TimedAction::TimedOut => None,
+    }
+}
+
+/// Asks `asked` for an action which only `visible_to` and admin learn.
+///
+/// Returns the answer for `asked`, `visible_to` and admin, `None` for others.
+pub fn ask_secret_to<I, O>(asked: PlayerId, visible_to: Vec<PlayerId>, param: O) -> Option<I>
+where
+    I: Serialize + DeserializeOwned + Debug,
+    O: Serialize,
+{
+    sync_if(vec![asked], visible_to, || action(asked, param))
+}
+
+/// Every player in `players` submits an action at the same time.
+///
+/// The host collects all of them before revealing any, so no one can react to
+/// others' choices. Like `action`, the result is visible to everyone in current scope.
+pub fn simultaneous_action<I, O>(players: Vec<PlayerId>, param: O) -> BTreeMap<PlayerId, I>
+where
+    I: DeserializeOwned + Debug,
+    O: Serialize,
+{
+    perform_io(Output::SimultaneousAction {
+        from: players,
+        param,
+    })
+}
+
+/// Every player in `players` may act, but only the first one to do so counts.
+///
+/// Others' pending actions are cancelled, the host discards them so they never answer
+/// a later request. The host decides who was first, so every player agrees on the winner.
+/// Returns `None` if no one acts within `deadline`, which waits like `action` if it's `None`.
+/// Panics if `players` is empty or lists a player more than once.
+pub fn first_action<I, O>(
+    players: Vec<PlayerId>,
+    param: O,
+    deadline: Option<Duration>,
+) -> Option<(PlayerId, I)>
+where
+    I: DeserializeOwned + Debug,
+    O: Serialize,
+{
+    assert!(!players.is_empty(), "first action of no players");
+    let unique: BTreeSet<_> = players.iter().collect();
+    assert_eq!(
+        unique.len(),
+        players.len(),
+        "first action of a player twice"
+    );
+
+    let res = perform_io(Output::FirstAction {
+        from: players,
+        param,
+        deadline_ms: deadline.map(|deadline| deadline.as_millis() as u64),
+    });
+    match res {
+        TimedAction::Acted(value) => Some(value),
+        TimedAction::TimedOut => None,
+    }
+}
+
+/// Every player in `actions` submits an action on their own param at the same time.
+///
+/// Like `simultaneous_action`, the host collects all of them before revealing any,
+/// and the result is visible to everyone in current scope. Players may act in any order,
+/// but the actions are returned in the order of `actions`.
+/// Panics if a player is listed more than once.
+pub fn gather<I, O>(actions: Vec<(PlayerId, O)>) -> Vec<I>
+where
+    I: DeserializeOwned + Debug,
+    O: Serialize,
+{
+    let players: BTreeSet<_> = actions.iter().map(|(player, _)| player).collect();
+    assert_eq!(players.len(), actions.len(), "gather from a player twice");
+
+    perform_io(Output::GatherActions { requests: actions })
+}
+
+/// Players the host removed from the game so far, like for being disruptive or gone for good.
+///
+/// Forfeited players are out of every scope and never act again. `action_timed` from them
+/// times out at once, while any other action from them fails the session, so check this
+/// before asking players who may be gone. Every player learns the same list at the same point.
+pub fn forfeited_players() -> Vec<PlayerId> {
+    perform_io(Output::ForfeitedPlayers::<()>)
+}
+
+/// `a` and `b` submit secret values at the same time, and each learns the other's.
+///
+/// Values are collected by `simultaneous_action` within `do_if` of the two,
+/// so neither can react to the other's value and no one else learns them.
+/// Returns values of `a` and `b` in order for them and admin, `None` for others.
+pub fn exchange<T, O>(a: PlayerId, b: PlayerId, param: O) -> Option<(T, T)>
+where
+    T: DeserializeOwned + Debug,
+    O: Serialize,
+{
+    assert_ne!(a, b, "exchange with oneself");
+
+    do_if(vec![a, b], || {
+        let mut values: BTreeMap<PlayerId, T> = simultaneous_action(vec![a, b], param);
+        let value_a = values.remove(&a).expect("host should collect every value");
+        let value_b = values.remove(&b).expect("host should collect every value");
+        (value_a, value_b)
+    })
+}
+
+/// Every player in `players` bids at the same time, and the highest bid wins.
+///
+/// Bids are collected by `simultaneous_action`, so no one learns others' bids before bidding.
+/// Ties are won by the player listed first in `players`.
+/// Returns the winner and the winning bid.
+pub fn auction<O>(players: Vec<PlayerId>, param: O) -> (PlayerId, i32)
+where
+    O: Serialize,
+{
+    assert!(!players.is_empty(), "auction without bidders");
+
+    let bids: BTreeMap<PlayerId, i32> = simultaneous_action(players.clone(), param);
+    players
+        .iter()
+        .map(|player| (*player, bids[player]))
+        .reduce(|best, bid| if bid.1 > best.1 { bid } else { best })
+        .unwrap()
+}
+
+/// Shuffles `deck` and deals `per_player` cards to each player in the room.
+///
+/// Only admin shuffles the deck, and each player only learns their own hand.
+/// Returns every hand for admin, only the own hand for players.
+pub fn deal<T>(deck: Vec<T>, per_player: usize) -> BTreeMap<PlayerId, Vec<T>>
+where
+    T: Serialize + DeserializeOwned + Debug,
+{
+    let players = CONTEXT.with(|ctx| ctx.borrow().room.players.clone());
+    assert!(
+        players.len() * per_player <= deck.len(),
+        "not enough cards to deal"
+    );
+
+    let mut hands = do_if_admin(|| {
+        let mut deck = deck;
+        shuffle(&mut deck);
+
+        let mut cards = deck.into_iter();
+        players
+            .iter()
+            .map(|&player| (player, cards.by_ref().take(per_player).collect()))
+            .collect::<BTreeMap<_, Vec<_>>>()
+    });
+
+    players
+        .iter()
+        .filter_map(|&player| {
+            let hand = sync_if(vec![], vec![player], || {
+                hands
+                    .as_mut()
+                    .and_then(|hands| hands.remove(&player))
+                    .expect("admin should have dealt every hand")
+            })?;
+            Some((player, hand))
+        })
+        .collect()
+}
+
+/// Every player picks an item from their pool, then passes the rest to the next player.
+///
+/// `pools` holds each player's own pool, or every pool for admin, like the result of `deal`.
+/// Picks are asked with `ask_secret_to` one by one rather than `simultaneous_action`,
+/// since a revealed index would tell the previous holder of the pool what was picked.
+/// No one learns others' picks before their own, so it plays the same as picking at once.
+///
+/// Each player passes to the next one in the room's player order, the last one to the first.
+/// Afterwards `pools` holds the pool each player received, and the passer still knows it.
+/// Returns own pick for players and every pick for admin.
+pub fn draft<T>(pools: &mut BTreeMap<PlayerId, Vec<T>>) -> BTreeMap<PlayerId, T>
+where
+    T: Serialize + DeserializeOwned + Debug,
+{
+    let players = CONTEXT.with(|ctx| ctx.borrow().room.players.clone());
+
+    let mut picks = BTreeMap::new();
+    for &player in &players {
+        let pool = pools.get(&player);
+        let Some(idx) = ask_secret_to::<usize, _>(player, vec![], pool) else {
+            continue;
+        };
+
+        let pool = pools
+            .get_mut(&player)
+            .expect("draft pool should be known to its player");
+        assert!(idx < pool.len(), "draft pick {idx} out of the pool");
+        picks.insert(player, pool.remove(idx));
+    }
+
+    let mut passed = std::mem::take(pools);
+    for (idx, &player) in players.iter().enumerate() {
+        let next = players[(idx + 1) % players.len()];
+        if let Some(pool) = transfer(player, next, passed.remove(&player)) {
+            pools.insert(next, pool);
+        }
+    }
+
+    picks
+}
+
+/// Sorts keys of every object, as `Value` keeps the insertion order.
+fn sort_keys(value: serde_json::Value) -> serde_json::Value {
+    use serde_json::Value;
+
+    match value {
+        Value::Object(map) => {
+            let mut entries: Vec<_> = map.into_iter().collect();
+            entries.sort_by(|a, b| a.0.cmp(&b.0));
+            Value::Object(
+                entries
+                    .into_iter()
+                    .map(|(key, value)| (key, sort_keys(value)))
+                    .collect(),
+            )
+        }
+        Value::Array(values) => Value::Array(values.into_iter().map(sort_keys).collect()),
+        value => value,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rulebook_runtime::testing::LocalRoom;
+
+    use super::*;
+    use crate::test_host::{play, Views};
+
+    const RED: PlayerId = PlayerId::new(Color::Red as u8);
+    const BLUE: PlayerId = PlayerId::new(Color::Blue as u8);
+    const GREEN: PlayerId = PlayerId::new(Color::Green as u8);
+
+    #[derive(Serialize)]
+    struct Board;
+
+    impl State for Board {
+        fn from_room_info(_room_info: &RoomInfo) -> Self {
+            Board
+        }
+    }
+
+    type Stores = Store<Board>;
+
+    fn room() -> RoomInfo {
+        RoomInfo {
+            players: vec![RED, BLUE, GREEN],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn simultaneous_action_reveals_every_action_to_the_scope() {
+        type Picks = BTreeMap<PlayerId, String>;
+
+        fn game(_: &RoomInfo, _: &mut Stores) -> (Picks, Option<Picks>) {
+            let public = simultaneous_action(vec![RED, BLUE], "rock paper scissors");
+            let private = do_if(vec![RED, GREEN], || {
+                simultaneous_action(vec![RED, GREEN], "again")
+            });
+            (public, private)
+        }
+
+        let mut local = LocalRoom::new(0);
+        local.script(RED, ["rock", "paper"]).unwrap();
+        local.script(BLUE, ["scissors"]).unwrap();
+        local.script(GREEN, ["rock"]).unwrap();
+        let Views { admin, players } = play(room(), local, game);
+
+        let public = BTreeMap::from([(RED, "rock".to_string()), (BLUE, "scissors".into())]);
+        let private = BTreeMap::from([(RED, "paper".to_string()), (GREEN, "rock".into())]);
+        assert_eq!(admin, (public.clone(), Some(private.clone())));
+        assert_eq!(players[&RED], (public.clone(), Some(private.clone())));
+        assert_eq!(players[&GREEN], (public.clone(), Some(private)));
+        assert_eq!(players[&BLUE], (public, None));
+    }
+
+    #[test]
+    fn transfer_reveals_the_value_only_to_both_ends() {
+        fn game(_: &RoomInfo, _: &mut Stores) -> (Option<i32>, Option<i32>) {
+            let secret = do_if_result(vec![RED], || random(1, 100));
+            let moved = transfer(RED, BLUE, secret);
+            (secret, moved)
+        }
+
+        let Views { admin, players } = play(room(), LocalRoom::new(0), game);
+
+        let (Some(secret), Some(moved)) = admin else {
+            panic!("admin doesn't know the value: {admin:?}");
+        };
+        assert_eq!(secret, moved);
+        assert_eq!(players[&RED], (Some(secret), Some(secret)));
+        assert_eq!(players[&BLUE], (None, Some(secret)));
+        assert_eq!(players[&GREEN], (None, None));
+    }
+
+    #[test]
+    fn deal_shows_each_player_only_their_hand() {
+        fn game(_: &RoomInfo, _: &mut Stores) -> BTreeMap<PlayerId, Vec<u32>> {
+            deal((0..10).collect(), 3)
+        }
+
+        let Views { admin, players } = play(room(), LocalRoom::new(0), game);
+
+        assert_eq!(
+            admin.keys().copied().collect::<BTreeSet<_>>(),
+            BTreeSet::from([RED, BLUE, GREEN])
+        );
+        let mut dealt: Vec<_> = admin.values().flatten().copied().collect();
+        assert!(admin.values().all(|hand| hand.len() == 3));
+        dealt.sort();
+        dealt.dedup();
+        assert_eq!(dealt.len(), 9, "cards dealt twice: {admin:?}");
+        assert!(dealt.iter().all(|card| *card < 10));
+
+        for (player, hands) in players {
+            assert_eq!(hands, BTreeMap::from([(player, admin[&player].clone())]));
+        }
+    }
+
+    #[test]
+    fn ask_secret_to_reveals_the_answer_only_to_the_audience() {
+        fn game(_: &RoomInfo, _: &mut Stores) -> Option<String> {
+            ask_secret_to(RED, vec![GREEN], "password")
+        }
+
+        let mut local = LocalRoom::new(0);
+        local.script(RED, ["swordfish"]).unwrap();
+        let Views { admin, players } = play(room(), local, game);
+
+        let answer = Some("swordfish".to_string());
+        assert_eq!(admin, answer);
+        assert_eq!(players[&RED], answer);
+        assert_eq!(players[&GREEN], answer);
+        assert_eq!(players[&BLUE], None);
+    }
+
+    #[test]
+    fn auction_breaks_ties_by_bidder_order() {
+        fn game(_: &RoomInfo, _: &mut Stores) -> [(PlayerId, i32); 2] {
+            [
+                auction(vec![BLUE, RED, GREEN], "bid"),
+                auction(vec![GREEN, BLUE, RED], "bid"),
+            ]
+        }
+
+        let mut local = LocalRoom::new(0);
+        local.script(RED, [5, 5]).unwrap();
+        local.script(BLUE, [3, 3]).unwrap();
+        local.script(GREEN, [5, 5]).unwrap();
+        let Views { admin, players } = play(room(), local, game);
+
+        let won = [(RED, 5), (GREEN, 5)];
+        assert_eq!(admin, won);
+        assert!(players.values().all(|view| *view == won), "{players:?}");
+    }
+
+    #[test]
+    fn draft_passes_pools_around_the_room() {
+        type Pools = BTreeMap<PlayerId, Vec<u32>>;
+
+        fn game(_: &RoomInfo, _: &mut Stores) -> (Pools, BTreeMap<PlayerId, u32>, Pools) {
+            let mut pools = deal((0..9).collect(), 3);
+            let hands = pools.clone();
+            let picks = draft(&mut pools);
+            (hands, picks, pools)
+        }
+
+        let mut local = LocalRoom::new(0);
+        local.script(RED, [0]).unwrap();
+        local.script(BLUE, [2]).unwrap();
+        local.script(GREEN, [1]).unwrap();
+        let Views { admin, players } = play(room(), local, game);
+
+        let (hands, picks, pools) = admin;
+        let rest = |player, idx| {
+            let mut hand: Vec<u32> = hands[&player].clone();
+            let pick = hand.remove(idx);
+            (pick, hand)
+        };
+        let (red_pick, red_rest) = rest(RED, 0);
+        let (blue_pick, blue_rest) = rest(BLUE, 2);
+        let (green_pick, green_rest) = rest(GREEN, 1);
+
+        assert_eq!(
+            picks,
+            BTreeMap::from([(RED, red_pick), (BLUE, blue_pick), (GREEN, green_pick)])
+        );
+        assert_eq!(
+            pools,
+            BTreeMap::from([
+                (BLUE, red_rest.clone()),
+                (GREEN, blue_rest.clone()),
+                (RED, green_rest.clone()),
+            ])
+        );
+
+        assert_eq!(players[&RED].1, BTreeMap::from([(RED, red_pick)]));
+        assert_eq!(
+            players[&RED].2,
+            BTreeMap::from([(BLUE, red_rest.clone()), (RED, green_rest.clone())])
+        );
+        assert_eq!(players[&BLUE].1, BTreeMap::from([(BLUE, blue_pick)]));
+        assert_eq!(
+            players[&BLUE].2,
+            BTreeMap::from([(GREEN, blue_rest.clone()), (BLUE, red_rest)])
+        );
+        assert_eq!(players[&GREEN].1, BTreeMap::from([(GREEN, green_pick)]));
+        assert_eq!(
+            players[&GREEN].2,
+            BTreeMap::from([(RED, green_rest), (GREEN, blue_rest)])
+        );
+    }
+
+    #[test]
+    fn exchange_reveals_the_values_only_to_both_sides() {
+        fn game(_: &RoomInfo, _: &mut Stores) -> Option<(String, String)> {
+            exchange(RED, BLUE, "trade")
+        }
+
+        let mut local = LocalRoom::new(0);
+        local.script(RED, ["apple"]).unwrap();
+        local.script(BLUE, ["pear"]).unwrap();
+        let Views { admin, players } = play(room(), local, game);
+
+        let traded = Some(("apple".to_string(), "pear".to_string()));
+        assert_eq!(admin, traded);
+        assert_eq!(players[&RED], traded);
+        assert_eq!(players[&BLUE], traded);
+        assert_eq!(players[&GREEN], None);
+    }
+
+    #[test]
+    fn turn_limit_draws_once_the_turns_run_out() {
+        fn game(_: &RoomInfo, _: &mut Stores) -> Outcome<u32> {
+            turn_limit(3, |turn| {
+                let guess: u32 = action(RED, turn);
+                Ok((guess == 42).then_some(turn))
+            })
+            .unwrap()
+        }
+
+        // a turn past the limit would run out of the script
+        let mut local = LocalRoom::new(0);
+        local.script(RED, [1, 2, 3]).unwrap();
+        let Views { admin, players } = play(room(), local, game);
+        assert_eq!(admin, Outcome::Draw);
+        assert!(
+            players.values().all(|view| *view == Outcome::Draw),
+            "{players:?}"
+        );
+
+        let mut local = LocalRoom::new(0);
+        local.script(RED, [1, 42]).unwrap();
+        let Views { admin, .. } = play(room(), local, game);
+        assert_eq!(admin, Outcome::Finished(1));
+    }
+
+    #[test]
+    fn visible_players_follow_nested_scopes() {
+        type Scopes = (
+            Vec<PlayerId>,
+            Option<(Vec<PlayerId>, Option<Vec<PlayerId>>)>,
+        );
+
+        fn game(_: &RoomInfo, _: &mut Stores) -> (Scopes, Vec<PlayerId>) {
+            let top = visible_players();
+            let nested = do_if(vec![RED, BLUE], || {
+                (visible_players(), do_if(vec![RED], visible_players))
+            });
+            ((top, nested), visible_players())
+        }
+
+        let Views { admin, players } = play(room(), LocalRoom::new(0), game);
+
+        let everyone = vec![RED, BLUE, GREEN];
+        let inner = Some((vec![RED, BLUE], Some(vec![RED])));
+        assert_eq!(admin, ((everyone.clone(), inner.clone()), everyone.clone()));
+        assert_eq!(players[&RED], ((everyone.clone(), inner), everyone.clone()));
+        assert_eq!(
+            players[&BLUE],
+            (
+                (everyone.clone(), Some((vec![RED, BLUE], None))),
+                everyone.clone()
+            )
+        );
+        assert_eq!(players[&GREEN], ((everyone.clone(), None), everyone));
+    }
+
+    #[test]
+    fn state_hash_tells_states_apart_regardless_of_map_order() {
+        #[derive(Serialize)]
+        struct Game {
+            turn: u32,
+            board: Vec<u32>,
+            scores: std::collections::HashMap<String, u32>,
+        }
+
+        let game = |turn, scores: &[(&str, u32)]| Game {
+            turn,
+            board: vec![1, 2],
+            scores: scores
+                .iter()
+                .map(|&(player, score)| (player.to_string(), score))
+                .collect(),
+        };
+        let scores: Vec<_> = ["red", "blue", "green", "lime", "aqua", "orange"]
+            .into_iter()
+            .zip(0..)
+            .collect();
+        let mut reversed = scores.clone();
+        reversed.reverse();
+
+        let hash = state_hash(&game(3, &scores));
+        assert_eq!(hash, state_hash(&game(3, &reversed)));
+        assert_ne!(hash, state_hash(&game(4, &scores)));
+        assert_ne!(hash, state_hash(&game(3, &scores[1..])));
+
+        // FNV-1a of `{"board":[1,2],"turn":3}`, the same on every machine
+        let pinned = serde_json::json!({ "turn": 3, "board": [1, 2] });
+        assert_eq!(state_hash(&pinned), 0x1d5b_0569_3b52_589c);
+    }
+}
@@ -0,0 +1,64 @@
+use serde_json::Value;
+
+use rulebook_interface_types::PatchOp;
+
+/// JSON Patch which turns `old` into `new`.
+pub(crate) fn diff(old: &Value, new: &Value) -> Vec<PatchOp> {
+    let mut ops = vec![];
+    diff_at(&mut String::new(), old, new, &mut ops);
+    ops
+}
+
+fn diff_at(path: &mut String, old: &Value, new: &Value, ops: &mut Vec<PatchOp>) {
+    match (old, new) {
+        (Value::Object(old), Value::Object(new)) => {
+            for key in old.keys().filter(|key| !new.contains_key(*key)) {
+                with_token(path, key, |path| {
+                    ops.push(PatchOp::Remove { path: path.clone() })
+                });
+            }
+            for (key, value) in new {
+                with_token(path, key, |path| match old.get(key) {
+                    Some(old) => diff_at(path, old, value, ops),
+                    None => ops.push(PatchOp::Add {
+                        path: path.clone(),
+                        value: value.clone(),
+                    }),
+                });
+            }
+        }
+        (Value::Array(old), Value::Array(new)) => {
+            for (idx, (old, new)) in old.iter().zip(new).enumerate() {
+                with_token(path, &idx.to_string(), |path| diff_at(path, old, new, ops));
+            }
+            // from the back, so indices of the rest don't shift
+            for idx in (new.len()..old.len()).rev() {
+                with_token(path, &idx.to_string(), |path| {
+                    ops.push(PatchOp::Remove { path: path.clone() })
+                });
+            }
+            for (idx, value) in new.iter().enumerate().skip(old.len()) {
+                with_token(path, &idx.to_string(), |path| {
+                    ops.push(PatchOp::Add {
+                        path: path.clone(),
+                        value: value.clone(),
+                    })
+                });
+            }
+        }
+        (old, new) if old == new => {}
+        (_, new) => ops.push(PatchOp::Replace {
+            path: path.clone(),
+            value: new.clone(),
+        }),
+    }
+}
+
+/// Runs `f` with the escaped token appended to the JSON Pointer.
+fn with_token(path: &mut String, token: &str, f: impl FnOnce(&mut String)) {
+    let len = path.len();
+    path.push('/');
+    path.push_str(&token.replace('~', "~0").replace('/', "~1"));
+    f(path);
+    path.truncate(len);
+}
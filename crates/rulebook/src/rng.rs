@@ -0,0 +1,73 @@
+use rulebook_interface_types::Output;
+
+use crate::perform_io;
+
+/// Random bytes from the host, same for every player who can see it.
+pub fn random_bytes(len: usize) -> Vec<u8> {
+    let bytes: Vec<u8> = perform_io(Output::RandomBytes::<()> { len });
+    assert_eq!(bytes.len(), len, "host sent wrong number of random bytes");
+    bytes
+}
+
+/// Shuffles the slice in place with a single host round-trip.
+pub fn shuffle<T>(slice: &mut [T]) {
+    if slice.len() < 2 {
+        return;
+    }
+
+    let mut rng = Rng::from_host();
+    // Fisher-Yates
+    for idx in (1..slice.len()).rev() {
+        let other = rng.below(idx as u64 + 1) as usize;
+        slice.swap(idx, other);
+    }
+}
+
+/// Picks an element uniformly, panics if the slice is empty.
+pub fn choose<T>(slice: &[T]) -> &T {
+    assert!(!slice.is_empty(), "choose from empty slice");
+    &slice[Rng::from_host().below(slice.len() as u64) as usize]
+}
+
+/// Rolls `n_dice` dice of `sides` sides each, with a single host round-trip.
+pub fn roll(n_dice: usize, sides: i32) -> Vec<i32> {
+    assert!(sides > 0, "dice without sides");
+    if n_dice == 0 {
+        return vec![];
+    }
+
+    let mut rng = Rng::from_host();
+    (0..n_dice)
+        .map(|_| rng.below(sides as u64) as i32 + 1)
+        .collect()
+}
+
+/// SplitMix64 seeded from the host, so every player derives the same numbers from it.
+struct Rng(u64);
+
+impl Rng {
+    fn from_host() -> Self {
+        let bytes: [u8; 8] = random_bytes(8).try_into().unwrap();
+        Rng(u64::from_le_bytes(bytes))
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform within `0..n`, rejects the biased tail of `u64`.
+    fn below(&mut self, n: u64) -> u64 {
+        // 2^64 % n
+        let tail = (u64::MAX % n + 1) % n;
+        loop {
+            let x = self.next_u64();
+            if x <= u64::MAX - tail {
+                return x % n;
+            }
+        }
+    }
+}
@@ -0,0 +1,325 @@
+//! Host for tests, running games natively in place of the runtime running them as wasm.
+//!
+//! A game is played once as the host, which sees everything and answers its outputs
+//! with a `LocalRoom`. Then it's played once for each player, whose copy only learns
+//! what the host reveals to them like clients of the server, and must perform
+//! the same outputs as the host's copy wherever the player can see.
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::mpsc;
+
+use futures::executor::block_on;
+use rulebook_runtime::testing::LocalRoom;
+use rulebook_runtime::OutputHandler;
+use serde_json::value::RawValue;
+
+use crate::{IoParams, Output, PlayerId, RoomInfo, Stores, TaskResult};
+
+const INPUT_CAP: usize = 1024;
+
+/// What each copy of the game returned.
+#[derive(Debug)]
+pub struct Views<T> {
+    pub admin: T,
+    pub players: BTreeMap<PlayerId, T>,
+}
+
+/// Plays `game` in the room, answered by `local`.
+pub fn play<S, T>(info: RoomInfo, local: LocalRoom, game: fn(&RoomInfo, &mut S) -> T) -> Views<T>
+where
+    S: Stores + 'static,
+    T: Send + 'static,
+{
+    keep_default_panic_hook();
+    let (admin, trace) = run(
+        Host::Admin {
+            local,
+            info: info.clone(),
+            trace: vec![],
+        },
+        game,
+    );
+
+    let players = info
+        .players
+        .iter()
+        .map(|&player| {
+            let host = Host::Player {
+                player,
+                trace: trace.clone(),
+                cursor: 0,
+            };
+            (player, run(host, game).0)
+        })
+        .collect();
+
+    Views { admin, players }
+}
+
+/// Chains the default hook after the game's, which would hide messages of failed tests.
+fn keep_default_panic_hook() {
+    static CHAIN: std::sync::Once = std::sync::Once::new();
+
+    CHAIN.call_once(|| {
+        let default = std::panic::take_hook();
+        crate::install_panic_hook();
+        let game = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            game(info);
+            default(info);
+        }));
+    });
+}
+
+/// Outputs of the game paired with the inputs the host answered.
+type Trace = Vec<(String, String)>;
+
+enum Host {
+    Admin {
+        local: LocalRoom,
+        info: RoomInfo,
+        trace: Trace,
+    },
+    Player {
+        player: PlayerId,
+        trace: Trace,
+        cursor: usize,
+    },
+}
+
+struct Running {
+    host: Host,
+    /// Input larger than the game's buffer, until it takes it.
+    pending: Option<String>,
+    /// Reports the failure to `run`.
+    failed: Box<dyn Fn(String)>,
+}
+
+thread_local! {
+    static RUNNING: RefCell<Option<Running>> = const { RefCell::new(None) };
+}
+
+/// Runs a copy of the game on its own thread, so a failed session can leave it blocked.
+fn run<S, T>(host: Host, game: fn(&RoomInfo, &mut S) -> T) -> (T, Trace)
+where
+    S: Stores + 'static,
+    T: Send + 'static,
+{
+    let (tx, rx) = mpsc::channel();
+    let failed = {
+        let tx = tx.clone();
+        Box::new(move |err| _ = tx.send(Err(err)))
+    };
+    std::thread::spawn(move || {
+        RUNNING.with(|running| {
+            *running.borrow_mut() = Some(Running {
+                host,
+                pending: None,
+                failed,
+            })
+        });
+
+        let mut res = None;
+        crate::start_session(INPUT_CAP, true, |info, stores: &mut S| {
+            res = Some(game(info, stores));
+            Ok(())
+        });
+
+        let trace = RUNNING.with(|running| match running.borrow_mut().take().unwrap().host {
+            Host::Admin { trace, .. } | Host::Player { trace, .. } => trace,
+        });
+        _ = tx.send(Ok((res.unwrap(), trace)));
+    });
+
+    match rx.recv().unwrap() {
+        Ok(res) => res,
+        Err(err) => panic!("session failed: {err}"),
+    }
+}
+
+/// Reports the failure to `run`, never returning as the game can't go on.
+fn fail(err: String) -> ! {
+    RUNNING.with(|running| (running.borrow().as_ref().unwrap().failed)(err));
+    loop {
+        std::thread::park();
+    }
+}
+
+impl Host {
+    fn io(&mut self, raw: &str) -> Result<String, String> {
+        let output: Output<Box<RawValue>> =
+            serde_json::from_str(raw).map_err(|err| format!("malformed output {raw}: {err}"))?;
+        if let Output::Error(err) = output {
+            return Err(format!("game error: {}", err.message));
+        }
+
+        match self {
+            Host::Admin { local, info, trace } => {
+                let input = block_on(answer(local, info, output))
+                    .map_err(|err| format!("host failed on {raw}: {err:#}"))?;
+                trace.push((raw.into(), input.clone()));
+                Ok(input)
+            }
+            Host::Player {
+                player,
+                trace,
+                cursor,
+            } => {
+                let (expected, input) = trace
+                    .get(*cursor)
+                    .ok_or_else(|| format!("{player} performed {raw} after the host ended"))?;
+                if raw != expected {
+                    return Err(format!(
+                        "{player} performed {raw} where host did {expected}"
+                    ));
+                }
+                *cursor += 1;
+
+                match output {
+                    Output::DoTaskIf { allowed } if !allowed.contains(player) => {
+                        let (targets, value) = skip_task(trace, cursor);
+                        let res = if targets.contains(player) {
+                            TaskResult::SyncResult(value)
+                        } else {
+                            TaskResult::Restricted
+                        };
+                        Ok(serde_json::to_string(&res).unwrap())
+                    }
+                    _ => Ok(input.clone()),
+                }
+            }
+        }
+    }
+}
+
+/// Moves the cursor past the task just started, returning its `TaskDone`.
+fn skip_task(trace: &Trace, cursor: &mut usize) -> (Vec<PlayerId>, Box<RawValue>) {
+    let mut depth = 0;
+    loop {
+        let (raw, _) = &trace[*cursor];
+        *cursor += 1;
+        match serde_json::from_str(raw).unwrap() {
+            Output::<Box<RawValue>>::DoTaskIf { .. } => depth += 1,
+            Output::TaskDone { targets, value } if depth == 0 => return (targets, value),
+            Output::TaskDone { .. } => depth -= 1,
+            _ => {}
+        }
+    }
+}
+
+/// Input to the output, like the runtime answers it with the handler.
+async fn answer(
+    local: &mut LocalRoom,
+    info: &RoomInfo,
+    output: Output<Box<RawValue>>,
+) -> anyhow::Result<String> {
+    let json = match output {
+        Output::Error(_) => unreachable!("failed before"),
+        Output::SessionStart => serde_json::to_string(info)?,
+        Output::UpdateState(state) => {
+            local.state(&state)?;
+            "null".into()
+        }
+        Output::UpdatePrivateState {
+            name,
+            target,
+            state,
+        } => {
+            local.private_state(&name, target, &state)?;
+            "null".into()
+        }
+        Output::SessionEnd { .. }
+        | Output::PatchState(_)
+        | Output::DebugSnapshot { .. }
+        | Output::GameResult(_) => "null".into(),
+        Output::DoTaskIf { allowed } => serde_json::to_string(&local.do_task_if(allowed).await?)?,
+        Output::TaskDone { targets, value } => {
+            local.task_done(targets, &value).await?;
+            "null".into()
+        }
+        Output::Notify { targets, value } => {
+            local.notify(targets, &value).await?;
+            "null".into()
+        }
+        Output::Random { start, end } => serde_json::to_string(&local.random(start, end).await?)?,
+        Output::RandomBytes { len } => serde_json::to_string(&local.random_bytes(len).await?)?,
+        Output::Action { from, param } => local.action(from, &param).await?.get().into(),
+        Output::ActionWithDeadline {
+            from,
+            param,
+            deadline_ms,
+        } => {
+            let deadline = std::time::Duration::from_millis(deadline_ms);
+            serde_json::to_string(&local.action_with_deadline(from, &param, deadline).await?)?
+        }
+        Output::FirstAction {
+            from,
+            param,
+            deadline_ms,
+        } => {
+            let deadline = deadline_ms.map(std::time::Duration::from_millis);
+            serde_json::to_string(&local.first_action(from, &param, deadline).await?)?
+        }
+        Output::SimultaneousAction { from, param } => {
+            local.simultaneous_action(from, &param).await?.get().into()
+        }
+        Output::GatherActions { requests } => local.gather_actions(requests).await?.get().into(),
+        Output::ForfeitedPlayers => serde_json::to_string(&local.forfeited_players().await?)?,
+    };
+    Ok(json)
+}
+
+/// Runs `f` on the host of the current thread, failing the session on errors and panics.
+fn with_host<T>(f: impl FnOnce(&mut Running) -> Result<T, String>) -> T {
+    let res = catch_unwind(AssertUnwindSafe(|| {
+        RUNNING.with(|running| f(running.borrow_mut().as_mut().expect("no game is running")))
+    }));
+    match res {
+        Ok(Ok(v)) => v,
+        Ok(Err(err)) => fail(err),
+        Err(_) => fail("host panicked".into()),
+    }
+}
+
+#[no_mangle]
+extern "C" fn rulebook_trigger_io(params: *const IoParams) -> usize {
+    let params = unsafe { &*params };
+    let output = unsafe { std::slice::from_raw_parts(params.output_ptr, params.output_len) };
+
+    with_host(|running| {
+        let output = std::str::from_utf8(output).map_err(|err| err.to_string())?;
+        let input = running.host.io(output)?;
+        if input.len() <= params.input_cap {
+            unsafe { std::ptr::copy_nonoverlapping(input.as_ptr(), params.input_ptr, input.len()) };
+        }
+        let len = input.len();
+        running.pending = Some(input).filter(|_| len > params.input_cap);
+        Ok(len)
+    })
+}
+
+#[no_mangle]
+extern "C" fn rulebook_take_input(input_ptr: *mut u8, input_cap: usize) -> usize {
+    with_host(|running| {
+        let input = running
+            .pending
+            .take()
+            .ok_or("took input while none is pending")?;
+        if input.len() > input_cap {
+            return Err(format!(
+                "input of {} bytes exceeds {input_cap}",
+                input.len()
+            ));
+        }
+        unsafe { std::ptr::copy_nonoverlapping(input.as_ptr(), input_ptr, input.len()) };
+        Ok(input.len())
+    })
+}
+
+#[no_mangle]
+extern "C" fn rulebook_log(_msg_ptr: *const u8, _msg_len: usize) {}
+
+#[no_mangle]
+extern "C" fn rulebook_log_at(_level: u32, _msg_ptr: *const u8, _msg_len: usize) {}